@@ -26,7 +26,7 @@ impl Span {
 #[derive(Debug)]
 pub struct MimicError {
     pub span: Option<Span>,
-    pub source: Option<SimpleFile<String, String>>,
+    pub source: Option<Box<SimpleFile<String, String>>>,
     pub ty: MimicErrorType,
 }
 
@@ -40,6 +40,18 @@ pub enum MimicErrorType {
         token: String,
     },
 
+    InvalidLiteral {
+        literal: String,
+    },
+
+    InvalidEscape {
+        sequence: String,
+    },
+
+    InvalidOperands {
+        mnemonic: String,
+    },
+
     UnknownRegister {
         register_name: String,
     },
@@ -60,6 +72,84 @@ pub enum MimicErrorType {
         address: usize,
     },
 
+    UnknownSyscall {
+        service: u32,
+    },
+
+    FileWriteFailed {
+        filename: PathBuf,
+    },
+
+    UnalignedAccess {
+        address: usize,
+    },
+
+    InvalidDeviceMapping {
+        address: u32,
+        len: u32,
+    },
+
+    InvalidScript {
+        message: String,
+    },
+
+    DialogScriptExhausted {
+        prompt: String,
+    },
+
+    InvalidExpression {
+        message: String,
+    },
+
+    UnknownSymbol {
+        name: String,
+    },
+
+    InvalidSnapshot {
+        message: String,
+    },
+
+    UnsupportedSnapshotVersion {
+        version: u32,
+    },
+
+    InvalidTrace {
+        message: String,
+    },
+
+    TraceDiverged {
+        instruction: u64,
+        message: String,
+    },
+
+    InvalidDirective {
+        message: String,
+    },
+
+    ValueOutOfRange {
+        value: i64,
+        min: i64,
+        max: i64,
+    },
+
+    InvalidMacro {
+        message: String,
+    },
+
+    SyntaxError {
+        message: String,
+    },
+
+    DuplicateSymbol {
+        name: String,
+    },
+
+    // An error in the expansion of a macro, located at the call
+    MacroExpansion {
+        name: String,
+        error: Box<MimicError>,
+    },
+
 }
 
 impl MimicError {
@@ -73,6 +163,18 @@ impl MimicError {
                 format!("Unknown token [{}]", token)
             },
 
+            MimicErrorType::InvalidLiteral { literal } => {
+                format!("Integer literal [{}] does not fit in 32 bits", literal)
+            },
+
+            MimicErrorType::InvalidEscape { sequence } => {
+                format!("Invalid escape sequence [{}]", sequence)
+            },
+
+            MimicErrorType::InvalidOperands { mnemonic } => {
+                format!("Invalid operands for [{}]", mnemonic)
+            },
+
             MimicErrorType::UnknownRegister { register_name } => {
                 format!("Unknown register name [{}]", register_name)
             },
@@ -96,11 +198,98 @@ impl MimicError {
             MimicErrorType::MemoryOutOfBounds { address } => {
                 format!("Out of bounds memory access at address {}", address)
             },
+
+            MimicErrorType::UnknownSyscall { service } => {
+                format!("Unknown syscall service {:#04X}", service)
+            },
+
+            MimicErrorType::FileWriteFailed { filename } => {
+                format!("Unable to write file [{:?}]", filename)
+            },
+
+            MimicErrorType::UnalignedAccess { address } => {
+                format!("Unaligned memory access at address {:#010X}", address)
+            },
+
+            MimicErrorType::InvalidDeviceMapping { address, len } => {
+                format!("Unable to map a device of {} bytes at address {:#010X}", len, address)
+            },
+
+            MimicErrorType::InvalidScript { message } => {
+                format!("Invalid script: {}", message)
+            },
+
+            MimicErrorType::DialogScriptExhausted { prompt } => {
+                format!("No scripted response left for dialog [{}]", prompt)
+            },
+
+            MimicErrorType::InvalidExpression { message } => {
+                format!("Invalid expression: {}", message)
+            },
+
+            MimicErrorType::UnknownSymbol { name } => {
+                format!("Unknown symbol [{}]", name)
+            },
+
+            MimicErrorType::InvalidSnapshot { message } => {
+                format!("Invalid snapshot: {}", message)
+            },
+
+            MimicErrorType::UnsupportedSnapshotVersion { version } => {
+                format!("Unsupported snapshot version {}", version)
+            },
+
+            MimicErrorType::InvalidTrace { message } => {
+                format!("Invalid trace: {}", message)
+            },
+
+            MimicErrorType::TraceDiverged { instruction, message } => {
+                format!("Replay diverged from the trace at instruction {}: {}", instruction, message)
+            },
+
+            MimicErrorType::InvalidDirective { message } => {
+                format!("Invalid directive: {}", message)
+            },
+
+            MimicErrorType::ValueOutOfRange { value, min, max } => {
+                format!("Value {} is out of range [{}, {}]", value, min, max)
+            },
+
+            MimicErrorType::InvalidMacro { message } => {
+                format!("Invalid macro: {}", message)
+            },
+
+            MimicErrorType::SyntaxError { message } => {
+                format!("Syntax error: {}", message)
+            },
+
+            MimicErrorType::DuplicateSymbol { name } => {
+                format!("Symbol [{}] is already defined", name)
+            },
+
+            MimicErrorType::MacroExpansion { name, error } => {
+                format!("{} (in macro [{}])", error.msg(), name)
+            },
         }
     }
 
     pub fn emit(&self) {
-        if let Some(f) = &self.source {
+        // The error inside the macro body, then where the macro was called
+        if let MimicErrorType::MacroExpansion { name, error } = &self.ty {
+            error.emit();
+            if let (Some(f), Some(span)) = (self.source.as_deref(), &self.span) {
+                let note = Diagnostic::note()
+                    .with_message(format!("In expansion of macro [{}]", name))
+                    .with_labels(vec![
+                        Label::primary((), span.range()).with_message("called here"),
+                    ]);
+                let writer = StandardStream::stderr(ColorChoice::Always);
+                term::emit(&mut writer.lock(), &Config{ ..Default::default()}, f, &note).expect("Unable to print error message");
+            }
+            return;
+        }
+
+        if let Some(f) = self.source.as_deref() {
             let e = match &self.ty {
                 MimicErrorType::UnknownToken { token } => {
                     Diagnostic::error()
@@ -111,6 +300,24 @@ impl MimicError {
                         ])
                 },
                 
+                MimicErrorType::InvalidScript { .. }
+                | MimicErrorType::InvalidLiteral { .. }
+                | MimicErrorType::InvalidEscape { .. }
+                | MimicErrorType::InvalidOperands { .. }
+                | MimicErrorType::InvalidExpression { .. }
+                | MimicErrorType::UnknownSymbol { .. }
+                | MimicErrorType::InvalidDirective { .. }
+                | MimicErrorType::ValueOutOfRange { .. }
+                | MimicErrorType::InvalidMacro { .. }
+                | MimicErrorType::SyntaxError { .. }
+                | MimicErrorType::DuplicateSymbol { .. } => {
+                    Diagnostic::error()
+                        .with_message(self.msg())
+                        .with_labels(vec![
+                            Label::primary((), self.span.as_ref().unwrap().range()),
+                        ])
+                },
+
                 _ => Diagnostic::error()
                         .with_message(self.msg()),
 
@@ -1,10 +1,32 @@
-use mimic_emulator::mips32::assembler::assemble_from_file;
+use mimic_emulator::mips32::assembler::{assemble_program_from_file_with_options, AssemblerOptions};
 
 use std::fs;
 
 pub fn main() {
 
-    let (text_bytes, _data_bytes) = assemble_from_file("test_files/mips32/bouncy.asm").unwrap();
+    // -D NAME=value or -DNAME=value sets a symbol for conditional assembly
+    let mut options = AssemblerOptions::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix("-D") {
+            Some("") => {
+                if let Some(definition) = args.next() {
+                    options = options.with_definition(&definition);
+                }
+            },
+            Some(definition) => options = options.with_definition(definition),
+            None => eprintln!("Ignoring argument [{}]", arg),
+        }
+    }
+
+    let program = match assemble_program_from_file_with_options("test_files/mips32/bouncy.asm", &options) {
+        Ok(program) => program,
+        Err(e) => {
+            e.emit();
+            std::process::exit(1);
+        },
+    };
+    let text_bytes = program.text;
 
     let text_bytes_correct = fs::read("test_files/mips32/bouncy.text").unwrap();
     let _data_bytes_correct = fs::read("test_files/mips32/bouncy.data").unwrap();
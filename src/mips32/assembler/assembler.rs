@@ -1,10 +1,13 @@
 use super::parser::{Stmt, Stmt_, Expr, Expr_};
-use super::lexer::Directive;
-use super::register_name_to_number;
+use super::lexer::{unescape, Directive};
+use super::pseudo::expand_pseudo;
+use super::expression::{check_range, evaluate, evaluate_field, expression_error, references_symbol, Field};
+use super::{register_name_to_number, Program};
 
 use crate::errors::{Span, MimicError, MimicErrorType};
+use crate::mips32::symbols::{SourceMap, SymbolTable};
 
-use codespan_reporting::files::SimpleFile;
+use codespan_reporting::files::{Files, SimpleFile};
 
 use std::collections::HashMap;
 
@@ -13,7 +16,6 @@ use std::collections::HashMap;
 struct Instruction {
     span: Span,
     source: SimpleFile<String, String>,
-    label: Option<String>,
     mnemonic: Expr,
     args: Vec<Expr>,
     inst: u32,
@@ -28,14 +30,13 @@ impl Instruction {
                 node: Expr_::Ident(mnemonic)
             },
             args: vec![],
-            label: None,
         }
     }
 
     fn unimplemented_instruction(&self) -> Result<(), MimicError> {
         Err(MimicError {
             span: Some(self.span),
-            source: Some(self.source.clone()),
+            source: Some(Box::new(self.source.clone())),
             ty: MimicErrorType::UnimplementedInstruction {
                 mnemonic: self.get_mnemonic()
             }
@@ -44,161 +45,391 @@ impl Instruction {
 
 
 
-    fn build_bytecode(&mut self, text_labels: &HashMap<String, u32>, location: usize) -> Result<(), MimicError> {
+    fn build_bytecode(&mut self, symbols: &HashMap<String, u32>, address: u32) -> Result<(), MimicError> {
         let mnemonic = self.get_mnemonic();
         match mnemonic.as_str() {
 
-            "add" => self.unimplemented_instruction()?,
+            "add" => {
+                let (rd, rs, rt) = self.parse_rtype()?;
+                let inst: u32 = (rs << 21) | (rt << 16) | (rd << 11) | 0x20;
+                self.inst = inst;
+            }
             "addi" => {
-                let (rt, rs, imm) = self.parse_itype1()?;
+                let (rt, rs, imm) = self.parse_itype1(Field::Signed(16), symbols)?;
                 let inst: u32 = (0x08 << 26) | (rs << 21) | (rt << 16) | imm;
                 self.inst = inst;
             }
             "addiu" => {
-                let (rt, rs, imm) = self.parse_itype1()?;
+                let (rt, rs, imm) = self.parse_itype1(Field::Signed(16), symbols)?;
                 let inst: u32 = (0x09 << 26) | (rs << 21) | (rt << 16) | imm;
                 self.inst = inst;
             }
             "addu" => {
                 let (rd, rs, rt) = self.parse_rtype()?;
-                let inst: u32 = 0 | (rs << 21) | (rt << 16) | (rd << 11) | 0x21;
+                let inst: u32 = (rs << 21) | (rt << 16) | (rd << 11) | 0x21;
+                self.inst = inst;
+            }
+            "and" => {
+                let (rd, rs, rt) = self.parse_rtype()?;
+                let inst: u32 = (rs << 21) | (rt << 16) | (rd << 11) | 0x24;
                 self.inst = inst;
             }
-            "and" => self.unimplemented_instruction()?,
             "andi" => {
-                let (rt, rs, imm) = self.parse_itype1()?;
+                let (rt, rs, imm) = self.parse_itype1(Field::Unsigned(16), symbols)?;
                 let inst: u32 = (0x0C << 26) | (rs << 21) | (rt << 16) | imm;
                 self.inst = inst;
             }
-            "b" => self.unimplemented_instruction()?,
-            "bal" => self.unimplemented_instruction()?,
             "beq" => {
-                let (rs, rt, imm) = self.parse_branch(text_labels, location)?;
-                let inst: u32 = (0x04 << 26) | (rs << 21) | (rt << 16) | (imm - 1);
+                let (rs, rt, imm) = self.parse_branch(symbols, address)?;
+                let inst: u32 = (0x04 << 26) | (rs << 21) | (rt << 16) | imm;
                 self.inst = inst;
             }
             "beql" => self.unimplemented_instruction()?,
-            "bgez" => self.unimplemented_instruction()?,
-            "bgezal" => self.unimplemented_instruction()?,
+            "bgez" => {
+                let rs = self.get_register_arg(0)?;
+                let imm = self.branch_offset(1, symbols, address)?;
+                let inst: u32 = (0x01 << 26) | (rs << 21) | (0x01 << 16) | imm;
+                self.inst = inst;
+            }
+            "bgezal" => {
+                let rs = self.get_register_arg(0)?;
+                let imm = self.branch_offset(1, symbols, address)?;
+                let inst: u32 = (0x01 << 26) | (rs << 21) | (0x11 << 16) | imm;
+                self.inst = inst;
+            }
             "bgezall" => self.unimplemented_instruction()?,
             "bgezl" => self.unimplemented_instruction()?,
-            "bgtz" => self.unimplemented_instruction()?,
+            "bgtz" => {
+                let rs = self.get_register_arg(0)?;
+                let imm = self.branch_offset(1, symbols, address)?;
+                let inst: u32 = (0x07 << 26) | (rs << 21) | (0x00 << 16) | imm;
+                self.inst = inst;
+            }
             "bgtzl" => self.unimplemented_instruction()?,
-            "blez" => self.unimplemented_instruction()?,
+            "blez" => {
+                let rs = self.get_register_arg(0)?;
+                let imm = self.branch_offset(1, symbols, address)?;
+                let inst: u32 = (0x06 << 26) | (rs << 21) | (0x00 << 16) | imm;
+                self.inst = inst;
+            }
             "blezl" => self.unimplemented_instruction()?,
-            "bltz" => self.unimplemented_instruction()?,
-            "bltzal" => self.unimplemented_instruction()?,
+            "bltz" => {
+                let rs = self.get_register_arg(0)?;
+                let imm = self.branch_offset(1, symbols, address)?;
+                let inst: u32 = (0x01 << 26) | (rs << 21) | (0x00 << 16) | imm;
+                self.inst = inst;
+            }
+            "bltzal" => {
+                let rs = self.get_register_arg(0)?;
+                let imm = self.branch_offset(1, symbols, address)?;
+                let inst: u32 = (0x01 << 26) | (rs << 21) | (0x10 << 16) | imm;
+                self.inst = inst;
+            }
             "bltzall" => self.unimplemented_instruction()?,
             "bltzl" => self.unimplemented_instruction()?,
             "bne" => {
-                let (rs, rt, imm) = self.parse_branch(text_labels, location)?;
-                let inst: u32 = (0x05 << 26) | (rs << 21) | (rt << 16) | (imm - 1);
+                let (rs, rt, imm) = self.parse_branch(symbols, address)?;
+                let inst: u32 = (0x05 << 26) | (rs << 21) | (rt << 16) | imm;
                 self.inst = inst;
             }
             "bnel" => self.unimplemented_instruction()?,
-            "break" => self.unimplemented_instruction()?,
-            "div" => self.unimplemented_instruction()?,
-            "divu" => self.unimplemented_instruction()?,
+            "break" => {
+                self.inst = 0x0000000D;
+            }
+            "div" => {
+                let rs = self.get_register_arg(0)?;
+                let rt = self.get_register_arg(1)?;
+                let inst: u32 = (rs << 21) | (rt << 16) | 0x1A;
+                self.inst = inst;
+            }
+            "divu" => {
+                let rs = self.get_register_arg(0)?;
+                let rt = self.get_register_arg(1)?;
+                let inst: u32 = (rs << 21) | (rt << 16) | 0x1B;
+                self.inst = inst;
+            }
             "j" => {
-                let index = self.parse_jump(text_labels)?;
-                let inst: u32 = (0x02 << 26) | (index + 0x00100000);
+                let index = self.parse_jump(symbols, address)?;
+                let inst: u32 = (0x02 << 26) | (index & 0x03FFFFFF);
+                self.inst = inst;
+            }
+            "jal" => {
+                let index = self.parse_jump(symbols, address)?;
+                let inst: u32 = (0x03 << 26) | (index & 0x03FFFFFF);
+                self.inst = inst;
+            }
+            "jalr" => {
+                // jalr $rs defaults to linking through $ra
+                let (rd, rs) = if self.args.len() == 1 {
+                    (31, self.get_register_arg(0)?)
+                } else {
+                    (self.get_register_arg(0)?, self.get_register_arg(1)?)
+                };
+                let inst: u32 = (rs << 21) | (rd << 11) | 0x09;
+                self.inst = inst;
+            }
+            "jr" => {
+                let rs = self.get_register_arg(0)?;
+                let inst: u32 = (rs << 21) | 0x08;
+                self.inst = inst;
+            }
+            "lb" => {
+                let (rt, base, offset) = self.parse_memory(symbols)?;
+                let inst: u32 = (0x20 << 26) | (base << 21) | (rt << 16) | offset;
+                self.inst = inst;
+            }
+            "lbu" => {
+                let (rt, base, offset) = self.parse_memory(symbols)?;
+                let inst: u32 = (0x24 << 26) | (base << 21) | (rt << 16) | offset;
+                self.inst = inst;
+            }
+            "lh" => {
+                let (rt, base, offset) = self.parse_memory(symbols)?;
+                let inst: u32 = (0x21 << 26) | (base << 21) | (rt << 16) | offset;
+                self.inst = inst;
+            }
+            "lhu" => {
+                let (rt, base, offset) = self.parse_memory(symbols)?;
+                let inst: u32 = (0x25 << 26) | (base << 21) | (rt << 16) | offset;
                 self.inst = inst;
             }
-            "jal" => self.unimplemented_instruction()?,
-            "jalr" => self.unimplemented_instruction()?,
-            "jr" => self.unimplemented_instruction()?,
-            "lb" => self.unimplemented_instruction()?,
-            "lbu" => self.unimplemented_instruction()?,
-            "lh" => self.unimplemented_instruction()?,
-            "lhu" => self.unimplemented_instruction()?,
             "ll" => self.unimplemented_instruction()?,
             "lui" => {
-                let (rt, imm) = self.parse_itype2()?;
+                let (rt, imm) = self.parse_itype2(Field::Unsigned(16), symbols)?;
                 let inst: u32 = (0x0F << 26) | (rt << 16) | imm;
                 self.inst = inst;
             }
-            "lw" => self.unimplemented_instruction()?,
+            "lw" => {
+                let (rt, base, offset) = self.parse_memory(symbols)?;
+                let inst: u32 = (0x23 << 26) | (base << 21) | (rt << 16) | offset;
+                self.inst = inst;
+            }
+            "lwl" => {
+                let (rt, base, offset) = self.parse_memory(symbols)?;
+                let inst: u32 = (0x22 << 26) | (base << 21) | (rt << 16) | offset;
+                self.inst = inst;
+            }
+            "lwr" => {
+                let (rt, base, offset) = self.parse_memory(symbols)?;
+                let inst: u32 = (0x26 << 26) | (base << 21) | (rt << 16) | offset;
+                self.inst = inst;
+            }
             "madd" => self.unimplemented_instruction()?,
             "maddu" => self.unimplemented_instruction()?,
-            "mfhi" => self.unimplemented_instruction()?,
-            "mflo" => self.unimplemented_instruction()?,
+            "mfhi" => {
+                let rd = self.get_register_arg(0)?;
+                self.inst = (rd << 11) | 0x10;
+            }
+            "mflo" => {
+                let rd = self.get_register_arg(0)?;
+                self.inst = (rd << 11) | 0x12;
+            }
             "msub" => self.unimplemented_instruction()?,
             "msubu" => self.unimplemented_instruction()?,
-            "mthi" => self.unimplemented_instruction()?,
-            "mtlo" => self.unimplemented_instruction()?,
-            "mul" => self.unimplemented_instruction()?,
-            "mult" => self.unimplemented_instruction()?,
-            "nop" => self.unimplemented_instruction()?,
-            "nor" => self.unimplemented_instruction()?,
+            "mthi" => {
+                let rs = self.get_register_arg(0)?;
+                self.inst = (rs << 21) | 0x11;
+            }
+            "mtlo" => {
+                let rs = self.get_register_arg(0)?;
+                self.inst = (rs << 21) | 0x13;
+            }
+            "mul" => {
+                let (rd, rs, rt) = self.parse_rtype()?;
+                let inst: u32 = (0x1C << 26) | (rs << 21) | (rt << 16) | (rd << 11) | 0x02;
+                self.inst = inst;
+            }
+            "mult" => {
+                let rs = self.get_register_arg(0)?;
+                let rt = self.get_register_arg(1)?;
+                let inst: u32 = (rs << 21) | (rt << 16) | 0x18;
+                self.inst = inst;
+            }
+            "multu" => {
+                let rs = self.get_register_arg(0)?;
+                let rt = self.get_register_arg(1)?;
+                let inst: u32 = (rs << 21) | (rt << 16) | 0x19;
+                self.inst = inst;
+            }
+            "nor" => {
+                let (rd, rs, rt) = self.parse_rtype()?;
+                let inst: u32 = (rs << 21) | (rt << 16) | (rd << 11) | 0x27;
+                self.inst = inst;
+            }
             "or" => {
                 let (rd, rs, rt) = self.parse_rtype()?;
-                let inst: u32 = 0 | (rs << 21) | (rt << 16) | (rd << 11) | 0x25;
+                let inst: u32 = (rs << 21) | (rt << 16) | (rd << 11) | 0x25;
                 self.inst = inst;
             }
             "ori" => {
-                let (rt, rs, imm) = self.parse_itype1()?;
+                let (rt, rs, imm) = self.parse_itype1(Field::Unsigned(16), symbols)?;
                 let inst: u32 = (0x0D << 26) | (rs << 21) | (rt << 16) | imm;
-                // println!("****{} - {:08x}", line, inst);
                 self.inst = inst;
             }
-            "sb" => self.unimplemented_instruction()?,
-            "sh" => self.unimplemented_instruction()?,
+            "sb" => {
+                let (rt, base, offset) = self.parse_memory(symbols)?;
+                let inst: u32 = (0x28 << 26) | (base << 21) | (rt << 16) | offset;
+                self.inst = inst;
+            }
+            "sh" => {
+                let (rt, base, offset) = self.parse_memory(symbols)?;
+                let inst: u32 = (0x29 << 26) | (base << 21) | (rt << 16) | offset;
+                self.inst = inst;
+            }
             "sll" => {
-                let (rd, rt, shmt) = self.parse_itype1()?;
-                let inst: u32 = 0 | (rt << 16) | (rd << 11) | (shmt << 6);
+                let (rd, rt, shamt) = self.parse_itype1(Field::Unsigned(5), symbols)?;
+                let inst: u32 = (rt << 16) | (rd << 11) | (shamt << 6) | 0x00;
+                self.inst = inst;
+            }
+            "sllv" => {
+                let (rd, rt, rs) = self.parse_rtype()?;
+                let inst: u32 = (rs << 21) | (rt << 16) | (rd << 11) | 0x04;
                 self.inst = inst;
             }
-            "sllv" => self.unimplemented_instruction()?,
             "slt" => {
                 let (rd, rs, rt) = self.parse_rtype()?;
-                let inst: u32 = 0 | (rs << 21) | (rt << 16) | (rd << 11) | 0x2A;
-                self.inst = inst;
-            }
-            "slti" => self.unimplemented_instruction()?,
-            "sltiu" => self.unimplemented_instruction()?,
-            "sltu" => self.unimplemented_instruction()?,
-            "sra" => self.unimplemented_instruction()?,
-            "srav" => self.unimplemented_instruction()?,
-            "srl" => self.unimplemented_instruction()?,
-            "srlv" => self.unimplemented_instruction()?,
-            "sub" => self.unimplemented_instruction()?,
-            "subu" => self.unimplemented_instruction()?,
-            "sw" => self.unimplemented_instruction()?,
-            "swl" => self.unimplemented_instruction()?,
-            "swr" => self.unimplemented_instruction()?,
+                let inst: u32 = (rs << 21) | (rt << 16) | (rd << 11) | 0x2A;
+                self.inst = inst;
+            }
+            "slti" => {
+                let (rt, rs, imm) = self.parse_itype1(Field::Signed(16), symbols)?;
+                let inst: u32 = (0x0A << 26) | (rs << 21) | (rt << 16) | imm;
+                self.inst = inst;
+            }
+            "sltiu" => {
+                let (rt, rs, imm) = self.parse_itype1(Field::Signed(16), symbols)?;
+                let inst: u32 = (0x0B << 26) | (rs << 21) | (rt << 16) | imm;
+                self.inst = inst;
+            }
+            "sltu" => {
+                let (rd, rs, rt) = self.parse_rtype()?;
+                let inst: u32 = (rs << 21) | (rt << 16) | (rd << 11) | 0x2B;
+                self.inst = inst;
+            }
+            "sra" => {
+                let (rd, rt, shamt) = self.parse_itype1(Field::Unsigned(5), symbols)?;
+                let inst: u32 = (rt << 16) | (rd << 11) | (shamt << 6) | 0x03;
+                self.inst = inst;
+            }
+            "srav" => {
+                let (rd, rt, rs) = self.parse_rtype()?;
+                let inst: u32 = (rs << 21) | (rt << 16) | (rd << 11) | 0x07;
+                self.inst = inst;
+            }
+            "srl" => {
+                let (rd, rt, shamt) = self.parse_itype1(Field::Unsigned(5), symbols)?;
+                let inst: u32 = (rt << 16) | (rd << 11) | (shamt << 6) | 0x02;
+                self.inst = inst;
+            }
+            "srlv" => {
+                let (rd, rt, rs) = self.parse_rtype()?;
+                let inst: u32 = (rs << 21) | (rt << 16) | (rd << 11) | 0x06;
+                self.inst = inst;
+            }
+            "sub" => {
+                let (rd, rs, rt) = self.parse_rtype()?;
+                let inst: u32 = (rs << 21) | (rt << 16) | (rd << 11) | 0x22;
+                self.inst = inst;
+            }
+            "subu" => {
+                let (rd, rs, rt) = self.parse_rtype()?;
+                let inst: u32 = (rs << 21) | (rt << 16) | (rd << 11) | 0x23;
+                self.inst = inst;
+            }
+            "sw" => {
+                let (rt, base, offset) = self.parse_memory(symbols)?;
+                let inst: u32 = (0x2B << 26) | (base << 21) | (rt << 16) | offset;
+                self.inst = inst;
+            }
+            "swl" => {
+                let (rt, base, offset) = self.parse_memory(symbols)?;
+                let inst: u32 = (0x2A << 26) | (base << 21) | (rt << 16) | offset;
+                self.inst = inst;
+            }
+            "swr" => {
+                let (rt, base, offset) = self.parse_memory(symbols)?;
+                let inst: u32 = (0x2E << 26) | (base << 21) | (rt << 16) | offset;
+                self.inst = inst;
+            }
             "syscall" => {
                 self.inst = 0x0000000C;
             }
             "xor" => {
                 let (rd, rs, rt) = self.parse_rtype()?;
-                let inst: u32 = 0 | (rs << 21) | (rt << 16) | (rd << 11) | 0x26;
+                let inst: u32 = (rs << 21) | (rt << 16) | (rd << 11) | 0x26;
+                self.inst = inst;
+            }
+            "xori" => {
+                let (rt, rs, imm) = self.parse_itype1(Field::Unsigned(16), symbols)?;
+                let inst: u32 = (0x0E << 26) | (rs << 21) | (rt << 16) | imm;
                 self.inst = inst;
             }
-            "xori" => self.unimplemented_instruction()?,
 
 
             _ => return Err(MimicError {
                 span: Some(self.mnemonic.span),
-                source: Some(self.source.clone()),
+                source: Some(Box::new(self.source.clone())),
                 ty: MimicErrorType::UnknownMnemonic { mnemonic }
             })
         }
         Ok(())
     }
 
-    fn parse_branch(&self, text_labels: &HashMap<String, u32>, location: usize) -> Result<(u32, u32, u32), MimicError> {
+    fn parse_branch(&self, symbols: &HashMap<String, u32>, address: u32) -> Result<(u32, u32, u32), MimicError> {
         let rs = self.get_register_arg(0)?;
         let rt = self.get_register_arg(1)?;
-        let label = self.get_ident_arg(2)?;
+        let offset = self.branch_offset(2, symbols, address)?;
 
-        let offset = (text_labels[&label] as i32 - location as i32) as u32 & 0x0000FFFF;
-        
         Ok((rs, rt, offset))
     }
 
-    fn parse_jump(&self, text_labels: &HashMap<String, u32>) -> Result<u32, MimicError> {
-        let label = self.get_ident_arg(0)?;
-        Ok(text_labels[&label] as u32)
+    // Offsets count words from the instruction after the branch
+    fn branch_offset(&self, index: usize, symbols: &HashMap<String, u32>, address: u32) -> Result<u32, MimicError> {
+        let target = self.get_expression_arg(index)?;
+
+        let offset = evaluate(target, symbols, &self.source)? - (address as i64 + 4);
+        if offset % 4 != 0 {
+            return Err(expression_error(target.span, &self.source, "branch target is not word aligned"));
+        }
+        check_range(offset >> 2, Field::Signed(16), target.span, &self.source)?;
+
+        Ok((offset >> 2) as u32 & 0x0000FFFF)
+    }
+
+    fn parse_jump(&self, symbols: &HashMap<String, u32>, address: u32) -> Result<u32, MimicError> {
+        let target = self.get_expression_arg(0)?;
+        let value = evaluate_field(target, Field::Any(32), symbols, &self.source)?;
+
+        if value % 4 != 0 {
+            Err(expression_error(target.span, &self.source, "jump target is not word aligned"))
+        } else if (value ^ address.wrapping_add(4)) & 0xF0000000 != 0 {
+            Err(expression_error(target.span, &self.source, "jump target is outside the current 256MB region"))
+        } else {
+            Ok(value >> 2)
+        }
+    }
+
+    // `rt, offset(base)`, as left by expand_instructions
+    fn parse_memory(&self, symbols: &HashMap<String, u32>) -> Result<(u32, u32, u32), MimicError> {
+        let rt = self.get_register_arg(0)?;
+        let arg = self.get_expression_arg(1)?;
+
+        if let Expr_::MemoryOperand { offset, base } = &arg.node {
+            let offset = match offset {
+                None => 0,
+                Some(offset) => evaluate_field(offset, Field::Signed(16), symbols, &self.source)?,
+            };
+            if let Expr_::Register(s) = &base.node {
+                let base = register_name_to_number(s.to_owned(), &self.source)? as u32;
+                return Ok((rt, base, offset));
+            }
+        }
+
+        Err(MimicError {
+            span: Some(arg.span),
+            source: Some(Box::new(self.source.clone())),
+            ty: MimicErrorType::IncorrectArgumentType {},
+        })
     }
 
     fn parse_rtype(&self) -> Result<(u32, u32, u32), MimicError> {
@@ -209,17 +440,17 @@ impl Instruction {
        Ok((rd, rs, rt))
     }
 
-    fn parse_itype1(&self) -> Result<(u32, u32, u32), MimicError> {
+    fn parse_itype1(&self, field: Field, symbols: &HashMap<String, u32>) -> Result<(u32, u32, u32), MimicError> {
         let rt = self.get_register_arg(0)?;
         let rs = self.get_register_arg(1)?;
-        let imm = self.get_integer_arg(2)?;
+        let imm = self.get_integer_arg(2, field, symbols)?;
 
         Ok((rt, rs, imm))
     }
 
-    fn parse_itype2(&self) -> Result<(u32, u32), MimicError> {
+    fn parse_itype2(&self, field: Field, symbols: &HashMap<String, u32>) -> Result<(u32, u32), MimicError> {
         let rt = self.get_register_arg(0)?;
-        let imm = self.get_integer_arg(1)?;
+        let imm = self.get_integer_arg(1, field, symbols)?;
 
         Ok((rt, imm))
     }
@@ -239,58 +470,29 @@ impl Instruction {
             } else {
                 Err(MimicError {
                     span: Some(arg.span),
-                    source: Some(self.source.clone()),
+                    source: Some(Box::new(self.source.clone())),
                     ty: MimicErrorType::IncorrectArgumentType {},
                 })
             }
         } else {
             Err(MimicError {
                span: Some(self.span),
-                source: Some(self.source.clone()),
+                source: Some(Box::new(self.source.clone())),
                ty: MimicErrorType::IncorrectArgument {}
             })
         }
     }
 
-    fn get_integer_arg(&self, index: usize) -> Result<u32, MimicError> {
-        return if let Some(arg) = self.args.get(index) {
-            if let Expr_::IntLiteral(i) = &arg.node {
-                // println!("get_integer_arg i: {}", i);
-                Ok(*i as u32)
-            } else {
-                Err(MimicError {
-                    span: Some(arg.span),
-                    source: Some(self.source.clone()),
-                    ty: MimicErrorType::IncorrectArgumentType {},
-                })
-            }
-        } else {
-            Err(MimicError {
-                span: Some(self.span),
-                source: Some(self.source.clone()),
-                ty: MimicErrorType::IncorrectArgument {}
-            })
-        }
+    fn get_integer_arg(&self, index: usize, field: Field, symbols: &HashMap<String, u32>) -> Result<u32, MimicError> {
+        evaluate_field(self.get_expression_arg(index)?, field, symbols, &self.source)
     }
 
-    fn get_ident_arg(&self, index: usize) -> Result<String, MimicError> {
-        return if let Some(arg) = self.args.get(index) {
-            if let Expr_::Ident(s) = &arg.node {
-                Ok(s.to_owned())
-            } else {
-                Err(MimicError {
-                    span: Some(arg.span),
-                    source: Some(self.source.clone()),
-                    ty: MimicErrorType::IncorrectArgumentType {},
-                })
-            }
-        } else {
-            Err(MimicError {
-                span: Some(self.span),
-                source: Some(self.source.clone()),
-                ty: MimicErrorType::IncorrectArgument {}
-            })
-        }
+    fn get_expression_arg(&self, index: usize) -> Result<&Expr, MimicError> {
+        self.args.get(index).ok_or_else(|| MimicError {
+            span: Some(self.span),
+            source: Some(Box::new(self.source.clone())),
+            ty: MimicErrorType::IncorrectArgument {}
+        })
     }
 }
 
@@ -298,7 +500,6 @@ struct InstructionBuilder {
     span: Span,
     mnemonic: Expr,
     args: Vec<Option<Expr>>,
-    label: Option<String>
 }
 
 impl InstructionBuilder {
@@ -306,7 +507,6 @@ impl InstructionBuilder {
         Instruction {
             span: self.span,
             source: source.clone(),
-            label: self.label.clone(),
             mnemonic: self.mnemonic.clone(),
             args: self.args.iter()
                 .map(|e| match e {
@@ -318,389 +518,450 @@ impl InstructionBuilder {
         }
     }
 
-    // pub fn with_label(&mut self, label: String) -> &mut Self {
-    //     self.label = Some(label);
-    //     self
-    // }
-
-    pub fn with_opt_label(&mut self, label: Option<String>) -> &mut Self {
-        self.label = label;
-        self
-    }
-
-    pub fn with_ident_argument(&mut self, index: usize, ident: String) -> &mut Self {
-        while self.args.len() <= index {
-            self.args.push(None);
-        }
-        self.args[index] = Some(
-            Expr {
-                span: self.span,
-                node: Expr_::Ident(ident.to_owned()),
-            },
-        );
-        self
-    }
-
-    pub fn with_register_argument(&mut self, index: usize, register: String) -> &mut Self {
-        while self.args.len() <= index {
-            self.args.push(None);
-        }
-        self.args[index] = Some(
-            Expr {
-                span: self.span,
-                node: Expr_::Register(register.to_owned()),
-            },
-        );
-        self
-    }
-
-    pub fn with_integer_argument(&mut self, index: usize, integer: u32) -> &mut Self {
+    pub fn with_expression_argument(&mut self, index: usize, expr: Expr) -> &mut Self {
         while self.args.len() <= index {
             self.args.push(None);
         }
-        self.args[index] = Some(
-            Expr {
-                span: self.span,
-                node: Expr_::IntLiteral(integer as i64),
-            },
-        );
+        self.args[index] = Some(expr);
         self
     }
 }
     
 
-#[allow(unused_assignments)]
-pub fn pack_data(data_section: Vec<Stmt>) -> (Vec<u8>, HashMap<String, u32>) {
-    let mut data_bytes: Vec<u8> = Vec::new();
-    let mut labels: HashMap<String, u32> = HashMap::new();
+// Statements of each section of a segment, with the origin given after the directive
+pub type Sections = Vec<(Option<Expr>, Vec<Stmt>)>;
 
-    let mut cur_index = 0;
+// Default start of each segment
+const TEXT_BASE: u32 = 0x00400000;
+const DATA_BASE: u32 = 0x10010000;
+const KTEXT_BASE: u32 = 0x80000000;
+const KDATA_BASE: u32 = 0x90000000;
 
+// A data value that uses a label, patched in once every label is known
+#[derive(Debug, Clone)]
+pub struct DataReference {
+    pub offset: usize,
+    pub size: usize,
+    pub value: Expr,
+}
 
-    for stmt in data_section {
+// Labels of a segment with where they are declared and their addresses
+pub type Labels = Vec<(String, Span, u32)>;
 
-        if let Stmt_::DataDeclaration { label, type_directive, data } = stmt.statement {
-            let mut label_str = String::new();
+// The data segment with its labels and the label references still to resolve
+#[derive(Debug, Clone)]
+pub struct PackedData {
+    pub bytes: Vec<u8>,
+    pub labels: Labels,
+    pub references: Vec<DataReference>,
+}
 
+// Packs the sections of one data segment, `Directive::Data` or `Directive::Kdata`
+pub fn pack_data(sections: Sections, segment: Directive, source: &SimpleFile<String, String>) -> Result<PackedData, MimicError> {
+    let (base, end) = segment_bounds(segment);
+    let capacity = (end - base) as usize;
+    let mut data_bytes: Vec<u8> = Vec::new();
+    let mut labels: Labels = Vec::new();
+    let mut references: Vec<DataReference> = Vec::new();
+    // Labels waiting for the next directive
+    let mut pending: Vec<(String, Span)> = Vec::new();
 
-            if let Expr_::Label(x) = label.node {
-                if let Expr_::Ident(s) = (*x).node {
-                    label_str = s;
-                } else {panic!("Unknown Error")}
-            } else {panic!("Unknown Error")}
 
-            if let Expr_::TypeDirectiveExpression(d) = type_directive.node {
-                match d {
-                    Directive::Asciiz => {
-                        if data.len() > 1 {panic!(".asciiz data type does not support arrays")}
-                        if data.len() == 0 {panic!(".asciiz requires a string")}
-                        if let Expr_::StringLiteral(s) = &data[0].node {
-                            // TODO replace other escaped characters
-                            let mut val = s.replace("\\n", "\n");
-                            val = val.strip_prefix("\"").unwrap().to_owned();
-                            val = val.strip_suffix("\"").unwrap().to_owned();
-                            for byte in val.as_bytes() {
-                                data_bytes.push(*byte);
-                            }
-                            data_bytes.push(0x00);
-                            // TODO: generalize data starting address
-                            labels.insert(label_str, 0x10010000 + cur_index);
-                            cur_index = data_bytes.len() as u32;
-                            continue;
+    for (origin, stmts) in sections {
+        if let Some(origin) = origin {
+            let address = section_origin(&origin, base + data_bytes.len() as u32, segment, source)?;
+            data_bytes.resize((address - base) as usize, 0x00);
+        }
 
-                        } else {panic!("Incorrect data type of .asciiz")}
-                    },
+        for stmt in stmts {
+
+            if let Stmt_::LabelDeclaration { label } = &stmt.statement {
+                pending.push(label_name(label));
+            } else if let Stmt_::DataDeclaration { type_directive, data } = stmt.statement {
+                if let Expr_::TypeDirectiveExpression(d) = type_directive.node {
+                    let values = expand_repeats(&data, capacity - data_bytes.len(), segment, source)?;
+
+                    // Values start on their natural boundary and labels point past the padding
+                    let alignment = match d {
+                        Directive::Half => 2,
+                        Directive::Word | Directive::Float => 4,
+                        Directive::Double => 8,
+                        Directive::Align => align_bytes(&data, stmt.span, source)?,
+                        _ => 1,
+                    };
+                    while !data_bytes.len().is_multiple_of(alignment) {
+                        data_bytes.push(0x00);
+                    }
+                    check_capacity(data_bytes.len(), capacity, stmt.span, segment, source)?;
+                    for (label, span) in pending.drain(..) {
+                        labels.push((label, span, base + data_bytes.len() as u32));
+                    }
 
-                    _ => todo!(),
-                }
-            } else {panic!("Incorrect type directive")}
+                    match d {
+                        Directive::Align => {},
+                        Directive::Space => {
+                            let (n, span) = single_integer(d, &data, stmt.span, source)?;
+                            if n < 0 {
+                                return Err(directive_error(span, source, ".space expects a non-negative size"));
+                            }
+                            check_capacity(data_bytes.len().saturating_add(n as usize), capacity, span, segment, source)?;
+                            data_bytes.resize(data_bytes.len() + n as usize, 0x00);
+                        },
+                        Directive::Ascii | Directive::Asciiz => {
+                            for value in values {
+                                if let Expr_::StringLiteral(s) = &value.node {
+                                    data_bytes.extend(string_literal_bytes(s, value.span, source)?);
+                                    if d == Directive::Asciiz {
+                                        data_bytes.push(0x00);
+                                    }
+                                    check_capacity(data_bytes.len(), capacity, value.span, segment, source)?;
+                                } else {
+                                    return Err(directive_error(value.span, source, "expected a string"));
+                                }
+                            }
+                        },
+                        Directive::Byte | Directive::Half | Directive::Word => {
+                            let size = match d {
+                                Directive::Byte => 1,
+                                Directive::Half => 2,
+                                _ => 4,
+                            };
+                            for value in values {
+                                if matches!(value.node, Expr_::StringLiteral(_) | Expr_::FloatLiteral(_)) {
+                                    return Err(directive_error(value.span, source, "expected an integer"));
+                                }
+                                if references_symbol(value) {
+                                    references.push(DataReference {
+                                        offset: data_bytes.len(),
+                                        size,
+                                        value: value.clone(),
+                                    });
+                                    data_bytes.extend_from_slice(&[0; 4][..size]);
+                                } else {
+                                    let i = evaluate_field(value, Field::Any(8 * size as u32), &HashMap::new(), source)?;
+                                    data_bytes.extend_from_slice(&i.to_le_bytes()[..size]);
+                                }
+                                check_capacity(data_bytes.len(), capacity, value.span, segment, source)?;
+                            }
+                        },
+                        Directive::Float | Directive::Double => {
+                            for value in values {
+                                let f = match value.node {
+                                    Expr_::FloatLiteral(f) => f,
+                                    Expr_::StringLiteral(_) => return Err(directive_error(value.span, source, "expected a number")),
+                                    _ => constant(value, source)? as f64,
+                                };
+                                if d == Directive::Float {
+                                    data_bytes.extend_from_slice(&(f as f32).to_le_bytes());
+                                } else {
+                                    data_bytes.extend_from_slice(&f.to_le_bytes());
+                                }
+                                check_capacity(data_bytes.len(), capacity, value.span, segment, source)?;
+                            }
+                        },
+                        _ => return Err(directive_error(type_directive.span, source, "not a data directive")),
+                    }
+                } else {panic!("Incorrect type directive")}
 
 
 
-        } else {
-            panic!("Incorrect statement in data section");
+            } else {
+                return Err(directive_error(stmt.span, source, &format!("instructions can't be placed in .{:?}", segment).to_lowercase()));
+            }
         }
     }
 
-
-    (data_bytes, labels)
-}
-
-#[allow(unused_assignments)]
-fn expand_instructions(text_section: Vec<Stmt>, data_labels: &HashMap<String, u32>, source: &SimpleFile<String, String>) -> Vec<Instruction> {
-    let mut instructions: Vec<Instruction> = Vec::new();
-    let mut cur_label: Option<String> = None;
+    // Labels at the end of the segment
+    for (label, span) in pending {
+        labels.push((label, span, base + data_bytes.len() as u32));
+    }
 
 
-    for inst_stmt in text_section {
-        let span = inst_stmt.span;
+    Ok(PackedData {
+        bytes: data_bytes,
+        labels,
+        references,
+    })
+}
 
-        if let Stmt_::Instruction { mnemonic, args } = &inst_stmt.statement {
+// Second pass over the data segment, once text and data labels are known
+fn resolve_data_references(
+    data_bytes: &mut [u8],
+    references: Vec<DataReference>,
+    addresses: &HashMap<String, u32>,
+    source: &SimpleFile<String, String>,
+) -> Result<(), MimicError> {
+    for reference in references {
+        let value = evaluate_field(&reference.value, Field::Any(8 * reference.size as u32), addresses, source)?;
+        data_bytes[reference.offset..reference.offset + reference.size].copy_from_slice(&value.to_le_bytes()[..reference.size]);
+    }
 
+    Ok(())
+}
 
-            if let Expr_::Ident(s) = &mnemonic.node {
-                match s.as_str() {
-                    "li" => {
-                        let mut dest = "".to_owned();
-                        let mut val = 0;
+fn directive_error(span: Span, source: &SimpleFile<String, String>, message: &str) -> MimicError {
+    MimicError {
+        span: Some(span),
+        source: Some(Box::new(source.clone())),
+        ty: MimicErrorType::InvalidDirective { message: message.to_owned() },
+    }
+}
 
-                        if let Expr_::Register(s) = &args[0].node {
-                            dest = s.to_owned();    
-                        } else {
-                            panic!("Expected register");
-                        }
+// Errors at `span` when `len` bytes don't fit in a segment of `capacity` bytes
+fn check_capacity(len: usize, capacity: usize, span: Span, segment: Directive, source: &SimpleFile<String, String>) -> Result<(), MimicError> {
+    match len <= capacity {
+        true => Ok(()),
+        false => Err(directive_error(span, source, &format!("the {:?} segment is full", segment).to_lowercase())),
+    }
+}
 
-                        if let Expr_::IntLiteral(i) = args[1].node {
-                            val = i as u32;
-                        } else {
-                            panic!("Expected number");
-                        }
+// Replaces each `value:count` with `count` copies of the value. Every value takes at
+// least a byte, so no more than `room` values can fit in what is left of the segment.
+fn expand_repeats<'a>(data: &'a [Expr], room: usize, segment: Directive, source: &SimpleFile<String, String>) -> Result<Vec<&'a Expr>, MimicError> {
+    let mut values = Vec::new();
+
+    for expr in data {
+        if let Expr_::Repeat { value, count } = &expr.node {
+            match constant(count, source)? {
+                n if n > 0 => {
+                    check_capacity(values.len().saturating_add(n as usize), room, count.span, segment, source)?;
+                    values.extend(std::iter::repeat_n(value.as_ref(), n as usize));
+                },
+                _ => return Err(directive_error(count.span, source, "repeat count must be positive")),
+            }
+        } else {
+            values.push(expr);
+        }
+    }
 
-                        if val > 0xFFFF {
-                            instructions.push(
-                                Instruction::builder(span, "lui".to_owned())
-                                    .with_opt_label(cur_label)
-                                    .with_register_argument(0, "$at".to_owned())
-                                    .with_integer_argument(1, val >> 16)
-                                    .build(source)
-                            );
-                            cur_label = None;
-                            instructions.push(
-                                Instruction::builder(span, "ori".to_owned())
-                                    .with_register_argument(0, dest)
-                                    .with_register_argument(1, "$at".to_owned())
-                                    .with_integer_argument(2, val & 0x0000FFFF)
-                                    .build(source)
-                            );
-                        } else {
-                            
-                            instructions.push(
-                                Instruction::builder(span, "addiu".to_owned())
-                                .with_opt_label(cur_label)
-                                .with_register_argument(0, dest)
-                                .with_register_argument(1, "$zero".to_owned())
-                                .with_integer_argument(2, val)
-                                .build(source)
-                            );
-                            cur_label = None;
-                        }
-                    }, // li
+    Ok(values)
+}
 
-                    "la" => {
-                        let mut dest = "".to_owned();
-                        let mut label = "".to_owned();
-                        let mut addr = 0;
+// The boundary in bytes given to .align
+fn align_bytes(data: &[Expr], span: Span, source: &SimpleFile<String, String>) -> Result<usize, MimicError> {
+    let (n, n_span) = single_integer(Directive::Align, data, span, source)?;
+    if !(0..=3).contains(&n) {
+        return Err(directive_error(n_span, source, ".align expects 0, 1, 2 or 3"));
+    }
+    Ok(1 << n)
+}
 
-                        if let Expr_::Register(s) = &args[0].node {
-                            dest = s.to_owned();
-                        } else {
-                            panic!("Expected register");
-                        }
+// The operand of .align and .space
+fn single_integer(d: Directive, data: &[Expr], span: Span, source: &SimpleFile<String, String>) -> Result<(i64, Span), MimicError> {
+    match data {
+        [value] => Ok((constant(value, source)?, value.span)),
+        _ => Err(directive_error(span, source, &format!(".{:?} takes exactly one value", d).to_lowercase())),
+    }
+}
 
-                        if let Expr_::Ident(s) = &args[1].node {
-                            label = s.to_owned();
-                        } else {
-                            panic!("Expected identifier");
-                        }
+// A value needed in the first pass, which can't depend on labels
+fn constant(expr: &Expr, source: &SimpleFile<String, String>) -> Result<i64, MimicError> {
+    match &expr.node {
+        Expr_::StringLiteral(_) | Expr_::FloatLiteral(_) => Err(directive_error(expr.span, source, "expected an integer")),
+        _ if references_symbol(expr) => Err(directive_error(expr.span, source, "expected a constant")),
+        _ => evaluate(expr, &HashMap::new(), source),
+    }
+}
 
-                        if let Some(a) = data_labels.get(&label) {
-                            addr = *a - 0x10010000;
-                        } else {
-                            panic!("Unknown label");
-                        }
+// Contents of a quoted string, with bad escapes reported at their exact span
+fn string_literal_bytes(s: &str, span: Span, source: &SimpleFile<String, String>) -> Result<Vec<u8>, MimicError> {
+    let body = &s[1..s.len() - 1];
+
+    unescape(body).map_err(|range| MimicError {
+        span: Some(Span {
+            lo: span.lo + 1 + range.start,
+            hi: span.lo + 1 + range.end,
+        }),
+        source: Some(Box::new(source.clone())),
+        ty: MimicErrorType::InvalidEscape { sequence: body[range].to_owned() },
+    })
+}
 
-                        // println!("dest: {}", register_name_to_number(dest.clone()).unwrap());
-
-                        instructions.push(
-                            Instruction::builder(span, "lui".to_owned())
-                            .with_opt_label(cur_label)
-                            .with_register_argument(0, "$at".to_owned())
-                            .with_integer_argument(1, 0x1001)
-                            .build(source)
-                        );
-                        cur_label = None;
-                        instructions.push(
-                            Instruction::builder(span, "ori".to_owned())
-                            .with_register_argument(0, dest)
-                            .with_register_argument(1, "$at".to_owned())
-                            .with_integer_argument(2, addr)
-                            .build(source)
-                        );
-
-                        // println!("{:#010X?}", instructions[instructions.len() - 1]);
-                    }, // la
-                    
-                    "move" => {
-                        let mut dest = "".to_owned();
-                        let mut src = "".to_owned();
-
-                        if let Expr_::Register(s) = &args[0].node {
-                            dest = s.to_owned();    
-                        } else {
-                            panic!("Expected register");
-                        }
+// The name of a declared label and its span
+fn label_name(label: &Expr) -> (String, Span) {
+    match &label.node {
+        Expr_::Label(l) => match &l.node {
+            Expr_::Ident(s) => (s.to_owned(), l.span),
+            _ => panic!("Expression other than Ident in LabelDeclaration statement"),
+        },
+        _ => panic!("Expression other than Ident in LabelDeclaration statement"),
+    }
+}
 
-                        if let Expr_::Register(s) = &args[1].node {
-                            src = s.to_owned();    
-                        } else {
-                            panic!("Expected register");
-                        }
+// The native instructions a source instruction assembles to
+fn expand_instruction(span: Span, mnemonic: &Expr, args: &[Expr], source: &SimpleFile<String, String>) -> Result<Vec<Instruction>, MimicError> {
+    let s = match &mnemonic.node {
+        Expr_::Ident(s) => s,
+        _ => panic!("Expected mnemonic"),
+    };
+
+    match expand_pseudo(s, args, span, source)? {
+        Some(expanded) => Ok(expanded
+            .into_iter()
+            .map(|(name, expanded_args)| {
+                let mut builder = Instruction::builder(span, name);
+                for (i, arg) in expanded_args.into_iter().enumerate() {
+                    builder.with_expression_argument(i, arg);
+                }
+                builder.build(source)
+            })
+            .collect()),
+        None => Ok(vec![Instruction {
+            span,
+            source: source.clone(),
+            mnemonic: mnemonic.clone(),
+            args: args.to_vec(),
+            inst: 0,
+        }]),
+    }
+}
 
-                        instructions.push(
-                            Instruction::builder(span, "addu".to_owned())
-                            .with_opt_label(cur_label)
-                            .with_register_argument(0, dest)
-                            .with_register_argument(1, "$zero".to_owned())
-                            .with_register_argument(2, src)
-                            .build(source)
-                        );
-                        cur_label = None;
-                    }, // move
-
-                    "blt" => {
-                        let mut cmp1 = "".to_owned();
-                        let mut cmp2 = "".to_owned();
-                        let mut label = "".to_owned();
-
-                        if let Expr_::Register(s) = &args[0].node {
-                            cmp1 = s.to_owned();    
-                        } else {
-                            panic!("Expected register");
-                        }
+// Start and end (exclusive) of the segment each section directive assembles into
+fn segment_bounds(d: Directive) -> (u32, u32) {
+    match d {
+        Directive::Text => (TEXT_BASE, 0x10000000),
+        Directive::Data => (DATA_BASE, 0x10040000),
+        Directive::Ktext => (KTEXT_BASE, KDATA_BASE),
+        _ => (KDATA_BASE, 0xFFFF0000),
+    }
+}
 
-                        if let Expr_::Register(s) = &args[1].node {
-                            cmp2 = s.to_owned();    
-                        } else {
-                            panic!("Expected register");
-                        }
+// Checks the address given after a section directive. Sections of the same kind are
+// laid out in order, so an origin can't go back over earlier code or data.
+fn section_origin(origin: &Expr, next: u32, d: Directive, source: &SimpleFile<String, String>) -> Result<u32, MimicError> {
+    let (base, end) = segment_bounds(d);
+    let address = constant(origin, source)?;
+
+    if address < base as i64 || address >= end as i64 {
+        Err(directive_error(origin.span, source, &format!("{:#X} is outside the {:?} segment", address, d).to_lowercase()))
+    } else if (address as u32) < next {
+        Err(directive_error(origin.span, source, &format!("{:#X} overlaps earlier contents of the segment", address)))
+    } else if matches!(d, Directive::Text | Directive::Ktext) && address % 4 != 0 {
+        Err(directive_error(origin.span, source, "code must be word aligned"))
+    } else {
+        Ok(address as u32)
+    }
+}
 
-                        if let Expr_::Ident(s) = &args[2].node {
-                            label = s.to_owned();
-                        } else {
-                            panic!("Expected identifier");
+// Expands the sections of one code segment and gives every instruction and label its address
+fn place_instructions(sections: Sections, d: Directive, source: &SimpleFile<String, String>) -> Result<(Vec<(u32, Instruction)>, Labels), MimicError> {
+    let mut placed = Vec::new();
+    let mut labels: Labels = Vec::new();
+    // Labels waiting for the next instruction
+    let mut pending: Vec<(String, Span)> = Vec::new();
+    let (mut next, end) = segment_bounds(d);
+
+    for (origin, stmts) in sections {
+        if let Some(origin) = origin {
+            next = section_origin(&origin, next, d, source)?;
+        }
+        for stmt in stmts {
+            match &stmt.statement {
+                Stmt_::LabelDeclaration { label } => pending.push(label_name(label)),
+                Stmt_::Instruction { mnemonic, args } => {
+                    for (label, span) in pending.drain(..) {
+                        labels.push((label, span, next));
+                    }
+                    for instruction in expand_instruction(stmt.span, mnemonic, args, source)? {
+                        if next >= end {
+                            return Err(directive_error(stmt.span, source, &format!("the {:?} segment is full", d).to_lowercase()));
                         }
-
-                        instructions.push(
-                            Instruction::builder(span, "slt".to_owned())
-                            .with_opt_label(cur_label)
-                            .with_register_argument(0, "$at".to_owned())
-                            .with_register_argument(1, cmp1)
-                            .with_register_argument(2, cmp2)
-                            .build(source)
-                        );
-                        cur_label = None;
-                        instructions.push(
-                            Instruction::builder(span, "bne".to_owned())
-                            .with_register_argument(0, "$at".to_owned())
-                            .with_register_argument(1, "$zero".to_owned())
-                            .with_ident_argument(2, label)
-                            .build(source)
-                        );
-
-                    }, // blt
-                    _ => {
-                        instructions.push(Instruction {
-                            label: cur_label,
-                            span,
-                            source: source.clone(),
-                            mnemonic: mnemonic.clone(),
-                            args: args.to_vec(),
-                            inst: 0,
-                        });
-
-                        cur_label = None;
+                        placed.push((next, instruction));
+                        next += 4;
                     }
-                }
-
-            } else {
-                panic!("Expected mnemonic");
-            }
-
-
-        } else if let Stmt_::LabelDeclaration { label } = &inst_stmt.statement {
-            if let Expr_::Label(l) = &label.node {
-                if let Expr_::Ident(s) = &l.node {
-                    cur_label = Some(s.to_owned());
-                } else {
-                    panic!("Expression other than Ident in LabelDeclaration statement");
-                }
-            } else {
-                panic!("Expression other than Ident in LabelDeclaration statement");
+                },
+                // Instructions are already word aligned, so only `.align 3` can add padding
+                Stmt_::DataDeclaration { type_directive, data } if matches!(type_directive.node, Expr_::TypeDirectiveExpression(Directive::Align)) => {
+                    next = next.next_multiple_of(align_bytes(data, stmt.span, source)? as u32);
+                },
+                _ => return Err(directive_error(stmt.span, source, &format!("data can't be placed in .{:?}", d).to_lowercase())),
             }
-        } else {
-            panic!("Non-instruction statement in .text");
         }
+    }
 
-        // println!("{:#?}", inst_stmt);
+    // Labels at the end of the segment
+    for (label, span) in pending {
+        labels.push((label, span, next));
     }
-    
 
-    instructions
+    Ok((placed, labels))
 }
 
+// Encodes placed instructions, filling gaps left by section origins with zeros
+fn encode_instructions(placed: &mut [(u32, Instruction)], base: u32, symbols: &HashMap<String, u32>) -> Result<Vec<u8>, MimicError> {
+    let mut bytes: Vec<u8> = Vec::new();
+
+    for (address, instruction) in placed.iter_mut() {
+        instruction.build_bytecode(symbols, *address)?;
+
+        bytes.resize((*address - base) as usize, 0);
+        bytes.extend_from_slice(&instruction.inst.to_le_bytes());
+    }
+
+    Ok(bytes)
+}
 
-pub fn assemble_ast(ast: Vec<Stmt>, source: &SimpleFile<String, String>) -> Result<(Vec<u8>, Vec<u8>), MimicError> {
-    let mut data: Vec<Stmt> = Vec::new();
-    let mut text: Vec<Stmt> = Vec::new();
+pub fn assemble_ast(ast: Vec<Stmt>, source: &SimpleFile<String, String>) -> Result<Program, MimicError> {
+    // Sections of each kind are concatenated in the order they appear
+    let mut sections: HashMap<Directive, Sections> = HashMap::new();
 
     for section in ast {
         match section.statement {
-            Stmt_::Section {section_directive, stmts} => {
+            Stmt_::Section {section_directive, origin, stmts} => {
                 if let Expr_::SectionDirectiveExpression(d) = section_directive.node {
-                    match d {
-                        // Directive::Text => text = assemble_text(*stmts),
-                        // Directive::Data => (data_bytes, data_labels) = pack_data(*stmts),
-                        Directive::Data => data = *stmts,
-                        Directive::Text => text = *stmts,
-                        _ => todo!(),
-                    }
+                    sections.entry(d).or_default().push((origin, *stmts));
                 }
             }
             _ => panic!("Shouldn't be here..."),
         }
     }
-
-    let (data_bytes, data_labels) = pack_data(data);
-
-    let mut text_labels: HashMap<String, u32> = HashMap::new();
-    let mut text_bytes: Vec<u8> = Vec::new();
-
-    let mut instructions: Vec<Instruction> = expand_instructions(text, &data_labels, source);
-    for (i, instruction) in instructions.iter().enumerate() {
-        if let Some(label) = &instruction.label {
-            text_labels.insert(label.to_owned(), i as u32);
+    let mut take = |d: Directive| sections.remove(&d).unwrap_or_default();
+
+    let data = pack_data(take(Directive::Data), Directive::Data, source)?;
+    let kdata = pack_data(take(Directive::Kdata), Directive::Kdata, source)?;
+    let (mut text, text_labels) = place_instructions(take(Directive::Text), Directive::Text, source)?;
+    let (mut ktext, ktext_labels) = place_instructions(take(Directive::Ktext), Directive::Ktext, source)?;
+
+    // Address of every label, for the second pass. Labels are taken in source order
+    // so a duplicate is reported where it is declared again.
+    let mut labels: Vec<&(String, Span, u32)> = [&data.labels, &kdata.labels, &text_labels, &ktext_labels].into_iter().flatten().collect();
+    labels.sort_by_key(|(_, span, _)| span.lo);
+    let mut addresses: HashMap<String, u32> = HashMap::new();
+    for (label, span, address) in labels {
+        if addresses.insert(label.to_owned(), *address).is_some() {
+            return Err(MimicError {
+                span: Some(*span),
+                source: Some(Box::new(source.clone())),
+                ty: MimicErrorType::DuplicateSymbol { name: label.to_owned() },
+            });
         }
     }
 
-    // println!("{:#08?}", text_labels);
-
-    for (i, instruction) in instructions.iter_mut().enumerate() {
-        instruction.build_bytecode(&text_labels, i)?;
-
-        let inst = instruction.inst;
-
-        // println!("{:#010X}", (inst >> 0) as u8);
-        
-        text_bytes.push((inst >> 0) as u8);
-        text_bytes.push((inst >> 8) as u8);
-        text_bytes.push((inst >> 16) as u8);
-        text_bytes.push((inst >> 24) as u8);
+    let text_bytes = encode_instructions(&mut text, TEXT_BASE, &addresses)?;
+    let ktext_bytes = encode_instructions(&mut ktext, KTEXT_BASE, &addresses)?;
 
+    let mut source_map = SourceMap::new(source.name().to_owned(), source.source());
+    for (address, instruction) in text.iter().chain(&ktext) {
+        source_map.insert(*address, source.line_index((), instruction.span.lo).unwrap_or(0) + 1);
     }
 
+    let PackedData { bytes: mut data_bytes, references, .. } = data;
+    resolve_data_references(&mut data_bytes, references, &addresses, source)?;
+    let PackedData { bytes: mut kdata_bytes, references, .. } = kdata;
+    resolve_data_references(&mut kdata_bytes, references, &addresses, source)?;
 
-    // println!("{:#?}", text_bytes);
-
-    // let text_bytes = assemble_text(text);
-
-    // for byte in &data_bytes {
-    //     println!("{}", *byte as char);
-    // }
-
-
-    // println!("{:#08X?}", data_labels);
-
+    let mut symbols = SymbolTable::new();
+    for (label, address) in addresses {
+        symbols.insert(label, address);
+    }
 
-    Ok((text_bytes, data_bytes))
+    Ok(Program {
+        text: text_bytes,
+        data: data_bytes,
+        ktext: ktext_bytes,
+        kdata: kdata_bytes,
+        symbols,
+        source_map,
+    })
     
 }
@@ -0,0 +1,233 @@
+use super::lexer::{Directive, Lexer, Token};
+use super::parser::{parse, BinaryOp, Expr, Expr_, Stmt_, UnaryOp};
+
+use crate::errors::{MimicError, MimicErrorType, Span};
+
+use codespan_reporting::files::SimpleFile;
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+
+// Width of the instruction or data field a value is stored in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Signed(u32),
+    Unsigned(u32),
+    // Either interpretation, as for .byte and .word
+    Any(u32),
+}
+
+impl Field {
+    pub fn range(self) -> (i64, i64) {
+        match self {
+            Field::Signed(bits) => (-(1 << (bits - 1)), (1 << (bits - 1)) - 1),
+            Field::Unsigned(bits) => (0, (1 << bits) - 1),
+            Field::Any(bits) => (-(1 << (bits - 1)), (1 << bits) - 1),
+        }
+    }
+
+    fn mask(self) -> u32 {
+        let bits = match self {
+            Field::Signed(bits) | Field::Unsigned(bits) | Field::Any(bits) => bits,
+        };
+        if bits >= 32 { u32::MAX } else { (1 << bits) - 1 }
+    }
+}
+
+// Whether the value depends on a label, and so is only known in the second pass
+pub fn references_symbol(expr: &Expr) -> bool {
+    match &expr.node {
+        Expr_::Ident(_) => true,
+        Expr_::Binary { lhs, rhs, .. } => references_symbol(lhs) || references_symbol(rhs),
+        Expr_::Unary { operand, .. } | Expr_::Hi(operand) | Expr_::Lo(operand) => references_symbol(operand),
+        _ => false,
+    }
+}
+
+// Value of a constant expression. Labels evaluate to their address.
+pub fn evaluate(expr: &Expr, symbols: &HashMap<String, u32>, source: &SimpleFile<String, String>) -> Result<i64, MimicError> {
+    match &expr.node {
+        Expr_::IntLiteral(i) => Ok(*i),
+        Expr_::Ident(name) => symbols.get(name).map(|a| *a as i64).ok_or_else(|| MimicError {
+            span: Some(expr.span),
+            source: Some(Box::new(source.clone())),
+            ty: MimicErrorType::UnknownSymbol { name: name.to_owned() },
+        }),
+        Expr_::Unary { op, operand } => {
+            let value = evaluate(operand, symbols, source)?;
+            Ok(match op {
+                UnaryOp::Neg => value.wrapping_neg(),
+                UnaryOp::Not => !value,
+                UnaryOp::LogicalNot => (value == 0) as i64,
+            })
+        },
+        Expr_::Binary { op, lhs, rhs } => {
+            let a = evaluate(lhs, symbols, source)?;
+            let b = evaluate(rhs, symbols, source)?;
+            match op {
+                BinaryOp::Add => Ok(a.wrapping_add(b)),
+                BinaryOp::Sub => Ok(a.wrapping_sub(b)),
+                BinaryOp::Mul => Ok(a.wrapping_mul(b)),
+                BinaryOp::Div | BinaryOp::Rem if b == 0 => Err(expression_error(rhs.span, source, "division by zero")),
+                BinaryOp::Div => Ok(a.wrapping_div(b)),
+                BinaryOp::Rem => Ok(a.wrapping_rem(b)),
+                BinaryOp::And => Ok(a & b),
+                BinaryOp::Or => Ok(a | b),
+                BinaryOp::Xor => Ok(a ^ b),
+                BinaryOp::Shl | BinaryOp::Shr if !(0..64).contains(&b) => {
+                    Err(expression_error(rhs.span, source, &format!("shift by {} is out of range", b)))
+                },
+                BinaryOp::Shl => Ok(a << b),
+                BinaryOp::Shr => Ok(a >> b),
+                BinaryOp::Eq => Ok((a == b) as i64),
+                BinaryOp::Ne => Ok((a != b) as i64),
+                BinaryOp::Lt => Ok((a < b) as i64),
+                BinaryOp::Le => Ok((a <= b) as i64),
+                BinaryOp::Gt => Ok((a > b) as i64),
+                BinaryOp::Ge => Ok((a >= b) as i64),
+                BinaryOp::LogicalAnd => Ok((a != 0 && b != 0) as i64),
+                BinaryOp::LogicalOr => Ok((a != 0 || b != 0) as i64),
+            }
+        },
+        Expr_::Hi(operand) => Ok((evaluate(operand, symbols, source)?.wrapping_add(0x8000) >> 16) & 0xFFFF),
+        Expr_::Lo(operand) => Ok(evaluate(operand, symbols, source)? as i16 as i64),
+        _ => Err(MimicError {
+            span: Some(expr.span),
+            source: Some(Box::new(source.clone())),
+            ty: MimicErrorType::IncorrectArgumentType {},
+        }),
+    }
+}
+
+// Evaluates an expression and checks that it fits the field it's stored in
+pub fn evaluate_field(
+    expr: &Expr,
+    field: Field,
+    symbols: &HashMap<String, u32>,
+    source: &SimpleFile<String, String>,
+) -> Result<u32, MimicError> {
+    let value = evaluate(expr, symbols, source)?;
+    check_range(value, field, expr.span, source)?;
+    Ok(value as u32 & field.mask())
+}
+
+pub fn check_range(value: i64, field: Field, span: Span, source: &SimpleFile<String, String>) -> Result<(), MimicError> {
+    let (min, max) = field.range();
+    if value < min || value > max {
+        return Err(MimicError {
+            span: Some(span),
+            source: Some(Box::new(source.clone())),
+            ty: MimicErrorType::ValueOutOfRange { value, min, max },
+        });
+    }
+    Ok(())
+}
+
+// Parses the text in `range` of `source` on its own as a single expression
+pub fn parse_expression(source: &SimpleFile<String, String>, range: Range<usize>) -> Result<Expr, MimicError> {
+    let text = &source.source()[range.clone()];
+    let whole = Span { lo: range.start, hi: range.end };
+    let tokens: Vec<(Token, Span)> = Lexer::new(text, source.clone())
+        .map(|(token, span)| (token, Span { lo: span.lo + range.start, hi: span.hi + range.start }))
+        .collect();
+    if let Some((token, span)) = tokens.iter().find(|(token, _)| matches!(token, Token::Unknown(_) | Token::InvalidLiteral(_))) {
+        let ty = match token {
+            Token::InvalidLiteral(literal) => MimicErrorType::InvalidLiteral { literal: literal.clone() },
+            Token::Unknown(token) => MimicErrorType::UnknownToken { token: token.clone() },
+            _ => unreachable!(),
+        };
+        return Err(MimicError { span: Some(*span), source: Some(Box::new(source.clone())), ty });
+    }
+
+    // Parsed as the value of a .word
+    let prefix = [
+        (Token::SectionDirective(Directive::Data), Span { lo: range.start, hi: range.start }),
+        (Token::Newline, Span { lo: range.start, hi: range.start }),
+        (Token::TypeDirective(Directive::Word), Span { lo: range.start, hi: range.start }),
+    ];
+    let ast = match parse(prefix.into_iter().chain(tokens)) {
+        Ok(ast) => ast,
+        Err((Some((Token::Newline, _)), _)) | Err((None, _)) => return Err(expression_error(whole, source, "expected an expression")),
+        Err((Some((_, span)), _)) => return Err(expression_error(span, source, "expected an expression")),
+    };
+
+    let data = match ast.first().map(|section| &section.statement) {
+        Some(Stmt_::Section { stmts, .. }) if ast.len() == 1 && stmts.len() == 1 => match &stmts[0].statement {
+            Stmt_::DataDeclaration { data, .. } => data,
+            _ => return Err(expression_error(whole, source, "expected an expression")),
+        },
+        _ => return Err(expression_error(whole, source, "expected an expression")),
+    };
+    match data.as_slice() {
+        [expr] if !matches!(expr.node, Expr_::Repeat { .. } | Expr_::StringLiteral(_) | Expr_::FloatLiteral(_)) => Ok(expr.clone()),
+        _ => Err(expression_error(whole, source, "expected an expression")),
+    }
+}
+
+pub fn expression_error(span: Span, source: &SimpleFile<String, String>, message: &str) -> MimicError {
+    MimicError {
+        span: Some(span),
+        source: Some(Box::new(source.clone())),
+        ty: MimicErrorType::InvalidExpression { message: message.to_owned() },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mips32::assembler::lexer::Lexer;
+    use crate::mips32::assembler::parser::{parse, Stmt_};
+
+    // Value of the single operand of `.word <text>`
+    fn value_of(text: &str, symbols: &HashMap<String, u32>) -> Result<i64, MimicError> {
+        let contents = format!(".data\n.word {}\n", text);
+        let source = SimpleFile::new("".to_owned(), contents.clone());
+        let ast = parse(Lexer::new(&contents, source.clone())).unwrap();
+        match &ast[0].statement {
+            Stmt_::Section { stmts, .. } => match &stmts[0].statement {
+                Stmt_::DataDeclaration { data, .. } => evaluate(&data[0], symbols, &source),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn precedence_and_operators() {
+        let symbols = HashMap::from([("start".to_owned(), 0x10010000), ("end".to_owned(), 0x10018010)]);
+        let cases = [
+            ("1 + 2 * 3", 7),
+            ("(1 + 2) * 3", 9),
+            ("1 << 4 | 1", 17),
+            ("0xFF & ~0xF ^ 1", 0xF1),
+            ("-7 / 2", -3),
+            ("-7 % 2", -1),
+            ("'a' + 1", 98),
+            ("'\\n'", 10),
+            ("end - start", 0x8010),
+            ("start + 4", 0x10010004),
+            ("%hi(end)", 0x1002),
+            ("%lo(end)", -0x7FF0),
+            ("-1 >> 1", -1),
+            ("1 + 1 == 2", 1),
+            ("2 < 1 || 3 >= 3 && !0", 1),
+            ("-1 < 0 != 0", 1),
+            ("6 & 3 == 3", 0),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(value_of(text, &symbols).unwrap(), expected, "{text}");
+        }
+
+        assert_eq!(value_of("1 / (2 - 2)", &symbols).unwrap_err().msg(), "Invalid expression: division by zero");
+        assert_eq!(value_of("missing + 1", &symbols).unwrap_err().msg(), "Unknown symbol [missing]");
+    }
+
+    #[test]
+    fn field_ranges() {
+        assert_eq!(Field::Signed(16).range(), (-32768, 32767));
+        assert_eq!(Field::Unsigned(5).range(), (0, 31));
+        assert_eq!(Field::Any(8).range(), (-128, 255));
+        assert_eq!(Field::Any(32).mask(), u32::MAX);
+    }
+}
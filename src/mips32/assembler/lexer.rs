@@ -3,6 +3,8 @@ use codespan_reporting::files::SimpleFile;
 
 use crate::errors::{MimicError, MimicErrorType, Span};
 
+use std::ops::Range;
+
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub enum Token {
@@ -17,8 +19,35 @@ pub enum Token {
     Period,
     Colon,
     Comma,
+    LParen,
+    RParen,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
+    ShiftLeft,
+    ShiftRight,
+    EqualEqual,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    AndAnd,
+    OrOr,
+    Bang,
+    // %hi and %lo
+    Hi,
+    Lo,
     DollarSign,
     Unknown(String),
+    // An integer literal wider than 32 bits
+    InvalidLiteral(String),
     SectionDirective(Directive),
     TypeDirective(Directive),
     Syscall,
@@ -26,7 +55,7 @@ pub enum Token {
 }
 
 #[allow(dead_code)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Directive {
     Align,
     Ascii,
@@ -50,27 +79,31 @@ lexer! {
 
     r#"[ \t\r]+"# => Token::Whitespace,
     r#"\n"# => Token::Newline,
+    // Separates statements like the end of a line
+    r#";"# => Token::Newline,
     r#"#[^\n]*"# => Token::Comment,
 
     r#"\"(\\.|[^\\"\n])*\""# => Token::Str(text.to_owned()),
-    r#"[0-9]+"# => {
-        if let Ok(i) = text.parse() {
-            Token::Integer(i)
-        } else {
-            panic!("integer {} is out of range", text)
-        }
-    },
-    r#"0[xX][0-9a-fA-F]+"# => {
-        if let Ok(i) = u32::from_str_radix(&text[2..], 16) {
-            Token::Integer(i as i64)
-        } else {
-            panic!("integer {} is out of range", text)
-        }
-    },
+    r#"[0-9]+\.[0-9]+([eE][-+]?[0-9]+)?"# => Token::Float(text.parse().unwrap()),
+    r#"[0-9]+"# => integer_literal(text, 10),
+    r#"'(\\.|\\x[0-9a-fA-F][0-9a-fA-F]?|\\[0-7][0-7]?[0-7]?|[^\\'\n])'"# => char_literal(text),
+    r#"0[xX][0-9a-fA-F]+"# => integer_literal(text, 16),
+    r#"0[bB][01]+"# => integer_literal(text, 2),
+    r#"0[oO][0-7]+"# => integer_literal(text, 8),
 
     r#"\.text"# => Token::SectionDirective(Directive::Text),
     r#"\.data"# => Token::SectionDirective(Directive::Data),
+    r#"\.ktext"# => Token::SectionDirective(Directive::Ktext),
+    r#"\.kdata"# => Token::SectionDirective(Directive::Kdata),
+    r#"\.align"# => Token::TypeDirective(Directive::Align),
+    r#"\.ascii"# => Token::TypeDirective(Directive::Ascii),
     r#"\.asciiz"# => Token::TypeDirective(Directive::Asciiz),
+    r#"\.byte"# => Token::TypeDirective(Directive::Byte),
+    r#"\.double"# => Token::TypeDirective(Directive::Double),
+    r#"\.float"# => Token::TypeDirective(Directive::Float),
+    r#"\.half"# => Token::TypeDirective(Directive::Half),
+    r#"\.space"# => Token::TypeDirective(Directive::Space),
+    r#"\.word"# => Token::TypeDirective(Directive::Word),
 
     r#"syscall"# => Token::Syscall,
 
@@ -79,6 +112,30 @@ lexer! {
     r#":"# => Token::Colon,
     // r#"\."# => Token::Period,
     r#","# => Token::Comma,
+    r#"\("# => Token::LParen,
+    r#"\)"# => Token::RParen,
+    r#"\+"# => Token::Plus,
+    r#"-"# => Token::Minus,
+    r#"\*"# => Token::Star,
+    r#"/"# => Token::Slash,
+    r#"%"# => Token::Percent,
+    r#"\&"# => Token::Ampersand,
+    r#"\|"# => Token::Pipe,
+    r#"\^"# => Token::Caret,
+    r#"\~"# => Token::Tilde,
+    r#"<<"# => Token::ShiftLeft,
+    r#">>"# => Token::ShiftRight,
+    r#"=="# => Token::EqualEqual,
+    r#"!="# => Token::NotEqual,
+    r#"<"# => Token::Less,
+    r#"<="# => Token::LessEqual,
+    r#">"# => Token::Greater,
+    r#">="# => Token::GreaterEqual,
+    r#"\&\&"# => Token::AndAnd,
+    r#"\|\|"# => Token::OrOr,
+    r#"!"# => Token::Bang,
+    r#"%hi"# => Token::Hi,
+    r#"%lo"# => Token::Lo,
     // r#"\$"# => Token::DollarSign,
 
     r#"[a-zA-Z_][a-zA-Z0-9_]*"# => Token::Ident(text.to_owned()),
@@ -88,12 +145,95 @@ lexer! {
     
 }
 
+// Literals take any 32 bit pattern; negative values come from unary minus
+fn integer_literal(text: &str, radix: u32) -> Token {
+    let digits = if radix == 10 { text } else { &text[2..] };
+
+    match u64::from_str_radix(digits, radix) {
+        Ok(i) if i <= u32::MAX as u64 => Token::Integer(i as i64),
+        _ => Token::InvalidLiteral(text.to_owned()),
+    }
+}
+
+// 'a', or an escape as in a string. Other characters are their code point.
+fn char_literal(text: &str) -> Token {
+    let inner = &text[1..text.len() - 1];
+
+    if !inner.starts_with('\\') {
+        return Token::Integer(inner.chars().next().map_or(0, |c| c as i64));
+    }
+    match unescape(inner).as_deref() {
+        Ok([byte]) => Token::Integer(*byte as i64),
+        _ => Token::Unknown(text.to_owned()),
+    }
+}
+
+// Decodes the C escapes in the text between the quotes of a string or character
+// literal: \n \t \r \a \b \f \v \\ \' \" \?, \xNN and up to three octal digits.
+// Everything else, including non-ASCII text, is kept as its UTF-8 bytes. A bad
+// escape is returned as its byte range within `body`.
+pub fn unescape(body: &str) -> Result<Vec<u8>, Range<usize>> {
+    let bytes = body.as_bytes();
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        let c = match bytes.get(i + 1) {
+            Some(c) => *c,
+            None => return Err(start..i + 1),
+        };
+        i += 2;
+
+        let byte = match c {
+            b'n' => b'\n',
+            b't' => b'\t',
+            b'r' => b'\r',
+            b'a' => 0x07,
+            b'b' => 0x08,
+            b'f' => 0x0C,
+            b'v' => 0x0B,
+            b'\\' | b'\'' | b'"' | b'?' => c,
+            b'x' => {
+                let digits = bytes[i..].iter().take(2).take_while(|b| b.is_ascii_hexdigit()).count();
+                if digits == 0 {
+                    return Err(start..i);
+                }
+                i += digits;
+                u8::from_str_radix(&body[i - digits..i], 16).unwrap()
+            },
+            b'0'..=b'7' => {
+                let digits = bytes[i..].iter().take(2).take_while(|b| (b'0'..=b'7').contains(b)).count();
+                i += digits;
+                match u8::from_str_radix(&body[start + 1..i], 8) {
+                    Ok(byte) => byte,
+                    Err(_) => return Err(start..i),
+                }
+            },
+            _ => {
+                let len = body[start + 1..].chars().next().map_or(1, |c| c.len_utf8());
+                return Err(start..start + 1 + len);
+            },
+        };
+        out.push(byte);
+    }
+
+    Ok(out)
+}
 
 #[derive(Debug, Clone)]
 pub struct Lexer<'a> {
     original: &'a str,
     remaining: &'a str,
     source: SimpleFile<String, String>,
+    // The last line has been terminated
+    ended: bool,
 }
 
 impl<'a> Lexer<'a> {
@@ -102,7 +242,26 @@ impl<'a> Lexer<'a> {
             original: s,
             remaining: s,
             source,
+            ended: false,
+        }
+    }
+
+    // The first token that couldn't be lexed, as an error
+    pub fn check(&self) -> Result<(), MimicError> {
+        for (tok, span) in self.clone() {
+            let ty = match tok {
+                Token::Unknown(token) => MimicErrorType::UnknownToken { token },
+                Token::InvalidLiteral(literal) => MimicErrorType::InvalidLiteral { literal },
+                _ => continue,
+            };
+            return Err(MimicError {
+                span: Some(span),
+                source: Some(Box::new(self.source.clone())),
+                ty,
+            });
         }
+
+        Ok(())
     }
 }
 
@@ -116,6 +275,11 @@ impl<'a> Iterator for Lexer<'a> {
                 self.remaining = new_remaining;
                 // (tok, Span {source: self.source.clone(), lo, hi})
                 (tok, Span {lo, hi})
+            } else if !self.ended {
+                // Statements end with a newline, so one more ends the last line
+                self.ended = true;
+                let end = self.original.len();
+                return Some((Token::Newline, Span { lo: end, hi: end }));
             } else {
                 return None;
             };
@@ -123,12 +287,6 @@ impl<'a> Iterator for Lexer<'a> {
             match tok {
                 Token::Whitespace => continue,
                 Token::Comment => continue,
-                Token::Newline => continue,
-                Token::Unknown(t) => MimicError {
-                    span: Some(span),
-                    source: Some(self.source.clone()),
-                    ty: MimicErrorType::UnknownToken{token: t},
-                }.emit(),
 
                 _ => return Some((tok, span)),
             }
@@ -137,3 +295,20 @@ impl<'a> Iterator for Lexer<'a> {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes() {
+        assert_eq!(unescape(r#"a\tb\\\"\0"#).unwrap(), b"a\tb\\\"\0");
+        assert_eq!(unescape(r"\x41\x7\101\0101").unwrap(), b"A\x07A\x081");
+        assert_eq!(unescape("é").unwrap(), "é".as_bytes());
+        assert_eq!(unescape(r"ab\q"), Err(2..4));
+        assert_eq!(unescape(r"\xg"), Err(0..2));
+        assert_eq!(unescape(r"\400"), Err(0..4));
+        assert_eq!(unescape(r"\é"), Err(0..3));
+        assert_eq!(unescape("\\"), Err(0..1));
+    }
+}
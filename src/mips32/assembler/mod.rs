@@ -1,25 +1,92 @@
 pub mod lexer;
+// The code plex generates calls each action closure in place and takes its stacks as &mut Vec
+#[allow(clippy::redundant_closure_call, clippy::ptr_arg)]
 pub mod parser;
 pub mod assembler;
+pub mod expression;
+pub mod pseudo;
+pub mod preprocessor;
 
 use lexer::{Lexer, Token};
 use parser::{parse, Stmt};
 use assembler::assemble_ast;
+use preprocessor::preprocess;
 
-use crate::errors::{MimicError, MimicErrorType};
+use crate::errors::{MimicError, MimicErrorType, Span};
+use crate::mips32::symbols::{SourceMap, SymbolTable};
 
 use codespan_reporting::files::SimpleFile;
 
 use std::path::Path;
 
 
+// An assembled program together with the addresses of its labels
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub text: Vec<u8>,
+    pub data: Vec<u8>,
+    // Kernel segments from .ktext and .kdata
+    pub ktext: Vec<u8>,
+    pub kdata: Vec<u8>,
+    pub symbols: SymbolTable,
+    pub source_map: SourceMap,
+}
+
+// Settings for assembling a program
+#[derive(Debug, Clone, Default)]
+pub struct AssemblerOptions {
+    // Symbols set before the first line, as if by `.set`
+    defines: Vec<(String, String)>,
+}
+
+impl AssemblerOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_define(mut self, name: &str, value: i64) -> Self {
+        self.defines.push((name.to_owned(), value.to_string()));
+        self
+    }
+
+    // A definition as given to -D on the command line, `NAME=value` or `NAME` for 1.
+    // The value is a constant expression.
+    pub fn with_definition(mut self, definition: &str) -> Self {
+        let (name, value) = definition.split_once('=').unwrap_or((definition, "1"));
+        self.defines.push((name.trim().to_owned(), value.trim().to_owned()));
+        self
+    }
+}
+
 pub fn assemble_from_string(contents: String) -> Result<(Vec<u8>, Vec<u8>), MimicError> {
+    assemble_program_from_string(contents).map(|program| (program.text, program.data))
+}
+
+pub fn assemble_from_file<P>(filename: P) -> Result<(Vec<u8>, Vec<u8>), MimicError>
+where
+    P: AsRef<Path>,
+{
+    assemble_program_from_file(filename).map(|program| (program.text, program.data))
+}
+
+pub fn assemble_program_from_string(contents: String) -> Result<Program, MimicError> {
+    assemble_program_from_string_with_options(contents, &AssemblerOptions::default())
+}
+
+pub fn assemble_program_from_file<P>(filename: P) -> Result<Program, MimicError>
+where
+    P: AsRef<Path>,
+{
+    assemble_program_from_file_with_options(filename, &AssemblerOptions::default())
+}
+
+pub fn assemble_program_from_string_with_options(contents: String, options: &AssemblerOptions) -> Result<Program, MimicError> {
     let file: SimpleFile<String, String> = SimpleFile::new("".to_owned(), contents);
 
-    assemble(file)
+    assemble(file, Path::new("."), options)
 }
 
-pub fn assemble_from_file<P>(filename: P) -> Result<(Vec<u8>, Vec<u8>), MimicError>
+pub fn assemble_program_from_file_with_options<P>(filename: P, options: &AssemblerOptions) -> Result<Program, MimicError>
 where
     P: AsRef<Path>,
 {
@@ -33,31 +100,56 @@ where
     };
 
     let file: SimpleFile<String, String> = SimpleFile::new(filename.as_ref().to_path_buf().file_name().unwrap().to_owned().into_string().unwrap(), contents.to_owned());
+    let dir = filename.as_ref().parent().unwrap_or(Path::new("."));
 
-    assemble(file)
+    assemble(file, dir, options)
 }
 
-fn assemble(file: SimpleFile<String, String>) -> Result<(Vec<u8>, Vec<u8>), MimicError> {
-
-    let tokens = Lexer::new(file.source().as_str(), file.clone());
+// Included files are found relative to `dir`
+fn assemble(file: SimpleFile<String, String>, dir: &Path, options: &AssemblerOptions) -> Result<Program, MimicError> {
 
-    let mut valid = true;
-    for (tok, _span) in tokens.clone() {
-        match tok {
-            Token::Unknown(_) => valid = false,
-            _ => {},
-        }
+    let preprocessed = preprocess(file.clone(), dir, &options.defines)?;
+    let expanded = SimpleFile::new(file.name().to_owned(), preprocessed.text().to_owned());
 
-        // println!("{:?}", tok);
-    }
+    let tokens = Lexer::new(expanded.source().as_str(), expanded.clone());
 
-    if !valid { 
-        // bail
-    }
+    tokens.check().map_err(|e| preprocessed.locate(e))?;
 
-    let ast: Vec<Stmt> = parse(tokens).unwrap();
+    // Comments are already gone, so only whitespace can come before the first token
+    let first_token = |at: usize| expanded.source()[..at].trim().is_empty();
+    let ast: Vec<Stmt> = parse(tokens).map_err(|(token, _)| match token {
+        Some((Token::Newline, span)) if span.lo < expanded.source().len() => preprocessed.locate(MimicError {
+            span: Some(span),
+            source: Some(Box::new(expanded.clone())),
+            ty: MimicErrorType::SyntaxError {
+                message: "unexpected end of line".to_owned(),
+            },
+        }),
+        Some((token, span)) if !matches!(token, Token::Newline) => preprocessed.locate(MimicError {
+            span: Some(span),
+            source: Some(Box::new(expanded.clone())),
+            ty: MimicErrorType::SyntaxError {
+                message: match first_token(span.lo) {
+                    true => format!("expected .text or .data before [{}]", &expanded.source()[span.range()]),
+                    false => format!("unexpected [{}]", &expanded.source()[span.range()]),
+                },
+            },
+        }),
+        // The end of the file as written, since included text may follow the last line
+        _ => MimicError {
+            span: Some(Span { lo: file.source().len(), hi: file.source().len() }),
+            source: Some(Box::new(file.clone())),
+            ty: MimicErrorType::SyntaxError {
+                message: match first_token(expanded.source().len()) {
+                    true => "the program is empty".to_owned(),
+                    false => "unexpected end of file".to_owned(),
+                },
+            },
+        },
+    })?;
 
-    let (text_bytes, data_bytes) = assemble_ast(ast, &file)?;
+    let mut program = assemble_ast(ast, &expanded).map_err(|e| preprocessed.locate(e))?;
+    program.source_map.set_source(file.source());
 
 
     // let (data_bytes, data_labels) = pack_data(&data);
@@ -71,7 +163,7 @@ fn assemble(file: SimpleFile<String, String>) -> Result<(Vec<u8>, Vec<u8>), Mimi
     // let mut file = File::create("test2.data").unwrap();
     // file.write_all(data_bytes.as_slice()).unwrap();
 
-    Ok((text_bytes, data_bytes))
+    Ok(program)
 }
 
 
@@ -114,7 +206,7 @@ fn register_name_to_number(reg: String, source: &SimpleFile<String, String>) ->
 
         _ => Err(MimicError {
             span: None,
-            source: Some(source.clone()),
+            source: Some(Box::new(source.clone())),
             ty: MimicErrorType::UnknownRegister{register_name: reg.to_owned()}
         }),
     }
@@ -16,10 +16,11 @@ pub struct Stmt {
 pub enum Stmt_ {
     Section {
         section_directive: Expr,
+        // Address given after the directive, as in `.data 0x10010100`
+        origin: Option<Expr>,
         stmts: Box<Vec<Stmt>>,
     },
     DataDeclaration {
-        label: Expr,
         type_directive: Expr,
         data: Vec<Expr>,
     },
@@ -50,6 +51,70 @@ pub enum Expr_ {
     TypeDirectiveExpression(Directive),
     SectionDirectiveExpression(Directive),
     Label(Box<Expr>),
+    // `offset(base)`, where the offset is any constant expression
+    MemoryOperand {
+        offset: Option<Box<Expr>>,
+        base: Box<Expr>,
+    },
+    Binary {
+        op: BinaryOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Unary {
+        op: UnaryOp,
+        operand: Box<Expr>,
+    },
+    // %hi(x), the upper half adjusted for the sign extension of %lo(x)
+    Hi(Box<Expr>),
+    // %lo(x), the sign extended lower half
+    Lo(Box<Expr>),
+    // `value:count` in a data declaration
+    Repeat {
+        value: Box<Expr>,
+        count: Box<Expr>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    // Comparisons and logical operators give 1 or 0
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    LogicalAnd,
+    LogicalOr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    LogicalNot,
+}
+
+fn binary(op: BinaryOp, lhs: Expr, rhs: Expr, span: Span) -> Expr {
+    Expr {
+        span,
+        node: Expr_::Binary {
+            op,
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        },
+    }
 }
 
 parser! {
@@ -65,6 +130,7 @@ parser! {
 
     Program: Vec<Stmt> {
         SectionList[s] => s,
+        Newline Program[s] => s,
     }
 
     SectionList: Vec<Stmt> {
@@ -76,40 +142,45 @@ parser! {
     }
 
     Section: Stmt {
-        SectionDirectiveExpression[x] OptStatementList[stmts] => Stmt {
+        SectionDirectiveExpression[x] Newline StatementList[stmts] => Stmt {
             span: span!(),
             statement: Stmt_::Section {
                 section_directive: x,
+                origin: None,
+                stmts: Box::new(stmts),
+            }
+        },
+        SectionDirectiveExpression[x] Expression[origin] Newline StatementList[stmts] => Stmt {
+            span: span!(),
+            statement: Stmt_::Section {
+                section_directive: x,
+                origin: Some(origin),
                 stmts: Box::new(stmts),
             }
         }
     }
 
+    // Statements end at a newline, except for labels
     StatementList: Vec<Stmt> {
-        Statement[s] => vec![s],
+        => vec![],
         StatementList[mut stmts] Statement[s] => {
             stmts.push(s);
             stmts
-        }
-    }
-
-    OptStatementList: Vec<Stmt> {
-        StatementList[stmts] => stmts,
-        => vec![],
+        },
+        StatementList[stmts] Newline => stmts,
     }
 
     Statement: Stmt {
-        DataDeclaration[s] => s,
-        Instruction[s] => s,
+        DataDeclaration[s] Newline => s,
+        Instruction[s] Newline => s,
         LabelDeclaration[s] => s,
     }
 
 
     DataDeclaration: Stmt {
-        Label[x] TypeDirectiveExpression[t] PrimaryExpressionList[exprs] => Stmt {
+        TypeDirectiveExpression[t] DataValueList[exprs] => Stmt {
             span: span!(),
             statement: Stmt_::DataDeclaration {
-                label: x,
                 type_directive: t,
                 data: exprs,
             }
@@ -127,6 +198,13 @@ parser! {
                 args: vec![],
             }
         },
+        Identifier[x] => Stmt {
+            span: span!(),
+            statement: Stmt_::Instruction {
+                mnemonic: x,
+                args: vec![],
+            }
+        },
         Identifier[x] ArgumentList[args] => Stmt {
             span: span!(),
             statement: Stmt_::Instruction {
@@ -167,14 +245,35 @@ parser! {
     }
 
 
-    PrimaryExpressionList: Vec<Expr> {
-        PrimaryExpression[x] => vec![x],
-        PrimaryExpressionList[mut a] Comma PrimaryExpression[x] => {
+    DataValueList: Vec<Expr> {
+        DataValue[x] => vec![x],
+        DataValueList[mut a] Comma DataValue[x] => {
             a.push(x);
             a
         }
     }
 
+    DataValue: Expr {
+        DataItem[x] => x,
+        DataItem[x] Colon Expression[n] => Expr {
+            span: span!(),
+            node: Expr_::Repeat {
+                value: Box::new(x),
+                count: Box::new(n),
+            },
+        }
+    }
+
+    DataItem: Expr {
+        Expression[x] => x,
+        FloatLiteral[x] => x,
+        Minus Float(f) => Expr {
+            span: span!(),
+            node: Expr_::FloatLiteral(-f),
+        },
+        StringLiteral[x] => x,
+    }
+
 
     ArgumentList: Vec<Expr> {
         Argument[x] => vec![x],
@@ -190,25 +289,135 @@ parser! {
 
     Argument: Expr {
         RegisterExpression[x] => x,
-        PrimaryExpression[x] => x,
+        Expression[x] => x,
+        MemoryOperand[x] => x,
     }
 
-    RegisterExpression: Expr {
-        Register(s) => Expr {
+    MemoryOperand: Expr {
+        LParen RegisterExpression[base] RParen => Expr {
             span: span!(),
-            node: Expr_::Register(s),
+            node: Expr_::MemoryOperand {
+                offset: None,
+                base: Box::new(base),
+            },
+        },
+        Expression[x] LParen RegisterExpression[base] RParen => Expr {
+            span: span!(),
+            node: Expr_::MemoryOperand {
+                offset: Some(Box::new(x)),
+                base: Box::new(base),
+            },
         }
     }
 
-    PrimaryExpression: Expr {
-        Literal[x] => x,
-        Identifier[x] => x,
+    // Constant expressions, with the precedence of C
+    Expression: Expr {
+        LogicalOr[x] => x,
+    }
+
+    LogicalOr: Expr {
+        LogicalOr[a] OrOr LogicalAnd[b] => binary(BinaryOp::LogicalOr, a, b, span!()),
+        LogicalAnd[x] => x,
+    }
+
+    LogicalAnd: Expr {
+        LogicalAnd[a] AndAnd BitOr[b] => binary(BinaryOp::LogicalAnd, a, b, span!()),
+        BitOr[x] => x,
+    }
+
+    BitOr: Expr {
+        BitOr[a] Pipe BitXor[b] => binary(BinaryOp::Or, a, b, span!()),
+        BitXor[x] => x,
+    }
+
+    BitXor: Expr {
+        BitXor[a] Caret BitAnd[b] => binary(BinaryOp::Xor, a, b, span!()),
+        BitAnd[x] => x,
+    }
+
+    BitAnd: Expr {
+        BitAnd[a] Ampersand Equality[b] => binary(BinaryOp::And, a, b, span!()),
+        Equality[x] => x,
+    }
+
+    Equality: Expr {
+        Equality[a] EqualEqual Relational[b] => binary(BinaryOp::Eq, a, b, span!()),
+        Equality[a] NotEqual Relational[b] => binary(BinaryOp::Ne, a, b, span!()),
+        Relational[x] => x,
+    }
+
+    Relational: Expr {
+        Relational[a] Less Shift[b] => binary(BinaryOp::Lt, a, b, span!()),
+        Relational[a] LessEqual Shift[b] => binary(BinaryOp::Le, a, b, span!()),
+        Relational[a] Greater Shift[b] => binary(BinaryOp::Gt, a, b, span!()),
+        Relational[a] GreaterEqual Shift[b] => binary(BinaryOp::Ge, a, b, span!()),
+        Shift[x] => x,
+    }
+
+    Shift: Expr {
+        Shift[a] ShiftLeft Additive[b] => binary(BinaryOp::Shl, a, b, span!()),
+        Shift[a] ShiftRight Additive[b] => binary(BinaryOp::Shr, a, b, span!()),
+        Additive[x] => x,
+    }
+
+    Additive: Expr {
+        Additive[a] Plus Multiplicative[b] => binary(BinaryOp::Add, a, b, span!()),
+        Additive[a] Minus Multiplicative[b] => binary(BinaryOp::Sub, a, b, span!()),
+        Multiplicative[x] => x,
+    }
+
+    Multiplicative: Expr {
+        Multiplicative[a] Star Unary[b] => binary(BinaryOp::Mul, a, b, span!()),
+        Multiplicative[a] Slash Unary[b] => binary(BinaryOp::Div, a, b, span!()),
+        Multiplicative[a] Percent Unary[b] => binary(BinaryOp::Rem, a, b, span!()),
+        Unary[x] => x,
     }
 
-    Literal: Expr {
+    Unary: Expr {
+        Minus Unary[x] => Expr {
+            span: span!(),
+            node: Expr_::Unary {
+                op: UnaryOp::Neg,
+                operand: Box::new(x),
+            },
+        },
+        Tilde Unary[x] => Expr {
+            span: span!(),
+            node: Expr_::Unary {
+                op: UnaryOp::Not,
+                operand: Box::new(x),
+            },
+        },
+        Bang Unary[x] => Expr {
+            span: span!(),
+            node: Expr_::Unary {
+                op: UnaryOp::LogicalNot,
+                operand: Box::new(x),
+            },
+        },
+        Plus Unary[x] => x,
+        Atom[x] => x,
+    }
+
+    Atom: Expr {
         IntLiteral[x] => x,
-        FloatLiteral[x] => x,
-        StringLiteral[x] => x,
+        Identifier[x] => x,
+        LParen Expression[x] RParen => x,
+        Hi LParen Expression[x] RParen => Expr {
+            span: span!(),
+            node: Expr_::Hi(Box::new(x)),
+        },
+        Lo LParen Expression[x] RParen => Expr {
+            span: span!(),
+            node: Expr_::Lo(Box::new(x)),
+        },
+    }
+
+    RegisterExpression: Expr {
+        Register(s) => Expr {
+            span: span!(),
+            node: Expr_::Register(s),
+        }
     }
 
     IntLiteral: Expr {
@@ -0,0 +1,819 @@
+use super::expression::{check_range, evaluate, parse_expression, Field};
+
+use crate::errors::{MimicError, MimicErrorType, Span};
+
+use codespan_reporting::files::SimpleFile;
+
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::path::Path;
+
+
+// Deepest nesting of macro expansions and included files
+const MAX_DEPTH: usize = 64;
+
+// Where a run of preprocessed text came from
+#[derive(Debug, Clone, Copy)]
+struct Origin {
+    file: usize,
+    start: usize,
+    len: usize,
+    // The text is a byte for byte copy of the original
+    verbatim: bool,
+    // The macro expansion the text belongs to
+    expansion: Option<usize>,
+}
+
+impl Origin {
+    fn span(&self) -> Span {
+        Span { lo: self.start, hi: self.start + self.len }
+    }
+}
+
+// Text together with the origin of each run of it
+#[derive(Debug, Clone, Default)]
+struct Mapped {
+    text: String,
+    // Start of each run in `text`, in order
+    pieces: Vec<(usize, Origin)>,
+}
+
+impl Mapped {
+    fn from_file(text: &str, file: usize, start: usize) -> Self {
+        let mut mapped = Mapped::default();
+        mapped.push_str(text, Origin { file, start, len: text.len(), verbatim: true, expansion: None });
+        mapped
+    }
+
+    fn push_str(&mut self, text: &str, origin: Origin) {
+        if !text.is_empty() {
+            self.pieces.push((self.text.len(), origin));
+            self.text.push_str(text);
+        }
+    }
+
+    fn push(&mut self, other: &Mapped) {
+        let base = self.text.len();
+        self.pieces.extend(other.pieces.iter().map(|(at, origin)| (base + at, *origin)));
+        self.text.push_str(&other.text);
+    }
+
+    // The end of a line, found at the end of the text before it
+    fn push_separator(&mut self, separator: &str) {
+        let origin = self.pieces.last().map_or(
+            Origin { file: 0, start: 0, len: 0, verbatim: false, expansion: None },
+            |(_, origin)| Origin { start: origin.start + origin.len, len: 0, verbatim: false, ..*origin },
+        );
+        self.push_str(separator, origin);
+    }
+
+    fn end_of(&self, index: usize) -> usize {
+        self.pieces.get(index + 1).map_or(self.text.len(), |(at, _)| *at)
+    }
+
+    fn slice(&self, range: Range<usize>) -> Mapped {
+        let mut sliced = Mapped::default();
+        for (i, (at, origin)) in self.pieces.iter().enumerate() {
+            let (lo, hi) = (range.start.max(*at), range.end.min(self.end_of(i)));
+            if lo >= hi {
+                continue;
+            }
+            let mut origin = *origin;
+            if origin.verbatim {
+                origin.start += lo - at;
+                origin.len = hi - lo;
+            }
+            sliced.push_str(&self.text[lo..hi], origin);
+        }
+        sliced
+    }
+
+    fn trim(&self) -> Mapped {
+        let start = self.text.len() - self.text.trim_start().len();
+        let end = self.text.trim_end().len().max(start);
+        self.slice(start..end)
+    }
+
+    // Original location of a range of the text. A range over several runs ends with the
+    // first run unless the last one is from the same place. Replaced text maps to all
+    // of the text it replaced.
+    fn origin(&self, range: Range<usize>) -> Option<Origin> {
+        let first = self.pieces.partition_point(|(at, _)| *at <= range.start).checked_sub(1)?;
+        let last = self.pieces.partition_point(|(at, _)| *at < range.end).saturating_sub(1).max(first);
+        let (at, origin) = self.pieces[first];
+        let (last_at, last_origin) = self.pieces[last];
+
+        let start = if origin.verbatim { origin.start + (range.start - at) } else { origin.start };
+        let end_of = |at: usize, origin: Origin| match origin.verbatim {
+            true => origin.start + (range.end - at).min(origin.len),
+            false => origin.start + origin.len,
+        };
+        let end = if last == first {
+            end_of(at, origin)
+        } else if last_origin.file == origin.file && last_origin.expansion == origin.expansion && last_origin.start >= start {
+            end_of(last_at, last_origin)
+        } else {
+            origin.start + origin.len
+        };
+        Some(Origin { start, len: end.max(start) - start, ..origin })
+    }
+
+    fn with_expansion(&self, expansion: usize) -> Mapped {
+        Mapped {
+            text: self.text.clone(),
+            pieces: self.pieces.iter().map(|(at, origin)| (*at, Origin { expansion: Some(expansion), ..*origin })).collect(),
+        }
+    }
+
+    // Replaces the words `replace` gives text for
+    fn replace_words<F>(&self, mut replace: F) -> Result<Mapped, MimicError>
+    where
+        F: FnMut(&str, Word, Range<usize>) -> Result<Option<Mapped>, MimicError>,
+    {
+        let mut replaced = Mapped::default();
+        let mut copied = 0;
+        for (range, word) in words(&self.text) {
+            if let Some(replacement) = replace(&self.text[range.clone()], word, range.clone())? {
+                replaced.push(&self.slice(copied..range.start));
+                replaced.push(&replacement);
+                copied = range.end;
+            }
+        }
+        replaced.push(&self.slice(copied..self.text.len()));
+        Ok(replaced)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Word {
+    Ident,
+    // %name
+    Param,
+    // .name
+    Directive,
+    // A string or character literal
+    Literal,
+    Comment,
+    Space,
+    Other,
+}
+
+// Splits a line into identifiers, directives, macro parameters, literals, comments,
+// whitespace and everything else, which includes registers and numbers
+fn words(line: &str) -> Vec<(Range<usize>, Word)> {
+    let bytes = line.as_bytes();
+    let is_word = |b: u8| b.is_ascii_alphanumeric() || b == b'_';
+    let mut words = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let start = i;
+        let word = match bytes[i] {
+            b'#' => {
+                i = bytes.len();
+                Word::Comment
+            },
+            quote @ (b'"' | b'\'') => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != quote {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                i = (i + 1).min(bytes.len());
+                Word::Literal
+            },
+            b'.' | b'%' | b'$' if bytes.get(i + 1).is_some_and(|b| is_word(*b)) => {
+                i += 1;
+                while i < bytes.len() && is_word(bytes[i]) {
+                    i += 1;
+                }
+                match bytes[start] {
+                    b'.' => Word::Directive,
+                    b'%' => Word::Param,
+                    _ => Word::Other,
+                }
+            },
+            b if is_word(b) => {
+                while i < bytes.len() && is_word(bytes[i]) {
+                    i += 1;
+                }
+                if b.is_ascii_digit() { Word::Other } else { Word::Ident }
+            },
+            b if b.is_ascii_whitespace() => {
+                while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                    i += 1;
+                }
+                Word::Space
+            },
+            _ => {
+                i += line[i..].chars().next().map_or(1, char::len_utf8);
+                Word::Other
+            },
+        };
+        // An escape can step past the end of an unterminated literal
+        words.push((start..i.min(bytes.len()), word));
+    }
+
+    words
+}
+
+// Words of a line other than whitespace
+fn tokens(line: &str) -> Vec<(Range<usize>, Word)> {
+    words(line).into_iter().filter(|(_, word)| *word != Word::Space).collect()
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Mapped>,
+}
+
+// An .if, .ifdef or .ifndef up to its .endif
+struct Conditional {
+    origin: Origin,
+    // Lines are assembled
+    active: bool,
+    // Some branch was taken, or the enclosing lines are skipped
+    taken: bool,
+    seen_else: bool,
+}
+
+// A macro being defined, up to its .end_macro
+struct Definition {
+    name: String,
+    origin: Origin,
+    params: Vec<String>,
+    body: Vec<Mapped>,
+}
+
+#[derive(Debug, Clone)]
+struct Expansion {
+    name: String,
+    call: Origin,
+}
+
+// Source with its macros expanded, files included, conditional lines dropped and
+// .eqv and .set names replaced. Each line of the main file is one line of the
+// preprocessed text, with the lines of macros and included files separated by `;`.
+#[derive(Debug, Clone)]
+pub struct Preprocessed {
+    output: Mapped,
+    files: Vec<SimpleFile<String, String>>,
+    expansions: Vec<Expansion>,
+}
+
+impl Preprocessed {
+    pub fn text(&self) -> &str {
+        &self.output.text
+    }
+
+    // Moves an error in the preprocessed text back to the source it came from
+    pub fn locate(&self, error: MimicError) -> MimicError {
+        match error.span.and_then(|span| self.output.origin(span.range())) {
+            Some(origin) => located(error.ty, origin, &self.files, &self.expansions),
+            None => MimicError { source: Some(Box::new(self.files[0].clone())), ..error },
+        }
+    }
+}
+
+// An error at `origin`, inside the macro expansions it came from
+fn located(ty: MimicErrorType, origin: Origin, files: &[SimpleFile<String, String>], expansions: &[Expansion]) -> MimicError {
+    let mut error = MimicError {
+        span: Some(origin.span()),
+        source: Some(Box::new(files[origin.file].clone())),
+        ty,
+    };
+
+    let mut expansion = origin.expansion;
+    while let Some(id) = expansion {
+        let call = expansions[id].call;
+        error = MimicError {
+            span: Some(call.span()),
+            source: Some(Box::new(files[call.file].clone())),
+            ty: MimicErrorType::MacroExpansion { name: expansions[id].name.clone(), error: Box::new(error) },
+        };
+        expansion = call.expansion;
+    }
+    error
+}
+
+struct Preprocessor {
+    files: Vec<SimpleFile<String, String>>,
+    expansions: Vec<Expansion>,
+    macros: HashMap<String, Vec<Macro>>,
+    eqv: HashMap<String, String>,
+    // Values from .set and definitions given to the assembler
+    symbols: HashMap<String, i64>,
+}
+
+// Expands `.macro`, `.eqv`, `.include` and conditional assembly in `file`, with
+// `defines` set as if by `.set` before the first line. Included paths are relative to `dir`.
+pub fn preprocess(file: SimpleFile<String, String>, dir: &Path, defines: &[(String, String)]) -> Result<Preprocessed, MimicError> {
+    let mut preprocessor = Preprocessor {
+        files: vec![file],
+        expansions: Vec::new(),
+        macros: HashMap::new(),
+        eqv: HashMap::new(),
+        symbols: HashMap::new(),
+    };
+
+    // Each definition reads as its own `NAME=value` file in errors
+    for (name, value) in defines {
+        preprocessor.files.push(SimpleFile::new("-D".to_owned(), format!("{}={}", name, value)));
+        let line = Mapped::from_file(preprocessor.files.last().unwrap().source(), preprocessor.files.len() - 1, 0);
+        let tokens = tokens(&line.text);
+        if !matches!(tokens.as_slice(), [(range, Word::Ident), ..] if range.len() == name.len()) {
+            return Err(preprocessor.directive_error(&line, 0..name.len(), &format!("invalid symbol name [{}]", name)));
+        }
+        preprocessor.set_symbol(&line, 0..name.len(), name.len() + 1, 0..line.text.len())?;
+    }
+
+    let mut output = Mapped::default();
+    preprocessor.process_file(0, dir, "\n", &mut output, 0)?;
+
+    Ok(Preprocessed {
+        output,
+        files: preprocessor.files,
+        expansions: preprocessor.expansions,
+    })
+}
+
+impl Preprocessor {
+    fn error(&self, ty: MimicErrorType, origin: Origin) -> MimicError {
+        located(ty, origin, &self.files, &self.expansions)
+    }
+
+    fn directive_error(&self, line: &Mapped, range: Range<usize>, message: &str) -> MimicError {
+        let origin = line.origin(range).expect("words of a line have an origin");
+        self.error(MimicErrorType::InvalidDirective { message: message.to_owned() }, origin)
+    }
+
+    fn process_file(&mut self, file: usize, dir: &Path, separator: &str, out: &mut Mapped, depth: usize) -> Result<(), MimicError> {
+        let text = self.files[file].source().clone();
+        let mut start = 0;
+        let lines = text.split('\n').map(|line| {
+            let mapped = Mapped::from_file(line, file, start);
+            start += line.len() + 1;
+            mapped
+        }).collect::<Vec<_>>();
+
+        // Renamed macro labels can't collide with labels in the source
+        for line in &lines {
+            let tokens = tokens(&line.text);
+            for pair in tokens.windows(2) {
+                let name = &line.text[pair[0].0.clone()];
+                if pair[0].1 == Word::Ident && &line.text[pair[1].0.clone()] == ":" && is_renamed_label(name) {
+                    let message = format!("label [{}] is reserved for labels renamed in macro expansions", name);
+                    return Err(self.error(MimicErrorType::InvalidMacro { message }, line.origin(pair[0].0.clone()).unwrap()));
+                }
+            }
+        }
+
+        self.process_lines(lines, dir, separator, out, depth)
+    }
+
+    fn process_lines(&mut self, lines: Vec<Mapped>, dir: &Path, separator: &str, out: &mut Mapped, depth: usize) -> Result<(), MimicError> {
+        let mut definition: Option<Definition> = None;
+        let mut conditionals: Vec<Conditional> = Vec::new();
+
+        for (i, line) in lines.into_iter().enumerate() {
+            if i > 0 {
+                out.push_separator(separator);
+            }
+
+            let tokens = tokens(&line.text);
+            let directive = match tokens.first() {
+                Some((range, Word::Directive)) => Some((range.clone(), &line.text[range.clone()])),
+                _ => None,
+            };
+
+            if let Some(d) = &mut definition {
+                match directive {
+                    Some((_, ".end_macro")) => {
+                        let d = definition.take().unwrap();
+                        self.macros.entry(d.name).or_default().push(Macro { params: d.params, body: d.body });
+                    },
+                    Some((range, ".macro")) => {
+                        return Err(self.directive_error(&line, range, "a macro can't be defined inside another macro"));
+                    },
+                    _ => d.body.push(line),
+                }
+                continue;
+            }
+
+            let active = conditionals.last().is_none_or(|c| c.active);
+            match directive {
+                Some((range, ".if" | ".ifdef" | ".ifndef")) => {
+                    let value = active && self.condition(&line, range.clone(), &tokens)?;
+                    let origin = line.origin(range).unwrap();
+                    conditionals.push(Conditional { origin, active: value, taken: value || !active, seen_else: false });
+                },
+                Some((range, ".elseif")) => {
+                    let c = match conditionals.last_mut() {
+                        Some(c) if !c.seen_else => c,
+                        Some(_) => return Err(self.directive_error(&line, range, ".elseif after .else")),
+                        None => return Err(self.directive_error(&line, range, ".elseif without .if")),
+                    };
+                    c.active = !c.taken && self.condition(&line, range, &tokens)?;
+                    c.taken |= c.active;
+                },
+                Some((range, ".else")) => {
+                    let c = match conditionals.last_mut() {
+                        Some(c) if !c.seen_else => c,
+                        Some(_) => return Err(self.directive_error(&line, range, ".else after .else")),
+                        None => return Err(self.directive_error(&line, range, ".else without .if")),
+                    };
+                    c.active = !c.taken;
+                    c.taken = true;
+                    c.seen_else = true;
+                },
+                Some((range, ".endif")) => {
+                    if conditionals.pop().is_none() {
+                        return Err(self.directive_error(&line, range, ".endif without .if"));
+                    }
+                },
+                _ if !active => {},
+                Some((range, ".set")) => match tokens.get(1) {
+                    Some((name, Word::Ident)) => {
+                        let value = match tokens.get(2) {
+                            Some((comma, Word::Other)) if &line.text[comma.clone()] == "," => comma.end,
+                            _ => name.end,
+                        };
+                        self.set_symbol(&line, name.clone(), value, range)?;
+                    },
+                    _ => return Err(self.directive_error(&line, range, "expected a name and a value")),
+                },
+                Some((range, ".macro")) => definition = Some(self.macro_header(&line, range, &tokens)?),
+                Some((range, ".end_macro")) => return Err(self.directive_error(&line, range, ".end_macro without .macro")),
+                Some((range, ".eqv")) => self.define_eqv(&line, range, &tokens)?,
+                Some((range, ".include")) => self.include(&line, range, &tokens, dir, out, depth)?,
+                _ => self.expand_line(&line, dir, out, depth)?,
+            }
+        }
+
+        if let Some(d) = definition {
+            return Err(self.error(MimicErrorType::InvalidDirective { message: ".macro without .end_macro".to_owned() }, d.origin));
+        }
+        match conditionals.first() {
+            Some(c) => Err(self.error(MimicErrorType::InvalidDirective { message: ".if without .endif".to_owned() }, c.origin)),
+            None => Ok(()),
+        }
+    }
+
+    // Whether the lines after `.if expr`, `.elseif expr`, `.ifdef NAME` or `.ifndef NAME` are assembled
+    fn condition(&self, line: &Mapped, directive: Range<usize>, tokens: &[(Range<usize>, Word)]) -> Result<bool, MimicError> {
+        match &line.text[directive.clone()] {
+            ".ifdef" | ".ifndef" => match tokens {
+                [_, (name, Word::Ident)] | [_, (name, Word::Ident), (_, Word::Comment)] => {
+                    let name = &line.text[name.clone()];
+                    let defined = self.symbols.contains_key(name) || self.eqv.contains_key(name);
+                    Ok(defined == (&line.text[directive] == ".ifdef"))
+                },
+                _ => Err(self.directive_error(line, directive, "expected a name")),
+            },
+            _ => Ok(self.constant(line, directive.end, directive)? != 0),
+        }
+    }
+
+    // `.set NAME, expr`, with the value starting at `value`. Errors without a place of
+    // their own are reported at `at`.
+    fn set_symbol(&mut self, line: &Mapped, name: Range<usize>, value: usize, at: Range<usize>) -> Result<(), MimicError> {
+        let value = self.constant(line, value, at)?;
+        self.symbols.insert(line.text[name].to_owned(), value);
+        Ok(())
+    }
+
+    // Value of the constant expression from `start` to the end of a line
+    fn constant(&self, line: &Mapped, start: usize, at: Range<usize>) -> Result<i64, MimicError> {
+        let expression = self.substitute(&line.slice(start..line.text.len()))?.trim();
+        let file = SimpleFile::new(String::new(), expression.text.clone());
+        let relocate = |error: MimicError| match error.span.and_then(|span| expression.origin(span.range())) {
+            Some(origin) => self.error(error.ty, origin),
+            None => self.directive_error(line, at.clone(), "expected an expression"),
+        };
+
+        let expr = parse_expression(&file, 0..expression.text.len()).map_err(relocate)?;
+        let value = evaluate(&expr, &HashMap::new(), &file).map_err(relocate)?;
+        check_range(value, Field::Any(32), expr.span, &file).map_err(relocate)?;
+        Ok(value)
+    }
+
+    // Drops the comment and replaces .eqv names, then .set names with their values
+    fn substitute(&self, line: &Mapped) -> Result<Mapped, MimicError> {
+        let replacement = |text: &str, range: Range<usize>, line: &Mapped| {
+            let mut replacement = Mapped::default();
+            replacement.push_str(text, Origin { verbatim: false, ..line.origin(range).unwrap() });
+            replacement
+        };
+
+        let line = line.replace_words(|text, word, range| match (word, self.eqv.get(text)) {
+            (Word::Comment, _) => Ok(Some(Mapped::default())),
+            (Word::Ident, Some(value)) => Ok(Some(replacement(value, range, line))),
+            _ => Ok(None),
+        })?;
+        line.replace_words(|text, word, range| match (word, self.symbols.get(text)) {
+            (Word::Ident, Some(value)) if *value < 0 => Ok(Some(replacement(&format!("({})", value), range, &line))),
+            (Word::Ident, Some(value)) => Ok(Some(replacement(&value.to_string(), range, &line))),
+            _ => Ok(None),
+        })
+    }
+
+    // `.macro name`, `.macro name(%a, %b)` or `.macro name %a, %b`
+    fn macro_header(&self, line: &Mapped, directive: Range<usize>, tokens: &[(Range<usize>, Word)]) -> Result<Definition, MimicError> {
+        let (name, origin) = match tokens.get(1) {
+            Some((range, Word::Ident)) => (line.text[range.clone()].to_owned(), line.origin(range.clone()).unwrap()),
+            _ => return Err(self.directive_error(line, directive, "expected a macro name")),
+        };
+
+        let mut params: Vec<String> = Vec::new();
+        for (range, word) in &tokens[2..] {
+            let text = &line.text[range.clone()];
+            match word {
+                Word::Param if params.iter().any(|p| p == text) => {
+                    return Err(self.directive_error(line, range.clone(), &format!("duplicate macro parameter [{}]", text)));
+                },
+                Word::Param => params.push(text.to_owned()),
+                Word::Comment => break,
+                Word::Other if matches!(text, "(" | ")" | ",") => {},
+                _ => return Err(self.directive_error(line, range.clone(), "expected a macro parameter such as %arg")),
+            }
+        }
+
+        Ok(Definition { name, origin, params, body: Vec::new() })
+    }
+
+    // `.eqv NAME text`
+    fn define_eqv(&mut self, line: &Mapped, directive: Range<usize>, tokens: &[(Range<usize>, Word)]) -> Result<(), MimicError> {
+        let value_start = match (tokens.get(1), tokens.get(2)) {
+            (Some((_, Word::Ident)), Some((value, word))) if *word != Word::Comment => value.start,
+            _ => return Err(self.directive_error(line, directive, "expected a name and a value")),
+        };
+        let value_end = tokens.iter().find(|(_, word)| *word == Word::Comment).map_or(line.text.len(), |(range, _)| range.start);
+
+        let name = line.text[tokens[1].0.clone()].to_owned();
+        self.eqv.insert(name, line.text[value_start..value_end].trim_end().to_owned());
+        Ok(())
+    }
+
+    // `.include "file"`, preprocessed onto the line of the directive
+    fn include(
+        &mut self,
+        line: &Mapped,
+        directive: Range<usize>,
+        tokens: &[(Range<usize>, Word)],
+        dir: &Path,
+        out: &mut Mapped,
+        depth: usize,
+    ) -> Result<(), MimicError> {
+        let range = match tokens.get(1) {
+            Some((range, Word::Literal)) if line.text[range.clone()].starts_with('"') && range.len() > 1 => range.clone(),
+            _ => return Err(self.directive_error(line, directive, "expected a file name in quotes")),
+        };
+        if depth >= MAX_DEPTH {
+            return Err(self.directive_error(line, range, "included files are nested too deeply"));
+        }
+
+        let name = &line.text[range.start + 1..range.end - 1];
+        let path = dir.join(name);
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|_| self.directive_error(line, range.clone(), &format!("unable to read [{}]", name)))?;
+
+        self.files.push(SimpleFile::new(name.to_owned(), contents));
+        let file = self.files.len() - 1;
+        let dir = path.parent().unwrap_or(dir).to_owned();
+        self.process_file(file, &dir, ";", out, depth + 1)
+    }
+
+    // Replaces .eqv and .set names, then expands the line if it calls a macro
+    fn expand_line(&mut self, line: &Mapped, dir: &Path, out: &mut Mapped, depth: usize) -> Result<(), MimicError> {
+        let line = self.substitute(line)?;
+
+        // Labels before a call stay where they are
+        let tokens = tokens(&line.text);
+        let mut first = 0;
+        while let [(_, Word::Ident), (colon, Word::Other), ..] = &tokens[first..] {
+            if &line.text[colon.clone()] != ":" {
+                break;
+            }
+            first += 2;
+        }
+
+        match tokens.get(first) {
+            Some((name, Word::Ident)) if self.macros.contains_key(&line.text[name.clone()]) => {
+                out.push(&line.slice(0..name.start));
+                let args = self.macro_arguments(&line, name.end)?;
+                self.expand_macro(&line, name.clone(), args, dir, out, depth)
+            },
+            _ => {
+                out.push(&line);
+                Ok(())
+            },
+        }
+    }
+
+    // Arguments after the macro name, in parentheses or not
+    fn macro_arguments(&self, line: &Mapped, start: usize) -> Result<Vec<Mapped>, MimicError> {
+        let rest = line.slice(start..line.text.len()).trim();
+        let words = words(&rest.text);
+
+        // Parentheses around the whole list
+        let mut level = 0;
+        let mut closing = None;
+        for (range, _) in words.iter().filter(|(_, word)| *word == Word::Other) {
+            match &rest.text[range.clone()] {
+                "(" => level += 1,
+                ")" if level == 1 => {
+                    closing = Some(range.start);
+                    break;
+                },
+                ")" => level -= 1,
+                _ => {},
+            }
+        }
+        let list = match closing {
+            Some(end) if rest.text.starts_with('(') && end + 1 == rest.text.len() => rest.slice(1..end),
+            _ => rest,
+        };
+        if list.text.trim().is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut args = Vec::new();
+        let mut level = 0;
+        let mut arg_start = 0;
+        for (range, word) in words_with_end(&list.text) {
+            let text = &list.text[range.clone()];
+            match (word, text) {
+                (Word::Other, "(") => level += 1,
+                (Word::Other, ")") => level -= 1,
+                (Word::Other, ",") | (Word::Space, "") if level == 0 => {
+                    let arg = list.slice(arg_start..range.start).trim();
+                    if arg.text.is_empty() {
+                        let origin = list.origin(range.clone()).or_else(|| line.origin(start..start)).unwrap();
+                        return Err(self.error(MimicErrorType::InvalidMacro { message: "empty macro argument".to_owned() }, origin));
+                    }
+                    args.push(arg);
+                    arg_start = range.end;
+                },
+                _ => {},
+            }
+        }
+        Ok(args)
+    }
+
+    fn expand_macro(
+        &mut self,
+        line: &Mapped,
+        name_range: Range<usize>,
+        args: Vec<Mapped>,
+        dir: &Path,
+        out: &mut Mapped,
+        depth: usize,
+    ) -> Result<(), MimicError> {
+        let name = line.text[name_range.clone()].to_owned();
+        let call = line.origin(name_range).unwrap();
+
+        // Reported at the first repeated call rather than once per level down to MAX_DEPTH
+        let mut cycle = vec![name.clone()];
+        let mut expansion = call.expansion;
+        while let Some(id) = expansion {
+            cycle.push(self.expansions[id].name.clone());
+            if self.expansions[id].name == name {
+                cycle.reverse();
+                let message = format!("macro [{}] expands itself: {}", name, cycle.join(" -> "));
+                return Err(self.error(MimicErrorType::InvalidMacro { message }, call));
+            }
+            expansion = self.expansions[id].call.expansion;
+        }
+        if depth >= MAX_DEPTH {
+            return Err(self.error(MimicErrorType::InvalidMacro { message: format!("macro [{}] is nested too deeply", name) }, call));
+        }
+
+        let definition = match self.macros[&name].iter().find(|m| m.params.len() == args.len()) {
+            Some(definition) => definition.clone(),
+            None => {
+                let message = format!("macro [{}] does not take {} arguments", name, args.len());
+                return Err(self.error(MimicErrorType::InvalidMacro { message }, call));
+            },
+        };
+
+        let id = self.expansions.len();
+        self.expansions.push(Expansion { name, call });
+
+        // Labels of the body are renamed in each expansion
+        let mut labels = HashSet::new();
+        for line in &definition.body {
+            let tokens = tokens(&line.text);
+            for pair in tokens.windows(2) {
+                if pair[0].1 == Word::Ident && &line.text[pair[1].0.clone()] == ":" {
+                    labels.insert(line.text[pair[0].0.clone()].to_owned());
+                }
+            }
+        }
+
+        let mut body = Vec::new();
+        for line in &definition.body {
+            let line = line.with_expansion(id);
+            body.push(line.replace_words(|text, word, range| {
+                if word == Word::Param {
+                    if let Some(i) = definition.params.iter().position(|p| p == text) {
+                        return Ok(Some(args[i].clone()));
+                    }
+                }
+                if word == Word::Ident && labels.contains(text) {
+                    let mut renamed = Mapped::default();
+                    renamed.push_str(&format!("{}_M{}", text, id), Origin { verbatim: false, ..line.origin(range).unwrap() });
+                    return Ok(Some(renamed));
+                }
+                Ok(None)
+            })?);
+        }
+
+        self.process_lines(body, dir, ";", out, depth + 1)
+    }
+}
+
+// `label_M<n>`, the name a label of a macro body gets in expansion n
+fn is_renamed_label(name: &str) -> bool {
+    name.rsplit_once("_M").is_some_and(|(label, id)| !label.is_empty() && !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()))
+}
+
+// Words of a line followed by an empty Space word at its end
+fn words_with_end(line: &str) -> impl Iterator<Item = (Range<usize>, Word)> {
+    words(line).into_iter().chain(std::iter::once((line.len()..line.len(), Word::Space)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(source: &str) -> Result<String, MimicError> {
+        let file = SimpleFile::new("test.asm".to_owned(), source.to_owned());
+        preprocess(file, Path::new("."), &[]).map(|p| p.text().to_owned())
+    }
+
+    #[test]
+    fn macros_and_eqv() {
+        let source = "\
+.eqv COUNT 4
+.macro inc(%r, %n)  # comment
+loop: addi %r, %r, %n
+.end_macro
+main: inc($t0, COUNT)
+    inc $t1, -1
+";
+        assert_eq!(
+            expand(source).unwrap(),
+            "\n\n\n\nmain: loop_M0: addi $t0, $t0, 4\n    loop_M1: addi $t1, $t1, -1\n",
+        );
+
+        assert_eq!(expand("li $t0, 'a' # ,\n").unwrap(), "li $t0, 'a' \n");
+        assert_eq!(expand("m(1)\n.macro m(%a)\n.end_macro").unwrap(), "m(1)\n\n");
+    }
+
+    #[test]
+    fn recursive_macros() {
+        let source = ".macro a\nb\n.end_macro\n.macro b\nnop\na\n.end_macro\nmain: a\n";
+        let mut error = expand(source).unwrap_err();
+        let mut names = Vec::new();
+        while let MimicErrorType::MacroExpansion { name, error: inner } = error.ty {
+            names.push(name);
+            error = *inner;
+        }
+        assert_eq!(names, ["a", "b"]);
+        match error.ty {
+            MimicErrorType::InvalidMacro { message } => assert_eq!(message, "macro [a] expands itself: a -> b -> a"),
+            ty => panic!("{:?}", ty),
+        }
+        assert_eq!(error.span.unwrap().range(), 35..36);
+    }
+
+    #[test]
+    fn renamed_labels_are_reserved() {
+        let source = "loop_M0: nop\n.macro m\nloop: j loop\n.end_macro\nm\n";
+        let error = expand(source).unwrap_err();
+        assert!(matches!(error.ty, MimicErrorType::InvalidMacro { .. }));
+        assert_eq!(error.span.unwrap().range(), 0..7);
+
+        assert!(expand("loop_M: nop\nM0: nop\nj loop_M0\n").is_ok());
+    }
+
+    #[test]
+    fn conditionals() {
+        let source = ".set N, 3\n.if N == 3\na\n.if 0\nb\n.endif\n.else\nc\n.endif\nN\n";
+        assert_eq!(expand(source).unwrap(), "\n\na\n\n\n\n\n\n\n3\n");
+        assert_eq!(expand(".eqv M (0 - 2)\n.set N, M\nN").unwrap(), "\n\n(-2)");
+    }
+
+    #[test]
+    fn origins() {
+        let mut mapped = Mapped::from_file("abc", 0, 10);
+        mapped.push_str("XY", Origin { file: 1, start: 5, len: 1, verbatim: false, expansion: None });
+        mapped.push(&Mapped::from_file("def", 0, 13));
+
+        assert_eq!(mapped.origin(1..2).unwrap().span().range(), 11..12);
+        assert_eq!(mapped.origin(4..5).unwrap().span().range(), 5..6);
+        assert_eq!(mapped.origin(1..7).unwrap().span().range(), 11..15);
+        assert_eq!(mapped.slice(2..6).origin(3..4).unwrap().span().range(), 13..14);
+    }
+}
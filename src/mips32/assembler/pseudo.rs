@@ -0,0 +1,618 @@
+use super::parser::{BinaryOp, Expr, Expr_};
+use super::expression::{check_range, evaluate, references_symbol, Field};
+
+use crate::errors::{MimicError, MimicErrorType, Span};
+
+use codespan_reporting::files::SimpleFile;
+
+use std::collections::HashMap;
+
+use Arg::*;
+use Shape::*;
+
+
+// Operand shapes a form of a pseudo-instruction accepts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Shape {
+    Reg,
+    // Constants that fit a signed or unsigned 16 bit field, or have a zero lower half.
+    // %lo(x) and %hi(x) count as signed and unsigned 16 bit values.
+    Imm16,
+    ImmU16,
+    ImmUpper,
+    // Any constant or label expression
+    Imm,
+    // A register, or an immediate that is loaded into $at first
+    Source,
+    // An immediate that is loaded into $at first
+    Loaded,
+    // `offset(base)` with an offset a load or store can take directly
+    Mem16,
+    // `offset(base)` with a wider or label offset
+    Indexed,
+}
+
+// Operands of the instructions a form expands to
+#[derive(Debug, Clone, Copy)]
+enum Arg {
+    // An operand as written, or $at for an immediate Source or Loaded operand
+    Op(usize),
+    Named(&'static str),
+    Int(i64),
+    // The constant or label of an operand, which is the offset of a memory operand
+    Value(usize),
+    // Base register of a memory operand, $zero for other operands
+    Base(usize),
+    // `(value >> 16) & 0xFFFF` and `value & 0xFFFF`, for lui/ori pairs
+    Upper(usize),
+    Lower(usize),
+    // %hi(value), and `%lo(value)($at)` to go with it
+    Hi(usize),
+    LoAt(usize),
+    // `value+n(base)`
+    Mem(usize, i64),
+    // `n($at)`
+    At(i64),
+    // `(32 - value) & 31`, the opposite shift of a rotate
+    Opposite(usize),
+}
+
+const AT: Arg = Named("$at");
+const ZERO: Arg = Named("$zero");
+
+struct Step {
+    // Empty for the mnemonic of the pseudo-instruction itself
+    mnemonic: &'static str,
+    args: &'static [Arg],
+}
+
+struct Form {
+    shapes: &'static [Shape],
+    steps: &'static [Step],
+}
+
+struct Pseudo {
+    mnemonics: &'static [&'static str],
+    // Also a machine instruction, used as is when no form matches
+    native: bool,
+    // Shortest expansion first
+    forms: &'static [Form],
+}
+
+macro_rules! form {
+    ([$($shape:ident),*] => $($mnemonic:literal [$($arg:expr),*]);+) => {
+        Form {
+            shapes: &[$($shape),*],
+            steps: &[$(Step { mnemonic: $mnemonic, args: &[$($arg),*] }),+],
+        }
+    };
+}
+
+static PSEUDO_INSTRUCTIONS: &[Pseudo] = &[
+    Pseudo { mnemonics: &["li"], native: false, forms: &[
+        form!([Reg, Imm16] => "addiu" [Op(0), ZERO, Op(1)]),
+        form!([Reg, ImmU16] => "ori" [Op(0), ZERO, Op(1)]),
+        form!([Reg, ImmUpper] => "lui" [Op(0), Upper(1)]),
+        form!([Reg, Imm] => "lui" [AT, Upper(1)]; "ori" [Op(0), AT, Lower(1)]),
+    ]},
+    Pseudo { mnemonics: &["la"], native: false, forms: &[
+        form!([Reg, Mem16] => "addiu" [Op(0), Base(1), Value(1)]),
+        form!([Reg, Indexed] => "lui" [AT, Upper(1)]; "ori" [AT, AT, Lower(1)]; "addu" [Op(0), AT, Base(1)]),
+        form!([Reg, Imm16] => "addiu" [Op(0), ZERO, Op(1)]),
+        form!([Reg, Imm] => "lui" [AT, Upper(1)]; "ori" [Op(0), AT, Lower(1)]),
+    ]},
+    Pseudo { mnemonics: &["nop"], native: false, forms: &[
+        form!([] => "sll" [ZERO, ZERO, Int(0)]),
+    ]},
+    Pseudo { mnemonics: &["move"], native: false, forms: &[
+        form!([Reg, Reg] => "addu" [Op(0), ZERO, Op(1)]),
+    ]},
+    Pseudo { mnemonics: &["not"], native: false, forms: &[
+        form!([Reg, Reg] => "nor" [Op(0), Op(1), ZERO]),
+    ]},
+    Pseudo { mnemonics: &["neg"], native: false, forms: &[
+        form!([Reg, Reg] => "sub" [Op(0), ZERO, Op(1)]),
+    ]},
+    Pseudo { mnemonics: &["negu"], native: false, forms: &[
+        form!([Reg, Reg] => "subu" [Op(0), ZERO, Op(1)]),
+    ]},
+    Pseudo { mnemonics: &["abs"], native: false, forms: &[
+        form!([Reg, Reg] => "sra" [AT, Op(1), Int(31)]; "xor" [Op(0), AT, Op(1)]; "subu" [Op(0), Op(0), AT]),
+    ]},
+
+    // Loads and stores of labels and wide addresses
+    Pseudo { mnemonics: &["lb", "lbu", "lh", "lhu", "lw", "sb", "sh", "sw"], native: true, forms: &[
+        form!([Reg, Imm16] => "" [Op(0), Mem(1, 0)]),
+        form!([Reg, Imm] => "lui" [AT, Hi(1)]; "" [Op(0), LoAt(1)]),
+        form!([Reg, Indexed] => "lui" [AT, Hi(1)]; "addu" [AT, AT, Base(1)]; "" [Op(0), LoAt(1)]),
+    ]},
+    Pseudo { mnemonics: &["ulw"], native: false, forms: &[
+        form!([Reg, Mem16] => "lwl" [Op(0), Mem(1, 3)]; "lwr" [Op(0), Mem(1, 0)]),
+        form!([Reg, Imm16] => "lwl" [Op(0), Mem(1, 3)]; "lwr" [Op(0), Mem(1, 0)]),
+        form!([Reg, Imm] => "lui" [AT, Upper(1)]; "ori" [AT, AT, Lower(1)]; "lwl" [Op(0), At(3)]; "lwr" [Op(0), At(0)]),
+        form!([Reg, Indexed] => "lui" [AT, Upper(1)]; "ori" [AT, AT, Lower(1)]; "addu" [AT, AT, Base(1)];
+            "lwl" [Op(0), At(3)]; "lwr" [Op(0), At(0)]),
+    ]},
+    Pseudo { mnemonics: &["usw"], native: false, forms: &[
+        form!([Reg, Mem16] => "swl" [Op(0), Mem(1, 3)]; "swr" [Op(0), Mem(1, 0)]),
+        form!([Reg, Imm16] => "swl" [Op(0), Mem(1, 3)]; "swr" [Op(0), Mem(1, 0)]),
+        form!([Reg, Imm] => "lui" [AT, Upper(1)]; "ori" [AT, AT, Lower(1)]; "swl" [Op(0), At(3)]; "swr" [Op(0), At(0)]),
+        form!([Reg, Indexed] => "lui" [AT, Upper(1)]; "ori" [AT, AT, Lower(1)]; "addu" [AT, AT, Base(1)];
+            "swl" [Op(0), At(3)]; "swr" [Op(0), At(0)]),
+    ]},
+
+    // Immediate operands for register instructions. Of the immediate instructions only
+    // addi takes 32 bit values, as the others report constants that don't fit their field.
+    Pseudo { mnemonics: &["addi"], native: true, forms: &[
+        form!([Reg, Reg, Imm16] => "addi" [Op(0), Op(1), Op(2)]),
+        form!([Reg, Reg, Loaded] => "add" [Op(0), Op(1), Op(2)]),
+    ]},
+    Pseudo { mnemonics: &["subi"], native: false, forms: &[
+        form!([Reg, Reg, Imm16] => "addi" [AT, ZERO, Op(2)]; "sub" [Op(0), Op(1), AT]),
+        form!([Reg, Reg, Loaded] => "sub" [Op(0), Op(1), Op(2)]),
+    ]},
+    Pseudo { mnemonics: &["subiu"], native: false, forms: &[
+        form!([Reg, Reg, Imm16] => "addiu" [AT, ZERO, Op(2)]; "subu" [Op(0), Op(1), AT]),
+        form!([Reg, Reg, Loaded] => "subu" [Op(0), Op(1), Op(2)]),
+    ]},
+    Pseudo { mnemonics: &["add"], native: true, forms: &[
+        form!([Reg, Reg, Imm16] => "addi" [Op(0), Op(1), Op(2)]),
+        form!([Reg, Reg, Source] => "add" [Op(0), Op(1), Op(2)]),
+    ]},
+    Pseudo { mnemonics: &["addu"], native: true, forms: &[
+        form!([Reg, Reg, Imm16] => "addiu" [Op(0), Op(1), Op(2)]),
+        form!([Reg, Reg, Source] => "addu" [Op(0), Op(1), Op(2)]),
+    ]},
+    Pseudo { mnemonics: &["and"], native: true, forms: &[
+        form!([Reg, Reg, ImmU16] => "andi" [Op(0), Op(1), Op(2)]),
+        form!([Reg, Reg, Source] => "and" [Op(0), Op(1), Op(2)]),
+    ]},
+    Pseudo { mnemonics: &["or"], native: true, forms: &[
+        form!([Reg, Reg, ImmU16] => "ori" [Op(0), Op(1), Op(2)]),
+        form!([Reg, Reg, Source] => "or" [Op(0), Op(1), Op(2)]),
+    ]},
+    Pseudo { mnemonics: &["xor"], native: true, forms: &[
+        form!([Reg, Reg, ImmU16] => "xori" [Op(0), Op(1), Op(2)]),
+        form!([Reg, Reg, Source] => "xor" [Op(0), Op(1), Op(2)]),
+    ]},
+    Pseudo { mnemonics: &["slt"], native: true, forms: &[
+        form!([Reg, Reg, Imm16] => "slti" [Op(0), Op(1), Op(2)]),
+        form!([Reg, Reg, Source] => "slt" [Op(0), Op(1), Op(2)]),
+    ]},
+    Pseudo { mnemonics: &["sltu"], native: true, forms: &[
+        form!([Reg, Reg, Imm16] => "sltiu" [Op(0), Op(1), Op(2)]),
+        form!([Reg, Reg, Source] => "sltu" [Op(0), Op(1), Op(2)]),
+    ]},
+    Pseudo { mnemonics: &["sub", "subu", "nor", "mul"], native: true, forms: &[
+        form!([Reg, Reg, Source] => "" [Op(0), Op(1), Op(2)]),
+    ]},
+
+    // Rotates
+    Pseudo { mnemonics: &["rol"], native: false, forms: &[
+        form!([Reg, Reg, Reg] => "subu" [AT, ZERO, Op(2)]; "srlv" [AT, Op(1), AT]; "sllv" [Op(0), Op(1), Op(2)]; "or" [Op(0), Op(0), AT]),
+        form!([Reg, Reg, Imm] => "srl" [AT, Op(1), Opposite(2)]; "sll" [Op(0), Op(1), Op(2)]; "or" [Op(0), Op(0), AT]),
+    ]},
+    Pseudo { mnemonics: &["ror"], native: false, forms: &[
+        form!([Reg, Reg, Reg] => "subu" [AT, ZERO, Op(2)]; "sllv" [AT, Op(1), AT]; "srlv" [Op(0), Op(1), Op(2)]; "or" [Op(0), Op(0), AT]),
+        form!([Reg, Reg, Imm] => "sll" [AT, Op(1), Opposite(2)]; "srl" [Op(0), Op(1), Op(2)]; "or" [Op(0), Op(0), AT]),
+    ]},
+
+    // Multiplication and division into a register. mulo and mulou are left out, since
+    // they trap on overflow and the core has no exceptions to raise.
+    Pseudo { mnemonics: &["mulu"], native: false, forms: &[
+        form!([Reg, Reg, Source] => "multu" [Op(1), Op(2)]; "mflo" [Op(0)]),
+    ]},
+    Pseudo { mnemonics: &["div"], native: true, forms: &[
+        form!([Reg, Reg, Source] => "div" [Op(1), Op(2)]; "mflo" [Op(0)]),
+    ]},
+    Pseudo { mnemonics: &["divu"], native: true, forms: &[
+        form!([Reg, Reg, Source] => "divu" [Op(1), Op(2)]; "mflo" [Op(0)]),
+    ]},
+    Pseudo { mnemonics: &["rem"], native: false, forms: &[
+        form!([Reg, Reg, Source] => "div" [Op(1), Op(2)]; "mfhi" [Op(0)]),
+    ]},
+    Pseudo { mnemonics: &["remu"], native: false, forms: &[
+        form!([Reg, Reg, Source] => "divu" [Op(1), Op(2)]; "mfhi" [Op(0)]),
+    ]},
+
+    // Comparisons into a register
+    Pseudo { mnemonics: &["seq"], native: false, forms: &[
+        form!([Reg, Reg, Source] => "subu" [Op(0), Op(1), Op(2)]; "sltiu" [Op(0), Op(0), Int(1)]),
+    ]},
+    Pseudo { mnemonics: &["sne"], native: false, forms: &[
+        form!([Reg, Reg, Source] => "subu" [Op(0), Op(1), Op(2)]; "sltu" [Op(0), ZERO, Op(0)]),
+    ]},
+    Pseudo { mnemonics: &["sge"], native: false, forms: &[
+        form!([Reg, Reg, Imm16] => "slti" [Op(0), Op(1), Op(2)]; "xori" [Op(0), Op(0), Int(1)]),
+        form!([Reg, Reg, Source] => "slt" [Op(0), Op(1), Op(2)]; "xori" [Op(0), Op(0), Int(1)]),
+    ]},
+    Pseudo { mnemonics: &["sgeu"], native: false, forms: &[
+        form!([Reg, Reg, Imm16] => "sltiu" [Op(0), Op(1), Op(2)]; "xori" [Op(0), Op(0), Int(1)]),
+        form!([Reg, Reg, Source] => "sltu" [Op(0), Op(1), Op(2)]; "xori" [Op(0), Op(0), Int(1)]),
+    ]},
+    Pseudo { mnemonics: &["sgt"], native: false, forms: &[
+        form!([Reg, Reg, Source] => "slt" [Op(0), Op(2), Op(1)]),
+    ]},
+    Pseudo { mnemonics: &["sgtu"], native: false, forms: &[
+        form!([Reg, Reg, Source] => "sltu" [Op(0), Op(2), Op(1)]),
+    ]},
+    Pseudo { mnemonics: &["sle"], native: false, forms: &[
+        form!([Reg, Reg, Source] => "slt" [Op(0), Op(2), Op(1)]; "xori" [Op(0), Op(0), Int(1)]),
+    ]},
+    Pseudo { mnemonics: &["sleu"], native: false, forms: &[
+        form!([Reg, Reg, Source] => "sltu" [Op(0), Op(2), Op(1)]; "xori" [Op(0), Op(0), Int(1)]),
+    ]},
+
+    // Branches
+    Pseudo { mnemonics: &["b"], native: false, forms: &[
+        form!([Imm] => "beq" [ZERO, ZERO, Op(0)]),
+    ]},
+    Pseudo { mnemonics: &["bal"], native: false, forms: &[
+        form!([Imm] => "bgezal" [ZERO, Op(0)]),
+    ]},
+    Pseudo { mnemonics: &["beqz"], native: false, forms: &[
+        form!([Reg, Imm] => "beq" [Op(0), ZERO, Op(1)]),
+    ]},
+    Pseudo { mnemonics: &["bnez"], native: false, forms: &[
+        form!([Reg, Imm] => "bne" [Op(0), ZERO, Op(1)]),
+    ]},
+    Pseudo { mnemonics: &["beq", "bne"], native: true, forms: &[
+        form!([Reg, Source, Imm] => "" [Op(0), Op(1), Op(2)]),
+    ]},
+    Pseudo { mnemonics: &["blt"], native: false, forms: &[
+        form!([Reg, Imm16, Imm] => "slti" [AT, Op(0), Op(1)]; "bne" [AT, ZERO, Op(2)]),
+        form!([Reg, Source, Imm] => "slt" [AT, Op(0), Op(1)]; "bne" [AT, ZERO, Op(2)]),
+    ]},
+    Pseudo { mnemonics: &["bltu"], native: false, forms: &[
+        form!([Reg, Imm16, Imm] => "sltiu" [AT, Op(0), Op(1)]; "bne" [AT, ZERO, Op(2)]),
+        form!([Reg, Source, Imm] => "sltu" [AT, Op(0), Op(1)]; "bne" [AT, ZERO, Op(2)]),
+    ]},
+    Pseudo { mnemonics: &["bge"], native: false, forms: &[
+        form!([Reg, Imm16, Imm] => "slti" [AT, Op(0), Op(1)]; "beq" [AT, ZERO, Op(2)]),
+        form!([Reg, Source, Imm] => "slt" [AT, Op(0), Op(1)]; "beq" [AT, ZERO, Op(2)]),
+    ]},
+    Pseudo { mnemonics: &["bgeu"], native: false, forms: &[
+        form!([Reg, Imm16, Imm] => "sltiu" [AT, Op(0), Op(1)]; "beq" [AT, ZERO, Op(2)]),
+        form!([Reg, Source, Imm] => "sltu" [AT, Op(0), Op(1)]; "beq" [AT, ZERO, Op(2)]),
+    ]},
+    Pseudo { mnemonics: &["bgt"], native: false, forms: &[
+        form!([Reg, Source, Imm] => "slt" [AT, Op(1), Op(0)]; "bne" [AT, ZERO, Op(2)]),
+    ]},
+    Pseudo { mnemonics: &["bgtu"], native: false, forms: &[
+        form!([Reg, Source, Imm] => "sltu" [AT, Op(1), Op(0)]; "bne" [AT, ZERO, Op(2)]),
+    ]},
+    Pseudo { mnemonics: &["ble"], native: false, forms: &[
+        form!([Reg, Source, Imm] => "slt" [AT, Op(1), Op(0)]; "beq" [AT, ZERO, Op(2)]),
+    ]},
+    Pseudo { mnemonics: &["bleu"], native: false, forms: &[
+        form!([Reg, Source, Imm] => "sltu" [AT, Op(1), Op(0)]; "beq" [AT, ZERO, Op(2)]),
+    ]},
+];
+
+// Mnemonics and operands of the instructions a pseudo-instruction expands to
+pub type Expansion = Vec<(String, Vec<Expr>)>;
+
+// The machine instructions for one source instruction. None if it should be
+// assembled as written.
+pub fn expand_pseudo(
+    mnemonic: &str,
+    args: &[Expr],
+    span: Span,
+    source: &SimpleFile<String, String>,
+) -> Result<Option<Expansion>, MimicError> {
+    let pseudo = match PSEUDO_INSTRUCTIONS.iter().find(|p| p.mnemonics.contains(&mnemonic)) {
+        Some(pseudo) => pseudo,
+        None => return Ok(None),
+    };
+
+    for form in pseudo.forms {
+        if form.shapes.len() != args.len() {
+            continue;
+        }
+        let mut matched = true;
+        for (shape, arg) in form.shapes.iter().zip(args) {
+            matched = matched && matches_shape(*shape, arg, source)?;
+        }
+        if matched {
+            return expand_form(mnemonic, form, args, span, source).map(Some);
+        }
+    }
+
+    if pseudo.native {
+        return Ok(None);
+    }
+    Err(MimicError {
+        span: Some(span),
+        source: Some(Box::new(source.clone())),
+        ty: MimicErrorType::InvalidOperands { mnemonic: mnemonic.to_owned() },
+    })
+}
+
+fn expand_form(
+    mnemonic: &str,
+    form: &Form,
+    args: &[Expr],
+    span: Span,
+    source: &SimpleFile<String, String>,
+) -> Result<Expansion, MimicError> {
+    let mut expanded = Vec::new();
+    let mut operands = args.to_vec();
+
+    // Immediate sources go through $at
+    for (shape, operand) in form.shapes.iter().zip(operands.iter_mut()) {
+        if matches!(shape, Source | Loaded) && !matches!(operand.node, Expr_::Register(_)) {
+            let load = [register("$at", operand.span), operand.clone()];
+            expanded.extend(expand_pseudo("li", &load, span, source)?.unwrap_or_default());
+            *operand = register("$at", operand.span);
+        }
+    }
+
+    for step in form.steps {
+        let name = if step.mnemonic.is_empty() { mnemonic } else { step.mnemonic };
+        let args = step.args.iter().map(|arg| step_argument(*arg, &operands, span)).collect();
+        expanded.push((name.to_owned(), args));
+    }
+
+    Ok(expanded)
+}
+
+fn step_argument(arg: Arg, operands: &[Expr], span: Span) -> Expr {
+    let memory = |offset: Expr, base: Expr| Expr {
+        span: offset.span,
+        node: Expr_::MemoryOperand {
+            offset: Some(Box::new(offset)),
+            base: Box::new(base),
+        },
+    };
+
+    match arg {
+        Op(i) => operands[i].clone(),
+        Named(name) => register(name, span),
+        Int(i) => Expr { span, node: Expr_::IntLiteral(i) },
+        Value(i) => value(&operands[i]),
+        Base(i) => base(&operands[i]),
+        Upper(i) => {
+            let shifted = with_constant(BinaryOp::Shr, value(&operands[i]), 16);
+            with_constant(BinaryOp::And, shifted, 0xFFFF)
+        },
+        Lower(i) => with_constant(BinaryOp::And, value(&operands[i]), 0xFFFF),
+        Hi(i) => {
+            let value = value(&operands[i]);
+            Expr { span: value.span, node: Expr_::Hi(Box::new(value)) }
+        },
+        LoAt(i) => {
+            let value = value(&operands[i]);
+            let lo = Expr { span: value.span, node: Expr_::Lo(Box::new(value)) };
+            memory(lo, register("$at", span))
+        },
+        Mem(i, 0) => memory(value(&operands[i]), base(&operands[i])),
+        Mem(i, n) => memory(with_constant(BinaryOp::Add, value(&operands[i]), n), base(&operands[i])),
+        At(n) => memory(Expr { span, node: Expr_::IntLiteral(n) }, register("$at", span)),
+        Opposite(i) => {
+            let value = value(&operands[i]);
+            let opposite = Expr {
+                span: value.span,
+                node: Expr_::Binary {
+                    op: BinaryOp::Sub,
+                    lhs: Box::new(Expr { span: value.span, node: Expr_::IntLiteral(32) }),
+                    rhs: Box::new(value),
+                },
+            };
+            with_constant(BinaryOp::And, opposite, 31)
+        },
+    }
+}
+
+fn matches_shape(shape: Shape, arg: &Expr, source: &SimpleFile<String, String>) -> Result<bool, MimicError> {
+    let fits = |field: Field| -> Result<bool, MimicError> {
+        let (min, max) = field.range();
+        Ok(constant(arg, source)?.is_some_and(|value| (min..=max).contains(&value)))
+    };
+
+    match shape {
+        Reg => Ok(matches!(arg.node, Expr_::Register(_))),
+        Imm16 => Ok(matches!(arg.node, Expr_::Lo(_)) || fits(Field::Signed(16))?),
+        ImmU16 => Ok(matches!(arg.node, Expr_::Hi(_)) || fits(Field::Unsigned(16))?),
+        ImmUpper => Ok(constant(arg, source)?.is_some_and(|value| value & 0xFFFF == 0)),
+        Imm | Loaded => {
+            constant(arg, source)?;
+            Ok(is_immediate(arg))
+        },
+        Source => Ok(matches!(arg.node, Expr_::Register(_)) || is_immediate(arg)),
+        Mem16 | Indexed => {
+            let offset = match &arg.node {
+                Expr_::MemoryOperand { offset, .. } => offset.as_deref(),
+                _ => return Ok(false),
+            };
+            let direct = match offset {
+                None => true,
+                Some(Expr { node: Expr_::Lo(_), .. }) => true,
+                Some(offset) => matches_shape(Imm16, offset, source)?,
+            };
+            Ok(direct == (shape == Mem16))
+        },
+    }
+}
+
+fn is_immediate(arg: &Expr) -> bool {
+    !matches!(
+        arg.node,
+        Expr_::Register(_) | Expr_::MemoryOperand { .. } | Expr_::StringLiteral(_) | Expr_::FloatLiteral(_)
+    )
+}
+
+// The value of an immediate that doesn't depend on labels, which must fit in 32 bits
+fn constant(arg: &Expr, source: &SimpleFile<String, String>) -> Result<Option<i64>, MimicError> {
+    if !is_immediate(arg) || references_symbol(arg) {
+        return Ok(None);
+    }
+    let value = evaluate(arg, &HashMap::new(), source)?;
+    check_range(value, Field::Any(32), arg.span, source)?;
+    Ok(Some(value))
+}
+
+fn value(operand: &Expr) -> Expr {
+    match &operand.node {
+        Expr_::MemoryOperand { offset: Some(offset), .. } => (**offset).clone(),
+        Expr_::MemoryOperand { offset: None, .. } => Expr { span: operand.span, node: Expr_::IntLiteral(0) },
+        _ => operand.clone(),
+    }
+}
+
+fn base(operand: &Expr) -> Expr {
+    match &operand.node {
+        Expr_::MemoryOperand { base, .. } => (**base).clone(),
+        _ => register("$zero", operand.span),
+    }
+}
+
+fn register(name: &str, span: Span) -> Expr {
+    Expr { span, node: Expr_::Register(name.to_owned()) }
+}
+
+fn with_constant(op: BinaryOp, expr: Expr, value: i64) -> Expr {
+    Expr {
+        span: expr.span,
+        node: Expr_::Binary {
+            op,
+            rhs: Box::new(Expr { span: expr.span, node: Expr_::IntLiteral(value) }),
+            lhs: Box::new(expr),
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mips32::assembler::lexer::Lexer;
+    use crate::mips32::assembler::parser::{parse, Stmt_};
+
+    // The expansion of one instruction, with operands written out and `label` at 0x10010004
+    fn expand(line: &str) -> Result<Option<String>, MimicError> {
+        let contents = format!(".text\n{}\n", line);
+        let source = SimpleFile::new("".to_owned(), contents.clone());
+        let ast = parse(Lexer::new(&contents, source.clone())).unwrap();
+        let (mnemonic, args, span) = match &ast[0].statement {
+            Stmt_::Section { stmts, .. } => match &stmts[0].statement {
+                Stmt_::Instruction { mnemonic: Expr { node: Expr_::Ident(m), .. }, args } => (m.clone(), args.clone(), stmts[0].span),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+
+        let symbols = HashMap::from([("label".to_owned(), 0x10010004)]);
+        let operand = |arg: &Expr| match &arg.node {
+            Expr_::Register(r) => r.clone(),
+            Expr_::MemoryOperand { base, .. } => {
+                let base = match &base.node { Expr_::Register(r) => r.clone(), _ => unreachable!() };
+                format!("{}({})", evaluate(&value(arg), &symbols, &source).unwrap(), base)
+            },
+            _ => format!("{}", evaluate(arg, &symbols, &source).unwrap()),
+        };
+        Ok(expand_pseudo(&mnemonic, &args, span, &source)?.map(|expansion| {
+            expansion
+                .iter()
+                .map(|(name, args)| format!("{} {}", name, args.iter().map(operand).collect::<Vec<_>>().join(", ")).trim_end().to_owned())
+                .collect::<Vec<_>>()
+                .join("; ")
+        }))
+    }
+
+    #[test]
+    fn shortest_forms() {
+        let cases = [
+            ("nop", "sll $zero, $zero, 0"),
+            ("li $t0, -5", "addiu $t0, $zero, -5"),
+            ("li $t0, 0xABCD", "ori $t0, $zero, 43981"),
+            ("li $t0, 0x10000", "lui $t0, 1"),
+            ("li $t0, 0x12345", "lui $at, 1; ori $t0, $at, 9029"),
+            ("la $t0, 8($t1)", "addiu $t0, $t1, 8"),
+            ("la $t0, label($t1)", "lui $at, 4097; ori $at, $at, 4; addu $t0, $at, $t1"),
+            ("la $t0, 12", "addiu $t0, $zero, 12"),
+            ("la $t0, label", "lui $at, 4097; ori $t0, $at, 4"),
+            ("move $t0, $t1", "addu $t0, $zero, $t1"),
+            ("not $t0, $t1", "nor $t0, $t1, $zero"),
+            ("neg $t0, $t1", "sub $t0, $zero, $t1"),
+            ("negu $t0, $t1", "subu $t0, $zero, $t1"),
+            ("abs $t0, $t1", "sra $at, $t1, 31; xor $t0, $at, $t1; subu $t0, $t0, $at"),
+            ("lw $t0, 4", "lw $t0, 4($zero)"),
+            ("sb $t0, label + 1", "lui $at, 4097; sb $t0, 5($at)"),
+            ("lh $t0, label($t1)", "lui $at, 4097; addu $at, $at, $t1; lh $t0, 4($at)"),
+            ("ulw $t0, 4($t1)", "lwl $t0, 7($t1); lwr $t0, 4($t1)"),
+            ("ulw $t0, 16", "lwl $t0, 19($zero); lwr $t0, 16($zero)"),
+            ("ulw $t0, label", "lui $at, 4097; ori $at, $at, 4; lwl $t0, 3($at); lwr $t0, 0($at)"),
+            ("usw $t0, label($t1)", "lui $at, 4097; ori $at, $at, 4; addu $at, $at, $t1; swl $t0, 3($at); swr $t0, 0($at)"),
+            ("addi $t0, $t1, -1", "addi $t0, $t1, -1"),
+            ("addi $t0, $t1, 0x12345", "lui $at, 1; ori $at, $at, 9029; add $t0, $t1, $at"),
+            ("subi $t0, $t1, 5", "addi $at, $zero, 5; sub $t0, $t1, $at"),
+            ("subi $t0, $t1, 0x8000", "ori $at, $zero, 32768; sub $t0, $t1, $at"),
+            ("subiu $t0, $t1, -5", "addiu $at, $zero, -5; subu $t0, $t1, $at"),
+            ("subiu $t0, $t1, 0x10000", "lui $at, 1; subu $t0, $t1, $at"),
+            ("add $t0, $t1, 5", "addi $t0, $t1, 5"),
+            ("add $t0, $t1, 0x8000", "ori $at, $zero, 32768; add $t0, $t1, $at"),
+            ("addu $t0, $t1, -5", "addiu $t0, $t1, -5"),
+            ("addu $t0, $t1, 0x10000", "lui $at, 1; addu $t0, $t1, $at"),
+            ("and $t0, $t1, 0xFF", "andi $t0, $t1, 255"),
+            ("and $t0, $t1, -1", "addiu $at, $zero, -1; and $t0, $t1, $at"),
+            ("or $t0, $t1, 0xFF", "ori $t0, $t1, 255"),
+            ("xor $t0, $t1, 0xFF", "xori $t0, $t1, 255"),
+            ("slt $t0, $t1, -5", "slti $t0, $t1, -5"),
+            ("slt $t0, $t1, 0x8000", "ori $at, $zero, 32768; slt $t0, $t1, $at"),
+            ("sltu $t0, $t1, 5", "sltiu $t0, $t1, 5"),
+            ("sub $t0, $t1, 5", "addiu $at, $zero, 5; sub $t0, $t1, $at"),
+            ("subu $t0, $t1, 5", "addiu $at, $zero, 5; subu $t0, $t1, $at"),
+            ("nor $t0, $t1, 5", "addiu $at, $zero, 5; nor $t0, $t1, $at"),
+            ("mul $t0, $t1, 5", "addiu $at, $zero, 5; mul $t0, $t1, $at"),
+            ("rol $t0, $t1, $t2", "subu $at, $zero, $t2; srlv $at, $t1, $at; sllv $t0, $t1, $t2; or $t0, $t0, $at"),
+            ("rol $t0, $t1, 4", "srl $at, $t1, 28; sll $t0, $t1, 4; or $t0, $t0, $at"),
+            ("ror $t0, $t1, $t2", "subu $at, $zero, $t2; sllv $at, $t1, $at; srlv $t0, $t1, $t2; or $t0, $t0, $at"),
+            ("ror $t0, $t1, 0", "sll $at, $t1, 0; srl $t0, $t1, 0; or $t0, $t0, $at"),
+            ("mulu $t0, $t1, $t2", "multu $t1, $t2; mflo $t0"),
+            ("div $t0, $t1, $t2", "div $t1, $t2; mflo $t0"),
+            ("divu $t0, $t1, 3", "addiu $at, $zero, 3; divu $t1, $at; mflo $t0"),
+            ("rem $t0, $t1, $t2", "div $t1, $t2; mfhi $t0"),
+            ("remu $t0, $t1, $t2", "divu $t1, $t2; mfhi $t0"),
+            ("seq $t0, $t1, $t2", "subu $t0, $t1, $t2; sltiu $t0, $t0, 1"),
+            ("sne $t0, $t1, $t2", "subu $t0, $t1, $t2; sltu $t0, $zero, $t0"),
+            ("sge $t0, $t1, 5", "slti $t0, $t1, 5; xori $t0, $t0, 1"),
+            ("sge $t0, $t1, $t2", "slt $t0, $t1, $t2; xori $t0, $t0, 1"),
+            ("sgeu $t0, $t1, 5", "sltiu $t0, $t1, 5; xori $t0, $t0, 1"),
+            ("sgt $t0, $t1, $t2", "slt $t0, $t2, $t1"),
+            ("sgtu $t0, $t1, $t2", "sltu $t0, $t2, $t1"),
+            ("sle $t0, $t1, $t2", "slt $t0, $t2, $t1; xori $t0, $t0, 1"),
+            ("sleu $t0, $t1, $t2", "sltu $t0, $t2, $t1; xori $t0, $t0, 1"),
+            ("b label", "beq $zero, $zero, 268500996"),
+            ("bal label", "bgezal $zero, 268500996"),
+            ("beqz $t0, label", "beq $t0, $zero, 268500996"),
+            ("bnez $t0, label", "bne $t0, $zero, 268500996"),
+            ("beq $t0, 5, label", "addiu $at, $zero, 5; beq $t0, $at, 268500996"),
+            ("blt $t0, 5, label", "slti $at, $t0, 5; bne $at, $zero, 268500996"),
+            ("blt $t0, $t1, label", "slt $at, $t0, $t1; bne $at, $zero, 268500996"),
+            ("bltu $t0, 5, label", "sltiu $at, $t0, 5; bne $at, $zero, 268500996"),
+            ("bge $t0, $t1, label", "slt $at, $t0, $t1; beq $at, $zero, 268500996"),
+            ("bgeu $t0, 0x8000, label", "ori $at, $zero, 32768; sltu $at, $t0, $at; beq $at, $zero, 268500996"),
+            ("bgt $t0, $t1, label", "slt $at, $t1, $t0; bne $at, $zero, 268500996"),
+            ("bgtu $t0, $t1, label", "sltu $at, $t1, $t0; bne $at, $zero, 268500996"),
+            ("ble $t0, 5, label", "addiu $at, $zero, 5; slt $at, $at, $t0; beq $at, $zero, 268500996"),
+            ("bleu $t0, $t1, label", "sltu $at, $t1, $t0; beq $at, $zero, 268500996"),
+        ];
+        for (line, expansion) in cases {
+            assert_eq!(expand(line).unwrap().as_deref(), Some(expansion), "{line}");
+        }
+    }
+
+    #[test]
+    fn native_and_invalid_forms() {
+        // Machine instructions are assembled as written
+        for line in ["addiu $t0, $t1, %lo(label)", "sll $t0, $t1, 2", "lw $t0, 4($t1)", "jal label", "syscall"] {
+            assert_eq!(expand(line).unwrap(), None, "{line}");
+        }
+
+        for line in ["nop $t0", "li $t0, $t1", "move $t0, 5", "subi $t0, $t1, $t2", "subiu $t0, $t1, $t2", "rol $t0, 4, $t1"] {
+            let error = expand(line).unwrap_err();
+            assert!(matches!(error.ty, MimicErrorType::InvalidOperands { .. }), "{line}");
+        }
+        assert!(matches!(expand("li $t0, 0x10000 * 0x10000").unwrap_err().ty, MimicErrorType::ValueOutOfRange { .. }));
+    }
+}
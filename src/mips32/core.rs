@@ -1,13 +1,76 @@
-use crate::errors::MimicError;
+use crate::errors::{MimicError, MimicErrorType};
+use crate::mips32::debug::callstack::{CallStack, ReturnMismatch};
+use crate::mips32::debug::convention::{ConventionChecker, Violation};
+use crate::mips32::debug::coverage::Coverage;
+use crate::mips32::debug::journal::UndoJournal;
+use crate::mips32::debug::profile::Profiler;
+use crate::mips32::debug::trace::{TraceRecord, Tracer};
+use crate::mips32::debug::{Breakpoint, Watchpoint};
+use crate::mips32::devices::MmioDevice;
 use crate::mips32::memory::Memory;
 use crate::mips32::registers::Registers;
+use crate::mips32::syscall::{SyscallHandler, SyscallOutcome};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub address: u32,
+    pub size: u32,
+    pub kind: AccessKind,
+    pub value: u32,
+    pub old_value: u32,
+}
+
+#[derive(Debug)]
+pub enum StopReason {
+    Exited(u32),
+    Breakpoint { id: usize, address: u32, hits: u64 },
+    // `pc` is the address of the instruction that made the access
+    Watchpoint { id: usize, pc: u32, access: MemoryAccess },
+    Step,
+    // The PC reached the target of `run_until`
+    ReachedAddress(u32),
+    InstructionLimit,
+    Fault(MimicError),
+    WaitingForInput,
+    FellOffText,
+    // Reverse execution ran out of recorded history
+    StartOfHistory,
+    UnexpectedReturn(ReturnMismatch),
+    ConventionViolation(Violation),
+}
 
 pub struct Core {
     pub(crate) memory: Memory,
     pub(crate) registers: Registers,
     pub(crate) pc: u32,
-    hi: u32,
-    lo: u32,
+    pub(crate) hi: u32,
+    pub(crate) lo: u32,
+    pub(crate) instructions: u64,
+    pub(crate) exit_code: Option<u32>,
+
+    pub(crate) breakpoints: Vec<Option<Breakpoint>>,
+    pub(crate) watchpoints: Vec<Option<Watchpoint>>,
+    pub(crate) last_access: Option<MemoryAccess>,
+    // Outcome of the last instruction if it was a conditional branch
+    pub(crate) branch_taken: Option<bool>,
+    pub(crate) resuming_from: Option<u32>,
+    // A second stop raised by the same instruction, reported on the next tick
+    pub(crate) pending_stop: Option<StopReason>,
+    pub(crate) journal: Option<UndoJournal>,
+    pub(crate) tracer: Option<Tracer>,
+    pub(crate) profiler: Option<Profiler>,
+    pub(crate) coverage: Option<Coverage>,
+    pub(crate) call_stack: Option<CallStack>,
+    pub(crate) convention: Option<ConventionChecker>,
 }
 
 impl Core {
@@ -18,32 +81,266 @@ impl Core {
             pc: 0x00100000,
             hi: 0,
             lo: 0,
+            instructions: 0,
+            exit_code: None,
+
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            last_access: None,
+            branch_taken: None,
+            resuming_from: None,
+            pending_stop: None,
+            journal: None,
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            call_stack: None,
+            convention: None,
         }
     }
 
-    pub fn tick<F>(&mut self, syscall_handler: F) -> Result<(), MimicError>
+    // Executes a single instruction. Returns the reason if the program cannot continue
+    pub fn tick<H>(&mut self, syscall_handler: &mut H) -> Result<Option<StopReason>, MimicError>
     where
-        F: FnMut(u32, [u32; 32]) -> [u32; 32],
+        H: SyscallHandler + ?Sized,
     {
         // println!("PC={:#08X}", self.pc);
 
         // println!("$t2 = {:#04X}", self.registers.get(10));
 
-        let inst = self.memory.get(self.pc).unwrap();
+        if let Some(reason) = self.pending_stop.take() {
+            return Ok(Some(reason));
+        }
+
+        if let Some(code) = self.exit_code {
+            return Ok(Some(StopReason::Exited(code)));
+        }
+
+        if self.memory.past_text_end(self.pc) {
+            return Ok(Some(StopReason::FellOffText));
+        }
+
+        if self.journal.as_ref().is_some_and(UndoJournal::needs_checkpoint) {
+            let snapshot = self.machine_snapshot();
+            if let Some(journal) = &mut self.journal {
+                journal.checkpoint(snapshot);
+            }
+        }
+
+        self.memory.tick_devices(self.instructions);
+        self.last_access = None;
+        self.branch_taken = None;
+
+        let inst = self.memory.get(self.pc)?;
 
         // println!("Executing instruction {inst:#08X} at PC={:#08X}", self.pc);
 
-        self.execute_instruction(inst, syscall_handler);
+        // The trace reuses the undo entry to find out what the instruction changed
+        let tracing = self.tracer.as_ref().is_some_and(|tracer| tracer.accepts(self));
+        let pc = self.pc();
+        let service = self.registers.get(2);
+        let undo = (tracing || self.journal.is_some()).then(|| self.begin_undo_entry());
+        let outcome = self.execute_instruction(inst, syscall_handler);
+        let undo = undo.map(|undo| self.finish_undo_entry(undo));
+
+        let outcome = outcome?;
+        if outcome == SyscallOutcome::WaitForInput {
+            return Ok(Some(StopReason::WaitingForInput));
+        }
 
-        self.pc += 1;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(pc, inst);
+        }
 
-        Ok(())
+        self.pc = self.pc.wrapping_add(1);
+        self.instructions += 1;
+
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(pc, self.branch_taken);
+        }
+
+        if let Some(undo) = undo {
+            if tracing {
+                let record = TraceRecord::new(self, inst, service, &undo, outcome);
+                if let Some(tracer) = &mut self.tracer {
+                    tracer.push(record);
+                }
+            }
+            let redo = self.journal.as_ref().and_then(|_| self.redo_entry(inst, outcome, &undo));
+            if let Some(journal) = &mut self.journal {
+                journal.push(undo, redo);
+            }
+        }
+
+        // Both see every instruction, even when the other one stops
+        let next = self.pc << 2;
+        let sp = self.registers.get(29);
+        let taken = self.branch_taken;
+        let returned = self.call_stack.as_mut().and_then(|stack| stack.record(pc, inst, next, taken, sp));
+        let violated = self
+            .convention
+            .as_mut()
+            .and_then(|checker| checker.record(pc, inst, next, taken, &self.registers.dump()));
+        match (returned, violated) {
+            (Some(reason), violated) => {
+                self.pending_stop = violated;
+                return Ok(Some(reason));
+            }
+            (None, Some(reason)) => return Ok(Some(reason)),
+            (None, None) => {}
+        }
+
+        if let SyscallOutcome::Exit(code) = outcome {
+            self.exit_code = Some(code);
+            return Ok(Some(StopReason::Exited(code)));
+        }
+
+        Ok(None)
+    }
+
+    pub fn run<H>(&mut self, syscall_handler: &mut H) -> StopReason
+    where
+        H: SyscallHandler + ?Sized,
+    {
+        self.run_loop(syscall_handler, None, |_| None)
+    }
+
+    pub fn run_for<H>(&mut self, syscall_handler: &mut H, instructions: u64) -> StopReason
+    where
+        H: SyscallHandler + ?Sized,
+    {
+        self.run_loop(syscall_handler, Some(instructions), |_| None)
+    }
+
+    // Runs until the PC reaches the byte address `address`, executing at least one instruction
+    pub fn run_until<H>(&mut self, syscall_handler: &mut H, address: u32) -> StopReason
+    where
+        H: SyscallHandler + ?Sized,
+    {
+        self.run_loop(syscall_handler, None, |core| {
+            (core.pc() == address).then_some(StopReason::ReachedAddress(address))
+        })
+    }
+
+    // Executes instructions until `done` returns a reason, a breakpoint or watchpoint
+    // triggers, or the program stops on its own. Like resuming in a debugger, a
+    // breakpoint at the PC where the previous run stopped is not hit again.
+    pub(crate) fn run_loop<H, F>(
+        &mut self,
+        syscall_handler: &mut H,
+        limit: Option<u64>,
+        mut done: F,
+    ) -> StopReason
+    where
+        H: SyscallHandler + ?Sized,
+        F: FnMut(&Core) -> Option<StopReason>,
+    {
+        let mut executed: u64 = 0;
+
+        let reason = loop {
+            if self.resuming_from != Some(self.pc()) {
+                if let Some(reason) = self.check_breakpoints() {
+                    break reason;
+                }
+            }
+
+            if limit.is_some_and(|limit| executed >= limit) {
+                break StopReason::InstructionLimit;
+            }
+
+            let pc = self.pc();
+            match self.tick(syscall_handler) {
+                Ok(Some(reason)) => break reason,
+                Ok(None) => executed += 1,
+                Err(e) => break StopReason::Fault(e),
+            }
+            self.resuming_from = None;
+
+            if let Some(reason) = self.check_watchpoints(pc) {
+                break reason;
+            }
+
+            if let Some(reason) = done(self) {
+                break reason;
+            }
+        };
+
+        self.resuming_from = Some(self.pc());
+        reason
+    }
+
+    pub fn exit_code(&self) -> Option<u32> {
+        self.exit_code
     }
 
     pub fn dump_registers(&self) -> [u32; 32] {
         self.registers.dump()
     }
 
+    pub fn get_register(&self, index: u32) -> u32 {
+        self.registers.get(index)
+    }
+
+    pub fn set_register(&mut self, index: u32, value: u32) {
+        self.registers.set(index, value);
+    }
+
+    // Byte address of the next instruction to execute
+    pub fn pc(&self) -> u32 {
+        self.pc << 2
+    }
+
+    pub fn hi(&self) -> u32 {
+        self.hi
+    }
+
+    pub fn lo(&self) -> u32 {
+        self.lo
+    }
+
+    pub fn instruction_count(&self) -> u64 {
+        self.instructions
+    }
+
+    // Reads for inspection, which leave memory mapped devices as they are. Loads made
+    // by the program go through `load`.
+    pub fn read_word(&self, address: u32) -> Result<u32, MimicError> {
+        self.memory.peek(address >> 2)
+    }
+
+    pub fn read_byte(&self, address: u32) -> Result<u8, MimicError> {
+        self.memory.peek_byte(address)
+    }
+
+    pub fn read_half(&self, address: u32) -> Result<u16, MimicError> {
+        self.memory.peek_half(address)
+    }
+
+    pub fn write_word(&mut self, address: u32, value: u32) {
+        self.memory.set(address >> 2, value);
+    }
+
+    pub fn write_byte(&mut self, address: u32, value: u8) {
+        self.memory.set_byte(address, value);
+    }
+
+    // Reads a NUL terminated string starting at the given byte address
+    pub fn read_string(&self, address: u32) -> Result<String, MimicError> {
+        let mut bytes: Vec<u8> = Vec::new();
+        let mut address = address;
+
+        loop {
+            let byte = self.read_byte(address)?;
+            if byte == 0 {
+                break;
+            }
+            bytes.push(byte);
+            address = address.wrapping_add(1);
+        }
+
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
     pub fn load_text(&mut self, text: Vec<u32>) {
         self.memory.load_text(text);
     }
@@ -52,6 +349,26 @@ impl Core {
         self.memory.load_data(data);
     }
 
+    pub fn map_device(&mut self, address: u32, len: u32, device: Rc<RefCell<dyn MmioDevice>>) -> Result<(), MimicError> {
+        self.memory.map_device(address, len, device)
+    }
+
+    // Loads the little-endian byte images produced by the assembler
+    pub fn load_program(&mut self, text_bytes: &[u8], data_bytes: &[u8]) {
+        self.memory.load_text(bytes_to_words(text_bytes));
+        self.memory.load_data(bytes_to_words(data_bytes));
+    }
+
+    // Loads the .ktext and .kdata images at 0x80000000 and 0x90000000
+    pub fn load_kernel(&mut self, ktext_bytes: &[u8], kdata_bytes: &[u8]) {
+        for (i, word) in bytes_to_words(ktext_bytes).into_iter().enumerate() {
+            self.memory.set(0x20000000 + i as u32, word);
+        }
+        for (i, word) in bytes_to_words(kdata_bytes).into_iter().enumerate() {
+            self.memory.set(0x24000000 + i as u32, word);
+        }
+    }
+
     pub fn clone_data_as_needed(&self, last_gen: &mut u32) -> Option<Vec<u32>> {
         if *last_gen < self.memory.data_generation {
             *last_gen = self.memory.data_generation;
@@ -61,6 +378,109 @@ impl Core {
         return None;
     }
 
+    fn effective_address(&self, rs: u32, imm: u32, align: u32) -> Result<u32, MimicError> {
+        let address = self.registers.get(rs).wrapping_add(sign_extend_16(imm));
+
+        if !address.is_multiple_of(align) {
+            return Err(MimicError {
+                span: None,
+                source: None,
+                ty: MimicErrorType::UnalignedAccess { address: address as usize },
+            });
+        }
+
+        if !self.memory.in_bounds(address >> 2) {
+            return Err(MimicError {
+                span: None,
+                source: None,
+                ty: MimicErrorType::MemoryOutOfBounds { address: (address >> 2) as usize },
+            });
+        }
+
+        Ok(address)
+    }
+
+    fn load(&mut self, rs: u32, imm: u32, size: u32) -> Result<u32, MimicError> {
+        let address = self.effective_address(rs, imm, size)?;
+        self.load_at(address, size)
+    }
+
+    fn load_at(&mut self, address: u32, size: u32) -> Result<u32, MimicError> {
+        let value = match size {
+            1 => self.memory.get_byte(address)? as u32,
+            2 => self.memory.get_half(address)? as u32,
+            _ => self.memory.get(address >> 2)?,
+        };
+
+        self.last_access = Some(MemoryAccess {
+            address,
+            size,
+            kind: AccessKind::Read,
+            value,
+            old_value: value,
+        });
+
+        Ok(value)
+    }
+
+    fn store(&mut self, rs: u32, imm: u32, size: u32, value: u32) -> Result<(), MimicError> {
+        let address = self.effective_address(rs, imm, size)?;
+        self.store_at(address, size, value)
+    }
+
+    fn store_at(&mut self, address: u32, size: u32, value: u32) -> Result<(), MimicError> {
+        // Reading a device register can have side effects, so its old value is not captured
+        let old_value = if self.memory.is_device(address >> 2) {
+            0
+        } else {
+            match size {
+                1 => self.memory.get_byte(address)? as u32,
+                2 => self.memory.get_half(address)? as u32,
+                _ => self.memory.get(address >> 2)?,
+            }
+        };
+
+        let value = match size {
+            1 => {
+                self.memory.set_byte(address, value as u8);
+                value & 0xFF
+            }
+            2 => {
+                self.memory.set_half(address, value as u16);
+                value & 0xFFFF
+            }
+            _ => {
+                self.memory.set(address >> 2, value);
+                value
+            }
+        };
+
+        self.last_access = Some(MemoryAccess {
+            address,
+            size,
+            kind: AccessKind::Write,
+            value,
+            old_value,
+        });
+
+        Ok(())
+    }
+
+    // Word holding the byte at the effective address, and the byte's position in
+    // it, for lwl, lwr, swl and swr
+    fn load_partial(&mut self, rs: u32, imm: u32) -> Result<(u32, u32, u32), MimicError> {
+        let address = self.effective_address(rs, imm, 1)?;
+        let word = self.load_at(address & !3, 4)?;
+        Ok((address & !3, address & 3, word))
+    }
+
+    fn branch_if(&mut self, taken: bool, offset: u32) {
+        self.branch_taken = Some(taken);
+        if taken {
+            self.branch_with_offset(offset);
+        }
+    }
+
     fn branch_with_offset(&mut self, mut offset: u32) {
         if offset & 0x00008000 != 0 {
             offset |= 0xFFFF0000;
@@ -74,23 +494,47 @@ impl Core {
 }
 
 impl Core {
-    pub(crate) fn execute_instruction<F>(&mut self, inst: u32, mut syscall_handler: F)
+    pub(crate) fn execute_instruction<H>(
+        &mut self,
+        inst: u32,
+        syscall_handler: &mut H,
+    ) -> Result<SyscallOutcome, MimicError>
     where
-        F: FnMut(u32, [u32; 32]) -> [u32; 32],
+        H: SyscallHandler + ?Sized,
     {
         let opcode = (inst >> 26) & 0x3F;
 
         // If instruction is SYSCALL
         if opcode == 0x00 && (inst & 0x3F) == 0x0C {
-            let new_regs = (syscall_handler)(inst, self.dump_registers());
-            self.registers.load(new_regs);
-            return;
+            return syscall_handler.syscall(inst, self);
         }
 
         // println!("{opcode:#04x}");
 
         match opcode {
-            0x00 => self.execute_rtype(inst),
+            0x00 => self.execute_rtype(inst)?,
+            0x01 => {
+                // bltz, bgez, bltzal, bgezal
+                let (rs, rt, imm) = extract_itype_1(inst);
+
+                let rs_val = self.registers.get(rs) as i32;
+                let taken = match rt {
+                    0x00 | 0x10 => rs_val < 0,
+                    0x01 | 0x11 => rs_val >= 0,
+                    _ => return Err(unimplemented_instruction(inst)),
+                };
+                if rt & 0x10 != 0 {
+                    self.registers.set(31, (self.pc + 1) << 2);
+                }
+                self.branch_if(taken, imm);
+            }
+            0x1C if inst & 0x3F == 0x02 => {
+                // mul
+                let (rs, rt, rd) = ((inst >> 21) & 0x1F, (inst >> 16) & 0x1F, (inst >> 11) & 0x1F);
+
+                let product = (self.registers.get(rs) as i32).wrapping_mul(self.registers.get(rt) as i32);
+                self.registers.set(rd, product as u32);
+            }
             0x02 => {
                 // j
                 let index = inst & 0x03FFFFFF;
@@ -99,15 +543,20 @@ impl Core {
 
                 // println!("index={index:#08X}; New PC={:#08X}", self.pc);
             }
+            0x03 => {
+                // jal
+                let index = inst & 0x03FFFFFF;
+                let target = (self.pc & 0xFC000000) | index;
+                self.registers.set(31, (self.pc + 1) << 2);
+                self.pc = target - 1;
+            }
             0x04 => {
                 // beq
                 let (rs, rt, imm) = extract_itype_1(inst);
 
                 let rs_val = self.registers.get(rs);
                 let rt_val = self.registers.get(rt);
-                if rs_val == rt_val {
-                    self.branch_with_offset(imm);
-                }
+                self.branch_if(rs_val == rt_val, imm);
             }
             0x05 => {
                 // bne
@@ -115,34 +564,47 @@ impl Core {
 
                 let rs_val = self.registers.get(rs);
                 let rt_val = self.registers.get(rt);
-                if rs_val != rt_val {
-                    self.branch_with_offset(imm);
-                }
+                self.branch_if(rs_val != rt_val, imm);
+            }
+            0x06 => {
+                // blez
+                let (rs, _rt, imm) = extract_itype_1(inst);
+
+                self.branch_if(self.registers.get(rs) as i32 <= 0, imm);
+            }
+            0x07 => {
+                // bgtz
+                let (rs, _rt, imm) = extract_itype_1(inst);
+
+                self.branch_if(self.registers.get(rs) as i32 > 0, imm);
             }
             0x08 => {
                 // addi
                 let (rs, rt, imm) = extract_itype_1(inst);
 
                 let rs_val = self.registers.get(rs);
-                self.registers.set(rt, rs_val + imm);
+                self.registers.set(rt, rs_val.wrapping_add(sign_extend_16(imm)));
             }
             0x09 => {
                 // addiu
                 let (rs, rt, imm) = extract_itype_1(inst);
 
                 let rs_val = self.registers.get(rs);
-                self.registers.set(rt, rs_val + imm);
+                self.registers.set(rt, rs_val.wrapping_add(sign_extend_16(imm)));
             }
             0x0A => {
                 // slti
                 let (rs, rt, imm) = extract_itype_1(inst);
 
+                let rs_val = self.registers.get(rs) as i32;
+                self.registers.set(rt, (rs_val < sign_extend_16(imm) as i32) as u32);
+            }
+            0x0B => {
+                // sltiu
+                let (rs, rt, imm) = extract_itype_1(inst);
+
                 let rs_val = self.registers.get(rs);
-                if rs_val < imm {
-                    self.registers.set(rt, 1);
-                } else {
-                    self.registers.set(rt, 0);
-                }
+                self.registers.set(rt, (rs_val < sign_extend_16(imm)) as u32);
             }
             0x0C => {
                 // andi
@@ -171,11 +633,102 @@ impl Core {
 
                 self.registers.set(rt, imm << 16);
             }
-            _ => todo!("Unimplemented instruction: {:#04X}", inst),
+            0x20 => {
+                // lb
+                let (rs, rt, imm) = extract_itype_1(inst);
+
+                let value = self.load(rs, imm, 1)? as u8 as i8 as i32 as u32;
+                self.registers.set(rt, value);
+            }
+            0x21 => {
+                // lh
+                let (rs, rt, imm) = extract_itype_1(inst);
+
+                let value = self.load(rs, imm, 2)? as u16 as i16 as i32 as u32;
+                self.registers.set(rt, value);
+            }
+            0x22 => {
+                // lwl
+                let (rs, rt, imm) = extract_itype_1(inst);
+
+                let (_, byte, word) = self.load_partial(rs, imm)?;
+                let shift = 24 - 8 * byte;
+                let kept = self.registers.get(rt) & ((1 << shift) - 1);
+                self.registers.set(rt, (word << shift) | kept);
+            }
+            0x23 => {
+                // lw
+                let (rs, rt, imm) = extract_itype_1(inst);
+
+                let value = self.load(rs, imm, 4)?;
+                self.registers.set(rt, value);
+            }
+            0x24 => {
+                // lbu
+                let (rs, rt, imm) = extract_itype_1(inst);
+
+                let value = self.load(rs, imm, 1)?;
+                self.registers.set(rt, value);
+            }
+            0x25 => {
+                // lhu
+                let (rs, rt, imm) = extract_itype_1(inst);
+
+                let value = self.load(rs, imm, 2)?;
+                self.registers.set(rt, value);
+            }
+            0x26 => {
+                // lwr
+                let (rs, rt, imm) = extract_itype_1(inst);
+
+                let (_, byte, word) = self.load_partial(rs, imm)?;
+                let shift = 8 * byte;
+                let kept = self.registers.get(rt) & !(0xFFFFFFFF >> shift);
+                self.registers.set(rt, (word >> shift) | kept);
+            }
+            0x28 => {
+                // sb
+                let (rs, rt, imm) = extract_itype_1(inst);
+
+                self.store(rs, imm, 1, self.registers.get(rt))?;
+            }
+            0x29 => {
+                // sh
+                let (rs, rt, imm) = extract_itype_1(inst);
+
+                self.store(rs, imm, 2, self.registers.get(rt))?;
+            }
+            0x2B => {
+                // sw
+                let (rs, rt, imm) = extract_itype_1(inst);
+
+                self.store(rs, imm, 4, self.registers.get(rt))?;
+            }
+            0x2A => {
+                // swl
+                let (rs, rt, imm) = extract_itype_1(inst);
+
+                let (address, byte, word) = self.load_partial(rs, imm)?;
+                let shift = 24 - 8 * byte;
+                let value = (word & !(0xFFFFFFFF >> shift)) | (self.registers.get(rt) >> shift);
+                self.store_at(address, 4, value)?;
+            }
+            0x2E => {
+                // swr
+                let (rs, rt, imm) = extract_itype_1(inst);
+
+                let (address, byte, word) = self.load_partial(rs, imm)?;
+                let shift = 8 * byte;
+                let value = (word & !(0xFFFFFFFF << shift)) | (self.registers.get(rt) << shift);
+                self.store_at(address, 4, value)?;
+            }
+            _ => return Err(unimplemented_instruction(inst)),
         }
+
+        Ok(SyscallOutcome::Continue)
     }
 
-    fn execute_rtype(&mut self, inst: u32) {
+    fn execute_rtype(&mut self, inst: u32) -> Result<(), MimicError> {
         let funct = inst & 0x3F;
         let shmt = (inst >> 6) & 0x1F;
         let rd = (inst >> 11) & 0x1F;
@@ -190,22 +743,92 @@ impl Core {
                 // sll
                 self.registers.set(rd, rt_val << shmt);
             }
+            0x02 => {
+                // srl
+                self.registers.set(rd, rt_val >> shmt);
+            }
+            0x03 => {
+                // sra
+                self.registers.set(rd, ((rt_val as i32) >> shmt) as u32);
+            }
+            0x04 => {
+                // sllv
+                self.registers.set(rd, rt_val << (rs_val & 0x1F));
+            }
+            0x06 => {
+                // srlv
+                self.registers.set(rd, rt_val >> (rs_val & 0x1F));
+            }
+            0x07 => {
+                // srav
+                self.registers.set(rd, ((rt_val as i32) >> (rs_val & 0x1F)) as u32);
+            }
+            0x08 => {
+                // jr
+                self.pc = (rs_val >> 2).wrapping_sub(1);
+            }
+            0x09 => {
+                // jalr
+                self.registers.set(rd, (self.pc + 1) << 2);
+                self.pc = (rs_val >> 2).wrapping_sub(1);
+            }
+            0x10 => {
+                // mfhi
+                self.registers.set(rd, self.hi);
+            }
+            0x11 => {
+                // mthi
+                self.hi = rs_val;
+            }
+            0x12 => {
+                // mflo
+                self.registers.set(rd, self.lo);
+            }
+            0x13 => {
+                // mtlo
+                self.lo = rs_val;
+            }
+            0x18 => {
+                // mult
+                let prod = (rs_val as i32 as i64) * (rt_val as i32 as i64);
+                self.lo = prod as u32;
+                self.hi = (prod >> 32) as u32;
+            }
+            0x19 => {
+                // multu
+                let prod = (rs_val as u64) * (rt_val as u64);
+                self.lo = prod as u32;
+                self.hi = (prod >> 32) as u32;
+            }
+            0x1A => {
+                // div, which leaves hi and lo unchanged when dividing by zero
+                if rt_val != 0 {
+                    self.lo = (rs_val as i32).wrapping_div(rt_val as i32) as u32;
+                    self.hi = (rs_val as i32).wrapping_rem(rt_val as i32) as u32;
+                }
+            }
+            0x1B => {
+                // divu
+                if let Some(quotient) = rs_val.checked_div(rt_val) {
+                    self.lo = quotient;
+                    self.hi = rs_val % rt_val;
+                }
+            }
             0x20 => {
                 // add
-                self.registers.set(rd, rt_val + rs_val);
+                self.registers.set(rd, rt_val.wrapping_add(rs_val));
             }
             0x21 => {
                 // addu
-                // println!(
-                //     "{}: {}({:#04X}) + {}({:#04X})",
-                //     self.pc - 0x00100000,
-                //     rs,
-                //     rs_val,
-                //     rt,
-                //     rt_val
-                // );
-                self.registers
-                    .set(rd, ((rt_val as i32) + (rs_val as i32)) as u32);
+                self.registers.set(rd, rt_val.wrapping_add(rs_val));
+            }
+            0x22 => {
+                // sub
+                self.registers.set(rd, rs_val.wrapping_sub(rt_val));
+            }
+            0x23 => {
+                // subu
+                self.registers.set(rd, rs_val.wrapping_sub(rt_val));
             }
             0x24 => {
                 // and
@@ -219,25 +842,23 @@ impl Core {
                 // xor
                 self.registers.set(rd, rt_val ^ rs_val);
             }
-            0x28 => {
-                // mult
-                let prod: i64 = (rt_val as i64) * (rs_val as i64);
-                self.lo = prod as u32;
-                self.hi = (prod >> 32) as u32;
+            0x27 => {
+                // nor
+                self.registers.set(rd, !(rt_val | rs_val));
             }
             0x2A => {
                 // slt
-                if rs_val < rt_val {
-                    self.registers.set(rd, 0x01);
-                } else {
-                    self.registers.set(rd, 0x00);
-                }
+                self.registers.set(rd, ((rs_val as i32) < (rt_val as i32)) as u32);
             }
-
-            _ => {
-                todo!("Unimplemented r-type instruction, funct: {:#04X}", funct)
+            0x2B => {
+                // sltu
+                self.registers.set(rd, (rs_val < rt_val) as u32);
             }
+
+            _ => return Err(unimplemented_instruction(inst)),
         }
+
+        Ok(())
     }
 
     // fn execute_addi(&mut self, inst: u32) {
@@ -252,6 +873,32 @@ fn extract_itype_1(inst: u32) -> (u32, u32, u32) {
     ((inst >> 21) & 0x1F, (inst >> 16) & 0x1F, inst & 0x0000FFFF)
 }
 
+fn unimplemented_instruction(inst: u32) -> MimicError {
+    MimicError {
+        span: None,
+        source: None,
+        ty: MimicErrorType::UnimplementedInstruction {
+            mnemonic: format!("{:#010X}", inst),
+        },
+    }
+}
+
+fn sign_extend_16(imm: u32) -> u32 {
+    imm as u16 as i16 as i32 as u32
+}
+
+fn bytes_to_words(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks(4)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .fold(0, |word, (i, byte)| word | (*byte as u32) << (i * 8))
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -262,7 +909,7 @@ mod tests {
 
     fn test_reg_reg(inst: u32, reg1: usize, reg2: usize) {
         let mut core = Core::new_mips_default();
-        core.execute_instruction(inst, empty_syscall_fn);
+        core.execute_instruction(inst, &mut empty_syscall_fn).unwrap();
 
         let regs = core.dump_registers();
 
@@ -271,7 +918,7 @@ mod tests {
 
     fn test_reg_imm(inst: u32, reg1: usize, imm: u32) {
         let mut core = Core::new_mips_default();
-        core.execute_instruction(inst, empty_syscall_fn);
+        core.execute_instruction(inst, &mut empty_syscall_fn).unwrap();
 
         let regs = core.dump_registers();
 
@@ -288,9 +935,42 @@ mod tests {
         test_reg_imm(0x200F002A, 15, 42);
     }
 
+    #[test]
+    fn addi_negative() {
+        // addi $t4, $zero, -1
+        test_reg_imm(0x200CFFFF, 12, 0xFFFFFFFF);
+    }
+
     #[test]
     fn addu_1() {}
 
+    #[test]
+    fn sw_lw_stack() {
+        let mut core = Core::new_mips_default();
+        core.set_register(8, 0xDEADBEEF);
+
+        // sw $t0, -4($sp)
+        core.execute_instruction(0xAFA8FFFC, &mut empty_syscall_fn).unwrap();
+        // lw $t1, -4($sp)
+        core.execute_instruction(0x8FA9FFFC, &mut empty_syscall_fn).unwrap();
+        // lb $t2, -4($sp)
+        core.execute_instruction(0x83AAFFFC, &mut empty_syscall_fn).unwrap();
+        // lhu $t3, -2($sp)
+        core.execute_instruction(0x97ABFFFE, &mut empty_syscall_fn).unwrap();
+
+        assert_eq!(core.get_register(9), 0xDEADBEEF);
+        assert_eq!(core.get_register(10), 0xFFFFFFEF);
+        assert_eq!(core.get_register(11), 0xDEAD);
+    }
+
+    #[test]
+    fn lw_unaligned() {
+        let mut core = Core::new_mips_default();
+
+        // lw $t1, 2($sp)
+        assert!(core.execute_instruction(0x8FA90002, &mut empty_syscall_fn).is_err());
+    }
+
     #[test]
     fn addiu_1() {}
 
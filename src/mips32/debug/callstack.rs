@@ -0,0 +1,244 @@
+use crate::mips32::core::{Core, StopReason};
+use crate::mips32::symbols::{SourceMap, SymbolTable};
+
+use std::fmt::{self, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    // Address of the jal/jalr that made the call
+    pub call_site: u32,
+    // Entry point of the called routine
+    pub target: u32,
+    pub return_address: u32,
+    // Caller's $sp at the time of the call
+    pub sp: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReturnMismatch {
+    // Address of the jr $ra
+    pub pc: u32,
+    // Return address of the innermost frame, None if the stack was empty
+    pub expected: Option<u32>,
+    pub actual: u32,
+}
+
+impl fmt::Display for ReturnMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.expected {
+            Some(expected) => write!(
+                f,
+                "jr $ra at {:#010X} returned to {:#010X}, expected {:#010X}",
+                self.pc, self.actual, expected
+            ),
+            None => write!(
+                f,
+                "jr $ra at {:#010X} returned to {:#010X} with no call in progress",
+                self.pc, self.actual
+            ),
+        }
+    }
+}
+
+// Shadow call stack maintained from jal/jalr and jr $ra. Other jumps,
+// including jr through other registers, do not change the stack.
+//
+// Reverse execution does not rewind the shadow stack.
+#[derive(Debug, Clone, Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+    mismatches: Vec<ReturnMismatch>,
+    stop_on_mismatch: bool,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Stop execution with StopReason::UnexpectedReturn when a return doesn't
+    // match the innermost call
+    pub fn with_stop_on_mismatch(mut self, stop: bool) -> Self {
+        self.stop_on_mismatch = stop;
+        self
+    }
+
+    // Innermost frame last
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn mismatches(&self) -> &[ReturnMismatch] {
+        &self.mismatches
+    }
+
+    // `next` is the PC after the instruction executed and `sp` the $sp at that point.
+    // `taken` is the outcome of the instruction if it is a conditional branch.
+    pub(crate) fn record(&mut self, pc: u32, inst: u32, next: u32, taken: Option<bool>, sp: u32) -> Option<StopReason> {
+        let opcode = inst >> 26;
+        let funct = inst & 0x3F;
+        let rs = (inst >> 21) & 0x1F;
+
+        if is_call(inst, taken) {
+            self.frames.push(Frame {
+                call_site: pc,
+                target: next,
+                return_address: pc.wrapping_add(4),
+                sp,
+            });
+        } else if opcode == 0x00 && funct == 0x08 && rs == 31 {
+            if self.frames.last().is_some_and(|frame| frame.return_address == next) {
+                self.frames.pop();
+                return None;
+            }
+
+            let mismatch = ReturnMismatch {
+                pc,
+                expected: self.frames.last().map(|frame| frame.return_address),
+                actual: next,
+            };
+            self.mismatches.push(mismatch);
+
+            // Unwinding several frames at once, like longjmp, keeps the stack usable
+            if let Some(depth) = self.frames.iter().rposition(|frame| frame.return_address == next) {
+                self.frames.truncate(depth);
+            }
+
+            if self.stop_on_mismatch {
+                return Some(StopReason::UnexpectedReturn(mismatch));
+            }
+        }
+
+        None
+    }
+}
+
+// jal, jalr, and bltzal/bgezal (including bal) when the branch is taken, even
+// to the next instruction as in `bal 1f; 1:` to read the PC
+pub(crate) fn is_call(inst: u32, taken: Option<bool>) -> bool {
+    match inst >> 26 {
+        0x03 => true,
+        0x00 => inst & 0x3F == 0x09,
+        0x01 => (inst >> 16) & 0x1E == 0x10 && taken == Some(true),
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BacktraceFrame {
+    // Current PC for the innermost frame, the call site for the others
+    pub pc: u32,
+    pub sp: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backtrace {
+    // Innermost frame first
+    pub frames: Vec<BacktraceFrame>,
+}
+
+impl Backtrace {
+    // One line per frame, with the enclosing label and source line when known:
+    // `#1  0x00400024 in fact+0x10 at line 12: jal fact  sp=0x7FFFEFE8`
+    pub fn format(&self, symbols: &SymbolTable, source_map: Option<&SourceMap>) -> String {
+        let mut out = String::new();
+
+        for (i, frame) in self.frames.iter().enumerate() {
+            write!(out, "#{:<3}{:#010X}", i, frame.pc).unwrap();
+
+            match symbols.label_for(frame.pc) {
+                Some((label, address)) if address == frame.pc => write!(out, " in {}", label),
+                Some((label, address)) => write!(out, " in {}+{:#X}", label, frame.pc - address),
+                None => write!(out, " in ??"),
+            }
+            .unwrap();
+
+            if let Some((line, text)) = source_map.and_then(|map| {
+                let line = map.line_at(frame.pc)?;
+                Some((line, map.line_text(line)?))
+            }) {
+                write!(out, " at line {}: {}", line, text.trim()).unwrap();
+            }
+
+            writeln!(out, "  sp={:#010X}", frame.sp).unwrap();
+        }
+
+        out
+    }
+}
+
+impl Core {
+    pub fn enable_call_stack(&mut self, call_stack: CallStack) {
+        self.call_stack = Some(call_stack);
+    }
+
+    pub fn disable_call_stack(&mut self) -> Option<CallStack> {
+        self.call_stack.take()
+    }
+
+    pub fn call_stack(&self) -> Option<&CallStack> {
+        self.call_stack.as_ref()
+    }
+
+    // Current location followed by the call site of each active call. Only
+    // the current location is known when call stack tracking is off.
+    pub fn backtrace(&self) -> Backtrace {
+        let mut frames = vec![BacktraceFrame {
+            pc: self.pc(),
+            sp: self.registers.get(29),
+        }];
+
+        if let Some(call_stack) = &self.call_stack {
+            frames.extend(call_stack.frames.iter().rev().map(|frame| BacktraceFrame {
+                pc: frame.call_site,
+                sp: frame.sp,
+            }));
+        }
+
+        Backtrace { frames }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unwinds_to_matching_frame() {
+        let mut stack = CallStack::new().with_stop_on_mismatch(true);
+        stack.record(0x00400000, 0x0C100010, 0x00400040, None, 0x100); // jal
+        stack.record(0x00400040, 0x0C100020, 0x00400080, None, 0xF0); // jal
+        assert_eq!(stack.depth(), 2);
+
+        // Returning straight to the outer caller skips the inner frame
+        let reason = stack.record(0x00400080, 0x03E00008, 0x00400004, None, 0xF0);
+        assert!(matches!(
+            reason,
+            Some(StopReason::UnexpectedReturn(ReturnMismatch {
+                expected: Some(0x00400044),
+                actual: 0x00400004,
+                ..
+            }))
+        ));
+        assert_eq!(stack.depth(), 0);
+
+        assert!(stack.record(0x00400004, 0x03E00008, 0x00400100, None, 0x100).is_some());
+        assert_eq!(stack.mismatches()[1].expected, None);
+    }
+
+    #[test]
+    fn linking_branch_to_the_next_instruction() {
+        let mut stack = CallStack::new().with_stop_on_mismatch(true);
+        stack.record(0x00400000, 0x04100000, 0x00400004, Some(false), 0x100); // bltzal $zero, 0
+        assert_eq!(stack.depth(), 0);
+        stack.record(0x00400004, 0x04110000, 0x00400008, Some(true), 0x100); // bal 0
+        assert_eq!(stack.depth(), 1);
+
+        assert!(stack.record(0x00400010, 0x03E00008, 0x00400008, None, 0x100).is_none());
+        assert!(stack.mismatches().is_empty());
+    }
+}
@@ -0,0 +1,268 @@
+use crate::mips32::core::{Core, StopReason};
+use crate::mips32::debug::callstack::is_call;
+use crate::mips32::registers::REGISTER_NAMES;
+use crate::mips32::symbols::SourceMap;
+
+use std::fmt;
+
+// $s0-$s7, $gp and $fp. $sp is checked separately.
+const PRESERVED: [u32; 10] = [16, 17, 18, 19, 20, 21, 22, 23, 28, 30];
+// $t0-$t9
+const TEMPORARIES: u32 = 0x0300FF00;
+const SP: u32 = 29;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationKind {
+    // A callee-saved register changed between the call and the return
+    Clobbered { register: u32, call_site: u32, expected: u32, actual: u32 },
+    // $sp at the return differs from $sp at the call
+    StackImbalance { call_site: u32, expected: u32, actual: u32 },
+    // The caller read a $t register the callee was free to overwrite
+    StaleTemporary { register: u32, call_site: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Violation {
+    // The jr $ra for clobbered registers and stack imbalance, otherwise the
+    // instruction that read the register
+    pub pc: u32,
+    pub kind: ViolationKind,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ViolationKind::Clobbered {
+                register,
+                call_site,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "${} not preserved by call at {:#010X}: was {:#X}, returned {:#X}",
+                REGISTER_NAMES[register as usize], call_site, expected, actual
+            ),
+            ViolationKind::StackImbalance {
+                call_site,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "stack not balanced at return from call at {:#010X}: $sp was {:#010X}, returned {:#010X}",
+                call_site, expected, actual
+            ),
+            ViolationKind::StaleTemporary { register, call_site } => write!(
+                f,
+                "${} read after call at {:#010X} without being reloaded",
+                REGISTER_NAMES[register as usize], call_site
+            ),
+        }
+    }
+}
+
+impl Violation {
+    // `line 14: message` followed by the offending source line
+    pub fn format(&self, source_map: &SourceMap) -> String {
+        let line = match source_map.line_at(self.pc) {
+            Some(line) => line,
+            None => return format!("{:#010X}: {}\n", self.pc, self),
+        };
+        let location = match source_map.name() {
+            "" => format!("line {}", line),
+            name => format!("{}:{}", name, line),
+        };
+
+        format!(
+            "{}: {}\n    {}\n",
+            location,
+            self,
+            source_map.line_text(line).unwrap_or_default().trim()
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CallFrame {
+    call_site: u32,
+    return_address: u32,
+    registers: [u32; 32],
+    // The caller's stale temporaries while the callee runs
+    stale: u32,
+}
+
+// Checks the o32 calling convention across each jal/jalr ... jr $ra pair
+#[derive(Debug, Clone, Default)]
+pub struct ConventionChecker {
+    frames: Vec<CallFrame>,
+    // Temporaries not written since the last call returned, with its call site
+    stale: u32,
+    stale_call_site: u32,
+    violations: Vec<Violation>,
+    stop_on_violation: bool,
+}
+
+impl ConventionChecker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Stop execution with StopReason::ConventionViolation at the first violation
+    pub fn with_stop_on_violation(mut self, stop: bool) -> Self {
+        self.stop_on_violation = stop;
+        self
+    }
+
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    // Reports every violation with its source location
+    pub fn report(&self, source_map: &SourceMap) -> String {
+        self.violations.iter().map(|v| v.format(source_map)).collect()
+    }
+
+    // Called after the instruction at `pc` executed, with the registers at that point
+    pub(crate) fn record(
+        &mut self,
+        pc: u32,
+        inst: u32,
+        next: u32,
+        taken: Option<bool>,
+        registers: &[u32; 32],
+    ) -> Option<StopReason> {
+        let found = self.violations.len();
+        let (reads, writes) = register_use(inst);
+
+        let stale_reads = reads & self.stale;
+        for register in (0..32).filter(|r| stale_reads & (1 << r) != 0) {
+            self.violations.push(Violation {
+                pc,
+                kind: ViolationKind::StaleTemporary {
+                    register,
+                    call_site: self.stale_call_site,
+                },
+            });
+        }
+        // Report each stale register once
+        self.stale &= !(reads | writes);
+
+        let opcode = inst >> 26;
+        let funct = inst & 0x3F;
+        let rs = (inst >> 21) & 0x1F;
+
+        if is_call(inst, taken) {
+            self.frames.push(CallFrame {
+                call_site: pc,
+                return_address: pc.wrapping_add(4),
+                registers: *registers,
+                stale: self.stale,
+            });
+            self.stale = 0;
+        } else if opcode == 0x00 && funct == 0x08 && rs == 31 {
+            // Returns that skip frames are left for the call stack to report
+            let depth = self.frames.iter().rposition(|frame| frame.return_address == next)?;
+            self.frames.truncate(depth + 1);
+            let frame = self.frames.pop().unwrap();
+
+            for register in PRESERVED {
+                let (expected, actual) = (frame.registers[register as usize], registers[register as usize]);
+                if expected != actual {
+                    self.violations.push(Violation {
+                        pc,
+                        kind: ViolationKind::Clobbered {
+                            register,
+                            call_site: frame.call_site,
+                            expected,
+                            actual,
+                        },
+                    });
+                }
+            }
+
+            let (expected, actual) = (frame.registers[SP as usize], registers[SP as usize]);
+            if expected != actual {
+                self.violations.push(Violation {
+                    pc,
+                    kind: ViolationKind::StackImbalance {
+                        call_site: frame.call_site,
+                        expected,
+                        actual,
+                    },
+                });
+            }
+
+            self.stale = frame.stale | TEMPORARIES;
+            self.stale_call_site = frame.call_site;
+        }
+
+        match self.violations.get(found) {
+            Some(violation) if self.stop_on_violation => Some(StopReason::ConventionViolation(*violation)),
+            _ => None,
+        }
+    }
+}
+
+// Bit masks of the registers an instruction reads and writes
+fn register_use(inst: u32) -> (u32, u32) {
+    let rs = 1 << ((inst >> 21) & 0x1F);
+    let rt = 1 << ((inst >> 16) & 0x1F);
+    let rd = 1 << ((inst >> 11) & 0x1F);
+    let ra = 1 << 31;
+
+    let (reads, writes) = match inst >> 26 {
+        0x00 => match inst & 0x3F {
+            0x00 | 0x02 | 0x03 => (rt, rd),
+            0x08 | 0x11 | 0x13 => (rs, 0),
+            0x09 => (rs, rd),
+            // syscall arguments are never temporaries
+            0x0C | 0x0D => (0, 0),
+            0x10 | 0x12 => (0, rd),
+            0x18..=0x1B => (rs | rt, 0),
+            _ => (rs | rt, rd),
+        },
+        0x01 => match (inst >> 16) & 0x1F {
+            0x10..=0x13 => (rs, ra),
+            _ => (rs, 0),
+        },
+        0x02 => (0, 0),
+        0x03 => (0, ra),
+        0x04 | 0x05 | 0x14 | 0x15 => (rs | rt, 0),
+        0x06 | 0x07 | 0x16 | 0x17 => (rs, 0),
+        0x0F => (0, rt),
+        0x1C if inst & 0x3F == 0x02 => (rs | rt, rd),
+        0x1C => (rs | rt, 0),
+        0x28..=0x2E | 0x38 => (rs | rt, 0),
+        _ => (rs, rt),
+    };
+
+    // $zero is never stale
+    (reads & !1, writes & !1)
+}
+
+impl Core {
+    pub fn enable_convention_checker(&mut self, checker: ConventionChecker) {
+        self.convention = Some(checker);
+    }
+
+    pub fn disable_convention_checker(&mut self) -> Option<ConventionChecker> {
+        self.convention.take()
+    }
+
+    pub fn convention_checker(&self) -> Option<&ConventionChecker> {
+        self.convention.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_masks() {
+        assert_eq!(register_use(0x03A84820), ((1 << 29) | (1 << 8), 1 << 9)); // add $t1, $sp, $t0
+        assert_eq!(register_use(0x8FA9FFFC), (1 << 29, 1 << 9)); // lw $t1, -4($sp)
+        assert_eq!(register_use(0xAFA8FFFC), ((1 << 29) | (1 << 8), 0)); // sw $t0, -4($sp)
+        assert_eq!(register_use(0x0C100003), (0, 1 << 31)); // jal
+        assert_eq!(register_use(0x00000000), (0, 0)); // nop
+    }
+}
@@ -0,0 +1,285 @@
+use crate::mips32::core::Core;
+use crate::mips32::debug::profile::InstructionClass;
+use crate::mips32::symbols::{SourceMap, SymbolTable, KTEXT_START, TEXT_START};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BranchCoverage {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+// Executed instructions and branch outcomes. Coverage from several runs of the
+// same program can be combined with `merge`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    executed: HashMap<u32, u64>,
+    branches: HashMap<u32, BranchCoverage>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // `taken` is the outcome of the instruction at `pc` if it is a conditional branch
+    pub(crate) fn record(&mut self, pc: u32, taken: Option<bool>) {
+        *self.executed.entry(pc).or_insert(0) += 1;
+
+        if let Some(taken) = taken {
+            let branch = self.branches.entry(pc).or_default();
+            if taken {
+                branch.taken += 1;
+            } else {
+                branch.not_taken += 1;
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &Coverage) {
+        for (pc, count) in &other.executed {
+            *self.executed.entry(*pc).or_insert(0) += count;
+        }
+        for (pc, branch) in &other.branches {
+            let entry = self.branches.entry(*pc).or_default();
+            entry.taken += branch.taken;
+            entry.not_taken += branch.not_taken;
+        }
+    }
+
+    pub fn is_executed(&self, pc: u32) -> bool {
+        self.executed.contains_key(&pc)
+    }
+
+    pub fn count_at(&self, pc: u32) -> u64 {
+        self.executed.get(&pc).copied().unwrap_or(0)
+    }
+
+    // Executed addresses in ascending order
+    pub fn executed(&self) -> Vec<u32> {
+        let mut pcs: Vec<u32> = self.executed.keys().copied().collect();
+        pcs.sort_unstable();
+        pcs
+    }
+
+    pub fn branch(&self, pc: u32) -> Option<BranchCoverage> {
+        self.branches.get(&pc).copied()
+    }
+
+    // Relates the recorded addresses to the assembled program. `text` and `ktext`
+    // are the text segments as produced by the assembler and are used to find
+    // branches that never executed.
+    pub fn report(&self, text: &[u8], ktext: &[u8], source_map: &SourceMap, symbols: &SymbolTable) -> CoverageReport {
+        let mut lines: BTreeMap<usize, LineCoverage> = BTreeMap::new();
+
+        let words = [(TEXT_START, text), (KTEXT_START, ktext)].into_iter().flat_map(|(start, segment)| {
+            segment.chunks_exact(4).enumerate().map(move |(i, word)| (start + 4 * i as u32, word))
+        });
+        for (pc, word) in words {
+            let line = match source_map.line_at(pc) {
+                Some(line) => line,
+                None => continue,
+            };
+            let inst = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+
+            let entry = lines.entry(line).or_insert_with(|| LineCoverage {
+                line,
+                count: 0,
+                branches: Vec::new(),
+            });
+            entry.count = entry.count.max(self.count_at(pc));
+            if InstructionClass::of(inst) == InstructionClass::Branch {
+                entry.branches.push((pc, self.branch(pc)));
+            }
+        }
+
+        let functions = symbols
+            .text_labels()
+            .into_iter()
+            .filter_map(|(label, address)| {
+                Some(FunctionCoverage {
+                    name: label.to_owned(),
+                    line: source_map.line_at(address)?,
+                    count: self.count_at(address),
+                })
+            })
+            .collect();
+
+        CoverageReport {
+            name: source_map.name().to_owned(),
+            source: (1..=source_map.line_count())
+                .map(|line| source_map.line_text(line).unwrap_or_default().to_owned())
+                .collect(),
+            lines: lines.into_values().collect(),
+            functions,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineCoverage {
+    pub line: usize,
+    // Executions of the most executed instruction on the line
+    pub count: u64,
+    // Address and outcomes of each branch on the line, None if never reached
+    pub branches: Vec<(u32, Option<BranchCoverage>)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionCoverage {
+    pub name: String,
+    pub line: usize,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoverageReport {
+    pub name: String,
+    pub source: Vec<String>,
+    pub lines: Vec<LineCoverage>,
+    pub functions: Vec<FunctionCoverage>,
+}
+
+impl CoverageReport {
+    pub fn lines_hit(&self) -> usize {
+        self.lines.iter().filter(|l| l.count > 0).count()
+    }
+
+    // Number of branch directions, and how many of them were taken at least once
+    pub fn branch_directions(&self) -> (usize, usize) {
+        let branches = self.lines.iter().flat_map(|l| &l.branches);
+        let found = 2 * branches.clone().count();
+        let hit = branches
+            .filter_map(|(_, b)| *b)
+            .map(|b| (b.taken > 0) as usize + (b.not_taken > 0) as usize)
+            .sum();
+        (found, hit)
+    }
+
+    // lcov tracefile with line (DA), branch (BRDA) and label (FN) records.
+    // Branch 0 of a block is the taken direction, branch 1 the fall through.
+    pub fn to_lcov(&self) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "TN:").unwrap();
+        writeln!(out, "SF:{}", self.name).unwrap();
+
+        for function in &self.functions {
+            writeln!(out, "FN:{},{}", function.line, function.name).unwrap();
+        }
+        for function in &self.functions {
+            writeln!(out, "FNDA:{},{}", function.count, function.name).unwrap();
+        }
+        writeln!(out, "FNF:{}", self.functions.len()).unwrap();
+        writeln!(out, "FNH:{}", self.functions.iter().filter(|f| f.count > 0).count()).unwrap();
+
+        for line in &self.lines {
+            for (block, (_, branch)) in line.branches.iter().enumerate() {
+                let (taken, not_taken) = match branch {
+                    Some(b) => (b.taken.to_string(), b.not_taken.to_string()),
+                    None => ("-".to_owned(), "-".to_owned()),
+                };
+                writeln!(out, "BRDA:{},{},0,{}", line.line, block, taken).unwrap();
+                writeln!(out, "BRDA:{},{},1,{}", line.line, block, not_taken).unwrap();
+            }
+        }
+        let (found, hit) = self.branch_directions();
+        writeln!(out, "BRF:{}", found).unwrap();
+        writeln!(out, "BRH:{}", hit).unwrap();
+
+        for line in &self.lines {
+            writeln!(out, "DA:{},{}", line.line, line.count).unwrap();
+        }
+        writeln!(out, "LF:{}", self.lines.len()).unwrap();
+        writeln!(out, "LH:{}", self.lines_hit()).unwrap();
+        writeln!(out, "end_of_record").unwrap();
+
+        out
+    }
+
+    // Source listing in the style of gcov: execution counts in the left
+    // column, `#####` for code that never ran and `-` for lines without code.
+    pub fn to_listing(&self) -> String {
+        let lines: HashMap<usize, &LineCoverage> = self.lines.iter().map(|l| (l.line, l)).collect();
+        let mut out = String::new();
+
+        for (i, text) in self.source.iter().enumerate() {
+            let number = i + 1;
+            let coverage = lines.get(&number);
+            let count = match coverage {
+                Some(l) if l.count > 0 => l.count.to_string(),
+                Some(_) => "#####".to_owned(),
+                None => "-".to_owned(),
+            };
+            writeln!(out, "{:>9}:{:>5}:{}", count, number, text).unwrap();
+
+            for (address, branch) in coverage.map(|l| l.branches.as_slice()).unwrap_or_default() {
+                match branch {
+                    Some(b) => writeln!(
+                        out,
+                        "{:>9} {:#010X} taken {}, not taken {}",
+                        "branch", address, b.taken, b.not_taken
+                    ),
+                    None => writeln!(out, "{:>9} {:#010X} never executed", "branch", address),
+                }
+                .unwrap();
+            }
+        }
+
+        out
+    }
+}
+
+impl Core {
+    pub fn start_coverage(&mut self, coverage: Coverage) {
+        self.coverage = Some(coverage);
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    pub fn stop_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn branch_outcomes_and_merge() {
+        let mut first = Coverage::new();
+        first.record(0x00400000, Some(true));
+        first.record(0x00400000, Some(true));
+        let mut second = Coverage::new();
+        second.record(0x00400000, Some(false));
+        second.record(0x00400004, None);
+
+        first.merge(&second);
+        assert_eq!(first.count_at(0x00400000), 3);
+        assert_eq!(first.branch(0x00400000), Some(BranchCoverage { taken: 2, not_taken: 1 }));
+        assert_eq!(first.branch(0x00400004), None);
+        assert_eq!(first.executed(), vec![0x00400000, 0x00400004]);
+    }
+
+    #[test]
+    fn branch_to_the_next_instruction() {
+        let mut core = Core::new_mips_default();
+        core.start_coverage(Coverage::new());
+        core.load_text(vec![
+            0x11090000, // beq $t0, $t1, 0
+            0x15090000, // bne $t0, $t1, 0
+            0x05110000, // bgezal $t0, 0
+        ]);
+        core.run_for(&mut |_, registers| registers, 3);
+
+        let coverage = core.coverage().unwrap();
+        assert_eq!(coverage.branch(0x00400000), Some(BranchCoverage { taken: 1, not_taken: 0 }));
+        assert_eq!(coverage.branch(0x00400004), Some(BranchCoverage { taken: 0, not_taken: 1 }));
+        assert_eq!(coverage.branch(0x00400008), Some(BranchCoverage { taken: 1, not_taken: 0 }));
+    }
+}
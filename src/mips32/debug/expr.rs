@@ -0,0 +1,707 @@
+use codespan_reporting::files::SimpleFile;
+
+use crate::errors::{MimicError, MimicErrorType, Span};
+use crate::mips32::core::Core;
+use crate::mips32::registers::REGISTER_NAMES;
+use crate::mips32::symbols::SymbolTable;
+
+use std::fmt;
+
+// Small expression language for breakpoint conditions, watches and trace
+// filters, e.g. `$t0 == 5 && mem.w[$sp + 4] > 3`.
+//
+//   $t0 $8 $pc $hi $lo     registers
+//   pc hi lo               same as the $ forms
+//   hits                   times the breakpoint has been reached, including now
+//   mem.b[e] mem.bu[e]     signed / unsigned byte at address e
+//   mem.h[e] mem.hu[e]     signed / unsigned half word
+//   mem.w[e]               word
+//   label:name name        address of an assembler symbol
+//   42 0x2A 0b101010 '*'   literals
+//   signed(e)              e read as a two's complement number
+//
+// Every value is 32 bits wide and unsigned unless it comes from a signed
+// view (mem.b, mem.h or signed), so `$t0 == 0xFFFFFFFF` and `$t0 == -1`
+// agree and kernel addresses compare above user ones. An operator works on
+// signed numbers when either operand is signed.
+//
+// Operators follow C precedence: unary - ! ~, then * / %, + -, << >>,
+// < <= > >=, == !=, &, ^, |, && and ||. Arithmetic wraps, comparisons and
+// logical operators give 0 or 1, and && / || short circuit.
+#[derive(Debug, Clone)]
+pub struct Expression {
+    text: String,
+    root: Expr,
+}
+
+#[derive(Debug, Clone)]
+struct Expr {
+    span: Span,
+    node: Node,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Literal(u32),
+    Register(u32),
+    Pc,
+    Hi,
+    Lo,
+    Hits,
+    Symbol(String),
+    Memory(MemoryView, Box<Expr>),
+    Signed(Box<Expr>),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MemoryView {
+    Byte,
+    ByteUnsigned,
+    Half,
+    HalfUnsigned,
+    Word,
+}
+
+// A 32 bit result and whether it is read as signed
+#[derive(Debug, Clone, Copy)]
+struct Value {
+    bits: u32,
+    signed: bool,
+}
+
+impl Value {
+    fn unsigned(bits: u32) -> Self {
+        Self { bits, signed: false }
+    }
+
+    fn flag(value: bool) -> Self {
+        Self::unsigned(value as u32)
+    }
+
+    fn to_i64(self) -> i64 {
+        if self.signed { self.bits as i32 as i64 } else { self.bits as i64 }
+    }
+}
+
+// Everything an expression can refer to while it is evaluated
+#[derive(Clone, Copy)]
+pub struct EvalContext<'a> {
+    core: Option<&'a Core>,
+    symbols: Option<&'a SymbolTable>,
+    hits: u64,
+}
+
+impl<'a> EvalContext<'a> {
+    pub fn new(core: &'a Core) -> Self {
+        Self {
+            core: Some(core),
+            symbols: None,
+            hits: 0,
+        }
+    }
+
+    pub fn with_symbols(mut self, symbols: &'a SymbolTable) -> Self {
+        self.symbols = Some(symbols);
+        self
+    }
+
+    pub fn with_hits(mut self, hits: u64) -> Self {
+        self.hits = hits;
+        self
+    }
+}
+
+impl Expression {
+    pub fn parse(text: &str) -> Result<Self, MimicError> {
+        let mut parser = Parser::new(text)?;
+        let root = parser.parse_binary(0)?;
+        parser.expect_end()?;
+
+        Ok(Self {
+            text: text.to_owned(),
+            root,
+        })
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn eval(&self, context: &EvalContext) -> Result<i64, MimicError> {
+        Ok(self.eval_node(&self.root, context)?.to_i64())
+    }
+
+    pub fn is_true(&self, context: &EvalContext) -> Result<bool, MimicError> {
+        Ok(self.eval(context)? != 0)
+    }
+
+    // Evaluates an expression that may only use literals and symbols
+    pub fn eval_constant(&self, symbols: &SymbolTable) -> Result<i64, MimicError> {
+        let context = EvalContext {
+            core: None,
+            symbols: Some(symbols),
+            hits: 0,
+        };
+        Ok(self.eval_node(&self.root, &context)?.to_i64())
+    }
+
+    fn eval_node(&self, expr: &Expr, context: &EvalContext) -> Result<Value, MimicError> {
+        let core = || {
+            context.core.ok_or_else(|| {
+                self.error(expr.span, "expected a constant expression".to_owned())
+            })
+        };
+
+        let value = match &expr.node {
+            Node::Literal(value) => Value::unsigned(*value),
+            Node::Register(index) => Value::unsigned(core()?.get_register(*index)),
+            Node::Pc => Value::unsigned(core()?.pc()),
+            Node::Hi => Value::unsigned(core()?.hi()),
+            Node::Lo => Value::unsigned(core()?.lo()),
+            Node::Hits => {
+                core()?;
+                Value::unsigned(context.hits.min(u32::MAX as u64) as u32)
+            }
+            Node::Symbol(name) => match context.symbols.and_then(|symbols| symbols.get(name)) {
+                Some(address) => Value::unsigned(address),
+                None => {
+                    return Err(MimicError {
+                        span: Some(expr.span),
+                        source: Some(Box::new(self.source())),
+                        ty: MimicErrorType::UnknownSymbol { name: name.to_owned() },
+                    })
+                }
+            },
+            Node::Memory(view, address) => {
+                let address = self.eval_node(address, context)?.bits;
+                let core = core()?;
+                match view {
+                    MemoryView::Byte => Value { bits: core.read_byte(address)? as i8 as u32, signed: true },
+                    MemoryView::ByteUnsigned => Value::unsigned(core.read_byte(address)? as u32),
+                    MemoryView::Half => Value { bits: core.read_half(address)? as i16 as u32, signed: true },
+                    MemoryView::HalfUnsigned => Value::unsigned(core.read_half(address)? as u32),
+                    MemoryView::Word => Value::unsigned(core.read_word(address)?),
+                }
+            }
+            Node::Signed(operand) => Value {
+                signed: true,
+                ..self.eval_node(operand, context)?
+            },
+            Node::Unary(op, operand) => {
+                let value = self.eval_node(operand, context)?;
+                match *op {
+                    "-" => Value { bits: value.bits.wrapping_neg(), ..value },
+                    "!" => Value::flag(value.bits == 0),
+                    _ => Value { bits: !value.bits, ..value },
+                }
+            }
+            Node::Binary("&&", lhs, rhs) => {
+                Value::flag(self.eval_node(lhs, context)?.bits != 0 && self.eval_node(rhs, context)?.bits != 0)
+            }
+            Node::Binary("||", lhs, rhs) => {
+                Value::flag(self.eval_node(lhs, context)?.bits != 0 || self.eval_node(rhs, context)?.bits != 0)
+            }
+            Node::Binary(op, lhs, rhs) => {
+                let lhs = self.eval_node(lhs, context)?;
+                let rhs = self.eval_node(rhs, context)?;
+                let signed = lhs.signed || rhs.signed;
+                let (a, b) = (lhs.bits, rhs.bits);
+                let (sa, sb) = (a as i32, b as i32);
+                let bits = match *op {
+                    "*" => a.wrapping_mul(b),
+                    "/" | "%" if b == 0 => {
+                        return Err(self.error(expr.span, "division by zero".to_owned()))
+                    }
+                    "/" if signed => sa.wrapping_div(sb) as u32,
+                    "/" => a / b,
+                    "%" if signed => sa.wrapping_rem(sb) as u32,
+                    "%" => a % b,
+                    "+" => a.wrapping_add(b),
+                    "-" => a.wrapping_sub(b),
+                    "<<" => a.wrapping_shl(b),
+                    ">>" if signed => sa.wrapping_shr(b) as u32,
+                    ">>" => a.wrapping_shr(b),
+                    "&" => a & b,
+                    "^" => a ^ b,
+                    "|" => a | b,
+                    _ => {
+                        let ordering = if signed { sa.cmp(&sb) } else { a.cmp(&b) };
+                        return Ok(Value::flag(match *op {
+                            "<" => ordering.is_lt(),
+                            "<=" => ordering.is_le(),
+                            ">" => ordering.is_gt(),
+                            ">=" => ordering.is_ge(),
+                            "==" => ordering.is_eq(),
+                            _ => ordering.is_ne(),
+                        }));
+                    }
+                };
+                Value { bits, signed }
+            }
+        };
+
+        Ok(value)
+    }
+
+    fn source(&self) -> SimpleFile<String, String> {
+        SimpleFile::new("expression".to_owned(), self.text.clone())
+    }
+
+    fn error(&self, span: Span, message: String) -> MimicError {
+        MimicError {
+            span: Some(span),
+            source: Some(Box::new(self.source())),
+            ty: MimicErrorType::InvalidExpression { message },
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+// Splits a breakpoint spec such as `label:loop hits > 100` or
+// `0x00400010 if $t0 == 5` into its location and optional condition
+pub(crate) fn parse_breakpoint_spec(text: &str) -> Result<(Expression, Option<Expression>), MimicError> {
+    let mut parser = Parser::new(text)?;
+    let location = parser.parse_unary()?;
+
+    if matches!(&parser.peek().0, Token::Ident(s) if s == "if") {
+        parser.advance();
+    }
+
+    let condition = if parser.peek().0 == Token::End {
+        None
+    } else {
+        let root = parser.parse_binary(0)?;
+        parser.expect_end()?;
+        Some(Expression {
+            text: text.to_owned(),
+            root,
+        })
+    };
+
+    Ok((
+        Expression {
+            text: text.to_owned(),
+            root: location,
+        },
+        condition,
+    ))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(u32),
+    Register(String),
+    Ident(String),
+    Symbol(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    End,
+}
+
+// Longest operators first so `<=` is not read as `<`
+const OPERATORS: [&str; 20] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "|", "^", "&", "<", ">", "+", "-", "*", "/",
+    "%", "!", "~",
+];
+
+const PRECEDENCE: [&[&str]; 10] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+// Deepest nesting of operators and parentheses. Parsing and evaluating recurse
+// once per level, so this keeps long inputs from overflowing the stack.
+const MAX_DEPTH: usize = 32;
+
+struct Parser<'a> {
+    text: &'a str,
+    tokens: Vec<(Token, Span)>,
+    position: usize,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Result<Self, MimicError> {
+        let mut parser = Self {
+            text,
+            tokens: Vec::new(),
+            position: 0,
+            depth: 0,
+        };
+        parser.tokenize()?;
+        Ok(parser)
+    }
+
+    fn error(&self, span: Span, message: String) -> MimicError {
+        MimicError {
+            span: Some(span),
+            source: Some(Box::new(SimpleFile::new("expression".to_owned(), self.text.to_owned()))),
+            ty: MimicErrorType::InvalidExpression { message },
+        }
+    }
+
+    fn tokenize(&mut self) -> Result<(), MimicError> {
+        let bytes = self.text.as_bytes();
+        let is_word = |c: u8| c.is_ascii_alphanumeric() || c == b'_' || c == b'.';
+        let mut i = 0;
+
+        while i < bytes.len() {
+            let c = bytes[i];
+            let lo = i;
+
+            if c.is_ascii_whitespace() {
+                i += 1;
+                continue;
+            }
+
+            let token = match c {
+                b'(' => Token::LParen,
+                b')' => Token::RParen,
+                b'[' => Token::LBracket,
+                b']' => Token::RBracket,
+                b'$' => {
+                    i += 1;
+                    while i < bytes.len() && is_word(bytes[i]) {
+                        i += 1;
+                    }
+                    self.tokens.push((Token::Register(self.text[lo + 1..i].to_owned()), Span { lo, hi: i }));
+                    continue;
+                }
+                b'\'' => {
+                    let value = match (bytes.get(i + 1), bytes.get(i + 2), bytes.get(i + 3)) {
+                        (Some(b'\\'), Some(e), Some(b'\'')) => {
+                            i += 4;
+                            match e {
+                                b'n' => b'\n',
+                                b't' => b'\t',
+                                b'0' => 0,
+                                _ => *e,
+                            }
+                        }
+                        (Some(c), Some(b'\''), _) => {
+                            i += 3;
+                            *c
+                        }
+                        _ => {
+                            return Err(self.error(
+                                Span { lo, hi: self.text.len() },
+                                "unterminated character literal".to_owned(),
+                            ))
+                        }
+                    };
+                    self.tokens.push((Token::Number(value as u32), Span { lo, hi: i }));
+                    continue;
+                }
+                _ if c.is_ascii_digit() => {
+                    while i < bytes.len() && is_word(bytes[i]) {
+                        i += 1;
+                    }
+                    let word = &self.text[lo..i];
+                    let parsed = if let Some(hex) = word.strip_prefix("0x").or(word.strip_prefix("0X")) {
+                        u32::from_str_radix(hex, 16)
+                    } else if let Some(binary) = word.strip_prefix("0b").or(word.strip_prefix("0B")) {
+                        u32::from_str_radix(binary, 2)
+                    } else {
+                        word.parse::<u32>()
+                    };
+                    let value = parsed.map_err(|_| {
+                        self.error(Span { lo, hi: i }, format!("invalid number `{}`", word))
+                    })?;
+                    self.tokens.push((Token::Number(value), Span { lo, hi: i }));
+                    continue;
+                }
+                _ if is_word(c) => {
+                    while i < bytes.len() && is_word(bytes[i]) {
+                        i += 1;
+                    }
+                    let word = &self.text[lo..i];
+
+                    if word == "label" && bytes.get(i) == Some(&b':') {
+                        let start = i + 1;
+                        i = start;
+                        while i < bytes.len() && is_word(bytes[i]) {
+                            i += 1;
+                        }
+                        if i == start {
+                            return Err(self.error(Span { lo, hi: i }, "expected a label name".to_owned()));
+                        }
+                        self.tokens.push((Token::Symbol(self.text[start..i].to_owned()), Span { lo, hi: i }));
+                    } else {
+                        self.tokens.push((Token::Ident(word.to_owned()), Span { lo, hi: i }));
+                    }
+                    continue;
+                }
+                _ => match OPERATORS.iter().find(|op| self.text[i..].starts_with(**op)) {
+                    Some(op) => {
+                        i += op.len() - 1;
+                        Token::Op(op)
+                    }
+                    None => {
+                        let hi = lo + self.text[lo..].chars().next().map_or(1, char::len_utf8);
+                        return Err(self.error(
+                            Span { lo, hi },
+                            format!("unexpected character `{}`", &self.text[lo..hi]),
+                        ));
+                    }
+                },
+            };
+
+            i += 1;
+            self.tokens.push((token, Span { lo, hi: i }));
+        }
+
+        let end = self.text.len();
+        self.tokens.push((Token::End, Span { lo: end, hi: end }));
+        Ok(())
+    }
+
+    fn peek(&self) -> &(Token, Span) {
+        &self.tokens[self.position]
+    }
+
+    fn advance(&mut self) -> (Token, Span) {
+        let token = self.tokens[self.position].clone();
+        if token.0 != Token::End {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn expect(&mut self, expected: Token, what: &str) -> Result<Span, MimicError> {
+        let (token, span) = self.advance();
+        if token == expected {
+            Ok(span)
+        } else {
+            Err(self.error(span, format!("expected {}", what)))
+        }
+    }
+
+    fn expect_end(&mut self) -> Result<(), MimicError> {
+        let (token, span) = self.peek().clone();
+        if token == Token::End {
+            Ok(())
+        } else {
+            Err(self.error(span, "unexpected trailing input".to_owned()))
+        }
+    }
+
+    fn nest(&mut self, span: Span) -> Result<(), MimicError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(self.error(span, "expression is nested too deeply".to_owned()));
+        }
+        Ok(())
+    }
+
+    fn parse_binary(&mut self, level: usize) -> Result<Expr, MimicError> {
+        if level == PRECEDENCE.len() {
+            return self.parse_unary();
+        }
+
+        // Each operator of a chain nests the ones before it one level deeper
+        let depth = self.depth;
+        let mut lhs = self.parse_binary(level + 1)?;
+        while let (Token::Op(op), span) = self.peek().clone() {
+            if !PRECEDENCE[level].contains(&op) {
+                break;
+            }
+            self.advance();
+            self.nest(span)?;
+
+            let rhs = self.parse_binary(level + 1)?;
+            lhs = Expr {
+                span: Span { lo: lhs.span.lo, hi: rhs.span.hi },
+                node: Node::Binary(op, Box::new(lhs), Box::new(rhs)),
+            };
+        }
+
+        self.depth = depth;
+        Ok(lhs)
+    }
+
+    // Unary operators, and the parentheses and brackets of primaries, nest one level
+    fn parse_unary(&mut self) -> Result<Expr, MimicError> {
+        let (token, span) = self.peek().clone();
+        self.nest(span)?;
+
+        let expr = match token {
+            Token::Op(op @ ("-" | "!" | "~")) => {
+                self.advance();
+                let operand = self.parse_unary()?;
+                Expr {
+                    span: Span { lo: span.lo, hi: operand.span.hi },
+                    node: Node::Unary(op, Box::new(operand)),
+                }
+            }
+            _ => self.parse_primary()?,
+        };
+
+        self.depth -= 1;
+        Ok(expr)
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, MimicError> {
+        let (token, span) = self.advance();
+
+        let node = match token {
+            Token::Number(value) => Node::Literal(value),
+            Token::Symbol(name) => Node::Symbol(name),
+            Token::Register(name) => match name.to_lowercase().as_str() {
+                "pc" => Node::Pc,
+                "hi" => Node::Hi,
+                "lo" => Node::Lo,
+                name => match name.parse::<u32>() {
+                    Ok(index) if index < 32 => Node::Register(index),
+                    _ => match REGISTER_NAMES.iter().position(|r| *r == name || (name == "s8" && *r == "fp")) {
+                        Some(index) => Node::Register(index as u32),
+                        None => return Err(self.error(span, format!("unknown register `${}`", name))),
+                    },
+                },
+            },
+            Token::Ident(name) => match name.as_str() {
+                "pc" => Node::Pc,
+                "hi" => Node::Hi,
+                "lo" => Node::Lo,
+                "hits" => Node::Hits,
+                "signed" => {
+                    self.expect(Token::LParen, "`(`")?;
+                    let operand = self.parse_binary(0)?;
+                    let end = self.expect(Token::RParen, "`)`")?;
+                    return Ok(Expr {
+                        span: Span { lo: span.lo, hi: end.hi },
+                        node: Node::Signed(Box::new(operand)),
+                    });
+                }
+                "mem.b" | "mem.bu" | "mem.h" | "mem.hu" | "mem.w" => {
+                    let view = match name.as_str() {
+                        "mem.b" => MemoryView::Byte,
+                        "mem.bu" => MemoryView::ByteUnsigned,
+                        "mem.h" => MemoryView::Half,
+                        "mem.hu" => MemoryView::HalfUnsigned,
+                        _ => MemoryView::Word,
+                    };
+                    self.expect(Token::LBracket, "`[`")?;
+                    let address = self.parse_binary(0)?;
+                    let end = self.expect(Token::RBracket, "`]`")?;
+                    return Ok(Expr {
+                        span: Span { lo: span.lo, hi: end.hi },
+                        node: Node::Memory(view, Box::new(address)),
+                    });
+                }
+                _ if name.starts_with("mem.") => {
+                    return Err(self.error(span, format!("unknown memory view `{}`", name)))
+                }
+                _ => Node::Symbol(name),
+            },
+            Token::LParen => {
+                let inner = self.parse_binary(0)?;
+                let end = self.expect(Token::RParen, "`)`")?;
+                return Ok(Expr {
+                    span: Span { lo: span.lo, hi: end.hi },
+                    node: inner.node,
+                });
+            }
+            Token::End => return Err(self.error(span, "unexpected end of expression".to_owned())),
+            _ => return Err(self.error(span, "expected a value".to_owned())),
+        };
+
+        Ok(Expr { span, node })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str, core: &Core) -> i64 {
+        Expression::parse(text).unwrap().eval(&EvalContext::new(core)).unwrap()
+    }
+
+    #[test]
+    fn precedence_and_literals() {
+        let core = Core::new_mips_default();
+        assert_eq!(eval("1 + 2 * 3 == 7 && 0x10 >> 2 == 0b100", &core), 1);
+        assert_eq!(eval("-'a' + ~0 % 5", &core), 0xFFFFFF9F);
+        assert_eq!(eval("signed(~0) % 5 - 'a'", &core), -98);
+        assert_eq!(eval("-1 >> 28", &core), 0xF);
+        assert_eq!(eval("signed(-1) >> 28", &core), -1);
+        assert_eq!(eval("(1 + 2) * 3 | 1 << 4", &core), 25);
+        assert_eq!(eval("!0 + !7", &core), 1);
+    }
+
+    #[test]
+    fn registers_memory_and_symbols() {
+        let mut core = Core::new_mips_default();
+        core.set_register(8, 5);
+        core.set_register(9, 0xFFFFFFFF);
+        core.write_word(0x7FFFF000, 0x000080FF);
+
+        assert_eq!(eval("$t0 == 5 && mem.w[$sp + 4] > 3", &core), 1);
+        assert_eq!(eval("$9 + $zero", &core), 0xFFFFFFFF);
+        assert_eq!(eval("signed($9) + $zero", &core), -1);
+        assert_eq!(eval("$9 == 0xFFFFFFFF && $9 == -1 && $9 > 0 && signed($9) < 0", &core), 1);
+        assert_eq!(eval("mem.b[0x7FFFF000] < 0 && mem.bu[0x7FFFF000] > 0", &core), 1);
+        assert_eq!(eval("mem.b[0x7FFFF000] + mem.bu[0x7FFFF000]", &core), 254);
+        assert_eq!(eval("mem.h[0x7FFFF000]", &core), -32513);
+        assert_eq!(eval("$pc == pc && pc == 0x00400000", &core), 1);
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert("loop".to_owned(), 0x00400010);
+        symbols.insert("handler".to_owned(), 0x80000180);
+        let expr = Expression::parse("label:loop + loop").unwrap();
+        let context = EvalContext::new(&core).with_symbols(&symbols);
+        assert_eq!(expr.eval(&context).unwrap(), 0x00800020);
+        assert_eq!(expr.eval_constant(&symbols).unwrap(), 0x00800020);
+
+        let kernel = Expression::parse("label:handler == 0x80000180 && handler > label:loop").unwrap();
+        assert_eq!(kernel.eval(&context).unwrap(), 1);
+        assert_eq!(
+            Expression::parse("label:handler").unwrap().eval_constant(&symbols).unwrap(),
+            0x80000180
+        );
+
+        let missing = Expression::parse("label:missing").unwrap();
+        assert!(matches!(
+            missing.eval(&context).unwrap_err().ty,
+            MimicErrorType::UnknownSymbol { .. }
+        ));
+        assert!(Expression::parse("$t0").unwrap().eval_constant(&symbols).is_err());
+    }
+
+    #[test]
+    fn parse_errors() {
+        for text in ["1 +", "$foo", "mem.q[0]", "(1", "1 2", "0xZZ", "3 # 4", "'a", "0x100000000", "signed 1"] {
+            assert!(Expression::parse(text).is_err(), "{}", text);
+        }
+
+        let core = Core::new_mips_default();
+        let expr = Expression::parse("1 / ($t0 - $t0)").unwrap();
+        assert!(expr.eval(&EvalContext::new(&core)).is_err());
+        assert_eq!(eval("0 && 1 / 0", &core), 0);
+
+        for text in ["-".repeat(100_000) + "1", "(".repeat(100_000) + "1", "1+".repeat(100_000) + "1"] {
+            let error = Expression::parse(&text).unwrap_err();
+            assert!(error.msg().contains("nested too deeply"));
+        }
+        let nested = "(".repeat(30) + "1" + &")".repeat(30);
+        assert_eq!(eval(&nested, &core), 1);
+    }
+}
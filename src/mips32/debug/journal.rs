@@ -0,0 +1,459 @@
+use crate::errors::MimicError;
+use crate::mips32::core::{Core, StopReason};
+use crate::mips32::snapshot::Snapshot;
+use crate::mips32::syscall::SyscallOutcome;
+
+use std::collections::VecDeque;
+
+// State overwritten by a single instruction
+#[derive(Debug, Clone)]
+pub(crate) struct UndoEntry {
+    pub(crate) pc: u32,
+    pub(crate) hi: u32,
+    pub(crate) lo: u32,
+    pub(crate) exit_code: Option<u32>,
+    // Register and memory word index with the value before the instruction
+    pub(crate) registers: Vec<(u32, u32)>,
+    pub(crate) memory: Vec<(u32, u32)>,
+}
+
+// In progress entry, taken before an instruction executes
+pub(crate) struct UndoStart {
+    pc: u32,
+    hi: u32,
+    lo: u32,
+    exit_code: Option<u32>,
+    registers: [u32; 32],
+}
+
+// What an instruction that depends on the outside world, a syscall or a device
+// access, left behind, so it isn't run again when replaying from a checkpoint
+#[derive(Debug, Clone)]
+pub(crate) struct RedoEntry {
+    instruction: u64,
+    pc: u32,
+    hi: u32,
+    lo: u32,
+    exit_code: Option<u32>,
+    // Register and memory word index with the value after the instruction
+    registers: Vec<(u32, u32)>,
+    memory: Vec<(u32, u32)>,
+}
+
+// Undo log used for reverse execution. A checkpoint of the registers and memory
+// is taken every `checkpoint_interval` instructions and the newest
+// `checkpoints` of them are kept. Per-instruction undo entries only cover the
+// instructions since the newest checkpoint; stepping back past it restores the
+// checkpoint before and executes forward again to rebuild them. About
+// checkpoint_interval * checkpoints instructions can be undone, and stepping
+// back further stops with StopReason::StartOfHistory.
+//
+// Only the core is rewound: output already written by syscalls, consumed
+// input and the state of memory mapped devices stay as they are. Syscalls and
+// device accesses are not repeated when executing forward again; their results
+// are replayed from the log. Changes made through the Core API between
+// instructions, such as `set_register`, are not recorded.
+#[derive(Debug, Clone)]
+pub struct UndoJournal {
+    checkpoint_interval: usize,
+    max_checkpoints: usize,
+    checkpoints: VecDeque<Snapshot>,
+    entries: Vec<UndoEntry>,
+    // For the instructions since the oldest checkpoint, by instruction count
+    redo: VecDeque<RedoEntry>,
+}
+
+impl UndoJournal {
+    pub fn new(checkpoint_interval: usize, checkpoints: usize) -> Self {
+        Self {
+            checkpoint_interval: checkpoint_interval.max(1),
+            max_checkpoints: checkpoints.max(1),
+            checkpoints: VecDeque::new(),
+            entries: Vec::new(),
+            redo: VecDeque::new(),
+        }
+    }
+
+    // Number of instructions that can currently be undone
+    pub fn len(&self) -> usize {
+        let replayable = match (self.checkpoints.front(), self.checkpoints.back()) {
+            (Some(oldest), Some(newest)) => (newest.instructions - oldest.instructions) as usize,
+            _ => 0,
+        };
+        replayable + self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn checkpoint_count(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.checkpoints.clear();
+        self.entries.clear();
+        self.redo.clear();
+    }
+
+    pub(crate) fn needs_checkpoint(&self) -> bool {
+        self.checkpoints.is_empty() || self.entries.len() >= self.checkpoint_interval
+    }
+
+    pub(crate) fn checkpoint(&mut self, snapshot: Snapshot) {
+        self.checkpoints.push_back(snapshot);
+        self.entries.clear();
+
+        if self.checkpoints.len() > self.max_checkpoints {
+            self.checkpoints.pop_front();
+            let oldest = self.checkpoints[0].instructions;
+            while self.redo.front().is_some_and(|redo| redo.instruction < oldest) {
+                self.redo.pop_front();
+            }
+        }
+    }
+
+    pub(crate) fn push(&mut self, entry: UndoEntry, redo: Option<RedoEntry>) {
+        self.entries.push(entry);
+        self.redo.extend(redo);
+    }
+
+    fn pop(&mut self) -> Option<UndoEntry> {
+        let entry = self.entries.pop()?;
+
+        // History after this point may now play out differently
+        let instruction = self.checkpoints.back().map_or(0, |c| c.instructions) + self.entries.len() as u64;
+        while self.redo.back().is_some_and(|redo| redo.instruction >= instruction) {
+            self.redo.pop_back();
+        }
+
+        Some(entry)
+    }
+
+    fn redo_at(&self, instruction: u64) -> Option<&RedoEntry> {
+        let index = self.redo.partition_point(|redo| redo.instruction < instruction);
+        self.redo.get(index).filter(|redo| redo.instruction == instruction)
+    }
+}
+
+impl Default for UndoJournal {
+    fn default() -> Self {
+        Self::new(10_000, 100)
+    }
+}
+
+impl Core {
+    // Starts recording the undo journal. Any previous history is discarded.
+    pub fn enable_undo_journal(&mut self, journal: UndoJournal) {
+        self.journal = Some(journal);
+    }
+
+    pub fn disable_undo_journal(&mut self) {
+        self.journal = None;
+    }
+
+    pub fn undo_journal(&self) -> Option<&UndoJournal> {
+        self.journal.as_ref()
+    }
+
+    pub(crate) fn begin_undo_entry(&mut self) -> UndoStart {
+        self.memory.write_log = Some(Vec::new());
+
+        UndoStart {
+            pc: self.pc,
+            hi: self.hi,
+            lo: self.lo,
+            exit_code: self.exit_code,
+            registers: self.registers.dump(),
+        }
+    }
+
+    pub(crate) fn finish_undo_entry(&mut self, start: UndoStart) -> UndoEntry {
+        let after = self.registers.dump();
+
+        UndoEntry {
+            pc: start.pc,
+            hi: start.hi,
+            lo: start.lo,
+            exit_code: start.exit_code,
+            registers: (0..32)
+                .filter(|i| start.registers[*i] != after[*i])
+                .map(|i| (i as u32, start.registers[i]))
+                .collect(),
+            memory: self.memory.write_log.take().unwrap_or_default(),
+        }
+    }
+
+    // Outside effects of the instruction that just executed, if it had any
+    pub(crate) fn redo_entry(&self, inst: u32, outcome: SyscallOutcome, undo: &UndoEntry) -> Option<RedoEntry> {
+        let syscall = inst >> 26 == 0x00 && inst & 0x3F == 0x0C;
+        let device = self.last_access.is_some_and(|access| self.memory.is_device(access.address >> 2));
+        if !syscall && !device {
+            return None;
+        }
+
+        Some(RedoEntry {
+            instruction: self.instructions - 1,
+            pc: self.pc,
+            hi: self.hi,
+            lo: self.lo,
+            exit_code: match outcome {
+                SyscallOutcome::Exit(code) => Some(code),
+                _ => self.exit_code,
+            },
+            registers: undo.registers.iter().map(|(index, _)| (*index, self.registers.get(*index))).collect(),
+            memory: undo
+                .memory
+                .iter()
+                .map(|(index, _)| (*index, self.memory.peek(*index).unwrap_or(0)))
+                .collect(),
+        })
+    }
+
+    // Goes back to the checkpoint before the newest one and executes forward up
+    // to the newest, rebuilding the undo entries in between
+    fn replay_previous_checkpoint(&mut self) -> Result<bool, MimicError> {
+        if self.journal.as_ref().is_none_or(|journal| journal.checkpoints.len() < 2) {
+            return Ok(false);
+        }
+        let mut journal = self.journal.take().unwrap();
+
+        let target = journal.checkpoints.pop_back().unwrap().instructions;
+        self.restore_machine(journal.checkpoints.back().unwrap());
+
+        let mut result = Ok(true);
+        while self.instructions < target {
+            let start = self.begin_undo_entry();
+            let executed = match journal.redo_at(self.instructions) {
+                Some(redo) => {
+                    for (index, value) in &redo.memory {
+                        self.memory.set(*index, *value);
+                    }
+                    for (index, value) in &redo.registers {
+                        self.registers.set(*index, *value);
+                    }
+                    self.pc = redo.pc;
+                    self.hi = redo.hi;
+                    self.lo = redo.lo;
+                    self.exit_code = redo.exit_code;
+                    Ok(())
+                }
+                // Syscalls always have a redo entry, so the handler is never called
+                None => self
+                    .memory
+                    .peek(self.pc)
+                    .and_then(|inst| self.execute_instruction(inst, &mut |_, registers| registers))
+                    .map(|_| self.pc = self.pc.wrapping_add(1)),
+            };
+            let entry = self.finish_undo_entry(start);
+
+            if let Err(e) = executed {
+                result = Err(e);
+                break;
+            }
+            self.instructions += 1;
+            journal.entries.push(entry);
+        }
+
+        self.journal = Some(journal);
+        result
+    }
+
+    // Undoes the most recently executed instruction
+    pub fn step_back(&mut self) -> StopReason {
+        if self.journal.as_ref().is_some_and(|journal| journal.entries.is_empty()) {
+            match self.replay_previous_checkpoint() {
+                Ok(true) => {}
+                Ok(false) => return StopReason::StartOfHistory,
+                Err(e) => return StopReason::Fault(e),
+            }
+        }
+
+        let entry = match self.journal.as_mut().and_then(UndoJournal::pop) {
+            Some(entry) => entry,
+            None => return StopReason::StartOfHistory,
+        };
+
+        // Later writes to the same word come later in the log
+        for (index, old) in entry.memory.into_iter().rev() {
+            self.memory.set(index, old);
+        }
+        for (index, old) in entry.registers {
+            self.registers.set(index, old);
+        }
+
+        self.pc = entry.pc;
+        self.hi = entry.hi;
+        self.lo = entry.lo;
+        self.exit_code = entry.exit_code;
+        self.instructions -= 1;
+        self.last_access = None;
+        self.resuming_from = Some(self.pc());
+        self.pending_stop = None;
+
+        StopReason::Step
+    }
+
+    // Undoes up to `instructions` instructions
+    pub fn rewind(&mut self, instructions: u64) -> StopReason {
+        for _ in 0..instructions {
+            if let StopReason::StartOfHistory = self.step_back() {
+                return StopReason::StartOfHistory;
+            }
+        }
+
+        StopReason::Step
+    }
+
+    // Runs backwards until the PC reaches an enabled breakpoint whose condition
+    // holds. Hit counts are left alone: `hits` in a condition is the count from
+    // running forwards, and hit conditions are not considered.
+    pub fn reverse_continue(&mut self) -> StopReason {
+        loop {
+            if let StopReason::StartOfHistory = self.step_back() {
+                return StopReason::StartOfHistory;
+            }
+
+            let pc = self.pc();
+            let hit = self.breakpoints.iter().enumerate().find_map(|(id, b)| {
+                let b = b.as_ref().filter(|b| b.enabled && b.address == pc)?;
+                b.condition_holds(self, b.reached).then_some((id, b.hits))
+            });
+
+            if let Some((id, hits)) = hit {
+                return StopReason::Breakpoint {
+                    id,
+                    address: pc,
+                    hits,
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mips32::debug::expr::{EvalContext, Expression};
+    use crate::mips32::debug::Breakpoint;
+    use crate::mips32::symbols::SymbolTable;
+
+    fn no_syscalls(_: u32, regs: [u32; 32]) -> [u32; 32] {
+        regs
+    }
+
+    #[test]
+    fn step_back_restores_registers_and_memory() {
+        let mut core = Core::new_mips_default();
+        core.enable_undo_journal(UndoJournal::default());
+        core.load_text(vec![
+            0x24080007, // addiu $t0, $zero, 7
+            0xAFA8FFFC, // sw $t0, -4($sp)
+            0xA3A0FFFC, // sb $zero, -4($sp)
+        ]);
+        let sp = core.get_register(29);
+
+        core.run_for(&mut no_syscalls, 3);
+        assert_eq!(core.read_word(sp - 4).unwrap(), 0);
+
+        assert!(matches!(core.step_back(), StopReason::Step));
+        assert_eq!(core.read_word(sp - 4).unwrap(), 7);
+        assert!(matches!(core.rewind(5), StopReason::StartOfHistory));
+        assert_eq!(core.read_word(sp - 4).unwrap(), 0);
+        assert_eq!(core.get_register(8), 0);
+        assert_eq!((core.pc(), core.instruction_count()), (0x00400000, 0));
+    }
+
+    #[test]
+    fn step_back_past_the_undo_entries_replays_from_a_checkpoint() {
+        let mut core = Core::new_mips_default();
+        core.enable_undo_journal(UndoJournal::new(4, 3));
+        core.load_text(vec![
+            0x0000000C, // syscall
+            0x01024021, // addu $t0, $t0, $v0
+            0xAFA8FFFC, // sw $t0, -4($sp)
+            0x1000FFFC, // beq $zero, $zero, -4
+        ]);
+        let sp = core.get_register(29);
+
+        // Each syscall returns a new value, so running one again would change the results
+        let mut calls = 0;
+        let mut syscalls = move |_: u32, mut registers: [u32; 32]| {
+            calls += 1;
+            registers[2] = calls;
+            registers
+        };
+        let state = |core: &Core| (core.pc(), core.get_register(2), core.get_register(8), core.read_word(sp - 4).unwrap());
+
+        let mut history = vec![state(&core)];
+        for _ in 0..20 {
+            core.step(&mut syscalls);
+            history.push(state(&core));
+        }
+
+        // Checkpoints before instructions 8, 12 and 16 are left, with undo entries for 16 to 19
+        let journal = core.undo_journal().unwrap();
+        assert_eq!((journal.checkpoint_count(), journal.len()), (3, 12));
+
+        for expected in history[8..20].iter().rev() {
+            assert!(matches!(core.step_back(), StopReason::Step));
+            assert_eq!(state(&core), *expected);
+        }
+        assert!(matches!(core.step_back(), StopReason::StartOfHistory));
+        assert_eq!(core.instruction_count(), 8);
+
+        // Running forward again takes new syscall results and can be undone as well
+        core.run_for(&mut syscalls, 6);
+        assert_eq!(core.get_register(2), 7);
+        assert_eq!(core.instruction_count(), 14);
+        assert!(matches!(core.rewind(6), StopReason::Step));
+        assert_eq!(state(&core), history[8]);
+    }
+
+    #[test]
+    fn reverse_continue_checks_conditions() {
+        let mut core = Core::new_mips_default();
+        core.enable_undo_journal(UndoJournal::default());
+        core.load_text(vec![
+            0x25080001, // addiu $t0, $t0, 1
+            0x1000FFFE, // beq $zero, $zero, -2
+        ]);
+
+        core.run_for(&mut no_syscalls, 20);
+        assert_eq!(core.get_register(8), 10);
+
+        let expr = Expression::parse("$t0 == 4").unwrap();
+        core.add_breakpoint(
+            Breakpoint::new(0x00400004).with_condition(move |core| expr.is_true(&EvalContext::new(core)).unwrap()),
+        );
+
+        assert!(matches!(
+            core.reverse_continue(),
+            StopReason::Breakpoint { id: 0, address: 0x00400004, .. }
+        ));
+        assert_eq!(core.get_register(8), 4);
+        assert!(matches!(core.reverse_continue(), StopReason::StartOfHistory));
+    }
+
+    #[test]
+    fn reverse_continue_leaves_hit_counts_alone() {
+        let mut core = Core::new_mips_default();
+        core.enable_undo_journal(UndoJournal::default());
+        core.load_text(vec![
+            0x25080001, // addiu $t0, $t0, 1
+            0x1000FFFE, // beq $zero, $zero, -2
+        ]);
+
+        let symbols = SymbolTable::new();
+        core.add_breakpoint(Breakpoint::parse("0x00400004 if hits == 3", &symbols).unwrap());
+        assert!(matches!(core.run(&mut no_syscalls), StopReason::Breakpoint { hits: 1, .. }));
+        assert_eq!(core.get_register(8), 3);
+
+        core.run_for(&mut no_syscalls, 6);
+        assert!(matches!(core.reverse_continue(), StopReason::StartOfHistory));
+        assert_eq!(core.get_register(8), 0);
+        let breakpoint = core.breakpoint(0).unwrap();
+        assert_eq!((breakpoint.reached(), breakpoint.hits()), (6, 1));
+    }
+}
@@ -0,0 +1,284 @@
+pub mod callstack;
+pub mod convention;
+pub mod coverage;
+pub mod expr;
+pub mod journal;
+pub mod profile;
+pub mod trace;
+
+use crate::errors::MimicError;
+use crate::mips32::core::{AccessKind, Core, StopReason};
+use crate::mips32::symbols::SymbolTable;
+use crate::mips32::syscall::SyscallHandler;
+
+use expr::{EvalContext, Expression};
+
+use std::fmt;
+use std::rc::Rc;
+
+// Called with the number of times the breakpoint has been reached, including now
+pub type Condition = Rc<dyn Fn(&Core, u64) -> bool>;
+
+// When a breakpoint whose condition holds actually stops execution, based on how
+// many times it has been reached so far (including this time)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HitCondition {
+    Always,
+    Equal(u64),
+    AtLeast(u64),
+    Multiple(u64),
+}
+
+impl HitCondition {
+    fn matches(&self, hits: u64) -> bool {
+        match self {
+            HitCondition::Always => true,
+            HitCondition::Equal(n) => hits == *n,
+            HitCondition::AtLeast(n) => hits >= *n,
+            HitCondition::Multiple(n) => *n != 0 && hits.is_multiple_of(*n),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Breakpoint {
+    pub address: u32,
+    pub enabled: bool,
+    condition: Option<Condition>,
+    hit_condition: HitCondition,
+    // Times the address was reached, and times it was reached with the condition holding
+    reached: u64,
+    hits: u64,
+}
+
+impl Breakpoint {
+    pub fn new(address: u32) -> Self {
+        Self {
+            address,
+            enabled: true,
+            condition: None,
+            hit_condition: HitCondition::Always,
+            reached: 0,
+            hits: 0,
+        }
+    }
+
+    pub fn with_condition<F>(mut self, condition: F) -> Self
+    where
+        F: Fn(&Core) -> bool + 'static,
+    {
+        self.condition = Some(Rc::new(move |core, _| condition(core)));
+        self
+    }
+
+    // Parses `<location> [if] [condition]` such as `label:loop hits > 100` or
+    // `0x00400010 if $t0 == 5 && mem.w[$sp + 4] > 3`
+    pub fn parse(spec: &str, symbols: &SymbolTable) -> Result<Self, MimicError> {
+        let (location, condition) = expr::parse_breakpoint_spec(spec)?;
+        let breakpoint = Self::new(location.eval_constant(symbols)? as u32);
+
+        Ok(match condition {
+            Some(condition) => breakpoint.with_expression(condition, symbols.clone()),
+            None => breakpoint,
+        })
+    }
+
+    // `hits` in the expression counts every time the address is reached. A
+    // condition that fails to evaluate, e.g. by reading unmapped memory, stops
+    // execution so the problem does not go unnoticed.
+    pub fn with_expression(mut self, condition: Expression, symbols: SymbolTable) -> Self {
+        self.condition = Some(Rc::new(move |core, reached| {
+            let context = EvalContext::new(core).with_symbols(&symbols).with_hits(reached);
+            condition.is_true(&context).unwrap_or(true)
+        }));
+        self
+    }
+
+    pub fn with_hit_condition(mut self, hit_condition: HitCondition) -> Self {
+        self.hit_condition = hit_condition;
+        self
+    }
+
+    pub fn reached(&self) -> u64 {
+        self.reached
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    // Evaluates the condition without counting a hit
+    pub(crate) fn condition_holds(&self, core: &Core, reached: u64) -> bool {
+        self.condition.as_ref().is_none_or(|condition| condition(core, reached))
+    }
+}
+
+impl fmt::Debug for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Breakpoint")
+            .field("address", &format_args!("{:#010X}", self.address))
+            .field("enabled", &self.enabled)
+            .field("conditional", &self.condition.is_some())
+            .field("hit_condition", &self.hit_condition)
+            .field("reached", &self.reached)
+            .field("hits", &self.hits)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+// Watches the byte addresses start..end
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u32,
+    pub end: u32,
+    pub kind: WatchKind,
+    pub enabled: bool,
+}
+
+impl Watchpoint {
+    pub fn new(address: u32, len: u32, kind: WatchKind) -> Self {
+        Self {
+            start: address,
+            end: address.wrapping_add(len),
+            kind,
+            enabled: true,
+        }
+    }
+}
+
+impl Core {
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        self.breakpoints.push(Some(breakpoint));
+        self.breakpoints.len() - 1
+    }
+
+    pub fn remove_breakpoint(&mut self, id: usize) -> Option<Breakpoint> {
+        self.breakpoints.get_mut(id).and_then(|b| b.take())
+    }
+
+    pub fn breakpoint(&self, id: usize) -> Option<&Breakpoint> {
+        self.breakpoints.get(id).and_then(|b| b.as_ref())
+    }
+
+    pub fn breakpoint_mut(&mut self, id: usize) -> Option<&mut Breakpoint> {
+        self.breakpoints.get_mut(id).and_then(|b| b.as_mut())
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        self.watchpoints.push(Some(watchpoint));
+        self.watchpoints.len() - 1
+    }
+
+    pub fn remove_watchpoint(&mut self, id: usize) -> Option<Watchpoint> {
+        self.watchpoints.get_mut(id).and_then(|w| w.take())
+    }
+
+    pub(crate) fn check_breakpoints(&mut self) -> Option<StopReason> {
+        let pc = self.pc();
+
+        for id in 0..self.breakpoints.len() {
+            let (condition, reached) = match &mut self.breakpoints[id] {
+                Some(b) if b.enabled && b.address == pc => {
+                    b.reached += 1;
+                    (b.condition.clone(), b.reached)
+                }
+                _ => continue,
+            };
+
+            if condition.is_some_and(|condition| !condition(self, reached)) {
+                continue;
+            }
+
+            let breakpoint = self.breakpoints[id].as_mut().unwrap();
+            breakpoint.hits += 1;
+            if breakpoint.hit_condition.matches(breakpoint.hits) {
+                return Some(StopReason::Breakpoint {
+                    id,
+                    address: pc,
+                    hits: breakpoint.hits,
+                });
+            }
+        }
+
+        None
+    }
+
+    pub(crate) fn check_watchpoints(&self, pc: u32) -> Option<StopReason> {
+        let access = self.last_access?;
+
+        self.watchpoints.iter().enumerate().find_map(|(id, w)| {
+            let w = w.as_ref().filter(|w| w.enabled)?;
+
+            let kind_matches = matches!(
+                (w.kind, access.kind),
+                (WatchKind::ReadWrite, _)
+                    | (WatchKind::Read, AccessKind::Read)
+                    | (WatchKind::Write, AccessKind::Write)
+            );
+            // In 64 bits, as a range can end at the top of the address space
+            let (start, end) = (w.start as u64, w.start as u64 + w.end.wrapping_sub(w.start) as u64);
+            let address = access.address as u64;
+            let overlaps = address < end && start < address + access.size as u64;
+
+            (kind_matches && overlaps).then_some(StopReason::Watchpoint { id, pc, access })
+        })
+    }
+
+    // Executes exactly one instruction
+    pub fn step<H>(&mut self, syscall_handler: &mut H) -> StopReason
+    where
+        H: SyscallHandler + ?Sized,
+    {
+        self.resuming_from = Some(self.pc());
+        self.run_loop(syscall_handler, None, |_| Some(StopReason::Step))
+    }
+
+    // Like `step`, but runs a called procedure to completion
+    pub fn step_over<H>(&mut self, syscall_handler: &mut H) -> StopReason
+    where
+        H: SyscallHandler + ?Sized,
+    {
+        let inst = match self.memory.peek(self.pc) {
+            Ok(inst) => inst,
+            Err(e) => return StopReason::Fault(e),
+        };
+
+        let is_call = inst >> 26 == 0x03 || (inst >> 26 == 0x00 && inst & 0x3F == 0x09);
+        if !is_call {
+            return self.step(syscall_handler);
+        }
+
+        self.run_to_return(syscall_handler, self.pc() + 4)
+    }
+
+    // Runs until the current procedure returns to the address in $ra. If $ra has
+    // already been overwritten by a nested call this stops wherever it now points.
+    pub fn step_out<H>(&mut self, syscall_handler: &mut H) -> StopReason
+    where
+        H: SyscallHandler + ?Sized,
+    {
+        let return_address = self.get_register(31);
+        self.run_to_return(syscall_handler, return_address)
+    }
+
+    // The stack pointer check keeps recursive calls from stopping at the same
+    // return address in a deeper frame
+    fn run_to_return<H>(&mut self, syscall_handler: &mut H, return_address: u32) -> StopReason
+    where
+        H: SyscallHandler + ?Sized,
+    {
+        let sp = self.get_register(29);
+
+        self.resuming_from = Some(self.pc());
+        self.run_loop(syscall_handler, None, |core| {
+            (core.pc() == return_address && core.get_register(29) >= sp).then_some(StopReason::Step)
+        })
+    }
+}
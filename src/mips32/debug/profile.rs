@@ -0,0 +1,302 @@
+use crate::mips32::core::Core;
+use crate::mips32::symbols::{SourceMap, SymbolTable};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum InstructionClass {
+    Alu,
+    Jump,
+    Branch,
+    Memory,
+    Other,
+}
+
+impl InstructionClass {
+    pub const ALL: [InstructionClass; 5] = [
+        InstructionClass::Alu,
+        InstructionClass::Jump,
+        InstructionClass::Branch,
+        InstructionClass::Memory,
+        InstructionClass::Other,
+    ];
+
+    // Grouped like the MARS Instruction Statistics tool
+    pub fn of(inst: u32) -> Self {
+        match inst >> 26 {
+            0x00 => match inst & 0x3F {
+                0x08 | 0x09 => InstructionClass::Jump,
+                0x0C | 0x0D => InstructionClass::Other,
+                _ => InstructionClass::Alu,
+            },
+            0x01 | 0x04..=0x07 => InstructionClass::Branch,
+            0x02 | 0x03 => InstructionClass::Jump,
+            0x08..=0x0F | 0x1C => InstructionClass::Alu,
+            0x20..=0x2E | 0x30 | 0x38 => InstructionClass::Memory,
+            _ => InstructionClass::Other,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            InstructionClass::Alu => "ALU",
+            InstructionClass::Jump => "Jump",
+            InstructionClass::Branch => "Branch",
+            InstructionClass::Memory => "Memory",
+            InstructionClass::Other => "Other",
+        }
+    }
+}
+
+// Execution counts per PC and per instruction class
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    counts: HashMap<u32, u64>,
+    classes: BTreeMap<InstructionClass, u64>,
+    total: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn record(&mut self, pc: u32, inst: u32) {
+        *self.counts.entry(pc).or_insert(0) += 1;
+        *self.classes.entry(InstructionClass::of(inst)).or_insert(0) += 1;
+        self.total += 1;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn count_at(&self, pc: u32) -> u64 {
+        self.counts.get(&pc).copied().unwrap_or(0)
+    }
+
+    pub fn class_count(&self, class: InstructionClass) -> u64 {
+        self.classes.get(&class).copied().unwrap_or(0)
+    }
+
+    // Counts summed per source line and per text label. Instructions before the
+    // first label of their segment are reported under `<none>`.
+    pub fn report(&self, source_map: &SourceMap, symbols: &SymbolTable) -> ProfileReport {
+        let mut lines: BTreeMap<usize, u64> = BTreeMap::new();
+        let mut labels: BTreeMap<u32, (String, u64)> = BTreeMap::new();
+
+        for (pc, count) in &self.counts {
+            if let Some(line) = source_map.line_at(*pc) {
+                *lines.entry(line).or_insert(0) += count;
+            }
+
+            let (name, address) = symbols.label_for(*pc).unwrap_or(("<none>", 0));
+            labels.entry(address).or_insert_with(|| (name.to_owned(), 0)).1 += count;
+        }
+
+        ProfileReport {
+            total: self.total,
+            classes: InstructionClass::ALL
+                .iter()
+                .map(|class| (*class, self.class_count(*class)))
+                .collect(),
+            lines: lines
+                .into_iter()
+                .map(|(line, count)| LineProfile {
+                    line,
+                    source: source_map.line_text(line).unwrap_or_default().trim().to_owned(),
+                    count,
+                })
+                .collect(),
+            labels: labels
+                .into_iter()
+                .map(|(address, (label, count))| LabelProfile { label, address, count })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineProfile {
+    pub line: usize,
+    pub source: String,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelProfile {
+    pub label: String,
+    pub address: u32,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileReport {
+    pub total: u64,
+    pub classes: Vec<(InstructionClass, u64)>,
+    pub lines: Vec<LineProfile>,
+    pub labels: Vec<LabelProfile>,
+}
+
+impl ProfileReport {
+    fn percent(&self, count: u64) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            count as f64 * 100.0 / self.total as f64
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "Instruction statistics").unwrap();
+        for (class, count) in &self.classes {
+            writeln!(out, "  {:<8} {:>10} {:>6.1}%", class.name(), count, self.percent(*count)).unwrap();
+        }
+        writeln!(out, "  {:<8} {:>10}", "Total", self.total).unwrap();
+
+        writeln!(out, "\nBy line").unwrap();
+        for line in &self.lines {
+            writeln!(
+                out,
+                "  {:>5} {:>10} {:>6.1}%  {}",
+                line.line,
+                line.count,
+                self.percent(line.count),
+                line.source
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "\nBy label").unwrap();
+        for label in &self.labels {
+            writeln!(
+                out,
+                "  {:<20} {:#010X} {:>10} {:>6.1}%",
+                label.label,
+                label.address,
+                label.count,
+                self.percent(label.count)
+            )
+            .unwrap();
+        }
+
+        out
+    }
+
+    pub fn to_json(&self) -> String {
+        let classes: Vec<String> = self
+            .classes
+            .iter()
+            .map(|(class, count)| format!("\"{}\":{}", class.name(), count))
+            .collect();
+        let lines: Vec<String> = self
+            .lines
+            .iter()
+            .map(|l| format!("{{\"line\":{},\"source\":{},\"count\":{}}}", l.line, json_string(&l.source), l.count))
+            .collect();
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|l| {
+                format!(
+                    "{{\"label\":{},\"address\":{},\"count\":{}}}",
+                    json_string(&l.label),
+                    l.address,
+                    l.count
+                )
+            })
+            .collect();
+
+        format!(
+            "{{\"total\":{},\"classes\":{{{}}},\"lines\":[{}],\"labels\":[{}]}}",
+            self.total,
+            classes.join(","),
+            lines.join(","),
+            labels.join(",")
+        )
+    }
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl Core {
+    pub fn start_profile(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    // Stops profiling and returns the counts collected so far
+    pub fn stop_profile(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classes() {
+        assert_eq!(InstructionClass::of(0x03A84820), InstructionClass::Alu); // add
+        assert_eq!(InstructionClass::of(0x3C011001), InstructionClass::Alu); // lui
+        assert_eq!(InstructionClass::of(0x03E00008), InstructionClass::Jump); // jr
+        assert_eq!(InstructionClass::of(0x0C100003), InstructionClass::Jump); // jal
+        assert_eq!(InstructionClass::of(0x1000FFFE), InstructionClass::Branch); // beq
+        assert_eq!(InstructionClass::of(0x8FA9FFFC), InstructionClass::Memory); // lw
+        assert_eq!(InstructionClass::of(0x0000000C), InstructionClass::Other); // syscall
+    }
+
+    #[test]
+    fn labels_per_segment() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("main".to_owned(), 0x00400000);
+        symbols.insert("handler".to_owned(), 0x80000180);
+        symbols.insert("buffer".to_owned(), 0x10010000);
+        symbols.insert("main".to_owned(), 0x00400004);
+
+        assert_eq!(symbols.text_labels(), [("main", 0x00400004), ("handler", 0x80000180)]);
+        assert_eq!(symbols.label_for(0x00400000), None);
+        assert_eq!(symbols.label_for(0x0040FFFC), Some(("main", 0x00400004)));
+        assert_eq!(symbols.label_for(0x10010000), None);
+        assert_eq!(symbols.label_for(0x80000000), None);
+        assert_eq!(symbols.label_for(0x80000184), Some(("handler", 0x80000180)));
+
+        let mut profiler = Profiler::new();
+        profiler.record(0x00400008, 0);
+        profiler.record(0x80000184, 0);
+        profiler.record(0x80000188, 0);
+        let report = profiler.report(&SourceMap::default(), &symbols);
+        assert_eq!(
+            report.labels,
+            [
+                LabelProfile { label: "main".to_owned(), address: 0x00400004, count: 1 },
+                LabelProfile { label: "handler".to_owned(), address: 0x80000180, count: 2 },
+            ]
+        );
+    }
+
+    #[test]
+    fn json_escapes() {
+        assert_eq!(json_string("a \"b\"\\\n\u{1}"), "\"a \\\"b\\\"\\\\\\n\\u0001\"");
+    }
+}
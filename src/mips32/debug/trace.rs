@@ -0,0 +1,526 @@
+use crate::errors::{MimicError, MimicErrorType};
+use crate::mips32::core::{AccessKind, Core, MemoryAccess};
+use crate::mips32::debug::expr::{EvalContext, Expression};
+use crate::mips32::debug::journal::UndoEntry;
+use crate::mips32::disassembler::disassemble;
+use crate::mips32::registers::REGISTER_NAMES;
+use crate::mips32::snapshot::{StateReader, StateWriter};
+use crate::mips32::symbols::SymbolTable;
+use crate::mips32::syscall::{SyscallHandler, SyscallOutcome};
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"MIMICTRC";
+pub const TRACE_VERSION: u32 = 1;
+
+// What a syscall did, recorded so the run can be replayed without the handler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyscallRecord {
+    pub service: u32,
+    // Byte address and new value of every word the handler wrote
+    pub memory: Vec<(u32, u32)>,
+    pub exit_code: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    // Number of instructions executed before this one
+    pub index: u64,
+    pub pc: u32,
+    pub word: u32,
+    // Registers written, with their new values
+    pub registers: Vec<(u32, u32)>,
+    pub hi: Option<u32>,
+    pub lo: Option<u32>,
+    pub access: Option<MemoryAccess>,
+    pub syscall: Option<SyscallRecord>,
+}
+
+impl TraceRecord {
+    pub(crate) fn new(core: &Core, word: u32, service: u32, undo: &UndoEntry, outcome: SyscallOutcome) -> Self {
+        let syscall = (word >> 26 == 0x00 && word & 0x3F == 0x0C).then(|| {
+            let mut words: Vec<u32> = undo.memory.iter().map(|(index, _)| *index).collect();
+            words.sort_unstable();
+            words.dedup();
+
+            SyscallRecord {
+                service,
+                memory: words
+                    .into_iter()
+                    .map(|index| (index << 2, core.memory.peek(index).unwrap_or(0)))
+                    .collect(),
+                exit_code: match outcome {
+                    SyscallOutcome::Exit(code) => Some(code),
+                    _ => None,
+                },
+            }
+        });
+
+        Self {
+            index: core.instruction_count() - 1,
+            pc: undo.pc << 2,
+            word,
+            registers: undo
+                .registers
+                .iter()
+                .map(|(index, _)| (*index, core.get_register(*index)))
+                .collect(),
+            hi: (core.hi() != undo.hi).then_some(core.hi()),
+            lo: (core.lo() != undo.lo).then_some(core.lo()),
+            access: core.last_access,
+            syscall,
+        }
+    }
+}
+
+impl fmt::Display for TraceRecord {
+    // One line per instruction so text traces diff well with standard tools
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>8} {:#010X} {:08X}  {:<28}",
+            self.index,
+            self.pc,
+            self.word,
+            disassemble(self.word, self.pc)
+        )?;
+
+        for (index, value) in &self.registers {
+            write!(f, " ${}={:#010X}", REGISTER_NAMES[*index as usize], value)?;
+        }
+        if let Some(hi) = self.hi {
+            write!(f, " hi={:#010X}", hi)?;
+        }
+        if let Some(lo) = self.lo {
+            write!(f, " lo={:#010X}", lo)?;
+        }
+        if let Some(access) = &self.access {
+            let kind = match access.kind {
+                AccessKind::Read => "load",
+                AccessKind::Write => "store",
+            };
+            write!(f, " {}{} [{:#010X}]={:#X}", kind, access.size, access.address, access.value)?;
+        }
+        if let Some(syscall) = &self.syscall {
+            write!(f, " syscall {}", syscall.service)?;
+            for (address, value) in &syscall.memory {
+                write!(f, " [{:#010X}]<-{:#010X}", address, value)?;
+            }
+            if let Some(code) = syscall.exit_code {
+                write!(f, " exit {}", code)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    records: Vec<TraceRecord>,
+}
+
+// The first point at which two traces differ. A record is None when that trace
+// ended before the other one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub position: usize,
+    pub expected: Option<TraceRecord>,
+    pub actual: Option<TraceRecord>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "traces diverge at record {}", self.position)?;
+        match &self.expected {
+            Some(record) => writeln!(f, "- {}", record)?,
+            None => writeln!(f, "- <end of trace>")?,
+        }
+        match &self.actual {
+            Some(record) => write!(f, "+ {}", record),
+            None => write!(f, "+ <end of trace>"),
+        }
+    }
+}
+
+impl Trace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn records(&self) -> &[TraceRecord] {
+        &self.records
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn to_text(&self) -> String {
+        self.records.iter().map(|record| format!("{}\n", record)).collect()
+    }
+
+    // Compares against a reference trace
+    pub fn diff(&self, reference: &Trace) -> Option<Divergence> {
+        let len = self.records.len().max(reference.records.len());
+
+        (0..len).find_map(|position| {
+            let expected = reference.records.get(position);
+            let actual = self.records.get(position);
+
+            (expected != actual).then(|| Divergence {
+                position,
+                expected: expected.cloned(),
+                actual: actual.cloned(),
+            })
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        for byte in MAGIC {
+            w.write_u8(*byte);
+        }
+        w.write_u32(TRACE_VERSION);
+        w.write_u64(self.records.len() as u64);
+
+        for record in &self.records {
+            w.write_u64(record.index);
+            w.write_u32(record.pc);
+            w.write_u32(record.word);
+
+            w.write_u8(record.registers.len() as u8);
+            for (index, value) in &record.registers {
+                w.write_u8(*index as u8);
+                w.write_u32(*value);
+            }
+
+            let flags = record.hi.is_some() as u8
+                | (record.lo.is_some() as u8) << 1
+                | (record.access.is_some() as u8) << 2
+                | (record.syscall.is_some() as u8) << 3;
+            w.write_u8(flags);
+
+            if let Some(hi) = record.hi {
+                w.write_u32(hi);
+            }
+            if let Some(lo) = record.lo {
+                w.write_u32(lo);
+            }
+            if let Some(access) = &record.access {
+                w.write_u32(access.address);
+                w.write_u8(access.size as u8);
+                w.write_bool(access.kind == AccessKind::Write);
+                w.write_u32(access.value);
+                w.write_u32(access.old_value);
+            }
+            if let Some(syscall) = &record.syscall {
+                w.write_u32(syscall.service);
+                w.write_u32(syscall.memory.len() as u32);
+                for (address, value) in &syscall.memory {
+                    w.write_u32(*address);
+                    w.write_u32(*value);
+                }
+                w.write_bool(syscall.exit_code.is_some());
+                w.write_u32(syscall.exit_code.unwrap_or(0));
+            }
+        }
+
+        w.into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MimicError> {
+        Self::read(&mut StateReader::new(bytes)).map_err(|e| match e.ty {
+            MimicErrorType::InvalidSnapshot { message } => invalid(&message),
+            _ => e,
+        })
+    }
+
+    fn read(r: &mut StateReader) -> Result<Self, MimicError> {
+        for byte in MAGIC {
+            if r.read_u8().ok() != Some(*byte) {
+                return Err(invalid("not a trace file"));
+            }
+        }
+
+        let version = r.read_u32()?;
+        if version != TRACE_VERSION {
+            return Err(invalid(&format!("unsupported version {}", version)));
+        }
+
+        let mut records = Vec::new();
+        for _ in 0..r.read_u64()? {
+            let index = r.read_u64()?;
+            let pc = r.read_u32()?;
+            let word = r.read_u32()?;
+
+            let mut registers = Vec::new();
+            for _ in 0..r.read_u8()? {
+                let register = r.read_u8()? as u32;
+                if register > 31 {
+                    return Err(invalid("register index out of range"));
+                }
+                registers.push((register, r.read_u32()?));
+            }
+
+            let flags = r.read_u8()?;
+            let hi = if flags & 0x1 != 0 { Some(r.read_u32()?) } else { None };
+            let lo = if flags & 0x2 != 0 { Some(r.read_u32()?) } else { None };
+            let access = if flags & 0x4 != 0 {
+                Some(MemoryAccess {
+                    address: r.read_u32()?,
+                    size: r.read_u8()? as u32,
+                    kind: if r.read_bool()? { AccessKind::Write } else { AccessKind::Read },
+                    value: r.read_u32()?,
+                    old_value: r.read_u32()?,
+                })
+            } else {
+                None
+            };
+            let syscall = if flags & 0x8 != 0 {
+                let service = r.read_u32()?;
+                let mut memory = Vec::new();
+                for _ in 0..r.read_u32()? {
+                    memory.push((r.read_u32()?, r.read_u32()?));
+                }
+                let exited = r.read_bool()?;
+                let code = r.read_u32()?;
+                Some(SyscallRecord {
+                    service,
+                    memory,
+                    exit_code: exited.then_some(code),
+                })
+            } else {
+                None
+            };
+
+            records.push(TraceRecord {
+                index,
+                pc,
+                word,
+                registers,
+                hi,
+                lo,
+                access,
+                syscall,
+            });
+        }
+
+        if !r.is_empty() {
+            return Err(invalid("trailing data"));
+        }
+
+        Ok(Self { records })
+    }
+
+    pub fn save<P>(&self, filename: P) -> Result<(), MimicError>
+    where
+        P: AsRef<Path>,
+    {
+        fs::write(&filename, self.to_bytes()).map_err(|_| MimicError {
+            span: None,
+            source: None,
+            ty: MimicErrorType::FileWriteFailed {
+                filename: filename.as_ref().to_path_buf(),
+            },
+        })
+    }
+
+    pub fn save_text<P>(&self, filename: P) -> Result<(), MimicError>
+    where
+        P: AsRef<Path>,
+    {
+        fs::write(&filename, self.to_text()).map_err(|_| MimicError {
+            span: None,
+            source: None,
+            ty: MimicErrorType::FileWriteFailed {
+                filename: filename.as_ref().to_path_buf(),
+            },
+        })
+    }
+
+    pub fn load<P>(filename: P) -> Result<Self, MimicError>
+    where
+        P: AsRef<Path>,
+    {
+        let bytes = fs::read(&filename).map_err(|_| MimicError {
+            span: None,
+            source: None,
+            ty: MimicErrorType::FileDoesNotExist {
+                filename: filename.as_ref().to_path_buf(),
+            },
+        })?;
+
+        Self::from_bytes(&bytes)
+    }
+}
+
+fn invalid(message: &str) -> MimicError {
+    MimicError {
+        span: None,
+        source: None,
+        ty: MimicErrorType::InvalidTrace {
+            message: message.to_owned(),
+        },
+    }
+}
+
+// Records executed instructions into a `Trace`. With a filter only the
+// instructions for which it holds, evaluated before they execute, are kept;
+// a filtered trace cannot be replayed.
+#[derive(Debug, Clone, Default)]
+pub struct Tracer {
+    trace: Trace,
+    filter: Option<Expression>,
+    symbols: SymbolTable,
+}
+
+impl Tracer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_filter(mut self, filter: Expression) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn with_symbols(mut self, symbols: SymbolTable) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    pub fn trace(&self) -> &Trace {
+        &self.trace
+    }
+
+    // A filter that fails to evaluate keeps the instruction
+    pub(crate) fn accepts(&self, core: &Core) -> bool {
+        self.filter.as_ref().is_none_or(|filter| {
+            filter
+                .is_true(&EvalContext::new(core).with_symbols(&self.symbols))
+                .unwrap_or(true)
+        })
+    }
+
+    pub(crate) fn push(&mut self, record: TraceRecord) {
+        self.trace.records.push(record);
+    }
+}
+
+impl Core {
+    pub fn start_trace(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.tracer.as_ref()
+    }
+
+    // Stops tracing and returns what was recorded
+    pub fn stop_trace(&mut self) -> Option<Trace> {
+        self.tracer.take().map(|tracer| tracer.trace)
+    }
+}
+
+// Syscall handler that answers every syscall with the effects recorded in a
+// trace, so a run can be reproduced without the original input
+#[derive(Debug, Clone)]
+pub struct TraceReplay {
+    syscalls: VecDeque<TraceRecord>,
+}
+
+impl TraceReplay {
+    pub fn new(trace: &Trace) -> Self {
+        Self {
+            syscalls: trace
+                .records
+                .iter()
+                .filter(|record| record.syscall.is_some())
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.syscalls.len()
+    }
+}
+
+impl SyscallHandler for TraceReplay {
+    fn syscall(&mut self, _inst: u32, core: &mut Core) -> Result<SyscallOutcome, MimicError> {
+        let diverged = |message: String| MimicError {
+            span: None,
+            source: None,
+            ty: MimicErrorType::TraceDiverged {
+                instruction: core.instruction_count(),
+                message,
+            },
+        };
+
+        let record = self
+            .syscalls
+            .pop_front()
+            .ok_or_else(|| diverged("no recorded syscalls left".to_owned()))?;
+        let syscall = record.syscall.unwrap();
+
+        if record.pc != core.pc() || syscall.service != core.get_register(2) {
+            return Err(diverged(format!(
+                "expected syscall {} at {:#010X}, found syscall {} at {:#010X}",
+                syscall.service,
+                record.pc,
+                core.get_register(2),
+                core.pc()
+            )));
+        }
+
+        for (index, value) in record.registers {
+            core.set_register(index, value);
+        }
+        for (address, value) in syscall.memory {
+            core.write_word(address, value);
+        }
+
+        Ok(match syscall.exit_code {
+            Some(code) => SyscallOutcome::Exit(code),
+            None => SyscallOutcome::Continue,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_and_corrupt_input() {
+        let mut core = Core::new_mips_default();
+        core.load_text(vec![
+            0x24080001, // addiu $t0, $zero, 1
+            0x25080001, // addiu $t0, $t0, 1
+            0x25080001, // addiu $t0, $t0, 1
+        ]);
+        core.start_trace(Tracer::new().with_filter(Expression::parse("$t0 >= 1").unwrap()));
+
+        let mut no_syscalls = |_: u32, regs: [u32; 32]| regs;
+        core.run(&mut no_syscalls);
+
+        let trace = core.stop_trace().unwrap();
+        assert_eq!(trace.records().iter().map(|r| r.index).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(trace.records()[1].registers, vec![(8, 3)]);
+
+        let bytes = trace.to_bytes();
+        assert_eq!(Trace::from_bytes(&bytes).unwrap(), trace);
+        assert!(matches!(
+            Trace::from_bytes(&bytes[..bytes.len() - 2]).unwrap_err().ty,
+            MimicErrorType::InvalidTrace { .. }
+        ));
+    }
+}
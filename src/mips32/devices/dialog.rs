@@ -0,0 +1,220 @@
+use codespan_reporting::files::SimpleFile;
+
+use crate::errors::{MimicError, MimicErrorType, Span};
+use crate::mips32::snapshot::{self, StateReader, StateWriter};
+
+use std::collections::VecDeque;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DialogResponse {
+    Yes,
+    No,
+    Cancel,
+    Text(String),
+}
+
+// What to answer once the scripted responses run out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExhaustedPolicy {
+    Cancel,
+    // Yes for confirmations and an empty answer for inputs
+    Default,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DialogRecord {
+    pub service: u32,
+    pub prompt: String,
+    pub response: Option<DialogResponse>,
+}
+
+// Answers the MARS dialog syscalls without a window. Responses are consumed in
+// order and every dialog shown is logged together with the answer it got.
+//
+// In text form every non-empty line is one response: `yes`, `no`, `cancel`, or
+// the text typed into an input dialog, optionally in double quotes.
+#[derive(Debug, Clone)]
+pub struct Dialogs {
+    responses: VecDeque<DialogResponse>,
+    policy: ExhaustedPolicy,
+    log: Vec<DialogRecord>,
+}
+
+impl Dialogs {
+    pub fn new() -> Self {
+        Self {
+            responses: VecDeque::new(),
+            policy: ExhaustedPolicy::Cancel,
+            log: Vec::new(),
+        }
+    }
+
+    pub fn with_response(mut self, response: DialogResponse) -> Self {
+        self.responses.push_back(response);
+        self
+    }
+
+    pub fn with_policy(mut self, policy: ExhaustedPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn from_file<P>(filename: P) -> Result<Self, MimicError>
+    where
+        P: AsRef<Path>,
+    {
+        let contents = std::fs::read_to_string(&filename).map_err(|_| MimicError {
+            span: None,
+            source: None,
+            ty: MimicErrorType::FileDoesNotExist {
+                filename: filename.as_ref().to_path_buf(),
+            },
+        })?;
+
+        Self::parse(&contents)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, MimicError> {
+        let mut dialogs = Self::new();
+        let mut lo = 0;
+
+        for line in text.split('\n') {
+            let span = Span { lo, hi: lo + line.len() };
+            lo += line.len() + 1;
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let response = match line {
+                "yes" => DialogResponse::Yes,
+                "no" => DialogResponse::No,
+                "cancel" => DialogResponse::Cancel,
+                _ if line.starts_with('"') => match line[1..].strip_suffix('"') {
+                    Some(s) => DialogResponse::Text(s.to_owned()),
+                    None => {
+                        return Err(MimicError {
+                            span: Some(span),
+                            source: Some(Box::new(SimpleFile::new("dialog script".to_owned(), text.to_owned()))),
+                            ty: MimicErrorType::InvalidScript {
+                                message: "unterminated string".to_owned(),
+                            },
+                        })
+                    }
+                },
+                _ => DialogResponse::Text(line.to_owned()),
+            };
+            dialogs.responses.push_back(response);
+        }
+
+        Ok(dialogs)
+    }
+
+    pub fn log(&self) -> &[DialogRecord] {
+        &self.log
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.responses.len()
+    }
+
+    // Only the responses still to be given are saved, not the log
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(self.responses.len() as u32);
+        for response in &self.responses {
+            match response {
+                DialogResponse::Yes => state.write_u8(0),
+                DialogResponse::No => state.write_u8(1),
+                DialogResponse::Cancel => state.write_u8(2),
+                DialogResponse::Text(text) => {
+                    state.write_u8(3);
+                    state.write_str(text);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn restore_state(&mut self, state: &mut StateReader) -> Result<(), MimicError> {
+        let mut responses = VecDeque::new();
+        for _ in 0..state.read_u32()? {
+            responses.push_back(match state.read_u8()? {
+                0 => DialogResponse::Yes,
+                1 => DialogResponse::No,
+                2 => DialogResponse::Cancel,
+                3 => DialogResponse::Text(state.read_string()?),
+                _ => return Err(snapshot::invalid("unknown dialog response")),
+            });
+        }
+
+        self.responses = responses;
+        Ok(())
+    }
+
+    // Logs a dialog that only displays information
+    pub fn show(&mut self, service: u32, prompt: String) {
+        self.log.push(DialogRecord { service, prompt, response: None });
+    }
+
+    // Logs a dialog that needs an answer and takes the next scripted response
+    pub fn ask(&mut self, service: u32, prompt: String) -> Result<DialogResponse, MimicError> {
+        let response = match self.responses.pop_front() {
+            Some(response) => response,
+            None => match self.policy {
+                ExhaustedPolicy::Cancel => DialogResponse::Cancel,
+                ExhaustedPolicy::Default if service == 50 => DialogResponse::Yes,
+                ExhaustedPolicy::Default => DialogResponse::Text(String::new()),
+                ExhaustedPolicy::Error => {
+                    return Err(MimicError {
+                        span: None,
+                        source: None,
+                        ty: MimicErrorType::DialogScriptExhausted { prompt },
+                    })
+                }
+            },
+        };
+
+        self.log.push(DialogRecord {
+            service,
+            prompt,
+            response: Some(response.clone()),
+        });
+
+        Ok(response)
+    }
+}
+
+impl Default for Dialogs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_responses() {
+        let mut dialogs = Dialogs::parse("yes\n# comment\n\n42\n\"  padded \"\ncancel\n").unwrap();
+
+        assert_eq!(dialogs.remaining(), 4);
+        assert_eq!(dialogs.ask(50, "a".to_owned()).unwrap(), DialogResponse::Yes);
+        assert_eq!(dialogs.ask(51, "b".to_owned()).unwrap(), DialogResponse::Text("42".to_owned()));
+        assert_eq!(dialogs.ask(54, "c".to_owned()).unwrap(), DialogResponse::Text("  padded ".to_owned()));
+        assert_eq!(dialogs.ask(51, "d".to_owned()).unwrap(), DialogResponse::Cancel);
+    }
+
+    #[test]
+    fn exhausted_policies() {
+        let mut dialogs = Dialogs::new().with_policy(ExhaustedPolicy::Default);
+        assert_eq!(dialogs.ask(50, "ok?".to_owned()).unwrap(), DialogResponse::Yes);
+        assert_eq!(dialogs.ask(51, "n?".to_owned()).unwrap(), DialogResponse::Text(String::new()));
+
+        let mut dialogs = Dialogs::new().with_policy(ExhaustedPolicy::Error);
+        assert!(dialogs.ask(50, "ok?".to_owned()).is_err());
+        assert!(dialogs.log().is_empty());
+    }
+}
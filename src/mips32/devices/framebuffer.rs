@@ -0,0 +1,340 @@
+use crate::errors::{MimicError, MimicErrorType};
+use crate::mips32::snapshot::{self, StateReader, StateWriter};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Ppm,
+    Png,
+}
+
+impl ImageFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Ppm => "ppm",
+            ImageFormat::Png => "png",
+        }
+    }
+}
+
+// A single image with pixels stored row-major as 0xRRGGBBAA
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u32>,
+}
+
+impl Frame {
+    pub fn new(width: u32, height: u32, color: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![color; (width * height) as usize],
+        }
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> Option<u32> {
+        if x < self.width && y < self.height {
+            Some(self.pixels[(y * self.width + x) as usize])
+        } else {
+            None
+        }
+    }
+
+    // Binary PPM (P6); the alpha channel is dropped
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut bytes = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+
+        for pixel in &self.pixels {
+            bytes.push((pixel >> 24) as u8);
+            bytes.push((pixel >> 16) as u8);
+            bytes.push((pixel >> 8) as u8);
+        }
+
+        bytes
+    }
+
+    // 8-bit RGBA PNG using uncompressed deflate blocks
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut raw: Vec<u8> = Vec::with_capacity(((self.width * 4 + 1) * self.height) as usize);
+        for row in self.pixels.chunks(self.width.max(1) as usize) {
+            raw.push(0x00); // Filter type None
+            for pixel in row {
+                raw.extend_from_slice(&pixel.to_be_bytes());
+            }
+        }
+
+        let mut ihdr: Vec<u8> = Vec::new();
+        ihdr.extend_from_slice(&self.width.to_be_bytes());
+        ihdr.extend_from_slice(&self.height.to_be_bytes());
+        ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+        let mut bytes: Vec<u8> = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+        push_png_chunk(&mut bytes, b"IHDR", &ihdr);
+        push_png_chunk(&mut bytes, b"IDAT", &zlib_stored(&raw));
+        push_png_chunk(&mut bytes, b"IEND", &[]);
+
+        bytes
+    }
+
+    pub fn encode(&self, format: ImageFormat) -> Vec<u8> {
+        match format {
+            ImageFormat::Ppm => self.to_ppm(),
+            ImageFormat::Png => self.to_png(),
+        }
+    }
+
+    pub fn save<P>(&self, filename: P, format: ImageFormat) -> Result<(), MimicError>
+    where
+        P: AsRef<Path>,
+    {
+        fs::write(&filename, self.encode(format)).map_err(|_| MimicError {
+            span: None,
+            source: None,
+            ty: MimicErrorType::FileWriteFailed {
+                filename: filename.as_ref().to_path_buf(),
+            },
+        })
+    }
+}
+
+// Headless double-buffered RGBA display. Drawing happens on the back buffer and
+// `present` makes it the visible frame, optionally dumping it to disk.
+#[derive(Debug)]
+pub struct Framebuffer {
+    back: Frame,
+    presented: Option<Frame>,
+    title: String,
+    frames_presented: u64,
+    syncs: u64,
+    dump: Option<(PathBuf, ImageFormat)>,
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            back: Frame::new(width, height, 0x000000FF),
+            presented: None,
+            title: String::new(),
+            frames_presented: 0,
+            syncs: 0,
+            dump: None,
+        }
+    }
+
+    // Writes every presented frame to `directory` as frame_NNNNNN.<ext>
+    pub fn with_frame_dump<P>(mut self, directory: P, format: ImageFormat) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        self.dump = Some((directory.as_ref().to_path_buf(), format));
+        self
+    }
+
+    pub fn width(&self) -> u32 {
+        self.back.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.back.height
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn set_title(&mut self, title: String) {
+        self.title = title;
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frames_presented
+    }
+
+    pub fn sync_count(&self) -> u64 {
+        self.syncs
+    }
+
+    pub fn back_buffer(&self) -> &Frame {
+        &self.back
+    }
+
+    pub fn presented_frame(&self) -> Option<&Frame> {
+        self.presented.as_ref()
+    }
+
+    pub fn fill(&mut self, color: u32) {
+        self.back.pixels.fill(color);
+    }
+
+    // Fills the rectangle from (x0, y0) inclusive to (x1, y1) exclusive, clipped to the display
+    pub fn fill_rect(&mut self, color: u32, x0: u32, y0: u32, x1: u32, y1: u32) {
+        let x1 = x1.min(self.back.width);
+        let y1 = y1.min(self.back.height);
+
+        for y in y0.min(y1)..y1 {
+            let row = (y * self.back.width) as usize;
+            self.back.pixels[row + x0.min(x1) as usize..row + x1 as usize].fill(color);
+        }
+    }
+
+    pub fn present(&mut self) -> Result<(), MimicError> {
+        if let Some((directory, format)) = &self.dump {
+            let filename = directory.join(format!(
+                "frame_{:06}.{}",
+                self.frames_presented,
+                format.extension()
+            ));
+            self.back.save(filename, *format)?;
+        }
+
+        self.presented = Some(self.back.clone());
+        self.frames_presented += 1;
+
+        Ok(())
+    }
+
+    // The dump directory is configuration rather than state and is not saved
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(self.back.width);
+        state.write_u32(self.back.height);
+        state.write_words(&self.back.pixels);
+        state.write_bool(self.presented.is_some());
+        if let Some(frame) = &self.presented {
+            state.write_words(&frame.pixels);
+        }
+        state.write_str(&self.title);
+        state.write_u64(self.frames_presented);
+        state.write_u64(self.syncs);
+    }
+
+    pub(crate) fn restore_state(&mut self, state: &mut StateReader) -> Result<(), MimicError> {
+        let (width, height) = (state.read_u32()?, state.read_u32()?);
+        if (width, height) != (self.back.width, self.back.height) {
+            return Err(snapshot::invalid("framebuffer size does not match the snapshot"));
+        }
+
+        let read_frame = |state: &mut StateReader| -> Result<Frame, MimicError> {
+            let pixels = state.read_words()?;
+            if pixels.len() != (width * height) as usize {
+                return Err(snapshot::invalid("framebuffer size does not match the snapshot"));
+            }
+            Ok(Frame { width, height, pixels })
+        };
+
+        self.back = read_frame(state)?;
+        self.presented = if state.read_bool()? { Some(read_frame(state)?) } else { None };
+        self.title = state.read_string()?;
+        self.frames_presented = state.read_u64()?;
+        self.syncs = state.read_u64()?;
+        Ok(())
+    }
+
+    // There is no display to wait on when headless, so syncing only counts
+    pub fn sync(&mut self) {
+        self.syncs += 1;
+    }
+}
+
+fn push_png_chunk(bytes: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+
+    let start = bytes.len();
+    bytes.extend_from_slice(kind);
+    bytes.extend_from_slice(data);
+    let crc = crc32(&bytes[start..]);
+
+    bytes.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = vec![0x78, 0x01];
+
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        bytes.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        bytes.push(last as u8);
+        bytes.extend_from_slice(&len.to_le_bytes());
+        bytes.extend_from_slice(&(!len).to_le_bytes());
+        bytes.extend_from_slice(block);
+    }
+
+    bytes.extend_from_slice(&adler32(data).to_be_bytes());
+    bytes
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFF_u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1_u32, 0_u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_rect_clips() {
+        let mut fb = Framebuffer::new(4, 3);
+        fb.fill(0x777777FF);
+        fb.fill_rect(0xFF0000FF, 2, 1, 10, 10);
+
+        let frame = fb.back_buffer();
+        assert_eq!(frame.get_pixel(1, 1), Some(0x777777FF));
+        assert_eq!(frame.get_pixel(2, 1), Some(0xFF0000FF));
+        assert_eq!(frame.get_pixel(3, 2), Some(0xFF0000FF));
+        assert_eq!(frame.get_pixel(3, 0), Some(0x777777FF));
+    }
+
+    #[test]
+    fn present_copies_back_buffer() {
+        let mut fb = Framebuffer::new(2, 2);
+        fb.fill(0x00FF00FF);
+        fb.present().unwrap();
+        fb.fill(0x0000FFFF);
+
+        assert_eq!(fb.frame_count(), 1);
+        assert_eq!(fb.presented_frame().unwrap().pixels, vec![0x00FF00FF; 4]);
+    }
+
+    #[test]
+    fn ppm_encoding() {
+        let frame = Frame::new(1, 1, 0x102030FF);
+        assert_eq!(frame.to_ppm(), b"P6\n1 1\n255\n\x10\x20\x30".to_vec());
+    }
+
+    #[test]
+    fn png_checksums() {
+        assert_eq!(crc32(b"IEND"), 0xAE426082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+
+        let png = Frame::new(2, 2, 0xFF0000FF).to_png();
+        assert_eq!(&png[..8], &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]);
+        assert_eq!(&png[png.len() - 8..], &[b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+    }
+}
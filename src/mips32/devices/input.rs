@@ -0,0 +1,460 @@
+use codespan_reporting::files::SimpleFile;
+
+use crate::errors::{MimicError, MimicErrorType, Span};
+use crate::mips32::devices::MmioDevice;
+use crate::mips32::snapshot::{self, StateReader, StateWriter};
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::rc::Rc;
+
+pub const KEY_UP: u8 = 0x80;
+pub const KEY_DOWN: u8 = 0x81;
+pub const KEY_LEFT: u8 = 0x82;
+pub const KEY_RIGHT: u8 = 0x83;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Instruction(u64),
+    Frame(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAction {
+    Press,
+    Release,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub trigger: Trigger,
+    pub action: KeyAction,
+    pub key: u8,
+}
+
+// A timeline of key events. In text form every non-empty line is
+//
+//   (instruction|frame) <count> (press|release|type) <key>
+//
+// where a key is a single character, a name such as `space` or `up`, a hex code
+// like `0x41`, or for `type` a quoted string that is pressed and released in order.
+#[derive(Debug, Clone, Default)]
+pub struct InputScript {
+    events: Vec<KeyEvent>,
+}
+
+impl InputScript {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn events(&self) -> &[KeyEvent] {
+        &self.events
+    }
+
+    pub fn press(mut self, trigger: Trigger, key: u8) -> Self {
+        self.events.push(KeyEvent { trigger, action: KeyAction::Press, key });
+        self
+    }
+
+    pub fn release(mut self, trigger: Trigger, key: u8) -> Self {
+        self.events.push(KeyEvent { trigger, action: KeyAction::Release, key });
+        self
+    }
+
+    pub fn type_text(mut self, trigger: Trigger, text: &str) -> Self {
+        for key in text.bytes() {
+            self = self.press(trigger, key).release(trigger, key);
+        }
+        self
+    }
+
+    pub fn from_file<P>(filename: P) -> Result<Self, MimicError>
+    where
+        P: AsRef<Path>,
+    {
+        let contents = std::fs::read_to_string(&filename).map_err(|_| MimicError {
+            span: None,
+            source: None,
+            ty: MimicErrorType::FileDoesNotExist {
+                filename: filename.as_ref().to_path_buf(),
+            },
+        })?;
+
+        Self::parse(&contents)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, MimicError> {
+        let mut script = Self::new();
+        let mut lo = 0;
+
+        for line in text.split('\n') {
+            let span = Span { lo, hi: lo + line.len() };
+            lo += line.len() + 1;
+
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let error = |message: &str| MimicError {
+                span: Some(span),
+                source: Some(Box::new(SimpleFile::new("input script".to_owned(), text.to_owned()))),
+                ty: MimicErrorType::InvalidScript { message: message.to_owned() },
+            };
+
+            let (kind, rest) = split_word(line);
+            let (count, rest) = split_word(rest);
+            let (action, key) = split_word(rest);
+            if key.is_empty() {
+                return Err(error("expected `<trigger> <count> <action> <key>`"));
+            }
+
+            let count: u64 = count.parse().map_err(|_| error("count must be an integer"))?;
+            let trigger = match kind {
+                "instruction" | "i" => Trigger::Instruction(count),
+                "frame" | "f" => Trigger::Frame(count),
+                _ => return Err(error("trigger must be `instruction` or `frame`")),
+            };
+
+            script = match action {
+                "press" => script.press(trigger, parse_key(key).ok_or_else(|| error("unknown key"))?),
+                "release" => script.release(trigger, parse_key(key).ok_or_else(|| error("unknown key"))?),
+                "type" => match key.strip_prefix('"').and_then(|k| k.strip_suffix('"')) {
+                    Some(s) => script.type_text(trigger, s),
+                    None => {
+                        let key = parse_key(key).ok_or_else(|| error("unknown key"))?;
+                        script.press(trigger, key).release(trigger, key)
+                    }
+                },
+                _ => return Err(error("action must be `press`, `release` or `type`")),
+            };
+        }
+
+        Ok(script)
+    }
+}
+
+fn split_word(s: &str) -> (&str, &str) {
+    let s = s.trim_start();
+    let end = s.find(char::is_whitespace).unwrap_or(s.len());
+    (&s[..end], s[end..].trim())
+}
+
+fn parse_key(key: &str) -> Option<u8> {
+    if key.len() == 1 {
+        return Some(key.as_bytes()[0]);
+    }
+
+    if let Some(hex) = key.strip_prefix("0x") {
+        return u8::from_str_radix(hex, 16).ok();
+    }
+
+    match key.to_lowercase().as_str() {
+        "space" => Some(b' '),
+        "enter" | "return" => Some(b'\n'),
+        "tab" => Some(b'\t'),
+        "backspace" => Some(0x08),
+        "escape" | "esc" => Some(0x1B),
+        "up" => Some(KEY_UP),
+        "down" => Some(KEY_DOWN),
+        "left" => Some(KEY_LEFT),
+        "right" => Some(KEY_RIGHT),
+        _ => None,
+    }
+}
+
+pub type SharedInput = Rc<RefCell<InputDevice>>;
+
+// Replays an `InputScript` against the emulated clock. Every press also queues
+// the key so programs that read characters see it exactly once.
+#[derive(Debug, Clone)]
+pub struct InputDevice {
+    by_instruction: VecDeque<KeyEvent>,
+    by_frame: VecDeque<KeyEvent>,
+    held: [bool; 256],
+    pending: VecDeque<u8>,
+    instructions: u64,
+    frames: u64,
+}
+
+impl InputDevice {
+    pub fn new(script: InputScript) -> Self {
+        let mut by_instruction: Vec<KeyEvent> = Vec::new();
+        let mut by_frame: Vec<KeyEvent> = Vec::new();
+        for event in script.events {
+            match event.trigger {
+                Trigger::Instruction(_) => by_instruction.push(event),
+                Trigger::Frame(_) => by_frame.push(event),
+            }
+        }
+
+        let count = |e: &KeyEvent| match e.trigger {
+            Trigger::Instruction(n) | Trigger::Frame(n) => n,
+        };
+        by_instruction.sort_by_key(count);
+        by_frame.sort_by_key(count);
+
+        let mut device = Self {
+            by_instruction: by_instruction.into(),
+            by_frame: by_frame.into(),
+            held: [false; 256],
+            pending: VecDeque::new(),
+            instructions: 0,
+            frames: 0,
+        };
+        device.apply_due();
+        device
+    }
+
+    pub fn shared(self) -> SharedInput {
+        Rc::new(RefCell::new(self))
+    }
+
+    pub fn advance_instructions(&mut self, instructions: u64) {
+        self.instructions = self.instructions.max(instructions);
+        self.apply_due();
+    }
+
+    pub fn advance_frames(&mut self, frames: u64) {
+        self.frames = self.frames.max(frames);
+        self.apply_due();
+    }
+
+    fn apply_due(&mut self) {
+        while let Some(event) = self.by_instruction.front().copied() {
+            match event.trigger {
+                Trigger::Instruction(n) if n <= self.instructions => self.apply(event),
+                _ => break,
+            }
+            self.by_instruction.pop_front();
+        }
+
+        while let Some(event) = self.by_frame.front().copied() {
+            match event.trigger {
+                Trigger::Frame(n) if n <= self.frames => self.apply(event),
+                _ => break,
+            }
+            self.by_frame.pop_front();
+        }
+    }
+
+    fn apply(&mut self, event: KeyEvent) {
+        match event.action {
+            KeyAction::Press => {
+                self.held[event.key as usize] = true;
+                self.pending.push_back(event.key);
+            }
+            KeyAction::Release => self.held[event.key as usize] = false,
+        }
+    }
+
+    pub fn is_down(&self, key: u8) -> bool {
+        self.held[key as usize]
+    }
+
+    pub fn has_key(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    pub fn peek_key(&self) -> Option<u8> {
+        self.pending.front().copied()
+    }
+
+    pub fn pop_key(&mut self) -> Option<u8> {
+        self.pending.pop_front()
+    }
+
+    // True once every scripted event has fired and every queued key has been read
+    pub fn is_exhausted(&self) -> bool {
+        self.by_instruction.is_empty() && self.by_frame.is_empty() && self.pending.is_empty()
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32((self.by_instruction.len() + self.by_frame.len()) as u32);
+        for event in self.by_instruction.iter().chain(&self.by_frame) {
+            match event.trigger {
+                Trigger::Instruction(n) => {
+                    state.write_u8(0);
+                    state.write_u64(n);
+                }
+                Trigger::Frame(n) => {
+                    state.write_u8(1);
+                    state.write_u64(n);
+                }
+            }
+            state.write_bool(event.action == KeyAction::Press);
+            state.write_u8(event.key);
+        }
+
+        let held: Vec<u8> = (0..=255).filter(|key| self.held[*key as usize]).collect();
+        state.write_bytes(&held);
+        state.write_bytes(&self.pending.iter().copied().collect::<Vec<u8>>());
+        state.write_u64(self.instructions);
+        state.write_u64(self.frames);
+    }
+
+    pub(crate) fn restore_state(&mut self, state: &mut StateReader) -> Result<(), MimicError> {
+        let mut by_instruction = VecDeque::new();
+        let mut by_frame = VecDeque::new();
+        for _ in 0..state.read_u32()? {
+            let kind = state.read_u8()?;
+            let count = state.read_u64()?;
+            let action = if state.read_bool()? { KeyAction::Press } else { KeyAction::Release };
+            let key = state.read_u8()?;
+
+            match kind {
+                0 => by_instruction.push_back(KeyEvent { trigger: Trigger::Instruction(count), action, key }),
+                1 => by_frame.push_back(KeyEvent { trigger: Trigger::Frame(count), action, key }),
+                _ => return Err(snapshot::invalid("unknown input trigger")),
+            }
+        }
+
+        let mut held = [false; 256];
+        for key in state.read_bytes()? {
+            held[*key as usize] = true;
+        }
+
+        self.by_instruction = by_instruction;
+        self.by_frame = by_frame;
+        self.held = held;
+        self.pending = state.read_bytes()?.iter().copied().collect();
+        self.instructions = state.read_u64()?;
+        self.frames = state.read_u64()?;
+        Ok(())
+    }
+}
+
+// Receiver half of a memory mapped keyboard, laid out like the MARS Keyboard and
+// Display MMIO Simulator. Offset 0x0 is the control register with the ready bit in
+// bit 0 and interrupt enable in bit 1. Offset 0x4 latches the key code and reading
+// it consumes the pending key.
+#[derive(Debug)]
+pub struct KeyboardMmio {
+    input: SharedInput,
+    data: u32,
+    interrupt_enable: bool,
+}
+
+impl KeyboardMmio {
+    pub fn new(input: SharedInput) -> Self {
+        Self {
+            input,
+            data: 0,
+            interrupt_enable: false,
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.input.borrow().has_key()
+    }
+
+    pub fn interrupt_pending(&self) -> bool {
+        self.interrupt_enable && self.is_ready()
+    }
+
+    // Registers other than the data register, which reads don't change
+    fn read_status(&self, offset: u32) -> u32 {
+        match offset {
+            0x0 => self.is_ready() as u32 | (self.interrupt_enable as u32) << 1,
+            _ => 0,
+        }
+    }
+}
+
+impl MmioDevice for KeyboardMmio {
+    fn read(&mut self, offset: u32) -> u32 {
+        match offset {
+            0x4 => {
+                if let Some(key) = self.input.borrow_mut().pop_key() {
+                    self.data = key as u32;
+                }
+                self.data
+            }
+            _ => self.read_status(offset),
+        }
+    }
+
+    fn peek(&self, offset: u32) -> u32 {
+        match offset {
+            0x4 => self.input.borrow().peek_key().map_or(self.data, |key| key as u32),
+            _ => self.read_status(offset),
+        }
+    }
+
+    fn write(&mut self, offset: u32, value: u32) {
+        if offset == 0x0 {
+            self.interrupt_enable = value & 0x2 != 0;
+        }
+    }
+
+    fn tick(&mut self, instructions: u64) {
+        self.input.borrow_mut().advance_instructions(instructions);
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(self.data);
+        state.write_bool(self.interrupt_enable);
+        self.input.borrow().save_state(state);
+    }
+
+    fn restore_state(&mut self, state: &mut StateReader) -> Result<(), MimicError> {
+        self.data = state.read_u32()?;
+        self.interrupt_enable = state.read_bool()?;
+        self.input.borrow_mut().restore_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_script() {
+        let script = InputScript::parse(
+            "# comment\n\
+             instruction 10 press a\n\
+             f 2 release 0x61\n\
+             frame 1 type \"hi there\"\n",
+        )
+        .unwrap();
+
+        let events = script.events();
+        assert_eq!(events.len(), 2 + 16);
+        assert_eq!(
+            events[0],
+            KeyEvent { trigger: Trigger::Instruction(10), action: KeyAction::Press, key: b'a' }
+        );
+        assert_eq!(events[1].action, KeyAction::Release);
+        assert_eq!(events[2].trigger, Trigger::Frame(1));
+        assert_eq!(events[7].key, b' ');
+    }
+
+    #[test]
+    fn parse_error_has_span() {
+        let err = InputScript::parse("frame 1 press a\nframe x press a\n").unwrap_err();
+        assert_eq!(err.span.unwrap().range(), 16..31);
+    }
+
+    #[test]
+    fn events_fire_in_order() {
+        let script = InputScript::new()
+            .press(Trigger::Instruction(5), b'x')
+            .release(Trigger::Frame(2), b'x')
+            .press(Trigger::Frame(1), b'y');
+        let mut input = InputDevice::new(script);
+
+        input.advance_instructions(4);
+        assert!(!input.has_key());
+
+        input.advance_instructions(5);
+        assert!(input.is_down(b'x'));
+        assert_eq!(input.pop_key(), Some(b'x'));
+
+        input.advance_frames(2);
+        assert!(!input.is_down(b'x'));
+        assert_eq!(input.pop_key(), Some(b'y'));
+        assert!(input.is_exhausted());
+    }
+}
@@ -0,0 +1,266 @@
+use crate::errors::MimicError;
+use crate::mips32::core::Core;
+use crate::mips32::devices::framebuffer::Frame;
+use crate::mips32::devices::input::{KeyboardMmio, SharedInput};
+use crate::mips32::devices::MmioDevice;
+use crate::mips32::snapshot::{StateReader, StateWriter};
+
+pub const BITMAP_GLOBAL_DATA: u32 = 0x10000000;
+pub const BITMAP_GP: u32 = 0x10008000;
+pub const BITMAP_STATIC_DATA: u32 = 0x10010000;
+pub const BITMAP_HEAP: u32 = 0x10040000;
+
+pub const MMIO_BASE: u32 = 0xFFFF0000;
+pub const MMIO_LEN: u32 = 0x10;
+
+// MARS "Bitmap Display" tool. The display is plain memory: every unit of
+// unit_width x unit_height pixels is one word at base + 4 * (row * columns + column)
+// holding a 0x00RRGGBB color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitmapDisplay {
+    base: u32,
+    unit_width: u32,
+    unit_height: u32,
+    display_width: u32,
+    display_height: u32,
+}
+
+impl BitmapDisplay {
+    pub fn new(display_width: u32, display_height: u32) -> Self {
+        Self {
+            base: BITMAP_STATIC_DATA,
+            unit_width: 1,
+            unit_height: 1,
+            display_width,
+            display_height,
+        }
+    }
+
+    pub fn with_unit_size(mut self, unit_width: u32, unit_height: u32) -> Self {
+        self.unit_width = unit_width.max(1);
+        self.unit_height = unit_height.max(1);
+        self
+    }
+
+    pub fn with_base_address(mut self, base: u32) -> Self {
+        self.base = base;
+        self
+    }
+
+    pub fn base_address(&self) -> u32 {
+        self.base
+    }
+
+    pub fn columns(&self) -> u32 {
+        self.display_width / self.unit_width
+    }
+
+    pub fn rows(&self) -> u32 {
+        self.display_height / self.unit_height
+    }
+
+    pub fn unit_address(&self, column: u32, row: u32) -> u32 {
+        self.base + 4 * (row * self.columns() + column)
+    }
+
+    pub fn render(&self, core: &Core) -> Result<Frame, MimicError> {
+        let mut frame = Frame::new(self.display_width, self.display_height, 0x000000FF);
+
+        for row in 0..self.rows() {
+            for column in 0..self.columns() {
+                let color = (core.read_word(self.unit_address(column, row))? << 8) | 0xFF;
+
+                for y in row * self.unit_height..(row + 1) * self.unit_height {
+                    let start = (y * self.display_width + column * self.unit_width) as usize;
+                    frame.pixels[start..start + self.unit_width as usize].fill(color);
+                }
+            }
+        }
+
+        Ok(frame)
+    }
+}
+
+impl Default for BitmapDisplay {
+    fn default() -> Self {
+        Self::new(512, 256)
+    }
+}
+
+// MARS "Keyboard and Display MMIO Simulator", normally mapped at MMIO_BASE:
+//
+//   0x0  receiver control     bit 0 ready, bit 1 interrupt enable
+//   0x4  receiver data        last key typed, reading clears ready
+//   0x8  transmitter control  bit 0 ready, bit 1 interrupt enable
+//   0xC  transmitter data     writing the low byte displays it and clears ready
+//
+// The transmitter becomes ready again `delay` instructions after each write and
+// ignores writes while it is busy. Writing a form feed (12) clears the display.
+#[derive(Debug)]
+pub struct KeyboardDisplayMmio {
+    keyboard: KeyboardMmio,
+    display: Vec<u8>,
+    transmitter_data: u32,
+    transmitter_ready: bool,
+    transmitter_interrupt_enable: bool,
+    delay: u64,
+    busy_until: u64,
+    instructions: u64,
+}
+
+impl KeyboardDisplayMmio {
+    pub fn new(input: SharedInput) -> Self {
+        Self {
+            keyboard: KeyboardMmio::new(input),
+            display: Vec::new(),
+            transmitter_data: 0,
+            transmitter_ready: true,
+            transmitter_interrupt_enable: false,
+            delay: 5,
+            busy_until: 0,
+            instructions: 0,
+        }
+    }
+
+    pub fn with_delay(mut self, delay: u64) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn display_output(&self) -> String {
+        String::from_utf8_lossy(&self.display).into_owned()
+    }
+
+    pub fn receiver_interrupt_pending(&self) -> bool {
+        self.keyboard.interrupt_pending()
+    }
+
+    pub fn transmitter_interrupt_pending(&self) -> bool {
+        self.transmitter_interrupt_enable && self.transmitter_ready
+    }
+}
+
+impl MmioDevice for KeyboardDisplayMmio {
+    fn read(&mut self, offset: u32) -> u32 {
+        match offset {
+            0x0 | 0x4 => self.keyboard.read(offset),
+            _ => self.peek(offset),
+        }
+    }
+
+    fn peek(&self, offset: u32) -> u32 {
+        match offset {
+            0x0 | 0x4 => self.keyboard.peek(offset),
+            0x8 => self.transmitter_ready as u32 | (self.transmitter_interrupt_enable as u32) << 1,
+            0xC => self.transmitter_data,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, value: u32) {
+        match offset {
+            0x0 | 0x4 => self.keyboard.write(offset, value),
+            0x8 => self.transmitter_interrupt_enable = value & 0x2 != 0,
+            0xC => {
+                if !self.transmitter_ready {
+                    return;
+                }
+
+                self.transmitter_data = value & 0xFF;
+                if self.transmitter_data == 12 {
+                    self.display.clear();
+                } else {
+                    self.display.push(self.transmitter_data as u8);
+                }
+
+                self.transmitter_ready = self.delay == 0;
+                self.busy_until = self.instructions + self.delay;
+            }
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, instructions: u64) {
+        self.keyboard.tick(instructions);
+
+        self.instructions = instructions;
+        if instructions >= self.busy_until {
+            self.transmitter_ready = true;
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.keyboard.save_state(state);
+        state.write_bytes(&self.display);
+        state.write_u32(self.transmitter_data);
+        state.write_bool(self.transmitter_ready);
+        state.write_bool(self.transmitter_interrupt_enable);
+        state.write_u64(self.busy_until);
+        state.write_u64(self.instructions);
+    }
+
+    fn restore_state(&mut self, state: &mut StateReader) -> Result<(), MimicError> {
+        self.keyboard.restore_state(state)?;
+        self.display = state.read_bytes()?.to_vec();
+        self.transmitter_data = state.read_u32()?;
+        self.transmitter_ready = state.read_bool()?;
+        self.transmitter_interrupt_enable = state.read_bool()?;
+        self.busy_until = state.read_u64()?;
+        self.instructions = state.read_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mips32::devices::input::{InputDevice, InputScript, Trigger};
+
+    #[test]
+    fn bitmap_render_scales_units() {
+        let mut core = Core::new_mips_default();
+        let display = BitmapDisplay::new(8, 4)
+            .with_unit_size(4, 2)
+            .with_base_address(BITMAP_GP);
+
+        core.write_word(display.unit_address(1, 1), 0x00FF8000);
+
+        let frame = display.render(&core).unwrap();
+        assert_eq!(display.columns(), 2);
+        assert_eq!(frame.get_pixel(3, 3), Some(0x000000FF));
+        assert_eq!(frame.get_pixel(4, 2), Some(0xFF8000FF));
+        assert_eq!(frame.get_pixel(7, 3), Some(0xFF8000FF));
+    }
+
+    #[test]
+    fn transmitter_ready_after_delay() {
+        let mut mmio = KeyboardDisplayMmio::new(InputDevice::new(InputScript::new()).shared())
+            .with_delay(3);
+
+        mmio.write(0xC, b'h' as u32);
+        mmio.write(0xC, b'x' as u32);
+        assert_eq!(mmio.read(0x8) & 1, 0);
+
+        mmio.tick(2);
+        assert_eq!(mmio.read(0x8) & 1, 0);
+        mmio.tick(3);
+        assert_eq!(mmio.read(0x8) & 1, 1);
+
+        mmio.write(0xC, b'i' as u32);
+        assert_eq!(mmio.display_output(), "hi");
+    }
+
+    #[test]
+    fn receiver_latches_key_and_interrupt_enable() {
+        let input = InputDevice::new(InputScript::new().type_text(Trigger::Instruction(0), "k")).shared();
+        let mut mmio = KeyboardDisplayMmio::new(input);
+
+        mmio.write(0x0, 0x2);
+        assert_eq!(mmio.read(0x0), 0x3);
+        assert!(mmio.receiver_interrupt_pending());
+
+        assert_eq!(mmio.read(0x4), b'k' as u32);
+        assert_eq!(mmio.read(0x0), 0x2);
+        assert_eq!(mmio.read(0x4), b'k' as u32);
+    }
+}
@@ -0,0 +1,194 @@
+use crate::errors::{MimicError, MimicErrorType};
+use crate::mips32::snapshot::{StateReader, StateWriter};
+
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoteEvent {
+    pub time_ms: u64,
+    pub pitch: u8,
+    pub duration_ms: u32,
+    pub instrument: u8,
+    pub volume: u8,
+}
+
+impl NoteEvent {
+    // Applies the same defaults as MARS for out of range syscall arguments
+    pub fn from_syscall(time_ms: u64, pitch: u32, duration: u32, instrument: u32, volume: u32) -> Self {
+        Self {
+            time_ms,
+            pitch: if pitch > 127 { 60 } else { pitch as u8 },
+            duration_ms: if (duration as i32) < 0 { 1000 } else { duration },
+            instrument: if instrument > 127 { 0 } else { instrument as u8 },
+            volume: if volume > 127 { 100 } else { volume as u8 },
+        }
+    }
+}
+
+// Collects notes played through the MIDI syscalls so they can be inspected or
+// written out as a Standard MIDI File.
+#[derive(Debug, Clone, Default)]
+pub struct MidiRecorder {
+    events: Vec<NoteEvent>,
+}
+
+impl MidiRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, event: NoteEvent) {
+        self.events.push(event);
+    }
+
+    pub fn events(&self) -> &[NoteEvent] {
+        &self.events
+    }
+
+    // Format 0 file at 1000 ticks per quarter note and 1,000,000 us per quarter
+    // note, so one tick is one millisecond. Every instrument gets its own channel
+    // (skipping the percussion channel) so overlapping notes keep their program.
+    pub fn to_smf(&self) -> Vec<u8> {
+        let mut instruments: Vec<u8> = Vec::new();
+        let mut messages: Vec<(u64, u8, [u8; 3])> = Vec::new();
+
+        for event in &self.events {
+            let index = match instruments.iter().position(|i| *i == event.instrument) {
+                Some(index) => index,
+                None => {
+                    instruments.push(event.instrument);
+                    instruments.len() - 1
+                }
+            };
+            let channel = [0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 11, 12, 13, 14, 15][index % 15];
+
+            messages.push((event.time_ms, 1, [0xC0 | channel, event.instrument, 0]));
+            messages.push((event.time_ms, 2, [0x90 | channel, event.pitch, event.volume]));
+            messages.push((
+                event.time_ms + event.duration_ms as u64,
+                0,
+                [0x80 | channel, event.pitch, 0],
+            ));
+        }
+
+        // Note offs first and program changes before the note ons they apply to
+        messages.sort_by_key(|(time, order, _)| (*time, *order));
+
+        let mut track: Vec<u8> = Vec::new();
+        track.extend_from_slice(&[0x00, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40]);
+
+        let mut last_time = 0;
+        for (time, _, message) in messages {
+            push_vlq(&mut track, time - last_time);
+            last_time = time;
+
+            if message[0] & 0xF0 == 0xC0 {
+                track.extend_from_slice(&message[..2]);
+            } else {
+                track.extend_from_slice(&message);
+            }
+        }
+        track.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend_from_slice(b"MThd");
+        bytes.extend_from_slice(&6_u32.to_be_bytes());
+        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x01, 0x03, 0xE8]);
+        bytes.extend_from_slice(b"MTrk");
+        bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&track);
+
+        bytes
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(self.events.len() as u32);
+        for event in &self.events {
+            state.write_u64(event.time_ms);
+            state.write_u8(event.pitch);
+            state.write_u32(event.duration_ms);
+            state.write_u8(event.instrument);
+            state.write_u8(event.volume);
+        }
+    }
+
+    pub(crate) fn restore_state(&mut self, state: &mut StateReader) -> Result<(), MimicError> {
+        let mut events = Vec::new();
+        for _ in 0..state.read_u32()? {
+            events.push(NoteEvent {
+                time_ms: state.read_u64()?,
+                pitch: state.read_u8()?,
+                duration_ms: state.read_u32()?,
+                instrument: state.read_u8()?,
+                volume: state.read_u8()?,
+            });
+        }
+
+        self.events = events;
+        Ok(())
+    }
+
+    pub fn save<P>(&self, filename: P) -> Result<(), MimicError>
+    where
+        P: AsRef<Path>,
+    {
+        fs::write(&filename, self.to_smf()).map_err(|_| MimicError {
+            span: None,
+            source: None,
+            ty: MimicErrorType::FileWriteFailed {
+                filename: filename.as_ref().to_path_buf(),
+            },
+        })
+    }
+}
+
+fn push_vlq(bytes: &mut Vec<u8>, mut value: u64) {
+    let mut groups: Vec<u8> = vec![(value & 0x7F) as u8];
+    value >>= 7;
+    while value > 0 {
+        groups.push(0x80 | (value & 0x7F) as u8);
+        value >>= 7;
+    }
+    bytes.extend(groups.iter().rev());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vlq() {
+        let mut bytes = Vec::new();
+        push_vlq(&mut bytes, 0);
+        push_vlq(&mut bytes, 0x7F);
+        push_vlq(&mut bytes, 0x80);
+        push_vlq(&mut bytes, 0x0FFFFFFF);
+        assert_eq!(bytes, vec![0x00, 0x7F, 0x81, 0x00, 0xFF, 0xFF, 0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn syscall_defaults() {
+        let event = NoteEvent::from_syscall(0, 200, 0xFFFFFFFF, 300, 128);
+        assert_eq!((event.pitch, event.duration_ms, event.instrument, event.volume), (60, 1000, 0, 100));
+    }
+
+    #[test]
+    fn smf_single_note() {
+        let mut midi = MidiRecorder::new();
+        midi.record(NoteEvent::from_syscall(0, 60, 500, 0, 100));
+
+        let smf = midi.to_smf();
+        assert_eq!(&smf[..14], b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x03\xE8");
+        assert_eq!(
+            &smf[22..],
+            &[
+                0x00, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40, // tempo
+                0x00, 0xC0, 0x00, // program change
+                0x00, 0x90, 60, 100, // note on
+                0x83, 0x74, 0x80, 60, 0, // note off after 500 ticks
+                0x00, 0xFF, 0x2F, 0x00,
+            ]
+        );
+    }
+}
@@ -0,0 +1,29 @@
+pub mod dialog;
+pub mod framebuffer;
+pub mod input;
+pub mod mars;
+pub mod midi;
+
+use crate::errors::MimicError;
+use crate::mips32::snapshot::{StateReader, StateWriter};
+
+// A device that answers loads and stores to a range of addresses. Offsets are in
+// bytes from the start of the mapping and always word aligned.
+pub trait MmioDevice {
+    fn read(&mut self, offset: u32) -> u32;
+    fn write(&mut self, offset: u32, value: u32);
+
+    // What `read` would return, without its side effects, for the debugger and other
+    // inspection that must not change the device
+    fn peek(&self, offset: u32) -> u32;
+
+    // Called once before every instruction with the number of instructions executed so far
+    fn tick(&mut self, _instructions: u64) {}
+
+    // Device state for machine snapshots. Stateless devices keep the defaults.
+    fn save_state(&self, _state: &mut StateWriter) {}
+
+    fn restore_state(&mut self, _state: &mut StateReader) -> Result<(), MimicError> {
+        Ok(())
+    }
+}
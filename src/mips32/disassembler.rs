@@ -0,0 +1,108 @@
+use crate::mips32::registers::REGISTER_NAMES;
+
+// Formats an instruction word in assembler syntax. `pc` is the byte address
+// of the instruction and is used to print branch and jump targets.
+pub fn disassemble(inst: u32, pc: u32) -> String {
+    let opcode = inst >> 26;
+    let rs = reg(inst >> 21);
+    let rt = reg(inst >> 16);
+    let rd = reg(inst >> 11);
+    let shamt = (inst >> 6) & 0x1F;
+    let imm = inst & 0xFFFF;
+    let simm = imm as u16 as i16 as i32;
+    let branch_target = pc.wrapping_add(4).wrapping_add((simm << 2) as u32);
+    let jump_target = (pc.wrapping_add(4) & 0xF0000000) | ((inst & 0x03FFFFFF) << 2);
+
+    match opcode {
+        0x00 => match inst & 0x3F {
+            _ if inst == 0 => "nop".to_owned(),
+            0x00 => format!("sll {}, {}, {}", rd, rt, shamt),
+            0x02 => format!("srl {}, {}, {}", rd, rt, shamt),
+            0x03 => format!("sra {}, {}, {}", rd, rt, shamt),
+            0x04 => format!("sllv {}, {}, {}", rd, rt, rs),
+            0x06 => format!("srlv {}, {}, {}", rd, rt, rs),
+            0x07 => format!("srav {}, {}, {}", rd, rt, rs),
+            0x08 => format!("jr {}", rs),
+            0x09 if (inst >> 11) & 0x1F == 31 => format!("jalr {}", rs),
+            0x09 => format!("jalr {}, {}", rd, rs),
+            0x0C => "syscall".to_owned(),
+            0x0D => "break".to_owned(),
+            0x10 => format!("mfhi {}", rd),
+            0x11 => format!("mthi {}", rs),
+            0x12 => format!("mflo {}", rd),
+            0x13 => format!("mtlo {}", rs),
+            0x18 => format!("mult {}, {}", rs, rt),
+            0x19 => format!("multu {}, {}", rs, rt),
+            0x1A => format!("div {}, {}", rs, rt),
+            0x1B => format!("divu {}, {}", rs, rt),
+            0x20 => format!("add {}, {}, {}", rd, rs, rt),
+            0x21 => format!("addu {}, {}, {}", rd, rs, rt),
+            0x22 => format!("sub {}, {}, {}", rd, rs, rt),
+            0x23 => format!("subu {}, {}, {}", rd, rs, rt),
+            0x24 => format!("and {}, {}, {}", rd, rs, rt),
+            0x25 => format!("or {}, {}, {}", rd, rs, rt),
+            0x26 => format!("xor {}, {}, {}", rd, rs, rt),
+            0x27 => format!("nor {}, {}, {}", rd, rs, rt),
+            0x2A => format!("slt {}, {}, {}", rd, rs, rt),
+            0x2B => format!("sltu {}, {}, {}", rd, rs, rt),
+            _ => format!(".word {:#010X}", inst),
+        },
+        0x01 => match (inst >> 16) & 0x1F {
+            0x00 => format!("bltz {}, {:#010X}", rs, branch_target),
+            0x01 => format!("bgez {}, {:#010X}", rs, branch_target),
+            0x10 => format!("bltzal {}, {:#010X}", rs, branch_target),
+            0x11 => format!("bgezal {}, {:#010X}", rs, branch_target),
+            _ => format!(".word {:#010X}", inst),
+        },
+        0x02 => format!("j {:#010X}", jump_target),
+        0x03 => format!("jal {:#010X}", jump_target),
+        0x04 => format!("beq {}, {}, {:#010X}", rs, rt, branch_target),
+        0x05 => format!("bne {}, {}, {:#010X}", rs, rt, branch_target),
+        0x06 => format!("blez {}, {:#010X}", rs, branch_target),
+        0x07 => format!("bgtz {}, {:#010X}", rs, branch_target),
+        0x08 => format!("addi {}, {}, {}", rt, rs, simm),
+        0x09 => format!("addiu {}, {}, {}", rt, rs, simm),
+        0x0A => format!("slti {}, {}, {}", rt, rs, simm),
+        0x0B => format!("sltiu {}, {}, {}", rt, rs, simm),
+        0x0C => format!("andi {}, {}, {:#X}", rt, rs, imm),
+        0x0D => format!("ori {}, {}, {:#X}", rt, rs, imm),
+        0x0E => format!("xori {}, {}, {:#X}", rt, rs, imm),
+        0x0F => format!("lui {}, {:#X}", rt, imm),
+        0x20 => format!("lb {}, {}({})", rt, simm, rs),
+        0x21 => format!("lh {}, {}({})", rt, simm, rs),
+        0x1C if inst & 0x3F == 0x02 => format!("mul {}, {}, {}", rd, rs, rt),
+        0x22 => format!("lwl {}, {}({})", rt, simm, rs),
+        0x23 => format!("lw {}, {}({})", rt, simm, rs),
+        0x24 => format!("lbu {}, {}({})", rt, simm, rs),
+        0x25 => format!("lhu {}, {}({})", rt, simm, rs),
+        0x26 => format!("lwr {}, {}({})", rt, simm, rs),
+        0x28 => format!("sb {}, {}({})", rt, simm, rs),
+        0x29 => format!("sh {}, {}({})", rt, simm, rs),
+        0x2A => format!("swl {}, {}({})", rt, simm, rs),
+        0x2B => format!("sw {}, {}({})", rt, simm, rs),
+        0x2E => format!("swr {}, {}({})", rt, simm, rs),
+        _ => format!(".word {:#010X}", inst),
+    }
+}
+
+fn reg(field: u32) -> String {
+    format!("${}", REGISTER_NAMES[(field & 0x1F) as usize])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats() {
+        assert_eq!(disassemble(0x00000000, 0x00400000), "nop");
+        assert_eq!(disassemble(0x03A84820, 0x00400000), "add $t1, $sp, $t0");
+        assert_eq!(disassemble(0x2508FFFF, 0x00400000), "addiu $t0, $t0, -1");
+        assert_eq!(disassemble(0x8FA9FFFC, 0x00400000), "lw $t1, -4($sp)");
+        assert_eq!(disassemble(0x1000FFFE, 0x00400004), "beq $zero, $zero, 0x00400000");
+        assert_eq!(disassemble(0x0C100003, 0x00400000), "jal 0x0040000C");
+        assert_eq!(disassemble(0x03E00008, 0x00400000), "jr $ra");
+        assert_eq!(disassemble(0x0000000C, 0x00400000), "syscall");
+        assert_eq!(disassemble(0xFC000000, 0x00400000), ".word 0xFC000000");
+    }
+}
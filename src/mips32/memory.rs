@@ -1,10 +1,40 @@
 use crate::errors::{MimicError, MimicErrorType};
+use crate::mips32::devices::MmioDevice;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+pub(crate) const PAGE_WORDS: u32 = 1024;
+
+// Addresses outside of the text and static data segments that user programs may touch
+const SPARSE_RANGES: [(u32, u32); 3] = [
+    (0x04000000, 0x04003FFF), // 0x10000000 - 0x1000FFFF, $gp area
+    (0x04010000, 0x1FFFFFFF), // 0x10040000 - 0x7FFFFFFF, heap and stack
+    (0x20000000, 0x3FFFBFFF), // 0x80000000 - 0xFFFEFFFF, kernel text and data
+];
+
+struct MmioMapping {
+    start: u32,
+    end: u32,
+    device: Rc<RefCell<dyn MmioDevice>>,
+}
+
+impl fmt::Debug for MmioMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MmioMapping({:#010X}..={:#010X})", self.start << 2, self.end << 2)
+    }
+}
 
 #[derive(Debug)]
 pub struct Memory {
     pub(crate) text: Vec<u32>,
     pub(crate) data: Vec<u32>,
+    pub(crate) pages: HashMap<u32, Vec<u32>>,
+    devices: Vec<MmioMapping>,
     blocksize: usize,
+    pub(crate) text_len: u32,
     text_start: u32,
     text_end: u32,
     data_start: u32,
@@ -13,6 +43,9 @@ pub struct Memory {
     pub(crate) text_generation: u32,
     pub(crate) data_generation: u32,
     blocks_allocated: usize,
+
+    // Old values of the words overwritten while this is Some, for the undo journal
+    pub(crate) write_log: Option<Vec<(u32, u32)>>,
 }
 
 impl Memory {
@@ -20,15 +53,20 @@ impl Memory {
         let mut mem = Self {
             text: Vec::with_capacity(blocksize),
             data: Vec::with_capacity(blocksize),
+            pages: HashMap::new(),
+            devices: Vec::new(),
             blocksize,
+            text_len: 0,
             text_start: 0x00100000, // 0x00400000 / 4
-            text_end: 0x04003FFF,   // 0x10010000 / 4 - 1
+            text_end: 0x03FFFFFF,   // 0x10000000 / 4 - 1
             data_start: 0x04004000, // 0x10010000 / 4
-            data_end: 0x1FFFFFFF,   // 0x80000000 / 4 - 1
+            data_end: 0x0400FFFF,   // 0x10040000 / 4 - 1
 
             text_generation: 0,
             data_generation: 0,
             blocks_allocated: 2,
+
+            write_log: None,
         };
 
         for _ in 0..blocksize {
@@ -43,6 +81,12 @@ impl Memory {
         for i in 0..text.len() {
             self.set(self.text_start + i as u32, text[i]);
         }
+        self.text_len = self.text_len.max(text.len() as u32);
+    }
+
+    // True if `index` is in the text segment but after the last loaded instruction
+    pub fn past_text_end(&self, index: u32) -> bool {
+        self.text_start + self.text_len <= index && index <= self.text_end
     }
 
     pub fn load_data(&mut self, data: Vec<u32>) {
@@ -51,7 +95,67 @@ impl Memory {
         }
     }
 
+    // Maps `device` over the byte addresses start..start + len, which must be
+    // non-empty and end within the address space
+    pub fn map_device(&mut self, start: u32, len: u32, device: Rc<RefCell<dyn MmioDevice>>) -> Result<(), MimicError> {
+        let last = match len.checked_sub(1).and_then(|n| start.checked_add(n)) {
+            Some(last) => last,
+            None => return Err(MimicError {
+                span: None,
+                source: None,
+                ty: MimicErrorType::InvalidDeviceMapping { address: start, len },
+            }),
+        };
+
+        self.devices.push(MmioMapping {
+            start: start >> 2,
+            end: last >> 2,
+            device,
+        });
+        Ok(())
+    }
+
+    // Start byte address and device of every mapping, in the order they were mapped
+    pub(crate) fn devices(&self) -> impl Iterator<Item = (u32, &Rc<RefCell<dyn MmioDevice>>)> {
+        self.devices.iter().map(|m| (m.start << 2, &m.device))
+    }
+
+    pub fn tick_devices(&mut self, instructions: u64) {
+        for mapping in &self.devices {
+            mapping.device.borrow_mut().tick(instructions);
+        }
+    }
+
+    pub fn in_bounds(&self, index: u32) -> bool {
+        self.device_at(index).is_some()
+            || (self.text_start <= index && index <= self.text_end)
+            || (self.data_start <= index && index <= self.data_end)
+            || SPARSE_RANGES.iter().any(|(lo, hi)| *lo <= index && index <= *hi)
+    }
+
+    pub fn is_device(&self, index: u32) -> bool {
+        self.device_at(index).is_some()
+    }
+
+    fn device_at(&self, index: u32) -> Option<&MmioMapping> {
+        self.devices
+            .iter()
+            .find(|m| m.start <= index && index <= m.end)
+    }
+
     pub fn get(&self, index: u32) -> Result<u32, MimicError> {
+        if let Some(mapping) = self.device_at(index) {
+            return Ok(mapping.device.borrow_mut().read((index - mapping.start) << 2));
+        }
+        self.peek(index)
+    }
+
+    // Like `get`, but device registers are read without side effects
+    pub fn peek(&self, index: u32) -> Result<u32, MimicError> {
+        if let Some(mapping) = self.device_at(index) {
+            return Ok(mapping.device.borrow().peek((index - mapping.start) << 2));
+        }
+
         if self.text_start <= index && index <= self.text_end {
             let index = index - self.text_start;
 
@@ -76,6 +180,13 @@ impl Memory {
             }
         }
 
+        if self.in_bounds(index) {
+            return Ok(self
+                .pages
+                .get(&(index / PAGE_WORDS))
+                .map_or(0, |page| page[(index % PAGE_WORDS) as usize]));
+        }
+
         Err(MimicError {
             span: None,
             source: None,
@@ -84,6 +195,18 @@ impl Memory {
     }
 
     pub fn set(&mut self, index: u32, value: u32) {
+        if let Some(mapping) = self.device_at(index) {
+            mapping.device.borrow_mut().write((index - mapping.start) << 2, value);
+            return;
+        }
+
+        if self.write_log.is_some() && self.in_bounds(index) {
+            let old = self.peek(index).unwrap_or(0);
+            if let Some(log) = &mut self.write_log {
+                log.push((index, old));
+            }
+        }
+
         if self.text_start <= index && index <= self.text_end {
             let index = index - self.text_start;
 
@@ -97,6 +220,7 @@ impl Memory {
 
             self.text[index as usize] = value;
             self.text_generation += 1;
+            return;
         }
 
         if self.data_start <= index && index <= self.data_end {
@@ -112,6 +236,56 @@ impl Memory {
 
             self.data[index as usize] = value;
             self.data_generation += 1;
+            return;
+        }
+
+        if self.in_bounds(index) {
+            let page = self
+                .pages
+                .entry(index / PAGE_WORDS)
+                .or_insert_with(|| vec![0; PAGE_WORDS as usize]);
+            page[(index % PAGE_WORDS) as usize] = value;
+        }
+    }
+
+    pub fn get_byte(&self, address: u32) -> Result<u8, MimicError> {
+        let word = self.get(address >> 2)?;
+        Ok((word >> ((address & 0x3) * 8)) as u8)
+    }
+
+    pub fn set_byte(&mut self, address: u32, value: u8) {
+        let shift = (address & 0x3) * 8;
+        if self.device_at(address >> 2).is_some() {
+            // Partial writes to device registers must not trigger read side effects
+            self.set(address >> 2, (value as u32) << shift);
+            return;
+        }
+        let word = self.get(address >> 2).unwrap_or(0);
+        self.set(address >> 2, (word & !(0xFF << shift)) | ((value as u32) << shift));
+    }
+
+    pub fn get_half(&self, address: u32) -> Result<u16, MimicError> {
+        let word = self.get(address >> 2)?;
+        Ok((word >> ((address & 0x2) * 8)) as u16)
+    }
+
+    pub fn peek_byte(&self, address: u32) -> Result<u8, MimicError> {
+        let word = self.peek(address >> 2)?;
+        Ok((word >> ((address & 0x3) * 8)) as u8)
+    }
+
+    pub fn peek_half(&self, address: u32) -> Result<u16, MimicError> {
+        let word = self.peek(address >> 2)?;
+        Ok((word >> ((address & 0x2) * 8)) as u16)
+    }
+
+    pub fn set_half(&mut self, address: u32, value: u16) {
+        let shift = (address & 0x2) * 8;
+        if self.device_at(address >> 2).is_some() {
+            self.set(address >> 2, (value as u32) << shift);
+            return;
         }
+        let word = self.get(address >> 2).unwrap_or(0);
+        self.set(address >> 2, (word & !(0xFFFF << shift)) | ((value as u32) << shift));
     }
 }
@@ -2,6 +2,16 @@
 pub mod assembler;
 #[cfg(feature = "mips32_emulator")]
 pub mod core;
+#[cfg(feature = "mips32_emulator")]
+pub mod debug;
+#[cfg(feature = "mips32_emulator")]
+pub mod devices;
+pub mod disassembler;
+#[cfg(feature = "mips32_emulator")]
+pub mod snapshot;
+#[cfg(feature = "mips32_emulator")]
+pub mod syscall;
+pub mod symbols;
 
 mod memory;
 mod registers;
@@ -0,0 +1,546 @@
+use crate::errors::{MimicError, MimicErrorType};
+use crate::mips32::core::Core;
+use crate::mips32::memory::PAGE_WORDS;
+use crate::mips32::syscall::SyscallHandler;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"MIMICSNP";
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+// Little-endian encoder for snapshot state
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.bytes.push(value as u8);
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Length prefixed
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn write_str(&mut self, s: &str) {
+        self.write_bytes(s.as_bytes());
+    }
+
+    pub fn write_words(&mut self, words: &[u32]) {
+        self.write_u32(words.len() as u32);
+        for word in words {
+            self.write_u32(*word);
+        }
+    }
+}
+
+// Decoder for state written by `StateWriter`. Running out of input is an error
+// rather than a panic so damaged files are reported cleanly.
+#[derive(Debug)]
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], MimicError> {
+        if self.bytes.len() - self.position < len {
+            return Err(invalid("unexpected end of data"));
+        }
+
+        let bytes = &self.bytes[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, MimicError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, MimicError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, MimicError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, MimicError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], MimicError> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn read_string(&mut self) -> Result<String, MimicError> {
+        String::from_utf8(self.read_bytes()?.to_vec()).map_err(|_| invalid("string is not valid UTF-8"))
+    }
+
+    pub fn read_words(&mut self) -> Result<Vec<u32>, MimicError> {
+        let len = self.read_u32()? as usize;
+        if (self.bytes.len() - self.position) / 4 < len {
+            return Err(invalid("unexpected end of data"));
+        }
+
+        (0..len).map(|_| self.read_u32()).collect()
+    }
+}
+
+pub(crate) fn invalid(message: &str) -> MimicError {
+    MimicError {
+        span: None,
+        source: None,
+        ty: MimicErrorType::InvalidSnapshot {
+            message: message.to_owned(),
+        },
+    }
+}
+
+// The complete state of a machine: registers, memory, memory mapped devices and
+// optionally the syscall handler. Breakpoints, watchpoints and the undo journal
+// belong to the debugger and are not part of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub(crate) pc: u32,
+    pub(crate) hi: u32,
+    pub(crate) lo: u32,
+    pub(crate) registers: [u32; 32],
+    pub(crate) instructions: u64,
+    pub(crate) exit_code: Option<u32>,
+
+    pub(crate) text: Vec<u32>,
+    pub(crate) text_len: u32,
+    pub(crate) data: Vec<u32>,
+    pub(crate) pages: BTreeMap<u32, Vec<u32>>,
+
+    // Start address and saved state of every mapped device
+    pub(crate) devices: Vec<(u32, Vec<u8>)>,
+    pub(crate) syscalls: Option<Vec<u8>>,
+}
+
+impl Snapshot {
+    // Byte address of the next instruction
+    pub fn pc(&self) -> u32 {
+        self.pc << 2
+    }
+
+    pub fn registers(&self) -> [u32; 32] {
+        self.registers
+    }
+
+    pub fn instruction_count(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn has_syscall_state(&self) -> bool {
+        self.syscalls.is_some()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.bytes.extend_from_slice(MAGIC);
+        w.write_u32(SNAPSHOT_VERSION);
+
+        w.write_u32(self.pc);
+        w.write_u32(self.hi);
+        w.write_u32(self.lo);
+        w.write_words(&self.registers);
+        w.write_u64(self.instructions);
+        w.write_bool(self.exit_code.is_some());
+        w.write_u32(self.exit_code.unwrap_or(0));
+
+        w.write_u32(self.text_len);
+        w.write_words(&self.text);
+        w.write_words(&self.data);
+        w.write_u32(self.pages.len() as u32);
+        for (page, words) in &self.pages {
+            w.write_u32(*page);
+            w.write_words(words);
+        }
+
+        w.write_u32(self.devices.len() as u32);
+        for (start, state) in &self.devices {
+            w.write_u32(*start);
+            w.write_bytes(state);
+        }
+
+        w.write_bool(self.syscalls.is_some());
+        w.write_bytes(self.syscalls.as_deref().unwrap_or_default());
+
+        w.into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MimicError> {
+        let mut r = StateReader::new(bytes);
+        if r.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err(invalid("not a snapshot file"));
+        }
+
+        let version = r.read_u32()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(MimicError {
+                span: None,
+                source: None,
+                ty: MimicErrorType::UnsupportedSnapshotVersion { version },
+            });
+        }
+
+        let pc = r.read_u32()?;
+        let hi = r.read_u32()?;
+        let lo = r.read_u32()?;
+        let registers: [u32; 32] = r
+            .read_words()?
+            .try_into()
+            .map_err(|_| invalid("expected 32 registers"))?;
+        let instructions = r.read_u64()?;
+        let exited = r.read_bool()?;
+        let exit_code = r.read_u32()?;
+
+        let text_len = r.read_u32()?;
+        let text = r.read_words()?;
+        let data = r.read_words()?;
+        let mut pages = BTreeMap::new();
+        for _ in 0..r.read_u32()? {
+            let page = r.read_u32()?;
+            let words = r.read_words()?;
+            if words.len() != PAGE_WORDS as usize {
+                return Err(invalid("memory page has the wrong size"));
+            }
+            pages.insert(page, words);
+        }
+
+        let mut devices = Vec::new();
+        for _ in 0..r.read_u32()? {
+            let start = r.read_u32()?;
+            devices.push((start, r.read_bytes()?.to_vec()));
+        }
+
+        let has_syscalls = r.read_bool()?;
+        let syscalls = r.read_bytes()?.to_vec();
+
+        if !r.is_empty() {
+            return Err(invalid("trailing data"));
+        }
+
+        Ok(Self {
+            pc,
+            hi,
+            lo,
+            registers,
+            instructions,
+            exit_code: exited.then_some(exit_code),
+            text,
+            text_len,
+            data,
+            pages,
+            devices,
+            syscalls: has_syscalls.then_some(syscalls),
+        })
+    }
+
+    pub fn save<P>(&self, filename: P) -> Result<(), MimicError>
+    where
+        P: AsRef<Path>,
+    {
+        fs::write(&filename, self.to_bytes()).map_err(|_| MimicError {
+            span: None,
+            source: None,
+            ty: MimicErrorType::FileWriteFailed {
+                filename: filename.as_ref().to_path_buf(),
+            },
+        })
+    }
+
+    pub fn load<P>(filename: P) -> Result<Self, MimicError>
+    where
+        P: AsRef<Path>,
+    {
+        let bytes = fs::read(&filename).map_err(|_| MimicError {
+            span: None,
+            source: None,
+            ty: MimicErrorType::FileDoesNotExist {
+                filename: filename.as_ref().to_path_buf(),
+            },
+        })?;
+
+        Self::from_bytes(&bytes)
+    }
+}
+
+impl Core {
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            devices: self
+                .memory
+                .devices()
+                .map(|(start, device)| {
+                    let mut state = StateWriter::new();
+                    device.borrow().save_state(&mut state);
+                    (start, state.into_bytes())
+                })
+                .collect(),
+            ..self.machine_snapshot()
+        }
+    }
+
+    // Registers and memory only, for the checkpoints of the undo journal
+    pub(crate) fn machine_snapshot(&self) -> Snapshot {
+        Snapshot {
+            pc: self.pc,
+            hi: self.hi,
+            lo: self.lo,
+            registers: self.registers.dump(),
+            instructions: self.instructions,
+            exit_code: self.exit_code,
+
+            text: self.memory.text.clone(),
+            text_len: self.memory.text_len,
+            data: self.memory.data.clone(),
+            pages: self
+                .memory
+                .pages
+                .iter()
+                .map(|(page, words)| (*page, words.clone()))
+                .collect(),
+
+            devices: Vec::new(),
+            syscalls: None,
+        }
+    }
+
+    // Like `snapshot`, but also captures the state of the syscall handler
+    pub fn snapshot_with<H>(&self, syscall_handler: &H) -> Snapshot
+    where
+        H: SyscallHandler + ?Sized,
+    {
+        let mut state = StateWriter::new();
+        syscall_handler.save_state(&mut state);
+
+        Snapshot {
+            syscalls: Some(state.into_bytes()),
+            ..self.snapshot()
+        }
+    }
+
+    // Restores the machine state. The same devices must already be mapped at
+    // the same addresses; the program itself is part of the snapshot. Nothing
+    // changes if any part of the snapshot can't be restored.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), MimicError> {
+        let mapped: Vec<u32> = self.memory.devices().map(|(start, _)| start).collect();
+        let saved: Vec<u32> = snapshot.devices.iter().map(|(start, _)| *start).collect();
+        if mapped != saved {
+            return Err(invalid("mapped devices do not match the snapshot"));
+        }
+
+        // Devices only restore in place, so a failure puts back the state they had
+        let devices: Vec<_> = self.memory.devices().map(|(_, device)| device.clone()).collect();
+        let current: Vec<Vec<u8>> = devices
+            .iter()
+            .map(|device| {
+                let mut state = StateWriter::new();
+                device.borrow().save_state(&mut state);
+                state.into_bytes()
+            })
+            .collect();
+        for (device, (_, state)) in devices.iter().zip(&snapshot.devices) {
+            let restored = device.borrow_mut().restore_state(&mut StateReader::new(state));
+            if let Err(e) = restored {
+                for (device, state) in devices.iter().zip(&current) {
+                    let _ = device.borrow_mut().restore_state(&mut StateReader::new(state));
+                }
+                return Err(e);
+            }
+        }
+
+        self.restore_machine(snapshot);
+        self.resuming_from = None;
+        self.pending_stop = None;
+        if let Some(journal) = &mut self.journal {
+            journal.clear();
+        }
+
+        Ok(())
+    }
+
+    // Registers and memory only, leaving devices and the debugger as they are
+    pub(crate) fn restore_machine(&mut self, snapshot: &Snapshot) {
+        self.pc = snapshot.pc;
+        self.hi = snapshot.hi;
+        self.lo = snapshot.lo;
+        self.registers.load(snapshot.registers);
+        self.instructions = snapshot.instructions;
+        self.exit_code = snapshot.exit_code;
+
+        self.memory.text = snapshot.text.clone();
+        self.memory.text_len = snapshot.text_len;
+        self.memory.data = snapshot.data.clone();
+        self.memory.pages = snapshot
+            .pages
+            .iter()
+            .map(|(page, words)| (*page, words.clone()))
+            .collect();
+        self.memory.text_generation += 1;
+        self.memory.data_generation += 1;
+
+        self.last_access = None;
+    }
+
+    // Restores the machine and, if the snapshot has it, the syscall handler state.
+    // Nothing changes if either of them can't be restored.
+    pub fn restore_with<H>(&mut self, snapshot: &Snapshot, syscall_handler: &mut H) -> Result<(), MimicError>
+    where
+        H: SyscallHandler + ?Sized,
+    {
+        let Some(state) = &snapshot.syscalls else {
+            return self.restore(snapshot);
+        };
+
+        let mut current = StateWriter::new();
+        syscall_handler.save_state(&mut current);
+        let current = current.into_bytes();
+
+        let restored = syscall_handler
+            .restore_state(&mut StateReader::new(state))
+            .and_then(|_| self.restore(snapshot));
+        if restored.is_err() {
+            let _ = syscall_handler.restore_state(&mut StateReader::new(&current));
+        }
+        restored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reader_reports_truncation() {
+        let mut w = StateWriter::new();
+        w.write_str("hello");
+        w.write_words(&[1, 2, 3]);
+        let bytes = w.into_bytes();
+
+        let mut r = StateReader::new(&bytes);
+        assert_eq!(r.read_string().unwrap(), "hello");
+        assert_eq!(r.read_words().unwrap(), vec![1, 2, 3]);
+        assert!(r.is_empty());
+
+        let mut r = StateReader::new(&bytes[..bytes.len() - 1]);
+        r.read_string().unwrap();
+        assert!(r.read_words().is_err());
+    }
+
+    #[test]
+    fn round_trip_and_restore() {
+        let mut core = Core::new_mips_default();
+        core.load_text(vec![0x24080007]); // addiu $t0, $zero, 7
+        core.write_word(0x7FFFEFF0, 0xDEADBEEF);
+        core.write_word(0x10010004, 42);
+
+        let mut no_syscalls = |_: u32, regs: [u32; 32]| regs;
+        core.tick(&mut no_syscalls).unwrap();
+
+        let snapshot = Snapshot::from_bytes(&core.snapshot().to_bytes()).unwrap();
+        assert_eq!(snapshot, core.snapshot());
+
+        let mut restored = Core::new_mips_default();
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.get_register(8), 7);
+        assert_eq!(restored.pc(), 0x00400004);
+        assert_eq!(restored.instruction_count(), 1);
+        assert_eq!(restored.read_word(0x7FFFEFF0).unwrap(), 0xDEADBEEF);
+        assert_eq!(restored.read_word(0x10010004).unwrap(), 42);
+    }
+
+    #[test]
+    fn failed_restore_changes_nothing() {
+        use crate::mips32::devices::input::{InputDevice, InputScript, KeyboardMmio, Trigger};
+        use crate::mips32::devices::MmioDevice;
+        use crate::mips32::syscall::MarsSyscalls;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let keyboard = |text| {
+            let input = InputDevice::new(InputScript::new().type_text(Trigger::Instruction(0), text)).shared();
+            Rc::new(RefCell::new(KeyboardMmio::new(input)))
+        };
+        let (first, second) = (keyboard("a"), keyboard("b"));
+        let mut core = Core::new_mips_default();
+        core.map_device(0xFFFF0000, 8, first.clone()).unwrap();
+        core.map_device(0xFFFF0010, 8, second).unwrap();
+
+        let mut snapshot = core.snapshot();
+        snapshot.registers[8] = 7;
+        snapshot.devices[0].1[0] = 0x41;
+        snapshot.devices[1].1.pop();
+
+        let saved = |device: &Rc<RefCell<KeyboardMmio>>| {
+            let mut state = StateWriter::new();
+            device.borrow().save_state(&mut state);
+            state.into_bytes()
+        };
+        let before = saved(&first);
+        assert!(core.restore(&snapshot).is_err());
+        assert_eq!(saved(&first), before);
+        assert_eq!(core.get_register(8), 0);
+
+        // A syscall handler state that doesn't parse leaves the machine alone as well
+        snapshot.devices[1].1 = core.snapshot().devices[1].1.clone();
+        snapshot.syscalls = Some(vec![1]);
+        let mut syscalls = MarsSyscalls::new();
+        assert!(core.restore_with(&snapshot, &mut syscalls).is_err());
+        assert_eq!(saved(&first), before);
+        assert_eq!(core.get_register(8), 0);
+        snapshot.syscalls = None;
+        core.restore_with(&snapshot, &mut syscalls).unwrap();
+        assert_eq!(core.get_register(8), 7);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let mut bytes = Core::new_mips_default().snapshot().to_bytes();
+        assert!(Snapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        bytes[8] = 2;
+        assert!(matches!(
+            Snapshot::from_bytes(&bytes).unwrap_err().ty,
+            MimicErrorType::UnsupportedSnapshotVersion { version: 2 }
+        ));
+        assert!(Snapshot::from_bytes(b"not a snapshot").is_err());
+    }
+}
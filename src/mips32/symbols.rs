@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+
+// Byte addresses of the labels defined by an assembled program
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    symbols: HashMap<String, u32>,
+    // Labels in the user and kernel text segments, kept sorted by address
+    text: Vec<(u32, String)>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, name: String, address: u32) {
+        if let Some(old) = self.symbols.insert(name.clone(), address) {
+            self.text.retain(|(start, label)| (*start, label.as_str()) != (old, name.as_str()));
+        }
+        if text_segment(address).is_some() {
+            let entry = (address, name);
+            let index = self.text.partition_point(|label| *label < entry);
+            self.text.insert(index, entry);
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<u32> {
+        self.symbols.get(name).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u32)> {
+        self.symbols.iter().map(|(name, address)| (name.as_str(), *address))
+    }
+
+    // Labels in the user and kernel text segments ordered by address
+    pub fn text_labels(&self) -> Vec<(&str, u32)> {
+        self.text.iter().map(|(address, name)| (name.as_str(), *address)).collect()
+    }
+
+    // The closest text label at or before `address` in the same segment, or None
+    // when `address` is outside the text segments
+    pub fn label_for(&self, address: u32) -> Option<(&str, u32)> {
+        let segment = text_segment(address)?;
+        let index = self.text.partition_point(|(start, _)| *start <= address);
+        let (start, name) = self.text[..index].last()?;
+        (text_segment(*start) == Some(segment)).then_some((name.as_str(), *start))
+    }
+}
+
+// Source line of every assembled instruction, for reports and listings
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    name: String,
+    source: Vec<String>,
+    // Start of each text segment with code and the 1-based line number of each
+    // word from there, 0 for padding
+    segments: Vec<(u32, Vec<usize>)>,
+}
+
+impl SourceMap {
+    pub fn new(name: String, source: &str) -> Self {
+        Self {
+            name,
+            source: source.lines().map(str::to_owned).collect(),
+            segments: Vec::new(),
+        }
+    }
+
+    // Text of the original source, for programs assembled from preprocessed text with the same lines
+    pub(crate) fn set_source(&mut self, source: &str) {
+        self.source = source.lines().map(str::to_owned).collect();
+    }
+
+    // Maps the instruction at `address` in one of the text segments to `line`
+    pub(crate) fn insert(&mut self, address: u32, line: usize) {
+        let start = text_segment(address).expect("instructions are in a text segment");
+        let lines = match self.segments.iter().position(|(s, _)| *s == start) {
+            Some(i) => &mut self.segments[i].1,
+            None => {
+                self.segments.push((start, Vec::new()));
+                &mut self.segments.last_mut().unwrap().1
+            },
+        };
+
+        let index = ((address - start) / 4) as usize;
+        if lines.len() <= index {
+            lines.resize(index + 1, 0);
+        }
+        lines[index] = line;
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Line of the instruction at a byte address in either text segment
+    pub fn line_at(&self, address: u32) -> Option<usize> {
+        let start = text_segment(address)?;
+        let (_, lines) = self.segments.iter().find(|(s, _)| *s == start)?;
+        lines.get(((address - start) / 4) as usize).copied().filter(|line| *line != 0)
+    }
+
+    pub fn line_text(&self, line: usize) -> Option<&str> {
+        self.source.get(line.checked_sub(1)?).map(String::as_str)
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.source.len()
+    }
+
+    // Addresses of all instructions assembled from `line`
+    pub fn addresses(&self, line: usize) -> impl Iterator<Item = u32> + '_ {
+        self.segments.iter().flat_map(move |(start, lines)| {
+            lines
+                .iter()
+                .enumerate()
+                .filter(move |(_, l)| **l == line)
+                .map(move |(i, _)| start + 4 * i as u32)
+        })
+    }
+}
+
+pub(crate) const TEXT_START: u32 = 0x00400000;
+pub(crate) const DATA_START: u32 = 0x10000000;
+pub(crate) const KTEXT_START: u32 = 0x80000000;
+const KDATA_START: u32 = 0x90000000;
+
+// Start of the text segment holding `address`
+fn text_segment(address: u32) -> Option<u32> {
+    [(TEXT_START, DATA_START), (KTEXT_START, KDATA_START)]
+        .into_iter()
+        .find(|(start, end)| (*start..*end).contains(&address))
+        .map(|(start, _)| start)
+}
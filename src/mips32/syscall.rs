@@ -0,0 +1,532 @@
+use crate::errors::{MimicError, MimicErrorType};
+use crate::mips32::core::Core;
+use crate::mips32::devices::dialog::{DialogResponse, Dialogs};
+use crate::mips32::devices::framebuffer::Framebuffer;
+use crate::mips32::devices::input::SharedInput;
+use crate::mips32::devices::midi::{MidiRecorder, NoteEvent};
+use crate::mips32::snapshot::{self, StateReader, StateWriter};
+
+use std::collections::VecDeque;
+
+const V0: u32 = 2;
+const V1: u32 = 3;
+const A0: u32 = 4;
+const A1: u32 = 5;
+const A2: u32 = 6;
+const A3: u32 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallOutcome {
+    Continue,
+    Exit(u32),
+    // The syscall is retried on the next tick once input has been provided
+    WaitForInput,
+}
+
+pub trait SyscallHandler {
+    fn syscall(&mut self, inst: u32, core: &mut Core) -> Result<SyscallOutcome, MimicError>;
+
+    // Handler state for machine snapshots, such as buffered input and output
+    fn save_state(&self, _state: &mut StateWriter) {}
+
+    fn restore_state(&mut self, _state: &mut StateReader) -> Result<(), MimicError> {
+        Ok(())
+    }
+}
+
+// Plain closures receive the register file and return the new one
+impl<F> SyscallHandler for F
+where
+    F: FnMut(u32, [u32; 32]) -> [u32; 32],
+{
+    fn syscall(&mut self, inst: u32, core: &mut Core) -> Result<SyscallOutcome, MimicError> {
+        let new_regs = (self)(inst, core.dump_registers());
+        core.registers.load(new_regs);
+        Ok(SyscallOutcome::Continue)
+    }
+}
+
+// Built-in handler for the Mimic syscall services used by the demos
+#[derive(Debug)]
+pub struct MimicSyscalls {
+    framebuffer: Framebuffer,
+    input: Option<SharedInput>,
+    output: String,
+    seed: u32,
+    rng_state: u32,
+}
+
+impl MimicSyscalls {
+    pub fn new(framebuffer: Framebuffer) -> Self {
+        Self {
+            framebuffer,
+            input: None,
+            output: String::new(),
+            seed: 0x2545F491,
+            rng_state: 0x2545F491,
+        }
+    }
+
+    pub fn with_seed(mut self, seed: u32) -> Self {
+        self.seed = seed.max(1);
+        self.rng_state = self.seed;
+        self
+    }
+
+    pub fn with_input(mut self, input: SharedInput) -> Self {
+        self.input = Some(input);
+        self
+    }
+
+    pub fn input(&self) -> Option<&SharedInput> {
+        self.input.as_ref()
+    }
+
+    pub fn framebuffer(&self) -> &Framebuffer {
+        &self.framebuffer
+    }
+
+    pub fn framebuffer_mut(&mut self) -> &mut Framebuffer {
+        &mut self.framebuffer
+    }
+
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    fn next_random(&mut self) -> u32 {
+        // xorshift32
+        let mut x = self.rng_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng_state = x;
+        x
+    }
+}
+
+impl Default for MimicSyscalls {
+    fn default() -> Self {
+        Self::new(Framebuffer::new(640, 480))
+    }
+}
+
+impl SyscallHandler for MimicSyscalls {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_str(&self.output);
+        state.write_u32(self.rng_state);
+        self.framebuffer.save_state(state);
+        state.write_bool(self.input.is_some());
+        if let Some(input) = &self.input {
+            input.borrow().save_state(state);
+        }
+    }
+
+    fn restore_state(&mut self, state: &mut StateReader) -> Result<(), MimicError> {
+        self.output = state.read_string()?;
+        self.rng_state = state.read_u32()?;
+        self.framebuffer.restore_state(state)?;
+        if state.read_bool()? {
+            match &self.input {
+                Some(input) => input.borrow_mut().restore_state(state)?,
+                None => return Err(snapshot::invalid("snapshot has input but the handler has no input device")),
+            }
+        }
+        Ok(())
+    }
+
+    fn syscall(&mut self, _inst: u32, core: &mut Core) -> Result<SyscallOutcome, MimicError> {
+        let service = core.get_register(V0);
+        let a0 = core.get_register(A0);
+
+        match service {
+            0x00 => {
+                // exit
+                return Ok(SyscallOutcome::Exit(0));
+            }
+            0x01 => {
+                // update frame
+                self.framebuffer.present()?;
+                if let Some(input) = &self.input {
+                    input.borrow_mut().advance_frames(self.framebuffer.frame_count());
+                }
+            }
+            0x02 => {
+                // sync frame
+                self.framebuffer.sync();
+            }
+            0x03 => {
+                // print string
+                let s = core.read_string(a0)?;
+                self.output.push_str(&s);
+            }
+            0x04 => {
+                // print integer, $a1 != 0 for signed
+                if core.get_register(A1) != 0 {
+                    self.output.push_str(&(a0 as i32).to_string());
+                } else {
+                    self.output.push_str(&a0.to_string());
+                }
+            }
+            0x05 => {
+                // set title
+                let title = core.read_string(a0)?;
+                self.framebuffer.set_title(title);
+            }
+            0x09 => {
+                // seed random number generator
+                self.rng_state = self.seed;
+            }
+            0x0A => {
+                // random integer
+                let value = self.next_random();
+                core.set_register(V0, value);
+            }
+            0x12 => {
+                // poll input, $v0 is 1 and $v1 the key code if a key press is pending
+                let key = self.input.as_ref().and_then(|input| {
+                    let mut input = input.borrow_mut();
+                    input.advance_instructions(core.instruction_count());
+                    input.pop_key()
+                });
+                core.set_register(V0, key.is_some() as u32);
+                core.set_register(V1, key.unwrap_or(0) as u32);
+            }
+            0x20 => {
+                // fill screen
+                self.framebuffer.fill(a0);
+            }
+            0x22 => {
+                // fill rectangle, corners packed as (x << 16) | y
+                let a1 = core.get_register(A1);
+                let a2 = core.get_register(A2);
+                self.framebuffer
+                    .fill_rect(a0, a1 >> 16, a1 & 0xFFFF, a2 >> 16, a2 & 0xFFFF);
+            }
+            _ => {
+                return Err(MimicError {
+                    span: None,
+                    source: None,
+                    ty: MimicErrorType::UnknownSyscall { service },
+                })
+            }
+        }
+
+        Ok(SyscallOutcome::Continue)
+    }
+}
+
+// Built-in handler for the MARS syscall services. Time only passes on the virtual
+// clock through sleeps, synchronous notes and, if configured, a fixed duration
+// per executed instruction. Dialogs are answered headlessly by `Dialogs`.
+#[derive(Debug, Default)]
+pub struct MarsSyscalls {
+    output: String,
+    midi: MidiRecorder,
+    dialogs: Dialogs,
+    // The core has no FPU, so the float and double dialogs (52, 53, 57, 58) use
+    // these in place of $f0-$f31. A double takes an even register and the next,
+    // with its low word in the even one.
+    float_registers: [u32; 32],
+    stdin: VecDeque<u8>,
+    elapsed_ms: u64,
+    instruction_ns: u64,
+}
+
+impl MarsSyscalls {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_dialogs(mut self, dialogs: Dialogs) -> Self {
+        self.dialogs = dialogs;
+        self
+    }
+
+    pub fn with_instruction_duration(mut self, nanoseconds: u64) -> Self {
+        self.instruction_ns = nanoseconds;
+        self
+    }
+
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    pub fn midi(&self) -> &MidiRecorder {
+        &self.midi
+    }
+
+    pub fn dialogs(&self) -> &Dialogs {
+        &self.dialogs
+    }
+
+    pub fn float_register(&self, register: u32) -> u32 {
+        self.float_registers[register as usize]
+    }
+
+    pub fn set_float_register(&mut self, register: u32, bits: u32) {
+        self.float_registers[register as usize] = bits;
+    }
+
+    pub fn double_register(&self, register: u32) -> u64 {
+        let register = register as usize & !1;
+        (self.float_registers[register + 1] as u64) << 32 | self.float_registers[register] as u64
+    }
+
+    pub fn set_double_register(&mut self, register: u32, bits: u64) {
+        let register = register as usize & !1;
+        self.float_registers[register] = bits as u32;
+        self.float_registers[register + 1] = (bits >> 32) as u32;
+    }
+
+    // Queues text for the console read syscalls
+    pub fn push_stdin(&mut self, text: &str) {
+        self.stdin.extend(text.bytes());
+    }
+
+    // Takes the next line including its newline, or whatever is left once the input ends
+    fn read_line(&mut self) -> Option<Vec<u8>> {
+        if self.stdin.is_empty() {
+            return None;
+        }
+
+        let len = match self.stdin.iter().position(|b| *b == b'\n') {
+            Some(i) => i + 1,
+            None => self.stdin.len(),
+        };
+        Some(self.stdin.drain(..len).collect())
+    }
+
+    pub fn clock_ms(&self, core: &Core) -> u64 {
+        self.elapsed_ms + core.instruction_count() * self.instruction_ns / 1_000_000
+    }
+}
+
+impl SyscallHandler for MarsSyscalls {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_str(&self.output);
+        state.write_bytes(&self.stdin.iter().copied().collect::<Vec<u8>>());
+        state.write_u64(self.elapsed_ms);
+        self.midi.save_state(state);
+        self.dialogs.save_state(state);
+        for bits in self.float_registers {
+            state.write_u32(bits);
+        }
+    }
+
+    fn restore_state(&mut self, state: &mut StateReader) -> Result<(), MimicError> {
+        self.output = state.read_string()?;
+        self.stdin = state.read_bytes()?.iter().copied().collect();
+        self.elapsed_ms = state.read_u64()?;
+        self.midi.restore_state(state)?;
+        self.dialogs.restore_state(state)?;
+        for bits in &mut self.float_registers {
+            *bits = state.read_u32()?;
+        }
+        Ok(())
+    }
+
+    fn syscall(&mut self, _inst: u32, core: &mut Core) -> Result<SyscallOutcome, MimicError> {
+        let service = core.get_register(V0);
+        let a0 = core.get_register(A0);
+
+        match service {
+            1 => {
+                // print integer
+                self.output.push_str(&(a0 as i32).to_string());
+            }
+            4 => {
+                // print string
+                let s = core.read_string(a0)?;
+                self.output.push_str(&s);
+            }
+            5 => {
+                // read integer
+                let line = match self.read_line() {
+                    Some(line) => line,
+                    None => return Ok(SyscallOutcome::WaitForInput),
+                };
+                let value = String::from_utf8_lossy(&line).trim().parse::<i32>().unwrap_or(0);
+                core.set_register(V0, value as u32);
+            }
+            8 => {
+                // read string into the $a1 byte buffer at $a0
+                let line = match self.read_line() {
+                    Some(line) => line,
+                    None => return Ok(SyscallOutcome::WaitForInput),
+                };
+                let capacity = core.get_register(A1) as usize;
+                let len = line.len().min(capacity.saturating_sub(1));
+                for (i, byte) in line[..len].iter().enumerate() {
+                    core.write_byte(a0 + i as u32, *byte);
+                }
+                if capacity > 0 {
+                    core.write_byte(a0 + len as u32, 0);
+                }
+            }
+            10 => {
+                // exit
+                return Ok(SyscallOutcome::Exit(0));
+            }
+            11 => {
+                // print character
+                self.output.push(a0 as u8 as char);
+            }
+            12 => {
+                // read character
+                match self.stdin.pop_front() {
+                    Some(c) => core.set_register(V0, c as u32),
+                    None => return Ok(SyscallOutcome::WaitForInput),
+                }
+            }
+            17 => {
+                // exit2
+                return Ok(SyscallOutcome::Exit(a0));
+            }
+            31 | 33 => {
+                // MIDI out, 33 waits for the note to finish
+                let event = NoteEvent::from_syscall(
+                    self.clock_ms(core),
+                    a0,
+                    core.get_register(A1),
+                    core.get_register(A2),
+                    core.get_register(A3),
+                );
+                self.midi.record(event);
+
+                if service == 33 {
+                    self.elapsed_ms += event.duration_ms as u64;
+                }
+            }
+            32 => {
+                // sleep
+                self.elapsed_ms += a0 as u64;
+            }
+            34 => {
+                // print integer in hexadecimal
+                self.output.push_str(&format!("{:#010x}", a0));
+            }
+            36 => {
+                // print integer as unsigned
+                self.output.push_str(&a0.to_string());
+            }
+            50 => {
+                // ConfirmDialog, $a0 is 0 for yes, 1 for no and 2 for cancel
+                let prompt = core.read_string(a0)?;
+                let answer = match self.dialogs.ask(service, prompt)? {
+                    DialogResponse::Yes => 0,
+                    DialogResponse::No => 1,
+                    DialogResponse::Cancel => 2,
+                    DialogResponse::Text(t) => match t.to_lowercase().as_str() {
+                        "y" | "yes" => 0,
+                        "n" | "no" => 1,
+                        _ => 2,
+                    },
+                };
+                core.set_register(A0, answer);
+            }
+            51 => {
+                // InputDialogInt, $a1 is 0 for ok, -1 for bad input, -2 for cancel, -3 for empty
+                let prompt = core.read_string(a0)?;
+                let (value, status) = match self.dialogs.ask(service, prompt)? {
+                    DialogResponse::Text(t) if t.trim().is_empty() => (0, -3),
+                    DialogResponse::Text(t) => match t.trim().parse::<i32>() {
+                        Ok(i) => (i, 0),
+                        Err(_) => (0, -1),
+                    },
+                    DialogResponse::Cancel => (0, -2),
+                    _ => (0, -1),
+                };
+                core.set_register(A0, value as u32);
+                core.set_register(A1, status as u32);
+            }
+            52 | 53 => {
+                // InputDialogFloat into $f0, InputDialogDouble into $f0 and $f1, $a1 status as for 51
+                let prompt = core.read_string(a0)?;
+                let (value, status) = match self.dialogs.ask(service, prompt)? {
+                    DialogResponse::Text(t) if t.trim().is_empty() => (0.0, -3),
+                    DialogResponse::Text(t) => match t.trim().parse::<f64>() {
+                        Ok(f) => (f, 0),
+                        Err(_) => (0.0, -1),
+                    },
+                    DialogResponse::Cancel => (0.0, -2),
+                    _ => (0.0, -1),
+                };
+                if status == 0 && service == 52 {
+                    self.set_float_register(0, (value as f32).to_bits());
+                } else if status == 0 {
+                    self.set_double_register(0, value.to_bits());
+                }
+                core.set_register(A1, status as u32);
+            }
+            54 => {
+                // InputDialogString into the $a2 byte buffer at $a1, $a1 status as for 51
+                // plus -4 when the answer had to be truncated
+                let prompt = core.read_string(a0)?;
+                let buffer = core.get_register(A1);
+                let capacity = core.get_register(A2) as usize;
+
+                let status: i32 = match self.dialogs.ask(service, prompt)? {
+                    DialogResponse::Text(t) if t.is_empty() => -3,
+                    DialogResponse::Text(t) => {
+                        let bytes = t.as_bytes();
+                        let len = bytes.len().min(capacity.saturating_sub(1));
+                        for (i, byte) in bytes[..len].iter().enumerate() {
+                            core.write_byte(buffer + i as u32, *byte);
+                        }
+                        if capacity > 0 {
+                            core.write_byte(buffer + len as u32, 0);
+                        }
+
+                        if len < bytes.len() {
+                            -4
+                        } else {
+                            0
+                        }
+                    }
+                    DialogResponse::Cancel => -2,
+                    _ => -1,
+                };
+                core.set_register(A1, status as u32);
+            }
+            55 => {
+                // MessageDialog, $a1 selects the icon
+                let message = core.read_string(a0)?;
+                self.dialogs.show(service, message);
+            }
+            56 => {
+                // MessageDialogInt
+                let message = core.read_string(a0)?;
+                let value = core.get_register(A1) as i32;
+                self.dialogs.show(service, format!("{}{}", message, value));
+            }
+            57 => {
+                // MessageDialogFloat with $f12
+                let message = core.read_string(a0)?;
+                let value = f32::from_bits(self.float_register(12));
+                self.dialogs.show(service, format!("{}{:?}", message, value));
+            }
+            58 => {
+                // MessageDialogDouble with $f12 and $f13
+                let message = core.read_string(a0)?;
+                let value = f64::from_bits(self.double_register(12));
+                self.dialogs.show(service, format!("{}{:?}", message, value));
+            }
+            59 => {
+                // MessageDialogString
+                let message = core.read_string(a0)?;
+                let value = core.read_string(core.get_register(A1))?;
+                self.dialogs.show(service, format!("{}{}", message, value));
+            }
+            _ => {
+                return Err(MimicError {
+                    span: None,
+                    source: None,
+                    ty: MimicErrorType::UnknownSyscall { service },
+                })
+            }
+        }
+
+        Ok(SyscallOutcome::Continue)
+    }
+}
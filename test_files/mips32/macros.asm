@@ -0,0 +1,20 @@
+.include "macros_lib.asm"
+.eqv COUNT 5
+.eqv TOTAL $s0
+
+# Adds 1 to n into TOTAL, with a loop label local to each expansion
+.macro sum_to(%n)
+    li $t0, %n
+loop:
+    add TOTAL, TOTAL, $t0
+    addi $t0, $t0, -1
+    bgtz $t0, loop
+.end_macro
+
+.text
+main:
+    li TOTAL, 0
+    sum_to(COUNT)
+    sum_to 3
+    print_int(TOTAL)
+    exit
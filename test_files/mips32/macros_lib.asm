@@ -0,0 +1,14 @@
+# Shared helpers for macros.asm
+.eqv SYS_PRINT_INT 1
+.eqv SYS_EXIT 10
+
+.macro print_int(%r)
+    move $a0, %r
+    li $v0, SYS_PRINT_INT
+    syscall
+.end_macro
+
+.macro exit
+    li $v0, SYS_EXIT
+    syscall
+.end_macro
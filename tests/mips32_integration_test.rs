@@ -1,6 +1,25 @@
-use mimic_emulator::mips32::assembler::assemble_from_file;
+use mimic_emulator::errors::MimicErrorType;
+use mimic_emulator::mips32::assembler::{
+    assemble_from_file, assemble_from_string, assemble_program_from_file, assemble_program_from_string,
+    assemble_program_from_string_with_options, AssemblerOptions,
+};
+use mimic_emulator::mips32::core::{AccessKind, Core, StopReason};
+use mimic_emulator::mips32::debug::callstack::{CallStack, ReturnMismatch};
+use mimic_emulator::mips32::debug::convention::{ConventionChecker, Violation, ViolationKind};
+use mimic_emulator::mips32::debug::coverage::{BranchCoverage, Coverage};
+use mimic_emulator::mips32::debug::expr::{EvalContext, Expression};
+use mimic_emulator::mips32::debug::profile::{InstructionClass, Profiler};
+use mimic_emulator::mips32::debug::trace::{Trace, TraceReplay, Tracer};
+use mimic_emulator::mips32::debug::{Breakpoint, HitCondition, WatchKind, Watchpoint};
+use mimic_emulator::mips32::devices::dialog::{DialogResponse, Dialogs};
+use mimic_emulator::mips32::devices::input::{InputDevice, InputScript, KeyboardMmio, Trigger};
+use mimic_emulator::mips32::devices::mars::{KeyboardDisplayMmio, MMIO_BASE, MMIO_LEN};
+use mimic_emulator::mips32::snapshot::Snapshot;
+use mimic_emulator::mips32::syscall::{MarsSyscalls, MimicSyscalls};
 
+use std::cell::RefCell;
 use std::fs;
+use std::rc::Rc;
 
 fn strip_trailing_zeros(input: Vec<u8>) -> Vec<u8> {
     let mut last_nonzero_index = input.len() - 1;
@@ -39,3 +58,1505 @@ fn bouncy() {
     assert_eq!(text_bytes, text_bytes_correct);
     assert_eq!(data_bytes, data_bytes_correct);
 }
+
+#[test]
+fn bouncy_headless_framebuffer() {
+    let (text_bytes, data_bytes) = assemble_from_file("test_files/mips32/bouncy.asm").unwrap();
+
+    let mut core = Core::new_mips_default();
+    core.load_program(&text_bytes, &data_bytes);
+
+    let mut syscalls = MimicSyscalls::default();
+    assert!(matches!(core.run_for(&mut syscalls, 2000), StopReason::InstructionLimit));
+
+    let fb = syscalls.framebuffer();
+    assert_eq!(fb.title(), "Bouncy Square");
+    assert!(fb.frame_count() > 0);
+    assert_eq!(fb.sync_count(), fb.frame_count());
+
+    // The square starts at (64, 128) moving down-right by one pixel per frame
+    let frame = fb.presented_frame().unwrap();
+    let offset = fb.frame_count() as u32;
+    assert_eq!(frame.get_pixel(0, 0), Some(0x777777FF));
+    assert_eq!(frame.get_pixel(64 + offset, 128 - offset), Some(0xFF0000FF));
+    assert_eq!(frame.get_pixel(63 + offset, 128 - offset), Some(0x777777FF));
+}
+
+#[test]
+fn bouncy_scripted_quit() {
+    let (text_bytes, data_bytes) = assemble_from_file("test_files/mips32/bouncy.asm").unwrap();
+
+    let mut core = Core::new_mips_default();
+    core.load_program(&text_bytes, &data_bytes);
+
+    let input = InputDevice::new(InputScript::parse("frame 3 type q").unwrap()).shared();
+    let mut syscalls = MimicSyscalls::default().with_input(input.clone());
+
+    assert!(matches!(core.run(&mut syscalls), StopReason::Exited(0)));
+
+    assert_eq!(syscalls.framebuffer().frame_count(), 3);
+    assert!(input.borrow().is_exhausted());
+}
+
+#[test]
+fn keyboard_mmio() {
+    let input = InputDevice::new(InputScript::new().type_text(Trigger::Instruction(2), "A")).shared();
+
+    let mut core = Core::new_mips_default();
+    core.map_device(0xFFFF0000, 8, Rc::new(RefCell::new(KeyboardMmio::new(input)))).unwrap();
+    for (address, len) in [(0xFFFF0100, 0), (0xFFFFFFF0, 0x20)] {
+        let device = Rc::new(RefCell::new(KeyboardMmio::new(InputDevice::new(InputScript::new()).shared())));
+        let error = core.map_device(address, len, device).unwrap_err();
+        assert!(matches!(error.ty, MimicErrorType::InvalidDeviceMapping { .. }), "{address:#X}");
+    }
+    core.load_text(vec![
+        0x3C08FFFF, // lui $t0, 0xFFFF
+        0x8D090000, // lw $t1, 0($t0)
+        0x8D090000, // lw $t1, 0($t0)
+        0x8D0A0004, // lw $t2, 4($t0)
+        0x8D0B0000, // lw $t3, 0($t0)
+    ]);
+
+    let mut no_syscalls = |_: u32, regs: [u32; 32]| regs;
+    core.tick(&mut no_syscalls).unwrap();
+    core.tick(&mut no_syscalls).unwrap();
+    assert_eq!(core.get_register(9), 0);
+    core.tick(&mut no_syscalls).unwrap();
+    assert_eq!(core.get_register(9), 1);
+
+    // Inspecting the data register leaves the key queued
+    let watch = Expression::parse("mem.w[0xFFFF0004]").unwrap();
+    for _ in 0..2 {
+        assert_eq!(core.read_word(0xFFFF0004).unwrap(), b'A' as u32);
+        assert_eq!(watch.eval(&EvalContext::new(&core)).unwrap(), b'A' as i64);
+    }
+    assert_eq!(core.read_word(0xFFFF0000).unwrap(), 1);
+
+    for _ in 0..2 {
+        core.tick(&mut no_syscalls).unwrap();
+    }
+    assert_eq!(core.get_register(9), 1);
+    assert_eq!(core.get_register(10), b'A' as u32);
+    assert_eq!(core.get_register(11), 0);
+}
+
+#[test]
+fn mars_keyboard_display_echo() {
+    let input = InputDevice::new(InputScript::parse("instruction 10 type \"hey\"").unwrap()).shared();
+    let mmio = Rc::new(RefCell::new(KeyboardDisplayMmio::new(input)));
+
+    let mut core = Core::new_mips_default();
+    core.map_device(MMIO_BASE, MMIO_LEN, mmio.clone()).unwrap();
+    core.load_text(vec![
+        0x3C08FFFF, // lui $t0, 0xFFFF
+        0x8D090000, // wait_key: lw $t1, 0($t0)
+        0x31290001, // andi $t1, $t1, 1
+        0x1120FFFD, // beq $t1, $zero, wait_key
+        0x8D0A0004, // lw $t2, 4($t0)
+        0x8D090008, // wait_display: lw $t1, 8($t0)
+        0x31290001, // andi $t1, $t1, 1
+        0x1120FFFD, // beq $t1, $zero, wait_display
+        0xAD0A000C, // sw $t2, 12($t0)
+        0x08100001, // j wait_key
+    ]);
+
+    let mut no_syscalls = |_: u32, regs: [u32; 32]| regs;
+    core.run_for(&mut no_syscalls, 200);
+
+    assert_eq!(mmio.borrow().display_output(), "hey");
+}
+
+#[test]
+fn mars_midi_melody() {
+    let source = "
+.text
+    li $a1, 250
+    li $a2, 0
+    li $a3, 100
+    li $v0, 33
+    li $a0, 60
+    syscall
+    li $a0, 64
+    syscall
+    li $v0, 31
+    li $a0, 67
+    syscall
+    li $v0, 10
+    syscall
+";
+    let (text_bytes, data_bytes) = assemble_from_string(source.to_owned()).unwrap();
+
+    let mut core = Core::new_mips_default();
+    core.load_program(&text_bytes, &data_bytes);
+
+    let mut syscalls = MarsSyscalls::new();
+    assert!(matches!(core.run(&mut syscalls), StopReason::Exited(0)));
+
+    let melody: Vec<(u64, u8)> = syscalls
+        .midi()
+        .events()
+        .iter()
+        .map(|e| (e.time_ms, e.pitch))
+        .collect();
+    assert_eq!(melody, vec![(0, 60), (250, 64), (500, 67)]);
+
+    let smf = syscalls.midi().to_smf();
+    assert_eq!(&smf[..4], b"MThd");
+    assert_eq!(&smf[14..18], b"MTrk");
+}
+
+#[test]
+fn mars_headless_dialogs() {
+    let source = "
+.data
+    ask_ok: .asciiz \"Continue?\"
+    ask_n: .asciiz \"How many?\"
+    ask_name: .asciiz \"Name:\"
+    name: .asciiz \"XXXXXXXX\"
+    got: .asciiz \"Got \"
+.text
+    li $v0, 50
+    la $a0, ask_ok
+    syscall
+    move $s0, $a0
+    li $v0, 51
+    la $a0, ask_n
+    syscall
+    move $s1, $a0
+    move $s2, $a1
+    li $v0, 54
+    la $a0, ask_name
+    la $a1, name
+    li $a2, 5
+    syscall
+    move $s3, $a1
+    li $v0, 56
+    la $a0, got
+    move $a1, $s1
+    syscall
+    li $v0, 50
+    la $a0, ask_ok
+    syscall
+    move $s4, $a0
+    li $v0, 10
+    syscall
+";
+    let (text_bytes, data_bytes) = assemble_from_string(source.to_owned()).unwrap();
+
+    let mut core = Core::new_mips_default();
+    core.load_program(&text_bytes, &data_bytes);
+
+    let dialogs = Dialogs::parse("no\n17\n\"Rustacean\"\n").unwrap();
+    let mut syscalls = MarsSyscalls::new().with_dialogs(dialogs);
+    assert!(matches!(core.run(&mut syscalls), StopReason::Exited(0)));
+
+    assert_eq!(core.get_register(16), 1);
+    assert_eq!(core.get_register(17), 17);
+    assert_eq!(core.get_register(18), 0);
+    assert_eq!(core.get_register(19) as i32, -4);
+    assert_eq!(core.read_string(0x1001001A).unwrap(), "Rust");
+    assert_eq!(core.get_register(20), 2);
+
+    let log = syscalls.dialogs().log();
+    assert_eq!(log.len(), 5);
+    assert_eq!(log[2].prompt, "Name:");
+    assert_eq!(log[3].prompt, "Got 17");
+    assert_eq!(log[3].response, None);
+    assert_eq!(log[4].response, Some(DialogResponse::Cancel));
+}
+
+#[test]
+fn mars_float_dialogs() {
+    let source = "
+.data
+    ask: .asciiz \"Value?\"
+    got: .asciiz \"Got \"
+.text
+    li $v0, 52
+    la $a0, ask
+    syscall
+    move $s0, $a1
+    li $v0, 53
+    la $a0, ask
+    syscall
+    move $s1, $a1
+    li $v0, 52
+    la $a0, ask
+    syscall
+    move $s2, $a1
+    li $v0, 57
+    la $a0, got
+    syscall
+    li $v0, 58
+    la $a0, got
+    syscall
+    li $v0, 10
+    syscall
+";
+    let (text_bytes, data_bytes) = assemble_from_string(source.to_owned()).unwrap();
+
+    let mut core = Core::new_mips_default();
+    core.load_program(&text_bytes, &data_bytes);
+
+    let dialogs = Dialogs::parse("2.5\n-0.1\nabc\n").unwrap();
+    let mut syscalls = MarsSyscalls::new().with_dialogs(dialogs);
+    syscalls.set_float_register(12, 1.5f32.to_bits());
+    syscalls.set_float_register(13, 0x3FF80000);
+    assert!(matches!(core.run(&mut syscalls), StopReason::Exited(0)));
+
+    assert_eq!(core.get_register(16), 0);
+    assert_eq!(core.get_register(17), 0);
+    assert_eq!(core.get_register(18) as i32, -1);
+    // The bad answer leaves $f0 and $f1 as the double dialog set them
+    assert_eq!(syscalls.double_register(0), (-0.1f64).to_bits());
+
+    let log = syscalls.dialogs().log();
+    assert_eq!(log[3].prompt, "Got 1.5");
+    // $f12 still holds the float in its low word
+    assert_eq!(log[4].prompt, format!("Got {:?}", f64::from_bits(0x3FF80000_3FC00000)));
+}
+
+#[test]
+fn run_stop_reasons() {
+    let source = "
+.text
+main:
+    li $v0, 5
+    syscall
+    move $a0, $v0
+    li $v0, 1
+    syscall
+    li $v0, 17
+    syscall
+";
+    let (text_bytes, data_bytes) = assemble_from_string(source.to_owned()).unwrap();
+
+    let mut core = Core::new_mips_default();
+    core.load_program(&text_bytes, &data_bytes);
+    let mut syscalls = MarsSyscalls::new();
+
+    assert!(matches!(core.run_until(&mut syscalls, 0x00400004), StopReason::ReachedAddress(0x00400004)));
+    assert!(matches!(core.run(&mut syscalls), StopReason::WaitingForInput));
+    assert_eq!(core.pc(), 0x00400004);
+
+    syscalls.push_stdin("42\n");
+    assert!(matches!(core.run_for(&mut syscalls, 2), StopReason::InstructionLimit));
+    assert!(matches!(core.run(&mut syscalls), StopReason::Exited(42)));
+    assert!(matches!(core.run(&mut syscalls), StopReason::Exited(42)));
+    assert_eq!(syscalls.output(), "42");
+}
+
+#[test]
+fn run_falls_off_text_and_faults() {
+    let mut core = Core::new_mips_default();
+    core.load_text(vec![0x24080001]); // addiu $t0, $zero, 1
+
+    let mut no_syscalls = |_: u32, regs: [u32; 32]| regs;
+    assert!(matches!(core.run(&mut no_syscalls), StopReason::FellOffText));
+    assert_eq!(core.instruction_count(), 1);
+
+    let mut core = Core::new_mips_default();
+    core.load_text(vec![0x8C080001]); // lw $t0, 1($zero)
+    assert!(matches!(core.run(&mut no_syscalls), StopReason::Fault(_)));
+}
+
+#[test]
+fn debugger_breakpoints_and_stepping() {
+    let source = "
+.text
+main:
+    li $s0, 0
+loop:
+    jal bump
+    addiu $s0, $s0, 1
+    li $t0, 5
+    bne $s0, $t0, loop
+    li $v0, 10
+    syscall
+bump:
+    addiu $s1, $s1, 2
+    jr $ra
+";
+    let (text_bytes, data_bytes) = assemble_from_string(source.to_owned()).unwrap();
+
+    let mut core = Core::new_mips_default();
+    core.load_program(&text_bytes, &data_bytes);
+    let mut syscalls = MarsSyscalls::new();
+
+    // Stop on the third call into bump, but only once $s0 has reached 2
+    let id = core.add_breakpoint(
+        Breakpoint::new(0x00400004)
+            .with_condition(|core| core.get_register(16) >= 1)
+            .with_hit_condition(HitCondition::Equal(2)),
+    );
+    assert!(matches!(
+        core.run(&mut syscalls),
+        StopReason::Breakpoint { id: 0, address: 0x00400004, hits: 2 }
+    ));
+    assert_eq!(core.get_register(16), 2);
+
+    assert!(matches!(core.step(&mut syscalls), StopReason::Step));
+    assert_eq!(core.pc(), 0x0040001C);
+    assert!(matches!(core.step_out(&mut syscalls), StopReason::Step));
+    assert_eq!(core.pc(), 0x00400008);
+    assert_eq!(core.get_register(17), 6);
+
+    core.remove_breakpoint(id);
+    core.add_breakpoint(Breakpoint::new(0x00400004));
+    assert!(matches!(core.run(&mut syscalls), StopReason::Breakpoint { id: 1, .. }));
+    assert!(matches!(core.step_over(&mut syscalls), StopReason::Step));
+    assert_eq!(core.pc(), 0x00400008);
+    assert_eq!(core.get_register(17), 8);
+}
+
+#[test]
+fn debugger_watchpoints() {
+    let mut core = Core::new_mips_default();
+    core.load_text(vec![
+        0x24080007, // addiu $t0, $zero, 7
+        0xAFA8FFFC, // sw $t0, -4($sp)
+        0x8FA9FFFC, // lw $t1, -4($sp)
+    ]);
+    let sp = core.get_register(29);
+
+    let mut no_syscalls = |_: u32, regs: [u32; 32]| regs;
+    core.add_watchpoint(Watchpoint::new(sp - 2, 1, WatchKind::Read));
+    core.add_watchpoint(Watchpoint::new(sp - 4, 4, WatchKind::Write));
+
+    match core.run(&mut no_syscalls) {
+        StopReason::Watchpoint { id: 1, pc: 0x00400004, access } => {
+            assert_eq!(access.kind, AccessKind::Write);
+            assert_eq!(access.value, 7);
+        }
+        reason => panic!("unexpected stop {:?}", reason),
+    }
+
+    assert!(matches!(
+        core.run(&mut no_syscalls),
+        StopReason::Watchpoint { id: 0, pc: 0x00400008, .. }
+    ));
+    assert_eq!(core.get_register(9), 7);
+
+    // A device and a watchpoint at the very top of the address space
+    let mut core = Core::new_mips_default();
+    let device = Rc::new(RefCell::new(KeyboardMmio::new(InputDevice::new(InputScript::new()).shared())));
+    core.map_device(0xFFFFFFF0, 16, device).unwrap();
+    core.load_text(vec![
+        0x8C09FFFC, // lw $t1, -4($zero)
+    ]);
+    core.add_watchpoint(Watchpoint::new(0xFFFFFFFC, 4, WatchKind::Read));
+    assert!(matches!(
+        core.run(&mut no_syscalls),
+        StopReason::Watchpoint { id: 0, pc: 0x00400000, .. }
+    ));
+}
+
+#[test]
+fn debugger_expression_breakpoints() {
+    let source = "
+.text
+main:
+    li $s0, 0
+loop:
+    addiu $s0, $s0, 1
+    li $t0, 10
+    bne $s0, $t0, loop
+    li $v0, 10
+    syscall
+";
+    let program = assemble_program_from_string(source.to_owned()).unwrap();
+
+    let mut core = Core::new_mips_default();
+    core.load_program(&program.text, &program.data);
+    let mut syscalls = MarsSyscalls::new();
+
+    let breakpoint = Breakpoint::parse("label:loop hits > 3", &program.symbols).unwrap();
+    core.add_breakpoint(breakpoint.clone());
+    assert!(matches!(core.run(&mut syscalls), StopReason::Breakpoint { address: 0x00400004, .. }));
+    assert_eq!(core.get_register(16), 3);
+    assert_eq!((core.breakpoint(0).unwrap().reached(), core.breakpoint(0).unwrap().hits()), (4, 1));
+
+    // Copies of a breakpoint count their hits separately
+    let mut other = Core::new_mips_default();
+    other.load_program(&program.text, &program.data);
+    other.add_breakpoint(breakpoint);
+    assert!(matches!(other.run(&mut MarsSyscalls::new()), StopReason::Breakpoint { address: 0x00400004, .. }));
+    assert_eq!(other.get_register(16), 3);
+
+    let watch = Expression::parse("$s0 * 2 + (pc == label:loop)").unwrap();
+    let context = EvalContext::new(&core).with_symbols(&program.symbols);
+    assert_eq!(watch.eval(&context).unwrap(), 7);
+
+    assert!(Breakpoint::parse("label:nowhere", &program.symbols).is_err());
+    assert!(Breakpoint::parse("loop if $t0 ==", &program.symbols).is_err());
+}
+
+#[test]
+fn snapshot_resumes_on_a_fresh_machine() {
+    let source = "
+.text
+main:
+    li $v0, 1
+    li $a0, 7
+    syscall
+    li $v0, 5
+    syscall
+    move $a0, $v0
+    li $v0, 17
+    syscall
+";
+    let (text_bytes, data_bytes) = assemble_from_string(source.to_owned()).unwrap();
+
+    let mut core = Core::new_mips_default();
+    core.load_program(&text_bytes, &data_bytes);
+    let mut syscalls = MarsSyscalls::new();
+    assert!(matches!(core.run(&mut syscalls), StopReason::WaitingForInput));
+
+    let filename = std::env::temp_dir().join(format!("mimic_snapshot_{}.bin", std::process::id()));
+    core.snapshot_with(&syscalls).save(&filename).unwrap();
+    let snapshot = Snapshot::load(&filename).unwrap();
+    fs::remove_file(&filename).unwrap();
+
+    let mut restored = Core::new_mips_default();
+    let mut restored_syscalls = MarsSyscalls::new();
+    restored.restore_with(&snapshot, &mut restored_syscalls).unwrap();
+    assert_eq!(restored_syscalls.output(), "7");
+
+    restored_syscalls.push_stdin("3\n");
+    assert!(matches!(restored.run(&mut restored_syscalls), StopReason::Exited(3)));
+
+    // Devices have to be mapped the same way as when the snapshot was taken
+    let input = InputDevice::new(InputScript::new()).shared();
+    let mut with_device = Core::new_mips_default();
+    with_device.map_device(MMIO_BASE, MMIO_LEN, Rc::new(RefCell::new(KeyboardDisplayMmio::new(input)))).unwrap();
+    assert!(with_device.restore(&snapshot).is_err());
+}
+
+#[test]
+fn trace_replay_and_diff() {
+    let reference = vec![
+        0x24020005, // addiu $v0, $zero, 5
+        0x0000000C, // syscall
+        0x27BDFFFC, // addiu $sp, $sp, -4
+        0xAFA20000, // sw $v0, 0($sp)
+        0x8FA40000, // lw $a0, 0($sp)
+        0x24020001, // addiu $v0, $zero, 1
+        0x0000000C, // syscall
+        0x24020011, // addiu $v0, $zero, 17
+        0x0000000C, // syscall
+    ];
+
+    let mut core = Core::new_mips_default();
+    core.load_text(reference.clone());
+    core.start_trace(Tracer::new());
+    let mut syscalls = MarsSyscalls::new();
+    syscalls.push_stdin("12\n");
+    assert!(matches!(core.run(&mut syscalls), StopReason::Exited(12)));
+    let trace = core.stop_trace().unwrap();
+
+    assert_eq!(trace.len(), 9);
+    let text = trace.to_text();
+    assert!(text.lines().nth(1).unwrap().contains("syscall 5"));
+    assert!(text.lines().nth(3).unwrap().contains("store4 [0x7FFFEFF8]=0xC"));
+
+    let filename = std::env::temp_dir().join(format!("mimic_trace_{}.bin", std::process::id()));
+    trace.save(&filename).unwrap();
+    let loaded = Trace::load(&filename).unwrap();
+    fs::remove_file(&filename).unwrap();
+    assert_eq!(loaded, trace);
+
+    // Replaying needs no input and produces the same trace
+    let mut replayed = Core::new_mips_default();
+    replayed.load_text(reference.clone());
+    replayed.start_trace(Tracer::new());
+    let mut replay = TraceReplay::new(&loaded);
+    assert!(matches!(replayed.run(&mut replay), StopReason::Exited(12)));
+    assert_eq!(replayed.stop_trace().unwrap().diff(&trace), None);
+
+    // A student run that stores to the wrong offset diverges at the store
+    let mut student = reference;
+    student[3] = 0xAFA20004; // sw $v0, 4($sp)
+    let mut core = Core::new_mips_default();
+    core.load_text(student);
+    core.start_trace(Tracer::new());
+    let mut replay = TraceReplay::new(&trace);
+    core.run(&mut replay);
+
+    let divergence = core.stop_trace().unwrap().diff(&trace).unwrap();
+    assert_eq!(divergence.position, 3);
+    assert!(divergence.to_string().contains("sw $v0, 4($sp)"));
+}
+
+#[test]
+fn profile_reports_lines_and_labels() {
+    let source = "
+.text
+main:
+    li $s0, 0
+loop:
+    addiu $s0, $s0, 1
+    li $t0, 10
+    bne $s0, $t0, loop
+    li $v0, 10
+    syscall
+";
+    let program = assemble_program_from_string(source.to_owned()).unwrap();
+
+    let mut core = Core::new_mips_default();
+    core.load_program(&program.text, &program.data);
+    core.start_profile(Profiler::new());
+    assert!(matches!(core.run(&mut MarsSyscalls::new()), StopReason::Exited(0)));
+    let profiler = core.stop_profile().unwrap();
+
+    assert_eq!(profiler.total(), 33);
+    assert_eq!(profiler.count_at(0x00400004), 10);
+    assert_eq!(profiler.class_count(InstructionClass::Alu), 22);
+    assert_eq!(profiler.class_count(InstructionClass::Branch), 10);
+    assert_eq!(profiler.class_count(InstructionClass::Other), 1);
+
+    let report = profiler.report(&program.source_map, &program.symbols);
+    let lines: Vec<(usize, u64)> = report.lines.iter().map(|l| (l.line, l.count)).collect();
+    assert_eq!(lines, vec![(4, 1), (6, 10), (7, 10), (8, 10), (9, 1), (10, 1)]);
+    assert_eq!(report.lines[3].source, "bne $s0, $t0, loop");
+    let labels: Vec<(&str, u64)> = report.labels.iter().map(|l| (l.label.as_str(), l.count)).collect();
+    assert_eq!(labels, vec![("main", 1), ("loop", 32)]);
+
+    assert!(report.to_text().contains("Branch"));
+    assert!(report
+        .to_json()
+        .contains("{\"line\":8,\"source\":\"bne $s0, $t0, loop\",\"count\":10}"));
+}
+
+#[test]
+fn coverage_lcov_and_listing() {
+    let source = "
+.text
+main:
+    li $s0, 0
+loop:
+    addiu $s0, $s0, 1
+    li $t0, 3
+    bne $s0, $t0, loop
+    beq $s0, $zero, skip
+    li $v0, 10
+    syscall
+skip:
+    li $v0, 17
+    syscall
+";
+    let program = assemble_program_from_string(source.to_owned()).unwrap();
+
+    let mut core = Core::new_mips_default();
+    core.load_program(&program.text, &program.data);
+    core.start_coverage(Coverage::new());
+    assert!(matches!(core.run(&mut MarsSyscalls::new()), StopReason::Exited(0)));
+    let coverage = core.stop_coverage().unwrap();
+
+    assert!(coverage.is_executed(0x00400010));
+    assert!(!coverage.is_executed(0x0040001C));
+    assert_eq!(coverage.branch(0x0040000C), Some(BranchCoverage { taken: 2, not_taken: 1 }));
+    assert_eq!(coverage.branch(0x00400010), Some(BranchCoverage { taken: 0, not_taken: 1 }));
+
+    let report = coverage.report(&program.text, &program.ktext, &program.source_map, &program.symbols);
+    assert_eq!((report.lines.len(), report.lines_hit()), (9, 7));
+    assert_eq!(report.branch_directions(), (4, 3));
+
+    let lcov = report.to_lcov();
+    for record in ["FN:13,skip", "FNDA:3,loop", "FNH:2", "BRDA:8,0,0,2", "BRDA:9,0,0,0", "DA:6,3", "DA:13,0", "LH:7"] {
+        assert!(lcov.lines().any(|line| line == record), "missing {record}");
+    }
+    assert!(lcov.ends_with("end_of_record\n"));
+
+    let listing = report.to_listing();
+    let lines: Vec<&str> = listing.lines().collect();
+    assert_eq!(lines[2], "        -:    3:main:");
+    assert_eq!(lines[5], "        3:    6:    addiu $s0, $s0, 1");
+    assert!(lines.contains(&"   branch 0x0040000C taken 2, not taken 1"));
+    assert!(lines.contains(&"    #####:   13:    li $v0, 17"));
+}
+
+#[test]
+fn call_stack_backtrace_and_bad_return() {
+    let source = "
+.text
+main:
+    li $a0, 0
+    li $t0, 2
+    li $t1, 0xFFFFFFF8
+    jal down
+    li $v0, 10
+    syscall
+down:
+    addu $sp, $sp, $t1
+    beq $a0, $t0, bottom
+    addiu $a0, $a0, 1
+    jal down
+bottom:
+    move $ra, $zero
+    jr $ra
+";
+    let program = assemble_program_from_string(source.to_owned()).unwrap();
+
+    let mut core = Core::new_mips_default();
+    core.load_program(&program.text, &program.data);
+    core.enable_call_stack(CallStack::new().with_stop_on_mismatch(true));
+    let sp = core.get_register(29);
+    let mut syscalls = MarsSyscalls::new();
+
+    core.add_breakpoint(Breakpoint::parse("bottom", &program.symbols).unwrap());
+    assert!(matches!(core.run(&mut syscalls), StopReason::Breakpoint { address: 0x0040002C, .. }));
+    assert_eq!(core.call_stack().unwrap().depth(), 3);
+
+    let backtrace = core.backtrace().format(&program.symbols, Some(&program.source_map));
+    let expected = [
+        format!("#0  0x0040002C in bottom at line 16: move $ra, $zero  sp={:#010X}", sp - 24),
+        format!("#1  0x00400028 in down+0xC at line 14: jal down  sp={:#010X}", sp - 16),
+        format!("#2  0x00400028 in down+0xC at line 14: jal down  sp={:#010X}", sp - 8),
+        format!("#3  0x00400010 in main+0x10 at line 7: jal down  sp={:#010X}", sp),
+    ];
+    assert_eq!(backtrace.lines().collect::<Vec<_>>(), expected);
+
+    let reason = core.run(&mut syscalls);
+    let mismatch = ReturnMismatch {
+        pc: 0x00400030,
+        expected: Some(0x0040002C),
+        actual: 0,
+    };
+    assert!(matches!(reason, StopReason::UnexpectedReturn(m) if m == mismatch));
+    assert_eq!(core.call_stack().unwrap().mismatches(), &[mismatch]);
+}
+
+#[test]
+fn calling_convention_violations() {
+    let source = "
+.text
+main:
+    li $s0, 1
+    li $t0, 5
+    jal bad
+    addu $t1, $t0, $zero
+    li $t0, 2
+    addu $t1, $t0, $zero
+    jal good
+    li $v0, 10
+    syscall
+bad:
+    li $s0, 7
+    li $t2, 0xFFFFFFF8
+    addu $sp, $sp, $t2
+    jr $ra
+good:
+    li $t0, 3
+    jr $ra
+";
+    let program = assemble_program_from_string(source.to_owned()).unwrap();
+
+    let mut core = Core::new_mips_default();
+    core.load_program(&program.text, &program.data);
+    core.enable_convention_checker(ConventionChecker::new());
+    let sp = core.get_register(29);
+    assert!(matches!(core.run(&mut MarsSyscalls::new()), StopReason::Exited(0)));
+
+    let checker = core.disable_convention_checker().unwrap();
+    assert_eq!(
+        checker.violations(),
+        &[
+            Violation {
+                pc: 0x00400034,
+                kind: ViolationKind::Clobbered {
+                    register: 16,
+                    call_site: 0x00400008,
+                    expected: 1,
+                    actual: 7,
+                },
+            },
+            Violation {
+                pc: 0x00400034,
+                kind: ViolationKind::StackImbalance {
+                    call_site: 0x00400008,
+                    expected: sp,
+                    actual: sp - 8,
+                },
+            },
+            Violation {
+                pc: 0x0040000C,
+                kind: ViolationKind::StaleTemporary {
+                    register: 8,
+                    call_site: 0x00400008,
+                },
+            },
+        ]
+    );
+
+    let report = checker.report(&program.source_map);
+    assert!(report.starts_with("line 17: $s0 not preserved by call at 0x00400008: was 0x1, returned 0x7\n    jr $ra\n"));
+    assert!(report.ends_with("line 7: $t0 read after call at 0x00400008 without being reloaded\n    addu $t1, $t0, $zero\n"));
+
+    let mut core = Core::new_mips_default();
+    core.load_program(&program.text, &program.data);
+    core.enable_convention_checker(ConventionChecker::new().with_stop_on_violation(true));
+    assert!(matches!(
+        core.run(&mut MarsSyscalls::new()),
+        StopReason::ConventionViolation(Violation { pc: 0x00400034, .. })
+    ));
+}
+
+#[test]
+fn return_mismatch_and_violation_on_one_instruction() {
+    let source = "
+.text
+main:
+    li $s0, 1
+    jal outer
+    li $v0, 10
+    syscall
+outer:
+    move $t3, $ra
+    li $s0, 7
+    jal inner
+inner:
+    move $ra, $t3
+    jr $ra
+";
+    let program = assemble_program_from_string(source.to_owned()).unwrap();
+
+    let mut core = Core::new_mips_default();
+    core.load_program(&program.text, &program.data);
+    core.enable_call_stack(CallStack::new().with_stop_on_mismatch(true));
+    core.enable_convention_checker(ConventionChecker::new().with_stop_on_violation(true));
+    let mut syscalls = MarsSyscalls::new();
+
+    // The `jr $ra` skips a frame and returns with $s0 clobbered, so both stops are reported
+    assert!(matches!(
+        core.run(&mut syscalls),
+        StopReason::UnexpectedReturn(ReturnMismatch { pc: 0x00400020, actual: 0x00400008, .. })
+    ));
+    assert!(matches!(
+        core.run(&mut syscalls),
+        StopReason::ConventionViolation(Violation { pc: 0x00400020, .. })
+    ));
+    assert_eq!(core.pc(), 0x00400008);
+    assert!(matches!(core.run(&mut syscalls), StopReason::Exited(0)));
+}
+
+#[test]
+fn data_directives() {
+    let source = "
+.data
+msg: .ascii \"hi\"
+b: .byte 1, 255:2
+h: .half 0x1234
+w: .word 7:2, 0xFFFFFFFF
+buf: .space 3
+al: .align 3
+f: .float 1.5
+d: .double 2
+z: .asciiz \"ok\"
+.text
+main:
+    li $v0, 10
+    syscall
+";
+    let program = assemble_program_from_string(source.to_owned()).unwrap();
+
+    let mut expected = vec![b'h', b'i', 1, 255, 255, 0, 0x34, 0x12];
+    expected.extend([7, 0, 0, 0, 7, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF]);
+    expected.extend([0, 0, 0, 0]);
+    expected.extend(1.5f32.to_le_bytes());
+    expected.extend([0, 0, 0, 0]);
+    expected.extend(2.0f64.to_le_bytes());
+    expected.extend([b'o', b'k', 0]);
+    assert_eq!(&program.data[..expected.len()], &expected[..]);
+
+    let labels = [("msg", 0), ("b", 2), ("h", 6), ("w", 8), ("buf", 20), ("al", 24), ("f", 24), ("d", 32), ("z", 40)];
+    for (label, offset) in labels {
+        assert_eq!(program.symbols.get(label), Some(0x10010000 + offset), "{label}");
+    }
+
+    let errors = [
+        ("x: .byte 1, 256", "256", "Value 256 is out of range [-128, 255]"),
+        ("x: .word 1:0", "0", "Invalid directive: repeat count must be positive"),
+        ("x: .align 4", "4", "Invalid directive: .align expects 0, 1, 2 or 3"),
+        ("x: .half \"no\"", "\"no\"", "Invalid directive: expected an integer"),
+        ("x: .space 1, 2", ".space 1, 2", "Invalid directive: .space takes exactly one value"),
+        ("x: .space 0xFFFFFFFF", "0xFFFFFFFF", "Invalid directive: the data segment is full"),
+        ("x: .word 0:0xFFFFFFF", "0xFFFFFFF", "Invalid directive: the data segment is full"),
+        ("x: .space 0x30000\n.byte 1", "1", "Invalid directive: the data segment is full"),
+        ("add $t0, $t0, $t0", "add $t0, $t0, $t0", "Invalid directive: instructions can't be placed in .data"),
+    ];
+    for (line, spanned, message) in errors {
+        let source = format!(".data\n{line}\n.text\nmain:\n    syscall\n");
+        let error = assemble_program_from_string(source.clone()).unwrap_err();
+        assert_eq!(error.msg(), message);
+        let start = source.find(line).unwrap() + line.find(spanned).unwrap();
+        assert_eq!(error.span.unwrap().range(), start..start + spanned.len(), "{line}");
+    }
+
+    let full = [
+        (".kdata\n.space 0x70000000\nx: .word 1\n", "0x70000000", "kdata"),
+        (".text 0x0FFFFFFC\nsyscall\nsyscall\n", "syscall\n", "text"),
+    ];
+    for (source, spanned, segment) in full {
+        let error = assemble_program_from_string(source.to_owned()).unwrap_err();
+        assert_eq!(error.msg(), format!("Invalid directive: the {segment} segment is full"));
+        let start = source.rfind(spanned).unwrap();
+        assert_eq!(error.span.unwrap().range(), start..start + spanned.trim_end().len(), "{source:?}");
+    }
+
+    // Only .align is allowed among the instructions
+    let program = assemble_program_from_string(".text\nsyscall\n.align 3\nnext: syscall\n.align 2\nend:\n".to_owned()).unwrap();
+    assert_eq!(program.symbols.get("next"), Some(0x00400008));
+    assert_eq!(program.symbols.get("end"), Some(0x0040000C));
+    assert_eq!(&program.text[4..8], &[0; 4]);
+    for line in [".asciiz \"x\"", ".word 1", ".space 4"] {
+        let source = format!(".text\nmain: {line}\n");
+        let error = assemble_program_from_string(source.clone()).unwrap_err();
+        assert_eq!(error.msg(), "Invalid directive: data can't be placed in .text");
+        let start = source.find(line).unwrap();
+        assert_eq!(error.span.unwrap().range(), start..start + line.len(), "{line}");
+    }
+}
+
+#[test]
+fn data_labels_and_references() {
+    let source = "
+.data
+table: .word case0, case1, end
+.byte 1
+value:
+    .word 5, value
+.asciiz \"x\"
+end:
+.text
+main:
+    li $t0, 1
+case0:
+    li $v0, 10
+case1:
+    syscall
+";
+    let program = assemble_program_from_string(source.to_owned()).unwrap();
+
+    assert_eq!(program.symbols.get("value"), Some(0x10010010));
+    assert_eq!(program.symbols.get("end"), Some(0x1001001A));
+    let words: Vec<u32> = program.data[..24]
+        .chunks(4)
+        .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+        .collect();
+    assert_eq!(words, vec![0x00400004, 0x00400008, 0x1001001A, 1, 5, 0x10010010]);
+
+    let source = ".data\n.word 1, nowhere\n.text\nmain:\n    syscall\n";
+    let error = assemble_program_from_string(source.to_owned()).unwrap_err();
+    assert_eq!(error.msg(), "Unknown symbol [nowhere]");
+    assert_eq!(error.span.unwrap().range(), 15..22);
+}
+
+#[test]
+fn interleaved_sections_and_origins() {
+    let source = "
+.data
+first: .word 1
+.text
+main:
+    jal helper
+    li $v0, 10
+    syscall
+.data
+second: .word 2, first
+.text 0x00400020
+helper:
+    jr $ra
+.kdata
+kword: .word handler
+.ktext
+handler:
+    j handler
+.data 0x10010100
+third: .byte 3
+";
+    let program = assemble_program_from_string(source.to_owned()).unwrap();
+
+    let symbols = [("first", 0x10010000), ("second", 0x10010004), ("third", 0x10010100), ("helper", 0x00400020), ("kword", 0x90000000), ("handler", 0x80000000)];
+    for (label, address) in symbols {
+        assert_eq!(program.symbols.get(label), Some(address), "{label}");
+    }
+    assert_eq!(&program.data[..12], &[1, 0, 0, 0, 2, 0, 0, 0, 0x00, 0x00, 0x01, 0x10]);
+    assert_eq!(program.data[0x100], 3);
+    assert_eq!(program.text.len(), 0x24);
+    assert_eq!(&program.text[..4], &0x0C100008u32.to_le_bytes());
+    assert_eq!(program.source_map.line_at(0x0040000C), None);
+    assert_eq!(program.source_map.line_at(0x00400020), Some(13));
+    assert_eq!(program.source_map.line_at(0x80000000), Some(18));
+    assert_eq!(program.source_map.line_at(0x80000004), None);
+    assert_eq!(program.source_map.addresses(18).collect::<Vec<_>>(), [0x80000000]);
+    assert_eq!(program.ktext, 0x08000000u32.to_le_bytes());
+    assert_eq!(program.kdata, 0x80000000u32.to_le_bytes());
+
+    let mut core = Core::new_mips_default();
+    core.load_program(&program.text, &program.data);
+    core.load_kernel(&program.ktext, &program.kdata);
+    assert_eq!(core.read_word(0x90000000).unwrap(), 0x80000000);
+    assert!(matches!(core.run(&mut MarsSyscalls::new()), StopReason::Exited(0)));
+
+    let errors = [
+        (".data 0x10000000", "0x10000000 is outside the data segment"),
+        (".text 0x00400000", "0x400000 overlaps earlier contents of the segment"),
+        (".text 0x00400006", "code must be word aligned"),
+        (".text main + 8", "expected a constant"),
+    ];
+    for (directive, message) in errors {
+        let source = format!(".text\nmain:\n    syscall\n{directive}\n    syscall\n");
+        let error = assemble_program_from_string(source).unwrap_err();
+        assert_eq!(error.msg(), format!("Invalid directive: {message}"));
+    }
+
+    // Origins are constant expressions
+    let source = ".eqv BASE 0x10010000 + 0x10\n.data BASE + 8\nx: .word 1\n.data (BASE) * 1 + 0x20\ny: .word 2\n";
+    let program = assemble_program_from_string(source.to_owned()).unwrap();
+    assert_eq!(program.symbols.get("x"), Some(0x10010018));
+    assert_eq!(program.symbols.get("y"), Some(0x10010030));
+}
+
+#[test]
+fn labels_share_addresses_and_carry_over() {
+    let source = "
+.text
+a:
+b:  addi $t0, $zero, 1
+f:
+.data
+value: .word 1
+.text
+g:  jal end
+    j a
+end:
+";
+    let program = assemble_program_from_string(source.to_owned()).unwrap();
+
+    let symbols = [("a", 0x00400000), ("b", 0x00400000), ("f", 0x00400004), ("g", 0x00400004), ("end", 0x0040000C), ("value", 0x10010000)];
+    for (label, address) in symbols {
+        assert_eq!(program.symbols.get(label), Some(address), "{label}");
+    }
+    assert_eq!(&program.text[4..8], &0x0C100003u32.to_le_bytes());
+
+    let duplicates = [
+        ".text\na: syscall\na: syscall\n",
+        ".data\nfoo: .word 1\n.text\nfoo: syscall\n",
+        ".text\nfoo: syscall\n.kdata\nfoo: .word 1\n",
+    ];
+    for source in duplicates {
+        let error = assemble_program_from_string(source.to_owned()).unwrap_err();
+        let name = if source.contains("foo") { "foo" } else { "a" };
+        assert_eq!(error.msg(), format!("Symbol [{name}] is already defined"));
+        let start = source.rfind(&format!("{name}:")).unwrap();
+        assert_eq!(error.span.unwrap().range(), start..start + name.len(), "{source:?}");
+    }
+}
+
+#[test]
+fn memory_operands() {
+    let source = "
+.data
+pad: .space 16
+array: .word 10, 20, 30, 40
+.text
+main:
+    li $t1, 4
+    lw $t0, array
+    lw $t2, array+8($t1)
+    sw $t2, ($sp)
+    lw $t3, 0($sp)
+    sh $t1, array+2
+    lbu $t4, array+2
+    lw $t5, 0x10018000
+    li $v0, 10
+    syscall
+";
+    let program = assemble_program_from_string(source.to_owned()).unwrap();
+    let words: Vec<u32> = program
+        .text
+        .chunks(4)
+        .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+        .collect();
+    assert_eq!(
+        &words[1..8],
+        &[
+            0x3C011001, // lui $at, 0x1001
+            0x8C280010, // lw $t0, 0x10($at)
+            0x3C011001, // lui $at, 0x1001
+            0x00290821, // addu $at, $at, $t1
+            0x8C2A0018, // lw $t2, 0x18($at)
+            0xAFAA0000, // sw $t2, 0($sp)
+            0x8FAB0000, // lw $t3, 0($sp)
+        ]
+    );
+    // The low half is negative once sign extended, so the high half is rounded up
+    assert_eq!(&words[12..14], &[0x3C011002, 0x8C2D8000]);
+
+    let mut core = Core::new_mips_default();
+    core.load_program(&program.text, &program.data);
+    core.run_until(&mut MarsSyscalls::new(), 0x00400030);
+    assert_eq!(core.get_register(8), 10);
+    assert_eq!(core.get_register(10), 40);
+    assert_eq!(core.get_register(11), 40);
+    assert_eq!(core.get_register(12), 4);
+    assert_eq!(core.read_word(0x10010010).unwrap(), 0x0004000A);
+
+    let source = ".text\nmain:\n    lw $t0, nowhere+4($t1)\n";
+    let error = assemble_program_from_string(source.to_owned()).unwrap_err();
+    assert_eq!(error.msg(), "Unknown symbol [nowhere]");
+    assert_eq!(error.span.unwrap().range(), 24..31);
+}
+
+#[test]
+fn constant_expressions() {
+    let source = "
+.data
+start: .word end - start, 'A' | 0x20, -1
+half: .half -2, 3 * (4 + 1)
+end:
+.text
+main:
+    li $t0, (1 << 12) - 1
+    li $t1, -8
+    li $t2, 0xFFFF
+    li $t3, end - start + 0x10000
+    lui $t4, %hi(half)
+    lh $t5, %lo(half)($t4)
+    la $t6, start + 4
+    lw $t7, -4($t6)
+    addiu $s0, $zero, 'z' - 'a'
+    sll $s1, $s0, 32 / 8
+    beq $zero, $zero, skip
+    li $s2, 1
+skip:
+    j exit
+    li $s2, 2
+exit:
+    li $v0, 10
+    syscall
+";
+    let program = assemble_program_from_string(source.to_owned()).unwrap();
+    let mut expected = vec![16, 0, 0, 0, 0x61, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF];
+    expected.extend([0xFE, 0xFF, 15, 0]);
+    assert_eq!(&program.data[..expected.len()], &expected[..]);
+
+    let words: Vec<u32> = program
+        .text
+        .chunks(4)
+        .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+        .collect();
+    assert_eq!(
+        &words[..3],
+        &[
+            0x24080FFF, // addiu $t0, $zero, 4095
+            0x2409FFF8, // addiu $t1, $zero, -8
+            0x340AFFFF, // ori $t2, $zero, 0xFFFF
+        ]
+    );
+
+    let mut core = Core::new_mips_default();
+    core.load_program(&program.text, &program.data);
+    core.run(&mut MarsSyscalls::new());
+    assert_eq!(core.get_register(8), 4095);
+    assert_eq!(core.get_register(9), 0xFFFFFFF8);
+    assert_eq!(core.get_register(10), 0xFFFF);
+    assert_eq!(core.get_register(11), 0x10010);
+    assert_eq!(core.get_register(13), 0xFFFFFFFE);
+    assert_eq!(core.get_register(14), 0x10010004);
+    assert_eq!(core.get_register(15), 16);
+    assert_eq!(core.get_register(16), 25);
+    assert_eq!(core.get_register(17), 400);
+    assert_eq!(core.get_register(18), 0);
+
+    let errors = [
+        ("addiu $t0, $zero, 0x8000", "0x8000", "Value 32768 is out of range [-32768, 32767]"),
+        ("ori $t0, $t0, -1", "-1", "Value -1 is out of range [0, 65535]"),
+        ("sll $t0, $t0, 4 * 8", "4 * 8", "Value 32 is out of range [0, 31]"),
+        ("li $t0, 1 / (2 - 2)", "2 - 2", "Invalid expression: division by zero"),
+        ("j main + 2", "main + 2", "Invalid expression: jump target is not word aligned"),
+        ("la $t0, missing", "missing", "Unknown symbol [missing]"),
+    ];
+    for (line, spanned, message) in errors {
+        let source = format!(".text\nmain:\n    {line}\n");
+        let error = assemble_program_from_string(source.clone()).unwrap_err();
+        assert_eq!(error.msg(), message);
+        let start = source.find(line).unwrap() + line.find(spanned).unwrap();
+        assert_eq!(error.span.unwrap().range(), start..start + spanned.len(), "{line}");
+    }
+}
+
+#[test]
+fn syntax_errors() {
+    let errors = [
+        (".data\n.word 1,\n", 14..14, "Syntax error: unexpected end of line"),
+        (".text\naddi $t0, $t0, (\nsyscall\n", 22..22, "Syntax error: unexpected end of line"),
+        (".text\naddi $t0, $t0, (", 22..22, "Syntax error: unexpected end of file"),
+        (".text\naddi $t0, , 1\n", 16..17, "Syntax error: unexpected [,]"),
+        ("", 0..0, "Syntax error: the program is empty"),
+        ("  # nothing\n", 12..12, "Syntax error: the program is empty"),
+        ("addi $t0, $t0, 1\n.text\n", 0..4, "Syntax error: expected .text or .data before [addi]"),
+        (".macro m\nadd $t0, , $t0\n.end_macro\n.text\nm\n", 41..42, "Syntax error: unexpected [,] (in macro [m])"),
+    ];
+    for (source, span, message) in errors {
+        let error = assemble_program_from_string(source.to_owned()).unwrap_err();
+        assert_eq!(error.msg(), message, "{source:?}");
+        assert_eq!(error.span.unwrap().range(), span, "{source:?}");
+    }
+}
+
+#[test]
+fn integer_literals() {
+    let source = "
+.data
+.word 0b1010, 0O17, -1, -0x80000000, '\\n', '\\''
+.byte -128, 0xff
+.text
+main:
+    addi $t0, $zero, -1
+    addiu $t1, $zero, 0b1111
+    ori $t2, $zero, 0o777
+    addiu $t3, $t0, -0x8000
+    li $t4, -2147483648
+    li $v0, 10
+    syscall
+";
+    let program = assemble_program_from_string(source.to_owned()).unwrap();
+    let mut expected = vec![10, 0, 0, 0, 15, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0x80];
+    expected.extend([b'\n', 0, 0, 0, b'\'', 0, 0, 0, 0x80, 0xFF]);
+    assert_eq!(&program.data[..expected.len()], &expected[..]);
+
+    let mut core = Core::new_mips_default();
+    core.load_program(&program.text, &program.data);
+    core.run(&mut MarsSyscalls::new());
+    assert_eq!(core.get_register(8), 0xFFFFFFFF);
+    assert_eq!(core.get_register(9), 15);
+    assert_eq!(core.get_register(10), 0o777);
+    assert_eq!(core.get_register(11), 0xFFFF7FFF);
+    assert_eq!(core.get_register(12), 0x80000000);
+
+    let errors = [
+        ("li $t0, 4294967296", "4294967296", "Integer literal [4294967296] does not fit in 32 bits"),
+        ("li $t0, 0x1FFFFFFFF", "0x1FFFFFFFF", "Integer literal [0x1FFFFFFFF] does not fit in 32 bits"),
+        ("li $t0, 99999999999999999999999", "99999999999999999999999", "Integer literal [99999999999999999999999] does not fit in 32 bits"),
+        ("li $t0, '\\q'", "'\\q'", "Unknown token ['\\q']"),
+        ("addiu $t0, $zero, -32769", "-32769", "Value -32769 is out of range [-32768, 32767]"),
+    ];
+    for (line, spanned, message) in errors {
+        let source = format!(".text\nmain:\n    {line}\n");
+        let error = assemble_program_from_string(source.clone()).unwrap_err();
+        assert_eq!(error.msg(), message);
+        let start = source.find(line).unwrap() + line.find(spanned).unwrap();
+        assert_eq!(error.span.unwrap().range(), start..start + spanned.len(), "{line}");
+    }
+}
+
+#[test]
+fn string_escapes() {
+    let source = r#"
+.data
+s: .ascii "tab\there\\ \"q\" \x41\101\0"
+u: .asciiz "é€"
+c: .byte '\x7f', '\177', '\?'
+.text
+main:
+    li $v0, 10
+    syscall
+"#;
+    let program = assemble_program_from_string(source.to_owned()).unwrap();
+    let mut expected = b"tab\there\\ \"q\" AA\0".to_vec();
+    expected.extend("é€".as_bytes());
+    expected.extend([0, 0x7F, 0x7F, b'?']);
+    assert_eq!(&program.data[..expected.len()], &expected[..]);
+    // Non-ASCII text is stored as UTF-8, so labels count bytes
+    assert_eq!(program.symbols.get("c"), Some(0x10010000 + 17 + 6));
+
+    let errors = [
+        (r#".ascii "ok\q""#, r"\q"),
+        (r#".asciiz "\xZZ""#, r"\x"),
+        (r#".ascii "a", "b\777""#, r"\777"),
+        (r#".ascii "\é""#, r"\é"),
+    ];
+    for (line, spanned) in errors {
+        let source = format!(".data\n{line}\n.text\nmain:\n    syscall\n");
+        let error = assemble_program_from_string(source.clone()).unwrap_err();
+        assert_eq!(error.msg(), format!("Invalid escape sequence [{spanned}]"));
+        let start = source.find(line).unwrap() + line.find(spanned).unwrap();
+        assert_eq!(error.span.unwrap().range(), start..start + spanned.len(), "{line}");
+    }
+}
+
+#[test]
+fn pseudo_instructions() {
+    // Each li and b takes the shortest expansion
+    let program = assemble_program_from_string("
+.text
+main:
+    li $t0, -5
+    li $t0, 0xABCD
+    li $t0, 0x10000
+    li $t0, 0x12345678
+    b main
+".to_owned()).unwrap();
+    let words: Vec<u32> = program.text.chunks(4).map(|w| u32::from_le_bytes(w.try_into().unwrap())).collect();
+    assert_eq!(words, vec![0x2408FFFB, 0x3408ABCD, 0x3C080001, 0x3C011234, 0x34285678, 0x1000FFFA]);
+
+    let source = "
+.data
+res: .space 64
+buf: .byte 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88
+.text
+main:
+    la $s0, res
+    li $t0, -5
+    abs $t1, $t0
+    neg $t2, $t1
+    not $t3, $zero
+    sw $t1, 0($s0)
+    sw $t2, res+4
+    sw $t3, res+8
+
+    seq $t1, $t0, -5
+    sne $t2, $t0, $t0
+    sge $t3, $t0, 3
+    sgt $t4, $t0, -0x12345
+    sle $t5, $t0, -6
+    sgeu $t6, $t0, 3
+    sw $t1, 12($s0)
+    sw $t2, 16($s0)
+    sw $t3, 20($s0)
+    sw $t4, 24($s0)
+    sw $t5, 28($s0)
+    sw $t6, 32($s0)
+
+    li $t0, -7
+    mul $t1, $t0, 3
+    div $t2, $t0, 2
+    rem $t3, $t0, 2
+    mulu $t4, $t0, 0x10000
+    sw $t1, 36($s0)
+    sw $t2, 40($s0)
+    sw $t3, 44($s0)
+    sw $t4, 48($s0)
+
+    ulw $t1, buf+1
+    la $s1, buf+2
+    usw $t1, 3($s1)
+    ulw $s2, 1($s1)
+
+    li $s3, 0
+    li $t0, -5
+    blt $t0, $zero, l1
+    j fail
+l1: bgt $t0, -6, l2
+    j fail
+l2: ble $t0, -5, l3
+    j fail
+l3: bge $t0, 0, fail
+    bltu $t0, 1, fail
+    bgtu $t0, 0x7FFFFFFF, l4
+    j fail
+l4: beqz $zero, l5
+    j fail
+l5: bnez $t0, l6
+    j fail
+l6: bleu $zero, $t0, done
+fail:
+    li $s3, 1
+done:
+    li $v0, 10
+    syscall
+";
+    let program = assemble_program_from_string(source.to_owned()).unwrap();
+    let mut core = Core::new_mips_default();
+    core.load_program(&program.text, &program.data);
+    core.run(&mut MarsSyscalls::new());
+
+    let expected: [u32; 13] = [5, -5i32 as u32, 0xFFFFFFFF, 1, 0, 0, 1, 0, 1, -21i32 as u32, -3i32 as u32, -1i32 as u32, 0xFFF90000];
+    for (i, value) in expected.iter().enumerate() {
+        assert_eq!(core.read_word(0x10010000 + 4 * i as u32).unwrap(), *value, "res+{}", 4 * i);
+    }
+    assert_eq!(core.get_register(9), 0x55443322);
+    assert_eq!(core.get_register(18), 0x33225544);
+    assert_eq!(core.read_word(0x10010044).unwrap(), 0x44332255);
+    assert_eq!(core.get_register(19), 0);
+
+    let errors = [
+        ("move $t0, 5", "Invalid operands for [move]"),
+        ("li $t0, $t1", "Invalid operands for [li]"),
+        ("blt $t0, 4, $t1", "Invalid operands for [blt]"),
+        ("ulw $t0", "Invalid operands for [ulw]"),
+    ];
+    for (line, message) in errors {
+        let source = format!(".text\nmain:\n    {line}\n");
+        let error = assemble_program_from_string(source.clone()).unwrap_err();
+        assert_eq!(error.msg(), message);
+        let start = source.find(line).unwrap();
+        assert_eq!(error.span.unwrap().range(), start..start + line.len(), "{line}");
+    }
+}
+
+#[test]
+fn rotates_and_wide_immediates() {
+    let source = "
+.text
+main:
+    li $t0, 0x80000001
+    li $t3, 4
+    rol $t1, $t0, 4
+    ror $t2, $t0, $t3
+    subi $t4, $t0, 2
+    addi $t5, $zero, 0x12345
+    li $v0, 10
+    syscall
+";
+    let program = assemble_program_from_string(source.to_owned()).unwrap();
+    let mut core = Core::new_mips_default();
+    core.load_program(&program.text, &program.data);
+    core.run(&mut MarsSyscalls::new());
+    assert_eq!(core.get_register(9), 0x00000018);
+    assert_eq!(core.get_register(10), 0x18000000);
+    assert_eq!(core.get_register(12), 0x7FFFFFFF);
+    assert_eq!(core.get_register(13), 0x12345);
+}
+
+#[test]
+fn statements_end_at_newlines() {
+    let source = "
+.text
+main:
+    nop
+    break; nop
+loop: j loop
+";
+    let program = assemble_program_from_string(source.to_owned()).unwrap();
+    let words: Vec<u32> = program.text.chunks(4).map(|w| u32::from_le_bytes(w.try_into().unwrap())).collect();
+    assert_eq!(words, vec![0, 0x0000000D, 0, 0x08100003]);
+    assert_eq!(program.symbols.get("loop"), Some(0x0040000C));
+
+    let error = assemble_program_from_string(".text\nnop $t0\n".to_owned()).unwrap_err();
+    assert_eq!(error.msg(), "Invalid operands for [nop]");
+    let error = assemble_program_from_string(".text\naddi $t0, $t0, 1 addi $t0, $t0, 1\n".to_owned()).unwrap_err();
+    assert_eq!(error.msg(), "Syntax error: unexpected [addi]");
+}
+
+#[test]
+fn macros_eqv_and_include() {
+    let program = assemble_program_from_file("test_files/mips32/macros.asm").unwrap();
+    let mut core = Core::new_mips_default();
+    core.load_program(&program.text, &program.data);
+    let mut syscalls = MarsSyscalls::new();
+    core.run(&mut syscalls);
+    assert_eq!(syscalls.output(), "21");
+
+    // Expanded instructions belong to the line of the call
+    let sum = program.symbols.get("main").unwrap() + 4;
+    assert_eq!(program.source_map.line_at(sum), Some(17));
+    assert_eq!(program.source_map.line_text(17), Some("    sum_to(COUNT)"));
+    assert!(program.symbols.get("loop_M0").is_some());
+
+    // An error in a macro body is reported there and at the call
+    let source = "
+.macro big(%r)
+    addiu %r, %r, 70000
+.end_macro
+.text
+main:
+    big($t0)
+";
+    let error = assemble_program_from_string(source.to_owned()).unwrap_err();
+    assert_eq!(error.msg(), "Value 70000 is out of range [-32768, 32767] (in macro [big])");
+    let call = source.find("big($t0)").unwrap();
+    assert_eq!(error.span.unwrap().range(), call..call + 3);
+    let MimicErrorType::MacroExpansion { error: inner, .. } = &error.ty else { panic!("{:?}", error.ty) };
+    let body = source.find("70000").unwrap();
+    assert_eq!(inner.span.unwrap().range(), body..body + 5);
+
+    let errors = [
+        (".macro m(%a)\n.end_macro\n.text\nmain:\n    m 1, 2\n", "m 1, 2", "m", "Invalid macro: macro [m] does not take 2 arguments"),
+        (".macro sq\n.text\n", ".macro sq", "sq", "Invalid directive: .macro without .end_macro"),
+        (".text\n.end_macro\n", ".end_macro", ".end_macro", "Invalid directive: .end_macro without .macro"),
+        (".include \"missing.asm\"\n", "\"missing.asm\"", "\"missing.asm\"", "Invalid directive: unable to read [missing.asm]"),
+        (".eqv N\n", ".eqv", ".eqv", "Invalid directive: expected a name and a value"),
+    ];
+    for (source, line, spanned, message) in errors {
+        let error = assemble_program_from_string(source.to_owned()).unwrap_err();
+        assert_eq!(error.msg(), message);
+        let start = source.find(line).unwrap() + line.find(spanned).unwrap();
+        assert_eq!(error.span.unwrap().range(), start..start + spanned.len(), "{source}");
+    }
+}
+
+#[test]
+fn conditional_assembly() {
+    let source = "
+.ifndef SIZE
+.set SIZE, 4
+.endif
+.set STEP, SIZE / 2
+.set STEP, STEP + 1
+
+.data
+array: .space SIZE * 4
+.text
+main:
+    li $t0, SIZE
+    li $t1, STEP
+.if SIZE > 8
+    li $t2, 2
+.elseif SIZE > 2 && STEP != 0
+    li $t2, 1
+    .if 0
+        li $t2, 99
+    .else
+        li $t3, 7
+    .endif
+.else
+    li $t2, 0
+.endif
+.ifdef DEBUG
+    li $t4, 1
+.endif
+    li $v0, 10
+    syscall
+";
+    let run = |options: &AssemblerOptions| {
+        let program = assemble_program_from_string_with_options(source.to_owned(), options).unwrap();
+        let mut core = Core::new_mips_default();
+        core.load_program(&program.text, &program.data);
+        core.run(&mut MarsSyscalls::new());
+        (program.data.len(), (8..13).map(|r| core.get_register(r)).collect::<Vec<_>>())
+    };
+
+    assert_eq!(run(&AssemblerOptions::new()), (16, vec![4, 3, 1, 7, 0]));
+    assert_eq!(run(&AssemblerOptions::new().with_definition("SIZE=0x10").with_define("DEBUG", 1)), (64, vec![16, 9, 2, 0, 1]));
+    assert_eq!(run(&AssemblerOptions::new().with_definition(" SIZE = 2 ")), (8, vec![2, 2, 0, 0, 0]));
+
+    let program = assemble_program_from_string_with_options(".data\n.space SIZE\n.text\nmain:\n    syscall\n".to_owned(), &AssemblerOptions::new().with_definition("SIZE")).unwrap();
+    assert_eq!(program.data.len(), 1);
+
+    let errors = [
+        (".text\n.else\n", ".else", "Invalid directive: .else without .if"),
+        (".if 1\n.text\n", ".if", "Invalid directive: .if without .endif"),
+        (".if 1\n.else\n.elseif 1\n.endif\n", ".elseif", "Invalid directive: .elseif after .else"),
+        (".text\n.endif\n", ".endif", "Invalid directive: .endif without .if"),
+        (".if missing + 1\n.endif\n", "missing", "Unknown symbol [missing]"),
+        (".if 1 +\n.endif\n", "1 +", "Invalid expression: expected an expression"),
+        (".set X\n", ".set", "Invalid directive: expected an expression"),
+        (".if\n.endif\n", ".if", "Invalid directive: expected an expression"),
+        (".set N, -1\n.data\n.space N * 4\n.text\n", "N * 4", "Invalid directive: .space expects a non-negative size"),
+        (".ifdef\n.endif\n", ".ifdef", "Invalid directive: expected a name"),
+    ];
+    for (source, spanned, message) in errors {
+        let error = assemble_program_from_string(source.to_owned()).unwrap_err();
+        assert_eq!(error.msg(), message, "{source}");
+        let start = source.find(spanned).unwrap();
+        assert_eq!(error.span.unwrap().range(), start..start + spanned.len(), "{source}");
+    }
+
+    let error = assemble_program_from_string_with_options(".text\n".to_owned(), &AssemblerOptions::new().with_definition("1X=2")).unwrap_err();
+    assert_eq!(error.msg(), "Invalid directive: invalid symbol name [1X]");
+    let error = assemble_program_from_string_with_options(".text\n".to_owned(), &AssemblerOptions::new().with_definition("N=4 * (2")).unwrap_err();
+    assert_eq!(error.msg(), "Invalid expression: expected an expression");
+    assert_eq!(error.source.unwrap().source(), "N=4 * (2");
+}